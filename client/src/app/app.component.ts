@@ -9,9 +9,15 @@ import {
   signal,
   effect,
   Signal,
+  isDevMode,
 } from '@angular/core';
 import { FormsModule } from '@angular/forms';
 
+// `ng serve` runs on its own port, a production build is served by the Rust server itself
+const SOCKET_URL = isDevMode()
+  ? 'ws://127.0.0.1:8080/room'
+  : `${location.protocol === 'https:' ? 'wss' : 'ws'}://${location.host}/room`;
+
 @Component({
   selector: 'app-root',
   templateUrl: './app.component.html',
@@ -26,7 +32,7 @@ export class AppComponent implements AfterViewInit {
 
   title = 'ng-19-ws-demo';
 
-  private socket = new WebSocket('ws://127.0.0.1:8080/room');
+  private socket = new WebSocket(SOCKET_URL);
   private eventListeners: (() => void)[] = [];
 
   brushSize = 16;
@@ -105,8 +111,9 @@ export class AppComponent implements AfterViewInit {
         case 'Draw':
           this.draw(command.data as DrawCommand);
           break;
+        // Sent by servers from before composite draws
         case 'Erase':
-          this.draw({ ...command.data, color: 'white' } as DrawCommand);
+          this.draw({ ...command.data, color: 'white', composite: 'destination-out' } as DrawCommand);
           break;
         case 'Clear':
           this.clearCanvas();
@@ -187,14 +194,15 @@ export class AppComponent implements AfterViewInit {
     this.isDrawing = false;
   }
 
-  private draw({ prev, brush_size, cur, color }: DrawCommand) {
+  private draw({ prev, brush_size, cur, color, composite }: DrawCommand) {
     const ctx = this.context();
     if (!ctx) return;
 
     ctx.beginPath();
     ctx.lineWidth = brush_size;
     ctx.lineCap = 'round';
-    ctx.strokeStyle = color;
+    // The board is white, so an erase paints white rather than cutting through the canvas
+    ctx.strokeStyle = composite === 'destination-out' ? 'white' : color;
     ctx.moveTo(prev[0], prev[1]);
     ctx.lineTo(cur[0], cur[1]);
     ctx.stroke();
@@ -221,6 +229,7 @@ export interface DrawCommand {
   cur: [number, number];
   brush_size: number;
   color: string;
+  composite?: 'source-over' | 'destination-out';
 }
 
 export interface WsMessage {
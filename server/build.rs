@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Compiled with protox so building the gRPC API doesn't need protoc installed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/whiteboard.proto");
+        let descriptors = protox::compile(["whiteboard.proto"], ["proto"]).expect("invalid proto/whiteboard.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("could not generate gRPC code");
+    }
+}
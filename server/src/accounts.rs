@@ -0,0 +1,679 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use argon2::password_hash::{PasswordHasher, PasswordVerifier};
+use argon2::Argon2;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ConfigHandle;
+use crate::events::now_millis;
+use crate::hub::Hub;
+use crate::ids::random_token;
+use crate::openapi::ApiError;
+use crate::protocol::Role;
+
+const MAX_USERNAME_CHARS: usize = 32;
+// Hashing takes time with the input's length, so nobody gets to hash megabytes
+const MAX_PASSWORD_BYTES: usize = 1024;
+const MAX_BODY_BYTES: u64 = 4 * 1024;
+// Checked against for usernames nobody has, so logging in takes as long as with a wrong password
+// and how long it takes doesn't tell which usernames exist. Argon2's default parameters, like the
+// hashes `register` makes
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$5TOjP32XuLzTX7VJGTXs+Q$cqdHcoOtSo5OP3v3iOr8TbgCwKA52pZ7WJzdTgECxLk";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        password_hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        token_hash TEXT PRIMARY KEY,
+        account_id INTEGER NOT NULL REFERENCES accounts (id),
+        expires_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS room_owners (
+        room TEXT PRIMARY KEY,
+        account_id INTEGER NOT NULL REFERENCES accounts (id)
+    );
+    CREATE TABLE IF NOT EXISTS room_members (
+        room TEXT NOT NULL,
+        account_id INTEGER NOT NULL REFERENCES accounts (id),
+        PRIMARY KEY (room, account_id)
+    );
+    CREATE TABLE IF NOT EXISTS private_rooms (
+        room TEXT PRIMARY KEY
+    );
+";
+
+/// A registered user
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Account {
+    pub id: i64,
+    pub username: String,
+    /// Unix seconds
+    pub created_at: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LoggedIn {
+    /// Sent as a bearer token, or as `?token=` where headers can't be set, like WebSocket upgrades
+    token: String,
+    /// Unix seconds
+    expires_at: i64,
+    account: Account,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct LinkRequest {
+    #[serde(default)]
+    role: Role,
+    /// Defaults to `links.default_ttl_secs`, and can't be over `links.max_ttl_secs`
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct JoinLink {
+    /// Passed as `?link=` on the WebSocket upgrade
+    link: String,
+    /// The upgrade URL with the link in it, relative to the server
+    url: String,
+    role: Role,
+    /// Unix seconds
+    expires_at: u64,
+}
+
+/// Who may join a room besides its owner, a room with no members is open to everyone
+#[derive(Serialize, ToSchema)]
+struct RoomAccess {
+    owner: Option<String>,
+    members: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    Invalid(String),
+    UsernameTaken,
+    BadCredentials,
+    Database(rusqlite::Error),
+    Internal(String),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Invalid(message) => write!(f, "{}", message),
+            AccountError::UsernameTaken => write!(f, "username is taken"),
+            AccountError::BadCredentials => write!(f, "wrong username or password"),
+            AccountError::Database(e) => write!(f, "database error: {}", e),
+            AccountError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AccountError {
+    fn from(e: rusqlite::Error) -> Self {
+        AccountError::Database(e)
+    }
+}
+
+// SQLite integers are signed
+fn now_secs() -> i64 {
+    (now_millis() / 1000) as i64
+}
+
+// Only a hash is stored, so a leaked database doesn't hand out logins
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn validate_username(username: &str) -> Result<(), AccountError> {
+    let valid_chars = username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if username.is_empty() || username.len() > MAX_USERNAME_CHARS || !valid_chars {
+        return Err(AccountError::Invalid(format!("usernames are 1 to {} letters, digits, '-', '_' or '.'", MAX_USERNAME_CHARS)));
+    }
+    Ok(())
+}
+
+/// Accounts, login sessions, room owners and access lists, kept in SQLite. Lookups are quick
+/// enough to make from async code, `register` and `login` hash and belong on a blocking thread
+pub struct Accounts {
+    db: Mutex<Connection>,
+}
+
+impl Accounts {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let db = Connection::open(path)?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Accounts { db: Mutex::new(db) })
+    }
+
+    pub fn register(&self, username: &str, password: &str, min_password_chars: usize) -> Result<Account, AccountError> {
+        validate_username(username)?;
+        if password.chars().count() < min_password_chars {
+            return Err(AccountError::Invalid(format!("passwords need at least {} characters", min_password_chars)));
+        }
+        if password.len() > MAX_PASSWORD_BYTES {
+            return Err(AccountError::Invalid(format!("passwords can't be longer than {} bytes", MAX_PASSWORD_BYTES)));
+        }
+        let hash = Argon2::default().hash_password(password.as_bytes()).map_err(|e| AccountError::Internal(e.to_string()))?.to_string();
+        let created_at = now_secs();
+
+        let db = self.db.lock().unwrap();
+        match db.execute("INSERT INTO accounts (username, password_hash, created_at) VALUES (?1, ?2, ?3)", params![username, hash, created_at]) {
+            Ok(_) => Ok(Account { id: db.last_insert_rowid(), username: username.to_string(), created_at }),
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation => Err(AccountError::UsernameTaken),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// A new session token for the account, valid for `ttl`
+    pub fn login(&self, username: &str, password: &str, ttl: Duration) -> Result<(String, i64, Account), AccountError> {
+        if password.len() > MAX_PASSWORD_BYTES {
+            return Err(AccountError::BadCredentials);
+        }
+        let found = self.db.lock().unwrap().query_row(
+            "SELECT id, username, created_at, password_hash FROM accounts WHERE username = ?1",
+            params![username],
+            |row| Ok((Account { id: row.get(0)?, username: row.get(1)?, created_at: row.get(2)? }, row.get::<_, String>(3)?)),
+        ).optional()?;
+        let (account, hash) = match found {
+            Some((account, hash)) => (Some(account), hash),
+            None => (None, DUMMY_HASH.to_string()),
+        };
+        let verified = Argon2::default().verify_password(password.as_bytes(), hash.as_str()).is_ok();
+        let Some(account) = account.filter(|_| verified) else {
+            return Err(AccountError::BadCredentials);
+        };
+
+        let token = random_token();
+        let now = now_secs();
+        let expires_at = now.saturating_add(ttl.as_secs().try_into().unwrap_or(i64::MAX));
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+        db.execute("INSERT INTO sessions (token_hash, account_id, expires_at) VALUES (?1, ?2, ?3)", params![hash_token(&token), account.id, expires_at])?;
+        Ok((token, expires_at, account))
+    }
+
+    pub fn logout(&self, token: &str) -> Result<(), AccountError> {
+        self.db.lock().unwrap().execute("DELETE FROM sessions WHERE token_hash = ?1", params![hash_token(token)])?;
+        Ok(())
+    }
+
+    /// The account a session token belongs to, if it's still valid
+    pub fn session(&self, token: &str) -> Result<Option<Account>, AccountError> {
+        let account = self.db.lock().unwrap().query_row(
+            "SELECT a.id, a.username, a.created_at FROM sessions s JOIN accounts a ON a.id = s.account_id
+             WHERE s.token_hash = ?1 AND s.expires_at > ?2",
+            params![hash_token(token), now_secs()],
+            |row| Ok(Account { id: row.get(0)?, username: row.get(1)?, created_at: row.get(2)? }),
+        ).optional()?;
+        Ok(account)
+    }
+
+    /// Make the account the room's owner, unless it already has one
+    pub fn claim(&self, room: &str, account_id: i64) -> Result<(), AccountError> {
+        self.db.lock().unwrap().execute("INSERT OR IGNORE INTO room_owners (room, account_id) VALUES (?1, ?2)", params![room, account_id])?;
+        Ok(())
+    }
+
+    pub fn owner(&self, room: &str) -> Result<Option<i64>, AccountError> {
+        let owner = self.db.lock().unwrap().query_row("SELECT account_id FROM room_owners WHERE room = ?1", params![room], |row| row.get(0)).optional()?;
+        Ok(owner)
+    }
+
+    /// The username of the room's owner
+    pub fn owner_name(&self, room: &str) -> Result<Option<String>, AccountError> {
+        let owner = self.db.lock().unwrap().query_row(
+            "SELECT a.username FROM room_owners o JOIN accounts a ON a.id = o.account_id WHERE o.room = ?1",
+            params![room],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(owner)
+    }
+
+    fn access(&self, room: &str) -> Result<RoomAccess, AccountError> {
+        let owner = self.owner_name(room)?;
+        let db = self.db.lock().unwrap();
+        let mut members = db.prepare("SELECT a.username FROM room_members m JOIN accounts a ON a.id = m.account_id WHERE m.room = ?1 ORDER BY a.username")?;
+        let members = members.query_map(params![room], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+        Ok(RoomAccess { owner, members })
+    }
+
+    fn add_member(&self, room: &str, username: &str) -> Result<(), AccountError> {
+        let db = self.db.lock().unwrap();
+        let added = db.execute("INSERT OR IGNORE INTO room_members (room, account_id) SELECT ?1, id FROM accounts WHERE username = ?2", params![room, username])?;
+        let exists = added > 0 || db.query_row("SELECT EXISTS (SELECT 1 FROM accounts WHERE username = ?1)", params![username], |row| row.get(0))?;
+        match exists {
+            true => Ok(()),
+            false => Err(AccountError::Invalid(format!("no account named {}", username))),
+        }
+    }
+
+    fn remove_member(&self, room: &str, username: &str) -> Result<(), AccountError> {
+        self.db.lock().unwrap().execute(
+            "DELETE FROM room_members WHERE room = ?1 AND account_id IN (SELECT id FROM accounts WHERE username = ?2)",
+            params![room, username],
+        )?;
+        Ok(())
+    }
+
+    /// Drop a room's owner, members and privacy, for a room that's gone for good
+    pub fn forget(&self, room: &str) -> Result<(), AccountError> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        for table in ["room_owners", "room_members", "private_rooms"] {
+            tx.execute(&format!("DELETE FROM {} WHERE room = ?1", table), params![room])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Keep everyone but the owner and members out of a room even while it has no members,
+    /// for `Visibility::Private`
+    pub fn set_private(&self, room: &str, private: bool) -> Result<(), AccountError> {
+        let db = self.db.lock().unwrap();
+        match private {
+            true => db.execute("INSERT OR IGNORE INTO private_rooms (room) VALUES (?1)", params![room])?,
+            false => db.execute("DELETE FROM private_rooms WHERE room = ?1", params![room])?,
+        };
+        Ok(())
+    }
+
+    /// Whether a room is open, or the session belongs to its owner or one of its members
+    pub fn may_join(&self, room: &str, token: Option<&str>) -> Result<bool, AccountError> {
+        let db = self.db.lock().unwrap();
+        let restricted: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM room_members WHERE room = ?1) OR EXISTS (SELECT 1 FROM private_rooms WHERE room = ?1)",
+            params![room],
+            |row| row.get(0),
+        )?;
+        let Some(token) = token.filter(|_| restricted) else {
+            return Ok(!restricted);
+        };
+        let allowed = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sessions s WHERE s.token_hash = ?1 AND s.expires_at > ?2 AND (
+                s.account_id IN (SELECT account_id FROM room_members WHERE room = ?3)
+                OR s.account_id IN (SELECT account_id FROM room_owners WHERE room = ?3)))",
+            params![hash_token(token), now_secs(), room],
+            |row| row.get(0),
+        )?;
+        Ok(allowed)
+    }
+}
+
+/// The routes under `/api/accounts` and `/api/rooms/<id>/members`, all 404 unless `accounts.enabled` is set
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+    let authorization = warp::header::optional::<String>("authorization");
+
+    let register = warp::path!("api" / "accounts")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(hub.clone())
+        .and(config.clone())
+        .and_then(register);
+
+    let login = warp::path!("api" / "accounts" / "login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(hub.clone())
+        .and(config.clone())
+        .and_then(login);
+
+    let logout = warp::path!("api" / "accounts" / "logout")
+        .and(warp::post())
+        .and(authorization)
+        .and(hub.clone())
+        .and_then(logout);
+
+    let me = warp::path!("api" / "accounts" / "me")
+        .and(warp::get())
+        .and(authorization)
+        .and(hub.clone())
+        .and_then(current_account);
+
+    let members = warp::path!("api" / "rooms" / String / "members")
+        .and(warp::get())
+        .and(authorization)
+        .and(hub.clone())
+        .and_then(room_members);
+
+    let add = warp::path!("api" / "rooms" / String / "members" / String)
+        .and(warp::put())
+        .and(authorization)
+        .and(hub.clone())
+        .and_then(add_member);
+
+    let remove = warp::path!("api" / "rooms" / String / "members" / String)
+        .and(warp::delete())
+        .and(authorization)
+        .and(hub.clone())
+        .and_then(remove_member);
+
+    let links = warp::path!("api" / "rooms" / String / "links")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(authorization)
+        .and(hub)
+        .and(config)
+        .and_then(create_link);
+
+    register.or(login).or(logout).or(me).or(members).or(add).or(remove).or(links)
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+fn failed(e: AccountError) -> Box<dyn Reply> {
+    match e {
+        AccountError::Invalid(_) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        AccountError::UsernameTaken => error(StatusCode::CONFLICT, &e.to_string()),
+        AccountError::BadCredentials => error(StatusCode::UNAUTHORIZED, &e.to_string()),
+        AccountError::Database(_) | AccountError::Internal(_) => {
+            log::error!("Accounts request failed: {}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+        }
+    }
+}
+
+pub fn bearer(header: Option<&str>) -> Option<&str> {
+    header?.strip_prefix("Bearer ")
+}
+
+// Answered rather than rejected while disabled, warp would turn a rejection here into a 405 from another route
+fn enabled(hub: &Hub) -> Result<Arc<Accounts>, Box<dyn Reply>> {
+    hub.accounts.clone().ok_or_else(|| error(StatusCode::NOT_FOUND, "not found"))
+}
+
+// Parsed here rather than with `warp::body::json`, whose rejections other routes would turn into a 405
+fn parse_credentials(body: &[u8]) -> Result<Credentials, Box<dyn Reply>> {
+    serde_json::from_slice(body).map_err(|e| error(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e)))
+}
+
+fn signed_in(accounts: &Accounts, header: Option<&str>) -> Result<Account, Box<dyn Reply>> {
+    match bearer(header).map(|token| accounts.session(token)) {
+        Some(Ok(Some(account))) => Ok(account),
+        Some(Err(e)) => Err(failed(e)),
+        _ => Err(error(StatusCode::UNAUTHORIZED, "missing or expired session")),
+    }
+}
+
+/// The id the room has here, if the signed in account owns it
+fn owner_of(hub: &Hub, accounts: &Accounts, room: &str, header: Option<&str>) -> Result<String, Box<dyn Reply>> {
+    let Some(room) = hub.room_id(room) else {
+        return Err(error(StatusCode::BAD_REQUEST, "invalid room id"));
+    };
+    let account = signed_in(accounts, header)?;
+    match accounts.owner(&room) {
+        Ok(Some(owner)) if owner == account.id => Ok(room),
+        Ok(_) => Err(error(StatusCode::FORBIDDEN, "only the room's owner can do that")),
+        Err(e) => Err(failed(e)),
+    }
+}
+
+async fn blocking<T: Send + 'static>(task: impl FnOnce() -> Result<T, AccountError> + Send + 'static) -> Result<T, AccountError> {
+    tokio::task::spawn_blocking(task).await.unwrap_or_else(|e| Err(AccountError::Internal(e.to_string())))
+}
+
+/// `POST /api/accounts`, register a username. Guests can still join any room that has no access list
+#[utoipa::path(
+    post,
+    path = "/api/accounts",
+    tag = "accounts",
+    request_body = Credentials,
+    responses(
+        (status = 201, description = "Registered, log in to get a session", body = Account),
+        (status = 400, description = "Invalid username or password", body = ApiError),
+        (status = 409, description = "The username is taken", body = ApiError),
+    ),
+)]
+async fn register(body: Bytes, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let (accounts, credentials) = match enabled(&hub).and_then(|accounts| Ok((accounts, parse_credentials(&body)?))) {
+        Ok(parsed) => parsed,
+        Err(reply) => return Ok(reply),
+    };
+    let min_password_chars = config.borrow().accounts.min_password_chars;
+    match blocking(move || accounts.register(&credentials.username, &credentials.password, min_password_chars)).await {
+        Ok(account) => {
+            log::info!("Registered account {} ({})", account.username, account.id);
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&account), StatusCode::CREATED)))
+        }
+        Err(e) => Ok(failed(e)),
+    }
+}
+
+/// `POST /api/accounts/login`, a session token lasting `accounts.session_ttl_secs`
+#[utoipa::path(
+    post,
+    path = "/api/accounts/login",
+    tag = "accounts",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "OK", body = LoggedIn),
+        (status = 401, description = "Wrong username or password", body = ApiError),
+    ),
+)]
+async fn login(body: Bytes, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let (accounts, credentials) = match enabled(&hub).and_then(|accounts| Ok((accounts, parse_credentials(&body)?))) {
+        Ok(parsed) => parsed,
+        Err(reply) => return Ok(reply),
+    };
+    let ttl = Duration::from_secs(config.borrow().accounts.session_ttl_secs);
+    match blocking(move || accounts.login(&credentials.username, &credentials.password, ttl)).await {
+        Ok((token, expires_at, account)) => Ok(Box::new(warp::reply::json(&LoggedIn { token, expires_at, account }))),
+        Err(e) => Ok(failed(e)),
+    }
+}
+
+/// `POST /api/accounts/logout`, ends the bearer token's session
+#[utoipa::path(
+    post,
+    path = "/api/accounts/logout",
+    tag = "accounts",
+    responses((status = 204, description = "Logged out, or the session had already ended")),
+    security(("session_token" = [])),
+)]
+async fn logout(header: Option<String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    match bearer(header.as_deref()).map(|token| accounts.logout(token)) {
+        Some(Err(e)) => Ok(failed(e)),
+        _ => Ok(Box::new(StatusCode::NO_CONTENT)),
+    }
+}
+
+/// `GET /api/accounts/me`, the bearer token's account
+#[utoipa::path(
+    get,
+    path = "/api/accounts/me",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "OK", body = Account),
+        (status = 401, description = "Missing or expired session", body = ApiError),
+    ),
+    security(("session_token" = [])),
+)]
+async fn current_account(header: Option<String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    match signed_in(&accounts, header.as_deref()) {
+        Ok(account) => Ok(Box::new(warp::reply::json(&account))),
+        Err(reply) => Ok(reply),
+    }
+}
+
+/// `GET /api/rooms/<id>/members`, the room's access list, for its owner
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/members",
+    tag = "accounts",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 200, description = "OK", body = RoomAccess),
+        (status = 401, description = "Missing or expired session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+    ),
+    security(("session_token" = [])),
+)]
+async fn room_members(id: String, header: Option<String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    let id = match owner_of(&hub, &accounts, &id, header.as_deref()) {
+        Ok(id) => id,
+        Err(reply) => return Ok(reply),
+    };
+    match accounts.access(&id) {
+        Ok(access) => Ok(Box::new(warp::reply::json(&access))),
+        Err(e) => Ok(failed(e)),
+    }
+}
+
+/// `PUT /api/rooms/<id>/members/<username>`, let an account join. From the first member on, only
+/// the owner and members can join or read the room
+#[utoipa::path(
+    put,
+    path = "/api/rooms/{id}/members/{username}",
+    tag = "accounts",
+    params(("id" = String, Path, description = "Room id"), ("username" = String, Path, description = "Account to add")),
+    responses(
+        (status = 204, description = "Added"),
+        (status = 400, description = "No such account", body = ApiError),
+        (status = 401, description = "Missing or expired session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+    ),
+    security(("session_token" = [])),
+)]
+async fn add_member(id: String, username: String, header: Option<String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    let id = match owner_of(&hub, &accounts, &id, header.as_deref()) {
+        Ok(id) => id,
+        Err(reply) => return Ok(reply),
+    };
+    match accounts.add_member(&id, &username) {
+        Ok(()) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Err(e) => Ok(failed(e)),
+    }
+}
+
+/// `DELETE /api/rooms/<id>/members/<username>`, the room opens up again once the last member is removed.
+/// People already in the room stay until they leave
+#[utoipa::path(
+    delete,
+    path = "/api/rooms/{id}/members/{username}",
+    tag = "accounts",
+    params(("id" = String, Path, description = "Room id"), ("username" = String, Path, description = "Account to remove")),
+    responses(
+        (status = 204, description = "Removed, or wasn't a member"),
+        (status = 401, description = "Missing or expired session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+    ),
+    security(("session_token" = [])),
+)]
+async fn remove_member(id: String, username: String, header: Option<String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    let id = match owner_of(&hub, &accounts, &id, header.as_deref()) {
+        Ok(id) => id,
+        Err(reply) => return Ok(reply),
+    };
+    match accounts.remove_member(&id, &username) {
+        Ok(()) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Err(e) => Ok(failed(e)),
+    }
+}
+
+/// `POST /api/rooms/<id>/links`, a link anyone can join the room with until it expires, without an
+/// access key or being on the access list. Viewers can't change the board
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/links",
+    tag = "accounts",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = LinkRequest,
+    responses(
+        (status = 201, description = "Created", body = JoinLink),
+        (status = 400, description = "Invalid body or lifetime", body = ApiError),
+        (status = 401, description = "Missing or expired session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+    ),
+    security(("session_token" = [])),
+)]
+async fn create_link(id: String, body: Bytes, header: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let accounts = match enabled(&hub) {
+        Ok(accounts) => accounts,
+        Err(reply) => return Ok(reply),
+    };
+    let id = match owner_of(&hub, &accounts, &id, header.as_deref()) {
+        Ok(id) => id,
+        Err(reply) => return Ok(reply),
+    };
+    let request: LinkRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e))),
+    };
+    let links = config.borrow().links.clone();
+    let ttl_secs = request.ttl_secs.unwrap_or(links.default_ttl_secs);
+    if ttl_secs == 0 || ttl_secs > links.max_ttl_secs {
+        return Ok(error(StatusCode::BAD_REQUEST, &format!("links last 1 to {} seconds", links.max_ttl_secs)));
+    }
+    let expires_at = (now_millis() / 1000).saturating_add(ttl_secs);
+    let link = hub.links.sign(&links.secret, &id, request.role, expires_at);
+    log::info!("Created a {} link into room {}, expiring at {}", request.role.name(), id, expires_at);
+    let url = format!("/room/{}?link={}", hub.public_id(&id), link);
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&JoinLink { link, url, role: request.role, expires_at }), StatusCode::CREATED)))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::PasswordHash;
+
+    use super::*;
+
+    #[test]
+    fn unknown_usernames_are_checked_like_wrong_passwords() {
+        let accounts = Accounts::open(Path::new(":memory:")).unwrap();
+        accounts.register("ann", "correct horse", 8).unwrap();
+        let ttl = Duration::from_secs(60);
+        assert!(matches!(accounts.login("ann", "wrong horse", ttl), Err(AccountError::BadCredentials)));
+        assert!(matches!(accounts.login("nobody", "correct horse", ttl), Err(AccountError::BadCredentials)));
+        assert_eq!(accounts.login("ann", "correct horse", ttl).unwrap().2.username, "ann");
+
+        // Hashed like every account's, or it would take a different time to check
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        let hashed = Argon2::default().hash_password(b"correct horse").unwrap();
+        assert_eq!((dummy.algorithm, dummy.version, &dummy.params), (hashed.algorithm, hashed.version, &hashed.params));
+    }
+}
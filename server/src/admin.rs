@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use warp::ws::{Message, WebSocket};
+
+use crate::hub::Hub;
+
+/// Stream every server event to an admin socket until it closes
+pub async fn stream_events(ws: WebSocket, hub: Arc<Hub>) {
+    let (mut tx, mut rx) = ws.split();
+    let mut events = hub.events.subscribe();
+    log::info!("Admin monitor connected");
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let text = match event {
+                    Ok(event) => serde_json::to_string(&event),
+                    Err(RecvError::Lagged(skipped)) => serde_json::to_string(&serde_json::json!({ "event": "lagged", "skipped": skipped })),
+                    Err(RecvError::Closed) => break,
+                };
+                let text = match text {
+                    Ok(text) => text,
+                    Err(e) => {
+                        log::error!("Serialization error: {}", e);
+                        continue;
+                    }
+                };
+                if tx.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // Monitors only listen, anything from them besides a close is ignored
+            msg = rx.next() => match msg {
+                Some(Ok(msg)) if !msg.is_close() => {}
+                _ => break,
+            }
+        }
+    }
+
+    log::info!("Admin monitor disconnected");
+}
@@ -0,0 +1,1244 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::ws::Message;
+use warp::{Filter, Rejection, Reply};
+
+use crate::accounts;
+use crate::admin;
+use crate::cluster;
+use crate::config::ConfigHandle;
+use crate::connection::ConnectionSnapshot;
+use crate::events::now_millis;
+use crate::hub::{is_archived, valid_room_id, Hub};
+use crate::ids::{self, UserId};
+use crate::metrics;
+use crate::moderation;
+use crate::openapi::ApiError;
+use crate::protocol::MessageType;
+use crate::render::{self, Region};
+use crate::replica;
+use crate::room::{RoomInfo, SessionSummary, Stroke, Template, UserContribution, Visibility};
+use crate::socket::{Inbound, Rejected};
+use crate::sse;
+use crate::tenants;
+use crate::trial;
+use crate::usage::UsageReport;
+
+// Generous for a batch of strokes, each one is still held to `limits.max_message_bytes`
+const MAX_COMMANDS_BYTES: u64 = 1024 * 1024;
+const MAX_ROOM_INFO_BYTES: usize = 4 * 1024;
+
+const DEFAULT_REPLAY_SECS: f64 = 5.0;
+
+#[derive(Serialize, ToSchema)]
+struct RoomStats {
+    id: String,
+    info: RoomInfo,
+    /// People in the room, a signed-in user's tabs count once
+    participants: usize,
+    total_strokes: u64,
+    history_size: usize,
+    /// WebSocket joiners waiting for a slot
+    waitlisted: usize,
+    messages_in_per_sec: f64,
+    messages_out_per_sec: f64,
+    age_secs: u64,
+    /// Ping round trips of the room's connections, None before any has answered a ping
+    median_rtt_ms: Option<f64>,
+    max_rtt_ms: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RoomCreated {
+    /// What it goes by in URLs, its own id unless `public_ids.scheme` says otherwise
+    id: String,
+    /// Unix seconds, when a trial room ends, see `trial`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// A public room resident in memory, for strangers looking for a board to join
+#[derive(Serialize, ToSchema)]
+struct LobbyRoom {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// A signed-in user's tabs count once
+    participants: usize,
+    /// `/api/rooms/<id>/thumbnail.png`, once one has been rendered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Lobby {
+    rooms: Vec<LobbyRoom>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct TemplateRequest {
+    /// Letters, digits, `-` and `_`, like room ids
+    name: String,
+}
+
+/// A template without its board
+#[derive(Serialize, ToSchema)]
+struct TemplateSummary {
+    name: String,
+    info: RoomInfo,
+    /// Ops on its board
+    ops: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saved_by: Option<String>,
+    /// Unix seconds
+    saved_at: u64,
+}
+
+impl From<&Template> for TemplateSummary {
+    fn from(template: &Template) -> Self {
+        TemplateSummary {
+            name: template.name.clone(),
+            info: template.info.clone(),
+            ops: template.history.len(),
+            saved_by: template.saved_by.clone(),
+            saved_at: template.saved_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Templates {
+    templates: Vec<TemplateSummary>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CommandRejection {
+    index: usize,
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CommandsApplied {
+    user_id: UserId,
+    applied: usize,
+    rejected: Vec<CommandRejection>,
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+
+    let create_config = config.clone();
+    let create = warp::path!("api" / "rooms")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        // Not length-limited up front, which would refuse the bodiless requests that used to be all there was
+        .and(warp::body::bytes())
+        .and(hub.clone())
+        .and_then(move |query, authorization, body, hub| create_room(query, authorization, body, hub, create_config.clone()));
+
+    let lobby_config = config.clone();
+    let lobby = warp::path!("api" / "lobby")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |query, hub| lobby(query, hub, lobby_config.clone()));
+
+    let archive_config = config.clone();
+    let archive = warp::path!("api" / "rooms" / String / "archive")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(hub.clone())
+        .and_then(move |id, authorization, hub| archive_room(id, authorization, hub, archive_config.clone()));
+
+    let restore_config = config.clone();
+    let restore = warp::path!("api" / "rooms" / String / "restore")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(hub.clone())
+        .and_then(move |id, authorization, hub| restore_room(id, authorization, hub, restore_config.clone()));
+
+    let save_template_config = config.clone();
+    let save_template = warp::path!("api" / "rooms" / String / "template")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(MAX_ROOM_INFO_BYTES as u64))
+        .and(warp::body::bytes())
+        .and(hub.clone())
+        .and_then(move |id, authorization, body, hub| save_template(id, authorization, body, hub, save_template_config.clone()));
+
+    let templates_config = config.clone();
+    let templates = warp::path!("api" / "templates")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |query, hub| templates(query, hub, templates_config.clone()));
+
+    let stats = warp::path!("api" / "rooms" / String / "stats")
+        .and(warp::get())
+        .and(hub.clone())
+        .and_then(room_stats);
+
+    let contributions_config = config.clone();
+    let contributions = warp::path!("api" / "rooms" / String / "contributions")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| room_contributions(id, query, hub, contributions_config.clone()));
+
+    let timeline_config = config.clone();
+    let timeline = warp::path!("api" / "rooms" / String / "users" / UserId / "timeline")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, user_id, query, hub| user_timeline(id, user_id, query, hub, timeline_config.clone()));
+
+    let sessions_config = config.clone();
+    let sessions = warp::path!("api" / "rooms" / String / "sessions")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| room_sessions(id, query, hub, sessions_config.clone()));
+
+    let sse_config = config.clone();
+    let room_events = warp::path!("api" / "rooms" / String / "events")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| sse::room_events(id, query, hub, sse_config.clone()));
+
+    let render_config = config.clone();
+    let render = warp::path!("api" / "rooms" / String / "render.png")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| render_room(id, query, hub, render_config.clone()));
+
+    let svg_config = config.clone();
+    let svg = warp::path!("api" / "rooms" / String / "export.svg")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| export_svg(id, query, hub, svg_config.clone()));
+
+    let replay_config = config.clone();
+    let replay = warp::path!("api" / "rooms" / String / "replay.gif")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| replay_gif(id, query, hub, replay_config.clone()));
+
+    let thumbnail_config = config.clone();
+    let thumbnail = warp::path!("api" / "rooms" / String / "thumbnail.png")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(move |id, query, hub| room_thumbnail(id, query, hub, thumbnail_config.clone()));
+
+    let commands_config = config.clone();
+    let commands = warp::path!("api" / "rooms" / String / "commands")
+        .and(warp::post())
+        .and(admin(config.clone()))
+        .and(warp::body::content_length_limit(MAX_COMMANDS_BYTES))
+        .and(warp::body::bytes())
+        .and(hub.clone())
+        .and_then(move |id, body, hub| inject_commands(id, body, hub, commands_config.clone()));
+
+    let connections = warp::path!("api" / "admin" / "connections")
+        .and(warp::get())
+        .and(admin(config.clone()))
+        .and(hub.clone())
+        .and_then(list_connections);
+
+    let connection = warp::path!("api" / "admin" / "connections" / String)
+        .and(warp::get())
+        .and(admin(config.clone()))
+        .and(hub.clone())
+        .and_then(get_connection);
+
+    let usage = warp::path!("api" / "admin" / "usage")
+        .and(warp::get())
+        .and(admin(config.clone()))
+        .and(hub.clone())
+        .and_then(usage_report);
+
+    let events = warp::path!("admin" / "events")
+        .and(admin_socket(config))
+        .and(warp::ws())
+        .and(hub.clone())
+        .map(|ws: warp::ws::Ws, hub| ws.on_upgrade(move |socket| admin::stream_events(socket, hub)));
+
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(hub.clone())
+        .then(|hub: Arc<Hub>| async move {
+            let body = metrics::render(&hub).await;
+            warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4")
+        });
+
+    create.or(lobby).or(archive).or(restore).or(save_template).or(templates).or(stats).or(contributions).or(timeline).or(sessions).or(room_events).or(render).or(svg).or(replay).or(thumbnail).or(commands).or(connections).or(connection).or(usage).or(events).or(metrics)
+}
+
+/// Passes only requests carrying one of `auth.admin_tokens` as a bearer token
+pub fn admin(config: ConfigHandle) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let config = config.clone();
+            async move { authorized(&config, accounts::bearer(header.as_deref())) }
+        })
+        .untuple_one()
+}
+
+/// `admin` for WebSocket upgrades, which also take the token as `?token=` for browsers that
+/// can't set headers. Nowhere else, query strings end up in access logs and browser history
+pub fn admin_socket(config: ConfigHandle) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |header: Option<String>, query: HashMap<String, String>| {
+            let config = config.clone();
+            async move { authorized(&config, accounts::bearer(header.as_deref()).or(query.get("token").map(String::as_str))) }
+        })
+        .untuple_one()
+}
+
+fn authorized(config: &ConfigHandle, token: Option<&str>) -> Result<(), Rejection> {
+    match token {
+        Some(token) if ids::is_one_of(&config.borrow().auth.admin_tokens, token) => Ok(()),
+        _ => Err(warp::reject::custom(Unauthorized)),
+    }
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized")
+    } else if err.find::<replica::ReadOnly>().is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "read replica, rooms are joined and changed on the primary")
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found")
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else {
+        log::debug!("Unhandled rejection: {:?}", err);
+        (StatusCode::BAD_REQUEST, "bad request")
+    };
+    Ok(error(status, message))
+}
+
+fn error(status: StatusCode, message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status)
+}
+
+fn not_found(what: &str) -> Box<dyn Reply> {
+    Box::new(error(StatusCode::NOT_FOUND, &format!("{} not found", what)))
+}
+
+/// `POST /api/rooms`, a new room with an id nobody can guess, for boards that shouldn't be open to
+/// whoever tries a name. Joining any other id still works as before. Created with a login session,
+/// the account owns the room and can give it an access list. The body, if any, names the room,
+/// says who can find it, when it opens and closes, and what's done to it on a schedule.
+/// `?template=` starts it from a saved template
+#[utoipa::path(
+    post,
+    path = "/api/rooms",
+    tag = "rooms",
+    params(
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("template" = Option<String>, Query, description = "Start with this template's board, and its info unless there's a body"),
+    ),
+    request_body(content = Option<RoomInfo>, description = "Unlisted and unnamed without one"),
+    responses(
+        (status = 201, description = "Join it at `/room/{id}`", body = RoomCreated),
+        (status = 400, description = "Invalid body or schedule, a closing time in the past, or a private room without a session", body = ApiError),
+        (status = 401, description = "Missing or invalid key, or an expired session", body = ApiError),
+        (status = 404, description = "No such template", body = ApiError),
+        (status = 413, description = "The body is too large", body = ApiError),
+        (status = 422, description = "Its name, description or a tag was refused by `moderation.url`", body = ApiError),
+    ),
+    security((), ("session_token" = [])),
+)]
+async fn create_room(query: HashMap<String, String>, authorization: Option<String>, body: Bytes, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let authorized = {
+        let keys = &config.borrow().auth.access_keys;
+        keys.is_empty() || query.get("key").is_some_and(|k| keys.contains(k))
+    };
+    if !authorized {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if body.len() > MAX_ROOM_INFO_BYTES {
+        return Ok(Box::new(error(StatusCode::PAYLOAD_TOO_LARGE, "body is too large")));
+    }
+    let info = match body.is_empty() {
+        true => None,
+        false => match serde_json::from_slice::<RoomInfo>(&body).map_err(|e| format!("invalid body: {}", e)).and_then(RoomInfo::normalize) {
+            Ok(info) => Some(info),
+            Err(e) => return Ok(Box::new(error(StatusCode::BAD_REQUEST, &e))),
+        },
+    };
+    let template = match query.get("template") {
+        Some(name) => match hub.template(name).await {
+            Ok(Some(template)) => Some(template),
+            Ok(None) => return Ok(Box::new(error(StatusCode::NOT_FOUND, "no such template"))),
+            Err(e) => {
+                log::error!("Could not load template {}: {}", name, e);
+                return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")));
+            }
+        },
+        None => None,
+    };
+    let (info, history) = match template {
+        Some(template) => (info.or(Some(template.info)), template.history),
+        None => (info, Vec::new()),
+    };
+    let owner = match (&hub.accounts, accounts::bearer(authorization.as_deref())) {
+        (Some(accounts), Some(token)) => match accounts.session(token) {
+            Ok(Some(account)) => Some((accounts, account)),
+            Ok(None) => return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or expired session"))),
+            Err(e) => {
+                log::error!("Could not look up a session: {}", e);
+                return Ok(Box::new(error(StatusCode::INTERNAL_SERVER_ERROR, "internal error")));
+            }
+        },
+        _ => None,
+    };
+    if info.as_ref().is_some_and(RoomInfo::has_closed) {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "closes_at has already passed")));
+    }
+    // Nobody else could get in, not even the person creating it
+    if owner.is_none() && info.as_ref().is_some_and(|info| info.visibility == Visibility::Private) {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "private rooms need an owner, create them with a login session")));
+    }
+    // Visitors without a session get a throwaway room
+    let trial = owner.is_none() && config.borrow().trial.enabled;
+    let id = match trial {
+        true => trial::random_room_id(),
+        false => ids::random_room_id(),
+    };
+    if let Some(info) = &info {
+        let moderation = config.borrow().moderation.clone();
+        match hub.moderation.check(&moderation, &id, &moderation::room_texts(info)).await {
+            Ok(()) => {}
+            Err(refused) if refused.code == "unavailable" => return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, &refused.message))),
+            Err(refused) => return Ok(Box::new(error(StatusCode::UNPROCESSABLE_ENTITY, &refused.message))),
+        }
+    }
+    if let Some((accounts, account)) = owner {
+        if let Err(e) = accounts.claim(&id, account.id) {
+            log::error!("Could not record the owner of room {}: {}", id, e);
+            return Ok(Box::new(error(StatusCode::INTERNAL_SERVER_ERROR, "internal error")));
+        }
+        log::info!("Account {} created room {}", account.username, id);
+    }
+    let created = match info {
+        Some(info) => hub.create(&id, info, history).await,
+        None => hub.open(&id).await,
+    };
+    if let Err(e) = created {
+        log::error!("Could not create room {}: {}", id, e);
+        return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")));
+    }
+    let expires_at = trial.then(|| config.borrow().trial.ttl_secs).filter(|&ttl| ttl > 0).map(|ttl| now_millis() / 1000 + ttl);
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&RoomCreated { id: hub.public_id(&id), expires_at }), StatusCode::CREATED)))
+}
+
+/// `GET /api/lobby`, the public rooms resident in memory, busiest first. A public room that was
+/// unloaded shows up again once someone joins it
+#[utoipa::path(
+    get,
+    path = "/api/lobby",
+    tag = "rooms",
+    params(("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set")),
+    responses(
+        (status = 200, description = "OK", body = Lobby),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+    ),
+)]
+async fn lobby(query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let authorized = {
+        let keys = &config.borrow().auth.access_keys;
+        keys.is_empty() || query.get("key").is_some_and(|k| keys.contains(k))
+    };
+    if !authorized {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    let mut rooms = Vec::new();
+    for room in hub.rooms().await {
+        let room = room.read().await;
+        // Giving a public room an access list takes it out of the lobby, strangers couldn't join it,
+        // and tenants' rooms are only theirs to list
+        if room.info.visibility != Visibility::Public || !hub.may_access(&room.id, None) || tenants::is_scoped(&room.id) {
+            continue;
+        }
+        let id = hub.public_id(&room.id);
+        rooms.push(LobbyRoom {
+            thumbnail: hub.thumbnails.has(&room.id).then(|| format!("/api/rooms/{}/thumbnail.png", id)),
+            id,
+            name: room.info.name.clone(),
+            description: room.info.description.clone(),
+            tags: room.info.tags.clone(),
+            participants: room.participants(),
+        });
+    }
+    rooms.sort_by(|a, b| b.participants.cmp(&a.participants).then_with(|| a.id.cmp(&b.id)));
+    Ok(Box::new(warp::reply::json(&Lobby { rooms })))
+}
+
+/// Admins, and the room's owner when accounts are on
+/// The room's id here and the owner's username, None for an admin token. Admins can also name the
+/// room by its own id rather than its public one
+fn owner_or_admin(id: &str, authorization: Option<&str>, hub: &Hub, config: &ConfigHandle) -> Result<(String, Option<String>), Box<dyn Reply>> {
+    let token = accounts::bearer(authorization);
+    let admin = token.is_some_and(|token| ids::is_one_of(&config.borrow().auth.admin_tokens, token));
+    let room_id = match hub.room_id(id) {
+        Some(room_id) => room_id,
+        None if admin && valid_room_id(id) => id.to_string(),
+        None => return Err(Box::new(error(StatusCode::BAD_REQUEST, "invalid room id"))),
+    };
+    if admin {
+        return Ok((room_id, None));
+    }
+    let Some(account) = hub.account(token) else {
+        return Err(Box::new(error(StatusCode::UNAUTHORIZED, "unauthorized")));
+    };
+    match hub.owner(&room_id) {
+        Some(owner) if owner == account.username => Ok((room_id, Some(owner))),
+        _ => Err(Box::new(error(StatusCode::FORBIDDEN, "only the room's owner can do that"))),
+    }
+}
+
+/// `POST /api/rooms/<id>/archive`, save the board and unload the room, closing everyone's connection
+/// with 1001. Until it's restored, joining gets a 410 and so does rendering it
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/archive",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 204, description = "Archived"),
+        (status = 401, description = "Neither an admin token nor a login session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+        (status = 409, description = "Already archived", body = ApiError),
+    ),
+    security(("admin_token" = []), ("session_token" = [])),
+)]
+async fn archive_room(id: String, authorization: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let id = match owner_or_admin(&id, authorization.as_deref(), &hub, &config) {
+        Ok((id, _)) => id,
+        Err(reply) => return Ok(reply),
+    };
+    match hub.archive(&id).await {
+        Ok(()) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Err(e) if is_archived(&e) => Ok(Box::new(error(StatusCode::CONFLICT, "room is already archived"))),
+        Err(e) => {
+            log::error!("Could not archive room {}: {}", id, e);
+            Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")))
+        }
+    }
+}
+
+/// `POST /api/rooms/<id>/restore`, make an archived room joinable again with the board it was archived with
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/restore",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 204, description = "Restored, it loads on the next join"),
+        (status = 401, description = "Neither an admin token nor a login session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+        (status = 404, description = "The room isn't archived", body = ApiError),
+    ),
+    security(("admin_token" = []), ("session_token" = [])),
+)]
+async fn restore_room(id: String, authorization: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let id = match owner_or_admin(&id, authorization.as_deref(), &hub, &config) {
+        Ok((id, _)) => id,
+        Err(reply) => return Ok(reply),
+    };
+    match hub.restore(&id).await {
+        Ok(true) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Ok(false) => Ok(Box::new(error(StatusCode::NOT_FOUND, "room isn't archived"))),
+        Err(e) => {
+            log::error!("Could not restore room {}: {}", id, e);
+            Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")))
+        }
+    }
+}
+
+/// `POST /api/rooms/<id>/template`, save the room's board and info as a template that new rooms can
+/// start from. Templates are shared by the whole server, one saved by another account can only be
+/// replaced with an admin token
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/template",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = TemplateRequest,
+    responses(
+        (status = 201, description = "Saved, replacing any template of the same name", body = TemplateSummary),
+        (status = 400, description = "Invalid body or template name", body = ApiError),
+        (status = 401, description = "Neither an admin token nor a login session", body = ApiError),
+        (status = 403, description = "Not the room's owner", body = ApiError),
+        (status = 409, description = "Another account saved a template with this name", body = ApiError),
+        (status = 410, description = "The room is archived", body = ApiError),
+    ),
+    security(("admin_token" = []), ("session_token" = [])),
+)]
+async fn save_template(id: String, authorization: Option<String>, body: Bytes, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let (id, saved_by) = match owner_or_admin(&id, authorization.as_deref(), &hub, &config) {
+        Ok(owner) => owner,
+        Err(reply) => return Ok(reply),
+    };
+    let request: TemplateRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(Box::new(error(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e)))),
+    };
+    if !valid_room_id(&request.name) {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "invalid template name")));
+    }
+    if saved_by.is_some() {
+        match hub.template(&request.name).await {
+            Ok(Some(existing)) if existing.saved_by != saved_by => {
+                return Ok(Box::new(error(StatusCode::CONFLICT, "another account saved a template with this name")));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Could not load template {}: {}", request.name, e);
+                return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")));
+            }
+        }
+    }
+    match hub.save_template(&id, &request.name, saved_by).await {
+        Ok(template) => Ok(Box::new(warp::reply::with_status(warp::reply::json(&TemplateSummary::from(&template)), StatusCode::CREATED))),
+        Err(e) if is_archived(&e) => Ok(Box::new(error(StatusCode::GONE, "room is archived"))),
+        Err(e) => {
+            log::error!("Could not save room {} as template {}: {}", id, request.name, e);
+            Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")))
+        }
+    }
+}
+
+/// `GET /api/templates`, the templates new rooms can start from with `POST /api/rooms?template=`
+#[utoipa::path(
+    get,
+    path = "/api/templates",
+    tag = "rooms",
+    params(("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set")),
+    responses(
+        (status = 200, description = "Sorted by name", body = Templates),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+    ),
+)]
+async fn templates(query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let authorized = {
+        let keys = &config.borrow().auth.access_keys;
+        keys.is_empty() || query.get("key").is_some_and(|k| keys.contains(k))
+    };
+    if !authorized {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    match hub.templates().await {
+        Ok(templates) => Ok(Box::new(warp::reply::json(&Templates { templates: templates.iter().map(TemplateSummary::from).collect() }))),
+        Err(e) => {
+            log::error!("Could not list templates: {}", e);
+            Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")))
+        }
+    }
+}
+
+/// Live stats of a room resident in memory
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/stats",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 200, description = "OK", body = RoomStats),
+        (status = 404, description = "The room isn't loaded", body = ApiError),
+    ),
+)]
+async fn room_stats(id: String, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(not_found("room"));
+    };
+    // Only rooms resident in memory have live stats
+    let Some(room) = hub.get(&id).await else {
+        return Ok(not_found("room"));
+    };
+    let room = room.read().await;
+
+    let mut rtts: Vec<f64> = room.users.values().filter_map(|peer| peer.stats.rtt_ms()).collect();
+    rtts.sort_by(f64::total_cmp);
+    Ok(Box::new(warp::reply::json(&RoomStats {
+        id: hub.public_id(&room.id),
+        info: room.info.clone(),
+        participants: room.participants(),
+        total_strokes: room.total_strokes,
+        history_size: room.history.len(),
+        waitlisted: room.waitlist.len(),
+        messages_in_per_sec: room.messages_in.per_second(),
+        messages_out_per_sec: room.messages_out.per_second(),
+        age_secs: room.created_at.elapsed().as_secs(),
+        median_rtt_ms: rtts.get(rtts.len() / 2).copied(),
+        max_rtt_ms: rtts.last().copied(),
+    })))
+}
+
+#[derive(Serialize, ToSchema)]
+struct RoomContributions {
+    id: String,
+    users: Vec<UserContribution>,
+}
+
+/// Strokes, erases, clears and direct messages per participant, since the room was loaded
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/contributions",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "One of `auth.access_keys`, when any are set"),
+        ("token" = Option<String>, Query, description = "A login session token, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "Most strokes first", body = RoomContributions),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 404, description = "The room isn't loaded", body = ApiError),
+    ),
+)]
+async fn room_contributions(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(not_found("room"));
+    };
+    let keys = config.borrow().auth.access_keys.clone();
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if !hub.may_access(&id, query.get("token").map(String::as_str)) {
+        return Ok(Box::new(error(StatusCode::FORBIDDEN, "not on the room's access list")));
+    }
+    let Some(room) = hub.get(&id).await else {
+        return Ok(not_found("room"));
+    };
+    let room = room.read().await;
+    Ok(Box::new(warp::reply::json(&RoomContributions { id: hub.public_id(&room.id), users: room.contributions() })))
+}
+
+/// One op someone drew, as it is on the board
+#[derive(Serialize, ToSchema)]
+struct TimedOp {
+    stroke_id: u64,
+    /// Unix milliseconds
+    drawn_at: u64,
+    /// Milliseconds since their first op on the board, to play them back at the pace they were drawn
+    offset_ms: u64,
+    op: MessageType,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UserTimeline {
+    id: String,
+    user_id: UserId,
+    /// Their name when they drew their latest op
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    ops: Vec<TimedOp>,
+}
+
+/// What one person drew that is still on the board, in the order they drew it, with when, so a
+/// client can replay just their strokes. Ops saved before the server kept who drew them aren't in
+/// anyone's timeline
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/users/{user_id}/timeline",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("user_id" = String, Path, description = "Their user id, as the room saw them"),
+        ("key" = Option<String>, Query, description = "One of `auth.access_keys`, when any are set"),
+        ("token" = Option<String>, Query, description = "A login session token, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "Oldest first, empty for someone with nothing on the board", body = UserTimeline),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 410, description = "The room is archived", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+)]
+async fn user_timeline(id: String, user_id: UserId, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let path = format!("api/rooms/{}/users/{}/timeline", id, user_id);
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "invalid room id")));
+    };
+    // The owner checks everything else
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&id, dead_after) {
+        return Ok(cluster::moved(&owner, &path, &query));
+    }
+    let keys = config.borrow().auth.access_keys.clone();
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if !hub.may_access(&id, query.get("token").map(String::as_str)) {
+        return Ok(Box::new(error(StatusCode::FORBIDDEN, "not on the room's access list")));
+    }
+    let room = match hub.open(&id).await {
+        Ok(room) => room,
+        Err(e) if is_archived(&e) => return Ok(Box::new(error(StatusCode::GONE, "room is archived"))),
+        Err(e) => {
+            log::error!("Could not load room {}: {}", id, e);
+            return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")));
+        }
+    };
+    let room = room.read().await;
+    Ok(Box::new(warp::reply::json(&timeline(&hub.public_id(&room.id), user_id, &room.history, &room.strokes))))
+}
+
+fn timeline(id: &str, user_id: UserId, history: &[MessageType], strokes: &[Stroke]) -> UserTimeline {
+    let theirs: Vec<(&MessageType, &Stroke)> = history.iter().zip(strokes).filter(|(_, stroke)| stroke.user_id == Some(user_id)).collect();
+    let first = theirs.iter().filter_map(|(_, stroke)| stroke.drawn_at).min().unwrap_or(0);
+    UserTimeline {
+        id: id.to_string(),
+        user_id,
+        name: theirs.iter().rev().find_map(|(_, stroke)| stroke.name.clone()),
+        ops: theirs
+            .into_iter()
+            .map(|(op, stroke)| {
+                let drawn_at = stroke.drawn_at.unwrap_or(first);
+                TimedOp { stroke_id: stroke.stroke_id, drawn_at, offset_ms: drawn_at - first, op: op.clone() }
+            })
+            .collect(),
+    }
+}
+
+/// Summaries of a room's past sessions, each from when the room was loaded until it was unloaded
+/// for being idle
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/sessions",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "One of `auth.access_keys`, when any are set"),
+        ("token" = Option<String>, Query, description = "A login session token, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "Newest first, empty for rooms without any", body = Vec<SessionSummary>),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+)]
+async fn room_sessions(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "invalid room id")));
+    };
+    let keys = config.borrow().auth.access_keys.clone();
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if !hub.may_access(&id, query.get("token").map(String::as_str)) {
+        return Ok(Box::new(error(StatusCode::FORBIDDEN, "not on the room's access list")));
+    }
+    match hub.sessions(&id).await {
+        Ok(sessions) => {
+            let sessions: Vec<SessionSummary> = sessions.into_iter().map(|session| SessionSummary { room: hub.public_id(&session.room), ..session }).collect();
+            Ok(Box::new(warp::reply::json(&sessions)))
+        }
+        Err(e) => {
+            log::error!("Could not load the sessions of room {}: {}", id, e);
+            Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")))
+        }
+    }
+}
+
+/// A room's board and the region of it to draw, up to `max_size` a side, checked the same way for
+/// every format. The region is `w` x `h` (or `width` x `height`) board pixels from `x`, `y`, by
+/// default the top left, and by default reaches just far enough to fit every stroke. A room owned
+/// by another cluster node is redirected there, to `file` under the room's path
+async fn board_to_draw(
+    id: &str,
+    file: &str,
+    query: &HashMap<String, String>,
+    hub: &Hub,
+    config: &ConfigHandle,
+    max_size: u32,
+) -> Result<(Vec<MessageType>, Vec<Stroke>, RoomInfo, Region), Box<dyn Reply>> {
+    let current = config.borrow().clone();
+    let path = format!("api/rooms/{}/{}", id, file);
+    let Some(id) = hub.room_id(id) else {
+        return Err(Box::new(error(StatusCode::BAD_REQUEST, "invalid room id")));
+    };
+    let id = id.as_str();
+    // The owner checks everything else
+    if let Some(owner) = hub.cluster.remote_owner(id, Duration::from_secs(current.cluster.dead_after_secs)) {
+        return Err(cluster::moved(&owner, &path, query));
+    }
+    let keys = &current.auth.access_keys;
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Err(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if !hub.may_access(id, query.get("token").map(String::as_str)) {
+        return Err(Box::new(error(StatusCode::FORBIDDEN, "not on the room's access list")));
+    }
+    let size = |short: &str, name: &str| query.get(short).or(query.get(name)).map(|v| v.parse::<u32>().ok().filter(|&n| n >= 1 && n <= max_size));
+    let (width, height) = match (size("w", "width"), size("h", "height")) {
+        (Some(None), _) | (_, Some(None)) => {
+            let message = format!("width and height must be between 1 and {}", max_size);
+            return Err(Box::new(error(StatusCode::BAD_REQUEST, &message)));
+        }
+        (width, height) => (width.flatten(), height.flatten()),
+    };
+    let coordinate = |name: &str| query.get(name).map(|v| v.parse::<f64>().ok().filter(|n| n.is_finite()));
+    let (x, y) = match (coordinate("x"), coordinate("y")) {
+        (Some(None), _) | (_, Some(None)) => return Err(Box::new(error(StatusCode::BAD_REQUEST, "x and y must be numbers"))),
+        (x, y) => (x.flatten().unwrap_or(0.0), y.flatten().unwrap_or(0.0)),
+    };
+
+    let room = match hub.open(id).await {
+        Ok(room) => room,
+        Err(e) if is_archived(&e) => return Err(Box::new(error(StatusCode::GONE, "room is archived"))),
+        Err(e) => {
+            log::error!("Could not load room {}: {}", id, e);
+            return Err(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")));
+        }
+    };
+    let (history, strokes, info) = {
+        let room = room.read().await;
+        (room.history.clone(), room.strokes.clone(), room.info.clone())
+    };
+    let (fit_width, fit_height) = render::extent(&history);
+    // From the region's corner to the far edge of what's drawn
+    let fit = |fit: u32, from: f64| ((fit as f64 - from).ceil().max(1.0) as u32).min(max_size);
+    let region = Region { x, y, width: width.unwrap_or_else(|| fit(fit_width, x)), height: height.unwrap_or_else(|| fit(fit_height, y)) };
+    Ok((history, strokes, info, region))
+}
+
+/// `GET /api/rooms/<id>/render.png`, the board as a PNG for previews and embeds. The image covers
+/// the region `board_to_draw` takes from the query, by default from the top left and just enough
+/// to fit every stroke
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/render.png",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("width" = Option<u32>, Query, description = "Image width, up to `render.max_size`. Also `w`"),
+        ("height" = Option<u32>, Query, description = "Image height, up to `render.max_size`. Also `h`"),
+        ("x" = Option<f64>, Query, description = "Left edge of the region to draw, in board pixels, 0 by default"),
+        ("y" = Option<f64>, Query, description = "Top edge of the region to draw, in board pixels, 0 by default"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "OK", content_type = "image/png", body = Vec<u8>),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id, size or region", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+    ),
+)]
+async fn render_room(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let max_size = config.borrow().render.max_size;
+    let (history, _, _, region) = match board_to_draw(&id, "render.png", &query, &hub, &config, max_size).await {
+        Ok(board) => board,
+        Err(reply) => return Ok(reply),
+    };
+    // Rasterizing is CPU-bound, keep it off the runtime's workers
+    let rendered = tokio::task::spawn_blocking(move || render::png(&history, region))
+        .await
+        .map_err(|e| e.to_string());
+    match rendered.and_then(|png| png) {
+        Ok(png) => Ok(Box::new(warp::reply::with_header(png, "content-type", "image/png"))),
+        Err(e) => {
+            log::error!("Could not render room {}: {}", id, e);
+            Ok(Box::new(error(StatusCode::INTERNAL_SERVER_ERROR, "render failed")))
+        }
+    }
+}
+
+/// `GET /api/rooms/<id>/export.svg`, the board as vector paths, which scale for print and docs,
+/// each with who drew it
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/export.svg",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("width" = Option<u32>, Query, description = "Viewport width, up to `render.max_size`. Also `w`"),
+        ("height" = Option<u32>, Query, description = "Viewport height, up to `render.max_size`. Also `h`"),
+        ("x" = Option<f64>, Query, description = "Left edge of the region to draw, in board pixels, 0 by default"),
+        ("y" = Option<f64>, Query, description = "Top edge of the region to draw, in board pixels, 0 by default"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "OK", content_type = "image/svg+xml", body = String),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id, size or region", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+    ),
+)]
+async fn export_svg(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let max_size = config.borrow().render.max_size;
+    match board_to_draw(&id, "export.svg", &query, &hub, &config, max_size).await {
+        Ok((history, strokes, info, region)) => {
+            let svg = render::svg(&history, &strokes, region, info.name.as_deref(), info.description.as_deref());
+            Ok(Box::new(warp::reply::with_header(svg, "content-type", "image/svg+xml")))
+        }
+        Err(reply) => Ok(reply),
+    }
+}
+
+/// `GET /api/rooms/<id>/replay.gif`, an animation of the board being drawn op by op for sharing
+/// how a sketch came together. Ops carry no timestamps, so they're spread evenly over the replay
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/replay.gif",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("width" = Option<u32>, Query, description = "Image width, up to `render.replay_max_size`. Also `w`"),
+        ("height" = Option<u32>, Query, description = "Image height, up to `render.replay_max_size`. Also `h`"),
+        ("x" = Option<f64>, Query, description = "Left edge of the region to draw, in board pixels, 0 by default"),
+        ("y" = Option<f64>, Query, description = "Top edge of the region to draw, in board pixels, 0 by default"),
+        ("duration" = Option<f64>, Query, description = "Seconds to draw the board over, 5 by default and up to `render.replay_max_secs`"),
+        ("speed" = Option<f64>, Query, description = "Ops drawn per second, instead of a duration. The replay is still capped at `render.replay_max_secs`"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "OK", content_type = "image/gif", body = Vec<u8>),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id, size, region, duration or speed", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+    ),
+)]
+async fn replay_gif(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let render = config.borrow().render.clone();
+    let number = |name: &str| query.get(name).map(|v| v.parse::<f64>().ok().filter(|n| n.is_finite() && *n > 0.0));
+    let (duration, speed) = match (number("duration"), number("speed")) {
+        (Some(Some(duration)), _) if duration > render.replay_max_secs => {
+            let message = format!("duration must be at most {} seconds", render.replay_max_secs);
+            return Ok(Box::new(error(StatusCode::BAD_REQUEST, &message)));
+        }
+        (Some(None), _) | (_, Some(None)) => {
+            return Ok(Box::new(error(StatusCode::BAD_REQUEST, "duration and speed must be positive numbers")));
+        }
+        (duration, speed) => (duration.flatten(), speed.flatten()),
+    };
+
+    let (history, _, _, region) = match board_to_draw(&id, "replay.gif", &query, &hub, &config, render.replay_max_size).await {
+        Ok(board) => board,
+        Err(reply) => return Ok(reply),
+    };
+    let duration = match (duration, speed) {
+        (Some(duration), _) => duration,
+        (None, Some(speed)) => (history.len() as f64 / speed).min(render.replay_max_secs),
+        (None, None) => DEFAULT_REPLAY_SECS.min(render.replay_max_secs),
+    };
+    let rendered = tokio::task::spawn_blocking(move || render::replay_gif(&history, region, duration))
+        .await
+        .map_err(|e| e.to_string());
+    match rendered.and_then(|gif| gif) {
+        Ok(gif) => Ok(Box::new(warp::reply::with_header(gif, "content-type", "image/gif"))),
+        Err(e) => {
+            log::error!("Could not render a replay of room {}: {}", id, e);
+            Ok(Box::new(error(StatusCode::INTERNAL_SERVER_ERROR, "render failed")))
+        }
+    }
+}
+
+/// `GET /api/rooms/<id>/thumbnail.png`, a small preview of a resident room for lobbies, re-rendered
+/// every `render.thumbnail_interval_secs` while the room changes
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/thumbnail.png",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "OK", content_type = "image/png", body = Vec<u8>),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 404, description = "The room isn't loaded or has no thumbnail yet", body = ApiError),
+    ),
+)]
+async fn room_thumbnail(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(not_found("thumbnail"));
+    };
+    let authorized = {
+        let keys = &config.borrow().auth.access_keys;
+        keys.is_empty() || query.get("key").is_some_and(|k| keys.contains(k))
+    };
+    if !authorized {
+        return Ok(Box::new(error(StatusCode::UNAUTHORIZED, "missing or invalid key")));
+    }
+    if !hub.may_access(&id, query.get("token").map(String::as_str)) {
+        return Ok(Box::new(error(StatusCode::FORBIDDEN, "not on the room's access list")));
+    }
+    match hub.thumbnails.get(&id) {
+        // Short-lived so previews in a lobby stay about as fresh as the thumbnails
+        Some(png) => Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_header(png, "content-type", "image/png"),
+            "cache-control",
+            "max-age=10",
+        ))),
+        None => Ok(not_found("thumbnail")),
+    }
+}
+
+/// `POST /api/rooms/<id>/commands`, draws one op or an array of them into a room as its bot, for
+/// scripts and CI jobs that don't hold a socket. Quotas apply, rate limits don't since the caller holds an admin token
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/commands",
+    tag = "admin",
+    params(("id" = String, Path, description = "Room id")),
+    request_body(content = Vec<MessageType>, description = "One op, or an array of them, in the WebSocket format"),
+    responses(
+        (status = 200, description = "Ops that were rejected are listed by index, the rest were drawn", body = CommandsApplied),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id or JSON", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn inject_commands(id: String, body: Bytes, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    if !valid_room_id(&id) {
+        return Ok(Box::new(error(StatusCode::BAD_REQUEST, "invalid room id")));
+    }
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&id, dead_after) {
+        return Ok(cluster::moved(&owner, &format!("api/rooms/{}/commands", id), &HashMap::new()));
+    }
+    // Each op is parsed like a socket frame, so a bad one is reported by index and the rest still apply
+    let commands = match serde_json::from_slice(&body) {
+        Ok(Value::Array(commands)) => commands,
+        Ok(command) => vec![command],
+        Err(e) => return Ok(Box::new(error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {}", e)))),
+    };
+    let room = match hub.open(&id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", id, e);
+            return Ok(Box::new(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable")));
+        }
+    };
+
+    let bot = hub.bots.get(&id);
+    let mut inbound = Inbound::unlimited(&config.borrow().limits);
+    let mut applied = 0;
+    let mut rejected = Vec::new();
+    let max_message_bytes = config.borrow().limits.max_message_bytes;
+    for (index, command) in commands.into_iter().enumerate() {
+        let frame = command.to_string();
+        if frame.len() > max_message_bytes {
+            rejected.push(CommandRejection { index, error: "message too large".to_string() });
+            continue;
+        }
+        let error = match inbound.handle(Message::text(frame), &hub, &room, &bot, &config).await {
+            Ok(()) => {
+                applied += 1;
+                continue;
+            }
+            Err(Rejected::RateLimited) => "rate limited".to_string(),
+            Err(Rejected::Invalid(e)) => format!("invalid message: {}", e),
+            Err(Rejected::OverQuota(e) | Rejected::Refused(e)) => e,
+        };
+        rejected.push(CommandRejection { index, error });
+    }
+    log::info!("Injected {} ops into room {} as user {}", applied, id, bot.user_id);
+
+    Ok(Box::new(warp::reply::json(&CommandsApplied { user_id: bot.user_id, applied, rejected })))
+}
+
+async fn connection_snapshots(hub: &Hub) -> Vec<ConnectionSnapshot> {
+    let mut snapshots = Vec::new();
+    for room in hub.rooms().await {
+        snapshots.extend(room.read().await.users.values().map(|peer| peer.stats.snapshot()));
+    }
+    snapshots.sort_by_key(|s| s.user_id);
+    snapshots
+}
+
+/// Every open connection with its counters
+#[utoipa::path(
+    get,
+    path = "/api/admin/connections",
+    tag = "admin",
+    responses(
+        (status = 200, description = "OK", body = Vec<ConnectionSnapshot>),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn list_connections(hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    Ok(Box::new(warp::reply::json(&connection_snapshots(&hub).await)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/connections/{user_id}",
+    tag = "admin",
+    params(("user_id" = UserId, Path, description = "16 hex digits")),
+    responses(
+        (status = 200, description = "OK", body = ConnectionSnapshot),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "Not found", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn get_connection(user_id: String, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    // Parsed here rather than by the route, which would answer a malformed id with a 405
+    let Ok(user_id) = user_id.parse::<UserId>() else {
+        return Ok(not_found("connection"));
+    };
+    match connection_snapshots(&hub).await.into_iter().find(|s| s.user_id == user_id) {
+        Some(snapshot) => Ok(Box::new(warp::reply::json(&snapshot))),
+        None => Ok(not_found("connection")),
+    }
+}
+
+/// Connection seconds, accepted messages and stored bytes per room and per connected user
+#[utoipa::path(
+    get,
+    path = "/api/admin/usage",
+    tag = "admin",
+    responses(
+        (status = 200, description = "OK", body = UsageReport),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn usage_report(hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    Ok(Box::new(warp::reply::json(&hub.usage.report())))
+}
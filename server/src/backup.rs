@@ -0,0 +1,376 @@
+use std::convert::Infallible;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::cli::{BackupArgs, Command};
+use crate::config::{Config, ConfigHandle};
+use crate::hub::{valid_room_id, Hub};
+use crate::notes::{self, Notes};
+use crate::openapi::ApiError;
+use crate::protocol::MessageType;
+use crate::room::{RoomInfo, SessionSummary, Stroke, Template};
+use crate::sealing::Keyring;
+use crate::storage::Storage;
+use crate::tenants;
+
+// Whole boards, a backup is as large as everything stored
+const MAX_BACKUP_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Every room and template, as `server backup` writes and `server restore` reads them
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Backup {
+    pub rooms: Vec<RoomBackup>,
+    pub templates: Vec<Template>,
+}
+
+/// What storage keeps for one room
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RoomBackup {
+    /// As the hub knows it, scoped to its tenant if it's in one
+    pub id: String,
+    pub history: Vec<MessageType>,
+    /// Who drew each op in `history`, empty if nobody was kept
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub strokes: Vec<Stroke>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<RoomInfo>,
+    /// The shared notes as one Yjs v1 update in base64, see `notes`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub summaries: Vec<SessionSummary>,
+    /// Unix seconds, set if the room is archived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<u64>,
+}
+
+impl Backup {
+    /// Refuses room ids and template names nobody could have made, before any of them is taken
+    /// for a file name
+    pub fn check(&self) -> io::Result<()> {
+        let invalid = |what: &str, name: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} {:?}", what, name));
+        if let Some(room) = self.rooms.iter().find(|room| !valid_stored_id(&room.id)) {
+            return Err(invalid("room id", &room.id));
+        }
+        if let Some(template) = self.templates.iter().find(|template| !valid_room_id(&template.name)) {
+            return Err(invalid("template name", &template.name));
+        }
+        Ok(())
+    }
+
+    /// Take a resident room's board, who drew it, info and notes from memory, newer than what storage has
+    pub fn resident(&mut self, id: &str, history: &[MessageType], strokes: &[Stroke], info: &RoomInfo, notes: &Notes) {
+        let index = match self.rooms.iter().position(|room| room.id == id) {
+            Some(index) => index,
+            None => {
+                self.rooms.push(RoomBackup { id: id.to_string(), ..RoomBackup::default() });
+                self.rooms.len() - 1
+            }
+        };
+        let room = &mut self.rooms[index];
+        room.history = history.to_vec();
+        room.strokes = strokes.to_vec();
+        room.info = Some(info.clone());
+        if !notes.is_empty() {
+            room.notes = Some(notes::encode(&notes.state()));
+        }
+    }
+}
+
+// A room id as the hub keeps it, scoped to its tenant if it's in one
+fn valid_stored_id(id: &str) -> bool {
+    match id.split_once(tenants::SEPARATOR) {
+        Some((prefix, room)) => valid_room_id(prefix) && valid_room_id(room),
+        None => valid_room_id(id),
+    }
+}
+
+/// Everything in `storage`
+pub async fn collect(storage: &dyn Storage) -> io::Result<Backup> {
+    let mut rooms = Vec::new();
+    for id in storage.rooms().await? {
+        rooms.push(RoomBackup {
+            history: storage.load(&id).await?,
+            strokes: storage.load_strokes(&id).await?.unwrap_or_default(),
+            info: storage.load_info(&id).await?,
+            notes: storage.load_notes(&id).await?.map(|state| notes::encode(&state)),
+            summaries: storage.summaries(&id).await?,
+            archived_at: storage.archived(&id).await?,
+            id,
+        });
+    }
+    rooms.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut templates = storage.templates().await?;
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Backup { rooms, templates })
+}
+
+/// Put a backup in `storage`, over whatever it has for the same rooms and templates. Summaries
+/// are only added to rooms that have none, so restoring twice doesn't repeat them
+pub async fn apply(storage: &dyn Storage, backup: &Backup) -> io::Result<()> {
+    backup.check()?;
+    for room in &backup.rooms {
+        match room.archived_at {
+            Some(archived_at) => storage.archive(&room.id, &room.history, archived_at).await?,
+            None => {
+                storage.save(&room.id, &room.history).await?;
+                storage.restore(&room.id).await?;
+            }
+        }
+        if !room.strokes.is_empty() {
+            storage.save_strokes(&room.id, &room.strokes).await?;
+        }
+        if let Some(info) = &room.info {
+            storage.save_info(&room.id, info).await?;
+        }
+        if let Some(state) = &room.notes {
+            let state = notes::decode(state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("notes of room {}: {}", room.id, e)))?;
+            storage.save_notes(&room.id, &state).await?;
+        }
+        if storage.summaries(&room.id).await?.is_empty() {
+            for summary in &room.summaries {
+                storage.save_summary(summary).await?;
+            }
+        }
+    }
+    for template in &backup.templates {
+        storage.save_template(template).await?;
+    }
+    Ok(())
+}
+
+/// One file per room in `rooms/` and per template in `templates/`, sealed with `keyring` if there is one
+pub async fn write_dir(backup: &Backup, dir: &Path, keyring: Option<&Keyring>) -> io::Result<()> {
+    backup.check()?;
+    let seal = |bytes: Vec<u8>| match keyring {
+        Some(keyring) => keyring.seal(&bytes),
+        None => bytes,
+    };
+    for (sub, files) in [
+        ("rooms", backup.rooms.iter().map(|room| Ok((room.id.clone(), serde_json::to_vec(room)?))).collect::<serde_json::Result<Vec<_>>>()),
+        ("templates", backup.templates.iter().map(|template| Ok((template.name.clone(), serde_json::to_vec(template)?))).collect()),
+    ] {
+        let sub = dir.join(sub);
+        tokio::fs::create_dir_all(&sub).await?;
+        for (name, bytes) in files.map_err(io::Error::other)? {
+            tokio::fs::write(sub.join(format!("{}.json", name)), seal(bytes)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// What `write_dir` wrote
+pub async fn read_dir(dir: &Path, keyring: Option<&Keyring>) -> io::Result<Backup> {
+    let mut backup = Backup::default();
+    for sub in ["rooms", "templates"] {
+        let mut entries = match tokio::fs::read_dir(dir.join(sub)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let bytes = tokio::fs::read(&path).await?;
+            let bytes = match keyring {
+                Some(keyring) => keyring.open(bytes)?,
+                None => bytes,
+            };
+            let parsed = match sub {
+                "rooms" => serde_json::from_slice(&bytes).map(|room| backup.rooms.push(room)),
+                _ => serde_json::from_slice(&bytes).map(|template| backup.templates.push(template)),
+            };
+            parsed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        }
+    }
+    backup.check()?;
+    backup.rooms.sort_by(|a, b| a.id.cmp(&b.id));
+    backup.templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(backup)
+}
+
+/// `server backup` and `server restore`: straight from the configured storage while the server is
+/// stopped, or with `--server` from a running one over `/api/admin/backup`
+pub async fn run(command: &Command, config: &Config, keyring: Option<Arc<Keyring>>) -> io::Result<String> {
+    match command {
+        Command::Backup { out, remote } => {
+            let backup = match remote.server.as_deref() {
+                Some(server) => {
+                    let request = reqwest::Client::new().get(format!("{}/api/admin/backup", server.trim_end_matches('/')));
+                    send(request, remote).await?.json().await.map_err(io::Error::other)?
+                }
+                None => collect(&*open(config, keyring.clone()).await?).await?,
+            };
+            write_dir(&backup, out, keyring.as_deref()).await?;
+            Ok(format!("Backed up {} rooms and {} templates to {}", backup.rooms.len(), backup.templates.len(), out.display()))
+        }
+        Command::Restore { input, remote } => {
+            let backup = read_dir(input, keyring.as_deref()).await?;
+            match remote.server.as_deref() {
+                Some(server) => {
+                    let request = reqwest::Client::new().post(format!("{}/api/admin/restore", server.trim_end_matches('/'))).json(&backup);
+                    send(request, remote).await?;
+                }
+                None => apply(&*open(config, keyring.clone()).await?, &backup).await?,
+            }
+            Ok(format!("Restored {} rooms and {} templates from {}", backup.rooms.len(), backup.templates.len(), input.display()))
+        }
+    }
+}
+
+// Memory storage is gone with the server that had it
+async fn open(config: &Config, keyring: Option<Arc<Keyring>>) -> io::Result<Arc<dyn Storage>> {
+    if config.storage.backend.dir().is_none() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "memory storage has nothing to back up or restore into, use --server with a running server"));
+    }
+    config.storage.backend.open(keyring, config.storage.durability).await
+}
+
+async fn send(request: reqwest::RequestBuilder, remote: &BackupArgs) -> io::Result<reqwest::Response> {
+    let request = match &remote.token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let response = request.send().await.map_err(io::Error::other)?;
+    match response.status().is_success() {
+        true => Ok(response),
+        false => Err(io::Error::other(format!("the server answered {}: {}", response.status(), response.text().await.unwrap_or_default()))),
+    }
+}
+
+/// `GET /api/admin/backup` and `POST /api/admin/restore`, for admins
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+
+    let backup = warp::path!("api" / "admin" / "backup")
+        .and(warp::get())
+        .and(api::admin(config.clone()))
+        .and(hub.clone())
+        .and_then(export_backup);
+
+    let restore = warp::path!("api" / "admin" / "restore")
+        .and(warp::post())
+        .and(api::admin(config))
+        .and(warp::body::content_length_limit(MAX_BACKUP_BYTES))
+        .and(warp::body::bytes())
+        .and(hub)
+        .and_then(import_backup);
+
+    backup.or(restore)
+}
+
+/// Every room and template, resident rooms as they are in memory
+#[utoipa::path(
+    get,
+    path = "/api/admin/backup",
+    tag = "admin",
+    responses(
+        (status = 200, description = "OK", body = Backup),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn export_backup(hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    match hub.export().await {
+        Ok(backup) => Ok(Box::new(warp::reply::json(&backup))),
+        Err(e) => {
+            log::error!("Could not back up storage: {}", e);
+            Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"))
+        }
+    }
+}
+
+/// Put a backup's rooms and templates in storage. Resident rooms in it are unloaded first, closing
+/// their connections with 1012, and load what was restored on the next join
+#[utoipa::path(
+    post,
+    path = "/api/admin/restore",
+    tag = "admin",
+    request_body = Backup,
+    responses(
+        (status = 204, description = "Restored"),
+        (status = 400, description = "Not a backup, or one with invalid room ids or template names", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn import_backup(body: Bytes, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let backup: Backup = match serde_json::from_slice(&body) {
+        Ok(backup) => backup,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &format!("invalid backup: {}", e))),
+    };
+    if let Err(e) = backup.check() {
+        return Ok(error(StatusCode::BAD_REQUEST, &format!("invalid backup: {}", e)));
+    }
+    match hub.import(&backup).await {
+        Ok(()) => Ok(Box::new(StatusCode::NO_CONTENT)),
+        Err(e) => {
+            log::error!("Could not restore a backup: {}", e);
+            Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"))
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn backups_survive_the_round_trip_through_a_directory() {
+        let storage = MemoryStorage::default();
+        let template = Template { name: "retro".to_string(), info: RoomInfo::default(), history: Vec::new(), saved_by: None, saved_at: 1 };
+        let info = RoomInfo { name: Some("Sketch".to_string()), ..RoomInfo::default() };
+        let backup = Backup {
+            rooms: vec![RoomBackup { id: "sketch".to_string(), info: Some(info.clone()), archived_at: Some(7), ..RoomBackup::default() }],
+            templates: vec![template],
+        };
+        apply(&storage, &backup).await.unwrap();
+        let collected = collect(&storage).await.unwrap();
+        assert_eq!(collected.rooms.len(), 1);
+        assert_eq!((collected.rooms[0].info.as_ref(), collected.rooms[0].archived_at), (Some(&info), Some(7)));
+
+        let dir = std::env::temp_dir().join(format!("ws-demo-backup-{}", std::process::id()));
+        write_dir(&collected, &dir, None).await.unwrap();
+        let read = read_dir(&dir, None).await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(serde_json::to_value(&read).unwrap(), serde_json::to_value(&collected).unwrap());
+    }
+
+    #[tokio::test]
+    async fn ids_that_would_leave_storage_are_refused() {
+        let storage = MemoryStorage::default();
+        let room = |id: &str| RoomBackup { id: id.to_string(), ..RoomBackup::default() };
+        let template = |name: &str| Template { name: name.to_string(), info: RoomInfo::default(), history: Vec::new(), saved_by: None, saved_at: 1 };
+        for backup in [
+            Backup { rooms: vec![room("../../x")], ..Backup::default() },
+            Backup { rooms: vec![room("acme./x")], ..Backup::default() },
+            Backup { rooms: vec![room("")], ..Backup::default() },
+            Backup { templates: vec![template("../retro")], ..Backup::default() },
+        ] {
+            assert_eq!(apply(&storage, &backup).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(storage.rooms().await.unwrap().is_empty() && storage.templates().await.unwrap().is_empty());
+        let scoped = Backup { rooms: vec![room("acme.sketch")], ..Backup::default() };
+        apply(&storage, &scoped).await.unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::connection::ConnectionStats;
+use crate::ids::UserId;
+
+/// Synthetic participants that draw for integrations rather than a connection, one per room,
+/// so everything injected into a room is attributed to the same user id
+#[derive(Default)]
+pub struct Bots {
+    by_room: Mutex<HashMap<String, Arc<ConnectionStats>>>,
+}
+
+impl Bots {
+    /// The room's bot, created on first use. It never joins, so it doesn't get the room's broadcasts
+    pub fn get(&self, room_id: &str) -> Arc<ConnectionStats> {
+        self.by_room.lock().unwrap()
+            .entry(room_id.to_string())
+            .or_insert_with(|| {
+                let user_id = UserId::random();
+                log::info!("Injected ops in room {} are drawn as user {}", room_id, user_id);
+                Arc::new(ConnectionStats::new(user_id, room_id.to_string(), None, "bot"))
+            })
+            .clone()
+    }
+
+    /// Whether `user_id` is the room's bot, without making it one
+    pub fn is_bot(&self, room_id: &str, user_id: UserId) -> bool {
+        self.by_room.lock().unwrap().get(room_id).is_some_and(|bot| bot.user_id == user_id)
+    }
+}
@@ -0,0 +1,123 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::ws::Message;
+
+use crate::config::{ChaosConfig, ConfigHandle};
+use crate::connection::ConnectionStats;
+
+// A frame waiting for its time, ties go in the order they were queued
+struct Delayed {
+    at: Instant,
+    n: u64,
+    message: Message,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.n) == (other.at, other.n)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.n).cmp(&(other.at, other.n))
+    }
+}
+
+/// Put `chaos` between a socket's send queue and its writer. Every frame is delayed by `latency_ms`
+/// plus up to `jitter_ms`, and text and binary frames may be dropped or held back another
+/// `latency_ms + jitter_ms` so that later ones overtake them. Pings, pongs and closes are never
+/// dropped and wait as long as any frame can, so a close comes after everything before it.
+/// Settings are read per frame, so a reload applies straight away
+pub fn inject(mut rx: UnboundedReceiverStream<Message>, config: ConfigHandle, stats: Arc<ConnectionStats>) -> UnboundedReceiverStream<Message> {
+    let (tx, delayed) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut waiting: BinaryHeap<Reverse<Delayed>> = BinaryHeap::new();
+        let mut n = 0;
+        let mut open = true;
+        while open || !waiting.is_empty() {
+            let next = waiting.peek().map(|Reverse(delayed)| delayed.at);
+            tokio::select! {
+                message = rx.next(), if open => match message {
+                    Some(message) => {
+                        let chaos = config.borrow().chaos;
+                        match delay(&chaos, &message) {
+                            Some(wait) => {
+                                n += 1;
+                                waiting.push(Reverse(Delayed { at: Instant::now() + wait, n, message }));
+                            }
+                            // Never reaches the writer, which would otherwise take it off the queue
+                            None => stats.sent(),
+                        }
+                    }
+                    None => open = false,
+                },
+                _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                    let now = Instant::now();
+                    while waiting.peek().is_some_and(|Reverse(delayed)| delayed.at <= now) {
+                        let Some(Reverse(delayed)) = waiting.pop() else {
+                            break;
+                        };
+                        if tx.send(delayed.message).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+    UnboundedReceiverStream::new(delayed)
+}
+
+// How long to hold a frame, None to drop it
+fn delay(chaos: &ChaosConfig, message: &Message) -> Option<Duration> {
+    let most = chaos.latency_ms + chaos.jitter_ms;
+    if !(message.is_text() || message.is_binary()) {
+        let held_back = if chaos.reorder_rate > 0.0 { most } else { 0 };
+        return Some(Duration::from_millis(most + held_back));
+    }
+    if rand::random::<f64>() < chaos.drop_rate {
+        return None;
+    }
+    let jitter = if chaos.jitter_ms > 0 { rand::random::<u64>() % (chaos.jitter_ms + 1) } else { 0 };
+    let held_back = if rand::random::<f64>() < chaos.reorder_rate { most } else { 0 };
+    Some(Duration::from_millis(chaos.latency_ms + jitter + held_back))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_frames_are_only_text_and_binary() {
+        let chaos = ChaosConfig { drop_rate: 1.0, ..ChaosConfig::default() };
+        assert_eq!(delay(&chaos, &Message::text("{}")), None);
+        assert_eq!(delay(&chaos, &Message::binary(vec![1])), None);
+        assert_eq!(delay(&chaos, &Message::close_with(1000u16, "bye")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn closes_wait_as_long_as_any_frame() {
+        let chaos = ChaosConfig { latency_ms: 10, jitter_ms: 5, reorder_rate: 1.0, ..ChaosConfig::default() };
+        for _ in 0..100 {
+            let text = delay(&chaos, &Message::text("{}")).unwrap();
+            assert!(text >= Duration::from_millis(25) && text <= Duration::from_millis(30), "{:?}", text);
+        }
+        assert_eq!(delay(&chaos, &Message::close_with(1000u16, "bye")), Some(Duration::from_millis(30)));
+    }
+}
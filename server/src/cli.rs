@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::{Args as ClapArgs, Parser, Subcommand};
+
+use crate::storage::StorageSpec;
+
+/// Shared whiteboard WebSocket server
+///
+/// Flags override `config.toml` and `WHITEBOARD_*` environment variables.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config file to load [default: ./config.toml if present]
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
+    /// Address to bind the HTTP/WebSocket listener to, `::` for IPv6 and IPv4 [default: 127.0.0.1]
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+
+    /// Port to listen on [default: 8080]
+    #[arg(long, short)]
+    pub port: Option<u16>,
+
+    /// Also listen on a Unix domain socket at this path
+    #[arg(long)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Don't open the TCP listener, serve only on --unix-socket
+    #[arg(long)]
+    pub no_tcp: bool,
+
+    /// Log filter, e.g. `info` or `ws_demo=debug,warp=info` [default: info]
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Where room history is kept: `memory` or `file:<dir>` [default: memory]
+    #[arg(long)]
+    pub storage: Option<StorageSpec>,
+
+    /// Directory of the built frontend to serve, e.g. `../client/dist/ng-19-ws-demo/browser`
+    #[arg(long)]
+    pub frontend: Option<PathBuf>,
+
+    /// Maximum number of operations kept in each room's history [default: 10000]
+    #[arg(long)]
+    pub history_limit: Option<usize>,
+
+    /// Seconds /readyz reports draining before the listener stops on shutdown [default: 5]
+    #[arg(long)]
+    pub shutdown_grace: Option<u64>,
+
+    /// Replay an op log written with `export.file` into the rooms, then keep serving them
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// How many times faster than recorded to replay, 0 for no waiting at all [default: 1]
+    #[arg(long)]
+    pub replay_speed: Option<f64>,
+
+    /// Seal file storage and the op log with `storage.encryption.key` where they aren't yet, e.g.
+    /// after rotating keys, then exit
+    #[arg(long)]
+    pub reseal: bool,
+}
+
+/// Run instead of serving
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write every room, its history and info, and every template to a directory
+    Backup {
+        /// Directory to write to, created if it doesn't exist
+        #[arg(long)]
+        out: PathBuf,
+        #[command(flatten)]
+        remote: BackupArgs,
+    },
+    /// Put what `backup` wrote back in storage, over what it has for the same rooms and templates
+    Restore {
+        /// Directory `backup` wrote
+        #[arg(long = "in")]
+        input: PathBuf,
+        #[command(flatten)]
+        remote: BackupArgs,
+    },
+}
+
+/// Without `--server`, `backup` and `restore` use the configured storage directly, which only
+/// the stopped server should have open
+#[derive(ClapArgs, Debug, Clone)]
+pub struct BackupArgs {
+    /// Base URL of a running server to use over its admin API instead, e.g. `http://127.0.0.1:8080`
+    #[arg(long)]
+    pub server: Option<String>,
+
+    /// One of its `auth.admin_tokens`
+    #[arg(long, requires = "server")]
+    pub token: Option<String>,
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::config::{Config, ConfigHandle};
+use crate::hub::Hub;
+use crate::ids::random_token;
+use crate::listener::Listener;
+use crate::openapi::ApiError;
+use crate::tenants;
+
+// Heartbeats are small, a node slower than this to answer one may as well be down
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2);
+// Node lists are short, this is plenty for any cluster
+const MAX_HEARTBEAT_BYTES: u64 = 64 * 1024;
+
+/// A node as the others know it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Node {
+    pub node_id: String,
+    /// Base HTTP URL, rooms are joined under it at `/room/<id>`
+    pub url: String,
+}
+
+/// `POST /cluster/heartbeat` and its answer: who's sending it and who they've heard from lately
+#[derive(Debug, Serialize, Deserialize)]
+struct Heartbeat {
+    from: Node,
+    members: Vec<Node>,
+}
+
+struct Member {
+    url: String,
+    /// When it last sent us a heartbeat or answered ours, `None` if we only heard of it
+    last_seen: Option<Instant>,
+    /// When it was last seen or mentioned by another node, it's forgotten once both are stale
+    mentioned: Instant,
+}
+
+impl Member {
+    fn alive(&self, dead_after: Duration) -> bool {
+        self.last_seen.is_some_and(|at| at.elapsed() < dead_after)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    node: Option<Node>,
+    members: HashMap<String, Member>,
+}
+
+/// The other nodes this one knows of. Everything is `None` while clustering is off
+#[derive(Default)]
+pub struct Cluster {
+    state: Mutex<State>,
+}
+
+/// `GET /api/admin/cluster`
+#[derive(Serialize, ToSchema)]
+pub struct ClusterStatus {
+    /// This node, absent when `cluster.enabled` is off
+    node: Option<Node>,
+    members: Vec<MemberStatus>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MemberStatus {
+    node_id: String,
+    url: String,
+    /// Heard from within `cluster.dead_after_secs`, only those own rooms
+    alive: bool,
+    /// Absent for nodes only other nodes have heard from so far
+    last_seen_ms_ago: Option<u64>,
+}
+
+impl Cluster {
+    /// The node `room_id` belongs on, when that's another one. Each room goes to whichever of the
+    /// nodes up ranks highest for it, so all of them agree without talking it over, and a node
+    /// coming or going only moves the rooms it wins or had
+    pub fn remote_owner(&self, room_id: &str, dead_after: Duration) -> Option<Node> {
+        let state = self.state.lock().unwrap();
+        let node = state.node.as_ref()?;
+        let (node_id, member) = state
+            .members
+            .iter()
+            .filter(|(_, member)| member.alive(dead_after))
+            .max_by_key(|(node_id, _)| (rank(node_id, room_id), node_id.as_str()))?;
+        let theirs = (rank(node_id, room_id), node_id.as_str());
+        let ours = (rank(&node.node_id, room_id), node.node_id.as_str());
+        (theirs > ours).then(|| Node { node_id: node_id.clone(), url: member.url.clone() })
+    }
+
+    fn heartbeat(&self, dead_after: Duration) -> Option<Heartbeat> {
+        let state = self.state.lock().unwrap();
+        let from = state.node.clone()?;
+        let members = state
+            .members
+            .iter()
+            .filter(|(_, member)| member.alive(dead_after))
+            .map(|(node_id, member)| Node { node_id: node_id.clone(), url: member.url.clone() })
+            .collect();
+        Some(Heartbeat { from, members })
+    }
+
+    fn heard_from(&self, heartbeat: Heartbeat, dead_after: Duration) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let own_id = state.node.as_ref().map(|node| node.node_id.clone());
+        if own_id.as_ref() == Some(&heartbeat.from.node_id) {
+            return;
+        }
+        for node in heartbeat.members.into_iter().filter(|node| Some(&node.node_id) != own_id.as_ref()) {
+            state
+                .members
+                .entry(node.node_id)
+                .and_modify(|member| member.mentioned = now)
+                .or_insert(Member { url: node.url, last_seen: None, mentioned: now });
+        }
+        let Node { node_id, url } = heartbeat.from;
+        let member = state.members.entry(node_id.clone()).or_insert(Member { url: url.clone(), last_seen: None, mentioned: now });
+        if !member.alive(dead_after) {
+            log::info!("Cluster node {} at {} is up", node_id, url);
+        }
+        *member = Member { url, last_seen: Some(now), mentioned: now };
+    }
+
+    // Where to send heartbeats: the configured peers and every node still heard from or of
+    fn targets(&self, peers: &[String], dead_after: Duration) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        state.members.retain(|node_id, member| {
+            let stale = member.mentioned.elapsed() >= dead_after && !member.alive(dead_after);
+            if stale {
+                log::info!("Cluster node {} not heard from for {:?}, forgetting it", node_id, dead_after);
+            }
+            !stale
+        });
+        let own_url = state.node.as_ref().map(|node| node.url.trim_end_matches('/'));
+        let mut targets: Vec<String> = peers
+            .iter()
+            .map(|url| url.trim_end_matches('/').to_string())
+            .chain(state.members.values().map(|member| member.url.trim_end_matches('/').to_string()))
+            .filter(|url| Some(url.as_str()) != own_url)
+            .collect();
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+
+    fn status(&self, dead_after: Duration) -> ClusterStatus {
+        let state = self.state.lock().unwrap();
+        let mut members: Vec<MemberStatus> = state
+            .members
+            .iter()
+            .map(|(node_id, member)| MemberStatus {
+                node_id: node_id.clone(),
+                url: member.url.clone(),
+                alive: member.alive(dead_after),
+                last_seen_ms_ago: member.last_seen.map(|at| at.elapsed().as_millis() as u64),
+            })
+            .collect();
+        members.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        ClusterStatus { node: state.node.clone(), members }
+    }
+}
+
+// How much a node wants a room: FNV-1a over both, finished off like MurmurHash3 so that node
+// ids differing in one character don't rank rooms alike
+fn rank(node_id: &str, room_id: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in node_id.bytes().chain([0]).chain(room_id.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Where `path` is on `node` over WebSocket, see `tenants::join_path`
+pub fn join_url(node: &Node, path: &str) -> String {
+    let base = node.url.trim_end_matches('/');
+    let base = match base.strip_prefix("http") {
+        Some(rest) => format!("ws{}", rest),
+        None => base.to_string(),
+    };
+    format!("{}/{}", base, path)
+}
+
+/// Where `path` is on `node` over HTTP, with the same `query`
+pub fn http_url(node: &Node, path: &str, query: &HashMap<String, String>) -> String {
+    let url = format!("{}/{}", node.url.trim_end_matches('/'), path);
+    match reqwest::Url::parse_with_params(&url, query) {
+        Ok(url) if !query.is_empty() => url.to_string(),
+        _ => url,
+    }
+}
+
+/// A 307 to `path` on `node`, for HTTP requests about a room it owns that reached this one
+pub fn moved(node: &Node, path: &str, query: &HashMap<String, String>) -> Box<dyn Reply> {
+    let url = http_url(node, path, query);
+    let body = warp::reply::json(&serde_json::json!({ "error": "room is on another node", "url": url }));
+    Box::new(warp::reply::with_header(warp::reply::with_status(body, StatusCode::TEMPORARY_REDIRECT), "location", url))
+}
+
+/// Join the cluster when `cluster.enabled` is set, advertising `cluster.url` or else the first TCP
+/// listener, and keep heartbeating the other nodes
+pub fn start(hub: Arc<Hub>, config: ConfigHandle, listeners: &[Listener]) {
+    let cluster = config.borrow().cluster.clone();
+    if !cluster.enabled {
+        return;
+    }
+    let url = match cluster.url.is_empty() {
+        false => cluster.url.trim_end_matches('/').to_string(),
+        true => match listeners.iter().find_map(Listener::tcp_addr) {
+            Some(addr) => format!("http://{}", advertised(addr)),
+            None => {
+                log::error!("cluster.url has to be set without a TCP listener, not joining the cluster");
+                return;
+            }
+        },
+    };
+    let node_id = match cluster.node_id.is_empty() {
+        false => cluster.node_id,
+        true => random_token()[..16].to_string(),
+    };
+    log::info!("Joining the cluster as node {} at {}", node_id, url);
+    hub.cluster.state.lock().unwrap().node = Some(Node { node_id, url });
+    tokio::spawn(heartbeat(hub, config));
+}
+
+// Other nodes can't reach a wildcard address, so guess the loopback one, which suits one machine
+fn advertised(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            log::warn!("Listening on {}, set cluster.url for nodes on other machines", addr);
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            log::warn!("Listening on {}, set cluster.url for nodes on other machines", addr);
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+// Heartbeat every known node each `cluster.heartbeat_secs`, then hand off the rooms that now
+// belong on one of them
+async fn heartbeat(hub: Arc<Hub>, config: ConfigHandle) {
+    let client = reqwest::Client::builder()
+        .timeout(HEARTBEAT_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client");
+    loop {
+        let current = config.borrow().clone();
+        let cluster = &current.cluster;
+        let dead_after = Duration::from_secs(cluster.dead_after_secs);
+        let Some(heartbeat) = hub.cluster.heartbeat(dead_after) else {
+            return;
+        };
+        let targets = hub.cluster.targets(&cluster.peers, dead_after);
+        let replies = join_all(targets.iter().map(|url| send(&client, url, &cluster.secret, &heartbeat))).await;
+        for reply in replies.into_iter().flatten() {
+            hub.cluster.heard_from(reply, dead_after);
+        }
+        hand_off(&hub, &current, dead_after).await;
+        tokio::time::sleep(Duration::from_secs(cluster.heartbeat_secs.max(1))).await;
+    }
+}
+
+async fn send(client: &reqwest::Client, url: &str, secret: &str, heartbeat: &Heartbeat) -> Option<Heartbeat> {
+    let mut request = client.post(format!("{}/cluster/heartbeat", url)).json(heartbeat);
+    if !secret.is_empty() {
+        request = request.bearer_auth(secret);
+    }
+    let reply = match request.send().await.and_then(|response| response.error_for_status()) {
+        Ok(response) => response.json().await,
+        Err(e) => Err(e),
+    };
+    reply.inspect_err(|e| log::debug!("Heartbeat to {} failed: {}", url, e)).ok()
+}
+
+// Rooms resident here whose owner is another node, since it came up or since this one was down
+async fn hand_off(hub: &Hub, config: &Config, dead_after: Duration) {
+    for room in hub.rooms().await {
+        let id = room.read().await.id.clone();
+        if let Some(owner) = hub.cluster.remote_owner(&id, dead_after) {
+            log::info!("Room {} belongs on cluster node {} now, handing it off", id, owner.node_id);
+            hub.hand_off(&room, &join_url(&owner, &tenants::join_path(config, &*hub.public_ids, &id))).await;
+        }
+    }
+}
+
+/// `POST /cluster/heartbeat` between nodes, and `GET /api/admin/cluster` with what this one knows
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = api::admin(config.clone());
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+
+    let heartbeat = warp::path!("cluster" / "heartbeat")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(MAX_HEARTBEAT_BYTES))
+        .and(warp::body::json())
+        .and(hub.clone())
+        .and(config.clone())
+        .map(receive);
+
+    let status = warp::path!("api" / "admin" / "cluster")
+        .and(warp::get())
+        .and(admin)
+        .and(hub)
+        .and(config)
+        .map(cluster_status);
+
+    heartbeat.or(status)
+}
+
+fn receive(authorization: Option<String>, heartbeat: Heartbeat, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    let (secret, dead_after) = {
+        let config = config.borrow();
+        (config.cluster.secret.clone(), Duration::from_secs(config.cluster.dead_after_secs))
+    };
+    if !secret.is_empty() && authorization.as_deref().and_then(|h| h.strip_prefix("Bearer ")) != Some(secret.as_str()) {
+        return error(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    // Answered before taking the sender in, it knows about itself
+    let Some(reply) = hub.cluster.heartbeat(dead_after) else {
+        return error(StatusCode::NOT_FOUND, "not found");
+    };
+    hub.cluster.heard_from(heartbeat, dead_after);
+    Box::new(warp::reply::json(&reply))
+}
+
+/// This node and every other one it knows of
+#[utoipa::path(
+    get,
+    path = "/api/admin/cluster",
+    tag = "admin",
+    responses(
+        (status = 200, description = "OK", body = ClusterStatus),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+fn cluster_status(hub: Arc<Hub>, config: ConfigHandle) -> impl Reply {
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    warp::reply::json(&hub.cluster.status(dead_after))
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(node_id: &str, others: &[&str]) -> Cluster {
+        let cluster = Cluster::default();
+        cluster.state.lock().unwrap().node = Some(Node { node_id: node_id.to_string(), url: format!("http://{}", node_id) });
+        for other in others {
+            let from = Node { node_id: other.to_string(), url: format!("http://{}", other) };
+            cluster.heard_from(Heartbeat { from, members: Vec::new() }, Duration::from_secs(10));
+        }
+        cluster
+    }
+
+    #[test]
+    fn nodes_agree_on_who_owns_a_room() {
+        let nodes = ["a", "b", "c"];
+        let clusters: Vec<Cluster> = nodes
+            .iter()
+            .map(|node| cluster(node, &nodes.iter().copied().filter(|other| other != node).collect::<Vec<_>>()))
+            .collect();
+        let dead_after = Duration::from_secs(10);
+        for room in 0..50 {
+            let room = format!("room-{}", room);
+            let local: Vec<&str> = nodes
+                .iter()
+                .zip(&clusters)
+                .filter(|(_, cluster)| cluster.remote_owner(&room, dead_after).is_none())
+                .map(|(node, _)| *node)
+                .collect();
+            assert_eq!(local.len(), 1, "{} owned by {:?}", room, local);
+            for cluster in &clusters {
+                let owner = cluster.remote_owner(&room, dead_after).map(|node| node.node_id);
+                let own_id = || cluster.state.lock().unwrap().node.clone().unwrap().node_id;
+                assert_eq!(owner.unwrap_or_else(own_id), local[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn only_nodes_heard_from_own_rooms() {
+        let cluster = cluster("a", &[]);
+        let from = Node { node_id: "b".to_string(), url: "http://b".to_string() };
+        let members = vec![Node { node_id: "c".to_string(), url: "http://c".to_string() }];
+        cluster.heard_from(Heartbeat { from, members }, Duration::from_secs(10));
+        let owners: Vec<_> = (0..50).filter_map(|room| cluster.remote_owner(&format!("room-{}", room), Duration::from_secs(10))).collect();
+        assert!(!owners.is_empty());
+        assert!(owners.iter().all(|node| node.node_id == "b"), "{:?}", owners);
+        assert_eq!(cluster.targets(&[], Duration::from_secs(10)), ["http://b", "http://c"]);
+    }
+
+    #[test]
+    fn join_urls_are_websockets() {
+        let node = Node { node_id: "a".to_string(), url: "https://a.example.com/".to_string() };
+        assert_eq!(join_url(&node, "room/sketch"), "wss://a.example.com/room/sketch");
+        let query = HashMap::from([("key".to_string(), "a b".to_string())]);
+        assert_eq!(http_url(&node, "api/rooms/sketch/events", &query), "https://a.example.com/api/rooms/sketch/events?key=a+b");
+    }
+}
@@ -0,0 +1,94 @@
+use crate::protocol::{Composite, DrawCommand, EraseCommand, Invalid, MessageType};
+
+// A kind byte, then prev and cur as four little-endian f64s, then the brush size as a little-endian u32
+const POINTS_BYTES: usize = 32;
+const STROKE_BYTES: usize = 1 + POINTS_BYTES + 4;
+
+/// Decode an op from a binary WebSocket frame, the compact form of `MessageType` for clients that
+/// draw a lot. The first byte is the op's index in `MessageType::TYPES`: `Clear` is that byte
+/// alone, `Erase` is followed by `prev`, `cur` and `brush_size` and decodes to a `destination-out`
+/// draw, and `Draw` by those and then the color as UTF-8 up to the end of the frame. Checked like a text op
+pub fn decode(bytes: &[u8]) -> Result<MessageType, Invalid> {
+    let Some(&kind) = bytes.first() else {
+        return Err(Invalid::Malformed("empty binary frame".to_string()));
+    };
+    let name = *MessageType::TYPES.get(kind as usize).ok_or_else(|| Invalid::UnknownType(format!("binary {}", kind)))?;
+    let op = match name {
+        "Clear" if bytes.len() == 1 => MessageType::Clear,
+        "Clear" => return Err(Invalid::Malformed(format!("a binary Clear is 1 byte, not {}", bytes.len()))),
+        _ if bytes.len() < STROKE_BYTES => return Err(Invalid::Malformed(format!("a binary {} is at least {} bytes, not {}", name, STROKE_BYTES, bytes.len()))),
+        "Erase" if bytes.len() > STROKE_BYTES => return Err(Invalid::Malformed(format!("a binary Erase is {} bytes, not {}", STROKE_BYTES, bytes.len()))),
+        "Erase" => {
+            let (prev, cur, brush_size) = stroke(bytes);
+            MessageType::Draw(EraseCommand { prev, cur, brush_size }.into())
+        }
+        _ => {
+            let (prev, cur, brush_size) = stroke(bytes);
+            let color = std::str::from_utf8(&bytes[STROKE_BYTES..]).map_err(|e| Invalid::BadField(format!("color isn't UTF-8: {}", e)))?;
+            MessageType::Draw(DrawCommand { prev, cur, color: color.to_string(), brush_size, composite: Composite::SourceOver })
+        }
+    };
+    op.check()?;
+    Ok(op)
+}
+
+/// The binary frame `decode` reads back as `op`, an erase being an `Erase`
+pub fn encode(op: &MessageType) -> Vec<u8> {
+    let kind = MessageType::TYPES.iter().position(|name| name.to_lowercase() == op.name()).expect("every op has a type") as u8;
+    let mut bytes = vec![kind];
+    let (points, brush_size, color) = match op {
+        MessageType::Draw(draw) if draw.composite.erases() => ([draw.prev, draw.cur], draw.brush_size, ""),
+        MessageType::Draw(draw) => ([draw.prev, draw.cur], draw.brush_size, draw.color.as_str()),
+        MessageType::Clear => return bytes,
+    };
+    for v in points.iter().flatten() {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    bytes.extend_from_slice(&brush_size.to_le_bytes());
+    bytes.extend_from_slice(color.as_bytes());
+    bytes
+}
+
+// Points and brush size of a frame at least `STROKE_BYTES` long
+fn stroke(bytes: &[u8]) -> ([f64; 2], [f64; 2], u32) {
+    let f64_at = |i: usize| f64::from_le_bytes(bytes[1 + i * 8..9 + i * 8].try_into().expect("8 bytes"));
+    let brush_size = u32::from_le_bytes(bytes[1 + POINTS_BYTES..STROKE_BYTES].try_into().expect("4 bytes"));
+    ([f64_at(0), f64_at(1)], [f64_at(2), f64_at(3)], brush_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn op() -> impl Strategy<Value = MessageType> {
+        let point = || [-1000.0..1000.0, -1000.0..1000.0];
+        prop_oneof![
+            (point(), point(), ".{0,16}", 1..=500u32).prop_map(|(prev, cur, color, brush_size)| MessageType::Draw(DrawCommand { prev, cur, color, brush_size, composite: Composite::SourceOver })),
+            (point(), point(), 1..=500u32).prop_map(|(prev, cur, brush_size)| MessageType::Draw(EraseCommand { prev, cur, brush_size }.into())),
+            Just(MessageType::Clear),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes_decode_or_are_rejected(bytes in prop::collection::vec(any::<u8>(), 0..128)) {
+            let _ = decode(&bytes);
+        }
+
+        #[test]
+        fn ops_survive_a_round_trip(op in op()) {
+            let decoded = decode(&encode(&op)).unwrap();
+            prop_assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&op).unwrap());
+        }
+
+        #[test]
+        fn truncated_strokes_are_rejected(op in op(), cut in any::<prop::sample::Index>()) {
+            let bytes = encode(&op);
+            // A Draw cut in its color is still a Draw, with a shorter color
+            let len = cut.index(bytes.len().min(STROKE_BYTES));
+            prop_assert!(matches!(decode(&bytes[..len]), Err(Invalid::Malformed(_))));
+        }
+    }
+}
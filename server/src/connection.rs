@@ -0,0 +1,396 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+use warp::ws::Message;
+
+use crate::config::BotConfig;
+use crate::filters::Filters;
+use crate::ids::{random_token, UserId};
+use crate::profiles;
+use crate::protocol::{DrawCommand, Member, Presence, Profile, Role, Viewport, VoiceState};
+
+const MAX_AGENT_CHARS: usize = 64;
+
+/// Why a connection left its room, on `Left` and `user_left`, so a crash can be told from a polite exit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// The client closed it or left, the polite exit
+    ClientClose,
+    /// It went quiet: no valid frame within `limits.handshake_timeout_secs`, unanswered pings, or
+    /// a long-poll session that stopped polling
+    Timeout,
+    /// The server sent it away: its room was archived, restored from a backup or moved to another
+    /// node, or its trial ended
+    Kicked,
+    /// The connection failed, broke the protocol, or a task handling it panicked
+    Error,
+    /// The server is shutting down
+    ServerShutdown,
+    /// It left for another room with `SwitchRoom`, the socket is still open
+    SwitchedRoom,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClose => "client_close",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Error => "error",
+            DisconnectReason::ServerShutdown => "server_shutdown",
+            DisconnectReason::SwitchedRoom => "switched_room",
+        }
+    }
+}
+
+/// Counters for one socket, shared between its reader, writer, and the rooms broadcasting to it
+pub struct ConnectionStats {
+    pub user_id: UserId,
+    // Changed by `moved_to` when a WebSocket switches rooms
+    room_id: Mutex<String>,
+    pub remote_addr: Option<SocketAddr>,
+    /// What the connection came in over, e.g. `websocket` or `grpc`
+    pub transport: &'static str,
+    /// The client it says it is with `?agent=`, e.g. `grading-bot/2.1`
+    pub agent: Option<String>,
+    /// It said it's a bot rather than a person with `?bot=1`, see `BotConfig`
+    pub is_bot: bool,
+    connected_at: u64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    parse_errors: AtomicU64,
+    rate_limited: AtomicU64,
+    queued: AtomicU64,
+    // Microseconds, u64::MAX until the first pong arrives
+    rtt_us: AtomicU64,
+    ping_sent_at: Mutex<Option<Instant>>,
+    // Bytes ephemeral frames may still use and when that was worked out, see `Peer::send_ephemeral`
+    allowance: Mutex<(f64, Instant)>,
+    shaped: AtomicU64,
+    // Why it's ending, once that's known, see `closing`
+    closed: Mutex<Option<DisconnectReason>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConnectionSnapshot {
+    pub user_id: UserId,
+    pub room_id: String,
+    #[schema(value_type = Option<String>, example = "203.0.113.7:51234")]
+    pub remote_addr: Option<SocketAddr>,
+    pub transport: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    pub is_bot: bool,
+    pub connected_at: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub parse_errors: u64,
+    pub rate_limited: u64,
+    /// Cursor and other ephemeral frames not sent to keep under `limits.outbound_bytes_per_second`
+    pub shaped: u64,
+    pub queue_depth: u64,
+    pub rtt_ms: Option<f64>,
+}
+
+impl ConnectionStats {
+    pub fn new(user_id: UserId, room_id: String, remote_addr: Option<SocketAddr>, transport: &'static str) -> Self {
+        ConnectionStats {
+            user_id,
+            room_id: Mutex::new(room_id),
+            remote_addr,
+            transport,
+            agent: None,
+            is_bot: false,
+            connected_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            rtt_us: AtomicU64::new(u64::MAX),
+            ping_sent_at: Mutex::new(None),
+            // Capped to a second's worth once the rate is known, so connections start with a full allowance
+            allowance: Mutex::new((f64::INFINITY, Instant::now())),
+            shaped: AtomicU64::new(0),
+            closed: Mutex::new(None),
+        }
+    }
+
+    /// What the client said it is in its handshake
+    pub fn identified(self, agent: Option<String>, is_bot: bool) -> Self {
+        ConnectionStats { agent, is_bot, ..self }
+    }
+
+    /// How logs name the connection, e.g. `user <id>` or `bot <id> (grading-bot/2.1)`
+    pub fn describe(&self) -> String {
+        let kind = if self.is_bot { "bot" } else { "user" };
+        match &self.agent {
+            Some(agent) => format!("{} {} ({})", kind, self.user_id, agent),
+            None => format!("{} {}", kind, self.user_id),
+        }
+    }
+
+    /// The room the connection is in
+    pub fn room_id(&self) -> String {
+        self.room_id.lock().unwrap().clone()
+    }
+
+    /// It switched to `room_id`, see `ControlMessage::SwitchRoom`
+    pub fn moved_to(&self, room_id: String) {
+        *self.room_id.lock().unwrap() = room_id;
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self) -> u64 {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the writer once a queued message has gone out on the socket
+    pub fn sent(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Everything sent counts against the allowance, down to a second's worth of debt, so a
+    // client busy with ops gets fewer cursors rather than more
+    fn spend(&self, bytes: u64) {
+        let mut allowance = self.allowance.lock().unwrap();
+        allowance.0 -= bytes as f64;
+    }
+
+    // Top the allowance up for the time since it was last worked out, then take `bytes` if it covers them
+    fn take_allowance(&self, bytes: u64, bytes_per_second: u64) -> bool {
+        let rate = bytes_per_second as f64;
+        let mut allowance = self.allowance.lock().unwrap();
+        let (left, at) = *allowance;
+        let left = (left + at.elapsed().as_secs_f64() * rate).clamp(-rate, rate);
+        *allowance = (left, Instant::now());
+        left >= bytes as f64
+    }
+
+    /// Note why the connection is ending. The first reason sticks, so a client answering the
+    /// server's close frame doesn't turn a kick into a client close
+    pub fn closing(&self, reason: DisconnectReason) {
+        self.closed.lock().unwrap().get_or_insert(reason);
+    }
+
+    /// Why the connection ended, a client close unless `closing` was told otherwise
+    pub fn close_reason(&self) -> DisconnectReason {
+        self.closed.lock().unwrap().unwrap_or(DisconnectReason::ClientClose)
+    }
+
+    pub fn ping_sent(&self) {
+        *self.ping_sent_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn pong_received(&self) {
+        if let Some(sent_at) = self.ping_sent_at.lock().unwrap().take() {
+            self.rtt_us.store(sent_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// The last ping's round trip, None until the first pong
+    pub fn rtt_ms(&self) -> Option<f64> {
+        let rtt_us = self.rtt_us.load(Ordering::Relaxed);
+        (rtt_us != u64::MAX).then(|| rtt_us as f64 / 1000.0)
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            user_id: self.user_id,
+            room_id: self.room_id(),
+            remote_addr: self.remote_addr,
+            transport: self.transport,
+            agent: self.agent.clone(),
+            is_bot: self.is_bot,
+            connected_at: self.connected_at,
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            shaped: self.shaped.load(Ordering::Relaxed),
+            queue_depth: self.queued.load(Ordering::Relaxed),
+            rtt_ms: self.rtt_ms(),
+        }
+    }
+}
+
+/// `?agent=` as it's shown: trimmed, without control characters and at most 64 characters. None if empty
+pub fn normalize_agent(agent: &str) -> Option<String> {
+    let agent: String = agent.chars().filter(|c| !c.is_control()).collect::<String>().trim().chars().take(MAX_AGENT_CHARS).collect();
+    Some(agent).filter(|agent| !agent.is_empty())
+}
+
+/// A room's handle on one connected socket
+pub struct Peer {
+    tx: mpsc::UnboundedSender<Message>,
+    pub stats: Arc<ConnectionStats>,
+    pub profile: Profile,
+    pub resume_token: String,
+    /// Unix milliseconds
+    pub hand_raised_at: Option<u64>,
+    pub reaction: Option<(String, Instant)>,
+    // The last one they sent, for new followers to start from
+    pub viewport: Option<Viewport>,
+    pub presence: Presence,
+    // Their last frame of any kind, or when they joined
+    pub last_input: Instant,
+    /// The username of the account the connection signed in with, guests have none
+    pub account: Option<String>,
+    /// Who everyone else sees this connection as: its own user id, or the one the participant
+    /// already had if it's another tab of a signed-in user in the room
+    pub participant: UserId,
+    /// Signed in as the room's owner. Looked up as they're let in and as they sign in, not under
+    /// the room lock: `Hub::owner` is a database query
+    pub owner: bool,
+    /// From the join link the connection came in with, editor without one
+    pub role: Role,
+    /// Asked for `?echo=1`: gets its own ops back like everyone else's, each with its seq and timestamp
+    pub echo: bool,
+    /// Round trip in milliseconds as last shown to the room, with `presence.share_latency`
+    pub latency_ms: Option<u32>,
+    /// Set while they're in the room's voice channel
+    pub voice: Option<VoiceState>,
+    // Their last segment and when it came, for `socket::bridge_gap`
+    pub last_segment: Option<(DrawCommand, Instant)>,
+    /// What their `Welcome` says they can do, see `socket::capabilities`
+    pub capabilities: Vec<&'static str>,
+    /// What they're sent of what's broadcast, see `filters`
+    pub filters: Filters,
+    /// Takes up one of `rooms.max_participants`, which bots don't with `bots.exclude_from_capacity`
+    pub takes_slot: bool,
+    /// What they draw counts towards their contribution, unlike bots' with `bots.exclude_from_contributions`
+    pub contributes: bool,
+}
+
+impl Peer {
+    pub fn new(tx: mpsc::UnboundedSender<Message>, stats: Arc<ConnectionStats>) -> Self {
+        Peer {
+            tx,
+            profile: Profile::default(),
+            resume_token: random_token(),
+            hand_raised_at: None,
+            reaction: None,
+            viewport: None,
+            presence: Presence::Active,
+            last_input: Instant::now(),
+            account: None,
+            owner: false,
+            participant: stats.user_id,
+            role: Role::Editor,
+            echo: false,
+            latency_ms: None,
+            voice: None,
+            last_segment: None,
+            capabilities: Vec::new(),
+            filters: Filters::default(),
+            takes_slot: true,
+            contributes: true,
+            stats,
+        }
+    }
+
+    /// Keep the token a reconnecting client passed, so `socket::join` can give it back its profile
+    pub fn resuming(self, token: Option<&String>) -> Self {
+        match token.filter(|t| profiles::valid_token(t)) {
+            Some(token) => Peer { resume_token: token.clone(), ..self },
+            None => self,
+        }
+    }
+
+    pub fn signed_in(self, account: Option<String>) -> Self {
+        Peer { account, ..self }
+    }
+
+    pub fn owning(self, owner: bool) -> Self {
+        Peer { owner, ..self }
+    }
+
+    pub fn with_role(self, role: Role) -> Self {
+        Peer { role, ..self }
+    }
+
+    pub fn echoing(self, echo: bool) -> Self {
+        Peer { echo, ..self }
+    }
+
+    pub fn with_capabilities(self, capabilities: Vec<&'static str>) -> Self {
+        Peer { capabilities, ..self }
+    }
+
+    pub fn with_filters(self, filters: Filters) -> Self {
+        Peer { filters, ..self }
+    }
+
+    /// Counted in capacity and contributions as `bots` has it, if the connection is a bot
+    pub fn counted_as(self, bots: &BotConfig) -> Self {
+        let is_bot = self.stats.is_bot;
+        Peer { takes_slot: !(is_bot && bots.exclude_from_capacity), contributes: !(is_bot && bots.exclude_from_contributions), ..self }
+    }
+
+    /// What follows are kept under: the account for signed-in users, so all their tabs share them,
+    /// and otherwise the resume token
+    pub fn identity(&self) -> String {
+        match &self.account {
+            Some(account) => format!("@{}", account),
+            None => self.resume_token.clone(),
+        }
+    }
+
+    pub fn member(&self) -> Member {
+        let reaction = self.reaction.as_ref().filter(|(_, at)| at.elapsed() < profiles::REACTION_TTL).map(|(emoji, _)| emoji.clone());
+        Member { user_id: self.participant, profile: self.profile.clone(), hand_raised_at: self.hand_raised_at, reaction, presence: self.presence, latency_ms: self.latency_ms, voice: self.voice, account: self.account.clone(), agent: self.stats.agent.clone(), is_bot: self.stats.is_bot }
+    }
+
+    /// Queue a message for the writer, false if the socket is already gone
+    pub fn send(&self, msg: Message) -> bool {
+        let len = msg.as_bytes().len() as u64;
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        if self.tx.send(msg).is_err() {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        self.stats.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.stats.bytes_out.fetch_add(len, Ordering::Relaxed);
+        self.stats.spend(len);
+        true
+    }
+
+    /// Close the connection from the server's side with `code` and `text`, noting why
+    pub fn disconnect(&self, reason: DisconnectReason, code: u16, text: &'static str) -> bool {
+        self.stats.closing(reason);
+        self.send(Message::close_with(code, text))
+    }
+
+    /// Queue a frame that's only worth sending while the client has bandwidth to spare, like a
+    /// cursor, which the next one replaces anyway. Not sent, and false, when it's over
+    /// `bytes_per_second` (0 is unlimited) counting everything else it was sent
+    pub fn send_ephemeral(&self, msg: Message, bytes_per_second: u64) -> bool {
+        if bytes_per_second > 0 && !self.stats.take_allowance(msg.as_bytes().len() as u64, bytes_per_second) {
+            self.stats.shaped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.send(msg)
+    }
+}
@@ -0,0 +1,67 @@
+use std::convert::Infallible;
+
+use warp::filters::path::FullPath;
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::http::{Method, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::{ConfigHandle, CorsConfig};
+
+// Only the REST API is meant to be called cross-origin
+const API_PREFIX: &str = "/api/";
+
+/// Answers CORS preflights for the API, `wrap` adds the headers
+pub fn preflight() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::options()
+        .and(warp::path("api"))
+        .map(|| warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT))
+}
+
+/// Adds `Access-Control-*` headers to API responses, errors included, for origins in `cors.allowed_origins`
+pub fn wrap<F, R>(routes: F, config: ConfigHandle) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(routes)
+        .map(move |method: Method, path: FullPath, headers: HeaderMap, reply: R| {
+            let mut response = reply.into_response();
+            if path.as_str().starts_with(API_PREFIX) {
+                decorate(&mut response, &method, &headers, &config.borrow().cors);
+            }
+            response
+        })
+}
+
+fn decorate(response: &mut Response, method: &Method, request: &HeaderMap, cors: &CorsConfig) {
+    let headers = response.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+
+    let Some(origin) = request.get(header::ORIGIN) else {
+        return;
+    };
+    let allowed = cors.allowed_origins.iter().any(|o| o == "*" || o.as_bytes() == origin.as_bytes());
+    if !allowed {
+        return;
+    }
+
+    // Echo the origin rather than `*`, browsers refuse a wildcard on credentialed requests
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    if cors.allow_credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+    if method == Method::OPTIONS {
+        let list = |values: &[String]| HeaderValue::from_str(&values.join(", ")).ok();
+        if let Some(methods) = list(&cors.allowed_methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Some(allowed_headers) = list(&cors.allowed_headers) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(cors.max_age_secs));
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+use tokio::sync::{broadcast, mpsc, Notify};
+
+use crate::connection::DisconnectReason;
+use crate::ids::UserId;
+use crate::protocol::MessageType;
+use crate::room::{RoomInfo, UserContribution};
+
+// Monitors that fall further behind than this miss events and are told how many
+const EVENT_BUFFER: usize = 1024;
+
+// Op consumers get more slack than monitors, the op stream is much busier. The op log never
+// falls behind further than this, rooms wait for it instead
+const OP_BUFFER: usize = 8192;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    RoomCreated { room: String, info: RoomInfo },
+    RoomClosed {
+        room: String,
+        info: RoomInfo,
+        /// With `rooms.contribution_summary` set
+        #[serde(skip_serializing_if = "Option::is_none")]
+        contributions: Option<Vec<UserContribution>>,
+    },
+    /// Its owner changed the room's info
+    RoomUpdated { room: String, info: RoomInfo },
+    /// Saved and unloaded until it's restored, see `Hub::archive`
+    RoomArchived { room: String, info: RoomInfo },
+    RoomRestored { room: String },
+    /// `first` when the room was empty until this user joined
+    UserJoined { room: String, user_id: UserId, remote_addr: Option<SocketAddr>, first: bool },
+    UserLeft { room: String, user_id: UserId, reason: DisconnectReason },
+    SnapshotSaved { room: String, ops: usize },
+    RateLimited { room: String, user_id: UserId },
+    /// Someone was reported for abuse, see `reports`
+    UserReported { room: String, user_id: UserId, report_id: String, reason: String },
+    Error { room: Option<String>, user_id: Option<UserId>, message: String },
+}
+
+impl ServerEvent {
+    /// Name used for webhook deliveries, None for events only meant for operators
+    pub fn webhook_name(&self) -> Option<&'static str> {
+        match self {
+            ServerEvent::RoomCreated { .. } => Some("room_created"),
+            ServerEvent::RoomClosed { .. } => Some("room_closed"),
+            ServerEvent::RoomUpdated { .. } => Some("room_updated"),
+            ServerEvent::RoomArchived { .. } => Some("room_archived"),
+            ServerEvent::RoomRestored { .. } => Some("room_restored"),
+            ServerEvent::UserJoined { .. } => Some("user_joined"),
+            ServerEvent::UserLeft { .. } => Some("user_left"),
+            ServerEvent::SnapshotSaved { .. } => Some("snapshot_saved"),
+            ServerEvent::UserReported { .. } => Some("user_reported"),
+            ServerEvent::RateLimited { .. } | ServerEvent::Error { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimedEvent {
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+/// Fan-out of server events to whoever is watching, emitting is free when nobody is
+pub struct EventBus {
+    tx: broadcast::Sender<TimedEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            tx: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn emit(&self, event: ServerEvent) {
+        let at = now_millis();
+        // Errors only mean there are no subscribers
+        let _ = self.tx.send(TimedEvent { at, event });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TimedEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// A canvas op accepted into a room, in the order the room applied it
+#[derive(Debug, Clone, Serialize)]
+pub struct OpRecord {
+    pub room: String,
+    pub user_id: UserId,
+    pub seq: u64,
+    /// The op's `Stroke::stroke_id`, None for a clear
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stroke_id: Option<u64>,
+    pub correlation_id: CorrelationId,
+    pub timestamp: u64,
+    pub op: MessageType,
+}
+
+/// Every accepted op across all rooms, for consumers outside the room broadcast
+pub struct OpFeed {
+    tx: broadcast::Sender<OpRecord>,
+    // The op log's own feed, which misses nothing, see `log`
+    log: OnceLock<mpsc::Sender<OpRecord>>,
+    // Rung by `flush`, for the op log
+    flush: Arc<Notify>,
+}
+
+impl Default for OpFeed {
+    fn default() -> Self {
+        OpFeed {
+            tx: broadcast::channel(OP_BUFFER).0,
+            log: OnceLock::new(),
+            flush: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl OpFeed {
+    /// Waits for the op log to have room for the op, when there is one, so replaying it rebuilds
+    /// every board. Subscribers that fall behind miss ops instead
+    pub async fn publish(&self, record: OpRecord) {
+        if let Some(log) = self.log.get() {
+            // Only closed once the op log stopped, which it said why
+            let _ = log.send(record.clone()).await;
+        }
+        let _ = self.tx.send(record);
+    }
+
+    /// Every op from here on, in the order published, for the op log. There's only the one
+    pub fn log(&self) -> Option<mpsc::Receiver<OpRecord>> {
+        let (tx, rx) = mpsc::channel(OP_BUFFER);
+        self.log.set(tx).ok().map(|()| rx)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<OpRecord> {
+        self.tx.subscribe()
+    }
+
+    /// Have the op log write out and sync everything published so far, without waiting for it
+    pub fn flush(&self) {
+        self.flush.notify_one();
+    }
+
+    pub fn flush_requests(&self) -> Arc<Notify> {
+        self.flush.clone()
+    }
+}
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tags an inbound frame so every log line, op record and error frame it causes can be matched up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    pub fn next() -> Self {
+        CorrelationId(NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl Serialize for CorrelationId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_op_log_gets_every_op_however_far_behind() {
+        let feed = Arc::new(OpFeed::default());
+        let mut log = feed.log().unwrap();
+        assert!(feed.log().is_none());
+        let mut lagging = feed.subscribe();
+        let ops = 3 * OP_BUFFER as u64;
+        let publisher = tokio::spawn({
+            let feed = feed.clone();
+            async move {
+                for seq in 1..=ops {
+                    let op = MessageType::Clear;
+                    feed.publish(OpRecord { room: "room".to_string(), user_id: UserId::random(), seq, stroke_id: None, correlation_id: CorrelationId::next(), timestamp: 0, op }).await;
+                }
+            }
+        });
+        for seq in 1..=ops {
+            assert_eq!(log.recv().await.unwrap().seq, seq);
+        }
+        publisher.await.unwrap();
+        assert!(matches!(lagging.recv().await, Err(broadcast::error::RecvError::Lagged(_))));
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
+
+use crate::config::ExportConfig;
+use crate::events::{OpFeed, OpRecord};
+use crate::sealing::Keyring;
+
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Publish every accepted op to NATS as `<subject_prefix>.<room>` and append it to `file`, whichever are
+/// configured. With a `keyring` the file's lines are sealed, NATS gets them as they are
+pub fn spawn(config: &ExportConfig, ops: &OpFeed, keyring: Option<Arc<Keyring>>) {
+    if let (Some(path), Some(mut rx)) = (config.file.clone(), ops.log()) {
+        let flush = ops.flush_requests();
+        tokio::spawn(async move {
+            if let Err(e) = append_to_file(&path, &mut rx, &flush, keyring.as_deref()).await {
+                log::error!("Stopped writing ops to {}: {}", path.display(), e);
+            }
+        });
+    }
+
+    let Some(url) = config.nats_url.clone() else {
+        return;
+    };
+    let prefix = config.subject_prefix.clone();
+    let mut rx = ops.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match publish_to_nats(&url, &prefix, &mut rx).await {
+                Ok(()) => break,
+                // Ops that arrive while we're reconnecting wait in the feed, until it laps us
+                Err(e) => log::warn!("NATS export interrupted, reconnecting in {:?}: {}", RECONNECT_BACKOFF, e),
+            }
+            tokio::time::sleep(RECONNECT_BACKOFF).await;
+        }
+    });
+}
+
+/// Write each op as a line of JSON, the op log `--replay` reads. When `flush` is rung, everything
+/// published by then is written and synced to disk
+async fn append_to_file(path: &Path, rx: &mut mpsc::Receiver<OpRecord>, flush: &Notify, keyring: Option<&Keyring>) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let mut writer = BufWriter::new(file);
+    log::info!("Writing ops to {}", path.display());
+    loop {
+        let record = tokio::select! {
+            record = rx.recv() => record,
+            () = flush.notified() => {
+                while let Ok(record) = rx.try_recv() {
+                    write_line(&mut writer, &record, keyring).await?;
+                }
+                writer.flush().await?;
+                writer.get_ref().sync_data().await?;
+                continue;
+            }
+        };
+        let Some(record) = record else {
+            return writer.flush().await;
+        };
+        write_line(&mut writer, &record, keyring).await?;
+        // Buffered while ops keep coming, flushed at the first pause
+        if rx.is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+async fn write_line(writer: &mut BufWriter<File>, record: &OpRecord, keyring: Option<&Keyring>) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+    if let Some(keyring) = keyring {
+        line = keyring.seal_line(&line);
+    }
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+/// A NATS session, up to the CONNECT, on the server at `url`. Returns the server's lines and where
+/// to write, and where it is, for logs
+pub async fn connect_nats(url: &str) -> io::Result<(Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf, String)> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let host = parsed.host_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "NATS url has no host"))?;
+    let addr = format!("{}:{}", host, parsed.port().unwrap_or(4222));
+
+    let (reader, mut writer) = TcpStream::connect(&addr).await?.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    match lines.next_line().await? {
+        Some(info) if info.starts_with("INFO") => {}
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected INFO from NATS server")),
+    }
+
+    let mut options = serde_json::json!({
+        "verbose": false,
+        "pedantic": false,
+        "name": "ws-demo",
+        "lang": "rust",
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    match (parsed.username(), parsed.password()) {
+        ("", _) => {}
+        (user, Some(pass)) => {
+            options["user"] = user.into();
+            options["pass"] = pass.into();
+        }
+        (token, None) => options["auth_token"] = token.into(),
+    }
+    writer.write_all(format!("CONNECT {}\r\n", options).as_bytes()).await?;
+    Ok((lines, writer, addr))
+}
+
+/// One NATS session speaking just enough of the client protocol to publish: CONNECT, PUB, and answering PING
+async fn publish_to_nats(url: &str, prefix: &str, rx: &mut broadcast::Receiver<OpRecord>) -> io::Result<()> {
+    let (mut lines, mut writer, addr) = connect_nats(url).await?;
+    log::info!("Exporting ops to NATS at {}", addr);
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line? {
+                // The server pings idle clients and drops the ones that don't answer
+                Some(line) if line.starts_with("PING") => writer.write_all(b"PONG\r\n").await?,
+                Some(line) if line.starts_with("-ERR") => log::warn!("NATS error: {}", line),
+                Some(_) => {}
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "NATS server closed the connection")),
+            },
+            record = rx.recv() => {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Op export fell behind, {} ops not published", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                let payload = serde_json::to_vec(&record).map_err(io::Error::other)?;
+                let mut frame = format!("PUB {}.{} {}\r\n", prefix, record.room, payload.len()).into_bytes();
+                frame.extend_from_slice(&payload);
+                frame.extend_from_slice(b"\r\n");
+                writer.write_all(&frame).await?;
+            }
+        }
+    }
+}
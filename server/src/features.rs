@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::cluster;
+use crate::config::{Config, ConfigHandle};
+use crate::hub::{valid_room_id, Hub};
+use crate::openapi::ApiError;
+use crate::protocol::ServerMessage;
+use crate::room::RoomInfo;
+use crate::socket;
+use crate::tenants::{self, Tenant};
+
+/// What rooms may do, for rolling things out a few rooms or tenants at a time. Set globally in
+/// `[features]`, for a tenant in its `features` or over the API, and for a room over the API; a
+/// flag left unset is whatever the level above it has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureFlags {
+    /// Direct messages between participants, `ControlMessage::Dm`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dms: Option<bool>,
+    /// Ops sent as binary frames, see `codec`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary: Option<bool>,
+    /// The shared notes document, see `notes`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<bool>,
+    /// Ops the board keeps, at most `rooms.history_limit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_limit: Option<usize>,
+}
+
+impl FeatureFlags {
+    pub fn is_empty(&self) -> bool {
+        *self == FeatureFlags::default()
+    }
+
+    /// These, with any left unset taken from `fallback`
+    pub fn or(self, fallback: FeatureFlags) -> FeatureFlags {
+        FeatureFlags {
+            dms: self.dms.or(fallback.dms),
+            binary: self.binary.or(fallback.binary),
+            notes: self.notes.or(fallback.notes),
+            history_limit: self.history_limit.or(fallback.history_limit),
+        }
+    }
+
+    pub fn validate(self) -> Result<Self, String> {
+        match self.history_limit {
+            Some(0) => Err("history_limit has to be at least 1".to_string()),
+            _ => Ok(self),
+        }
+    }
+}
+
+/// The flags a room ends up with, what its frames are handled by
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct Features {
+    pub dms: bool,
+    pub binary: bool,
+    pub notes: bool,
+    pub history_limit: usize,
+}
+
+impl Features {
+    /// What a connection over `transport` can do in a room with these features, by the names
+    /// `Welcome` lists them under. `relays` is whether the room passes on frames of unknown types
+    pub fn capabilities(&self, config: &Config, transport: &str, relays: bool) -> Vec<&'static str> {
+        let capabilities = [
+            // Only WebSockets carry binary frames
+            ("binary", self.binary && transport == "websocket"),
+            ("dms", self.dms),
+            ("notes", self.notes),
+            ("undo_clear", config.rooms.clear_undo_secs > 0),
+            ("checksums", config.rooms.checksum_interval_secs > 0),
+            ("relay", relays),
+        ];
+        capabilities.into_iter().filter_map(|(name, on)| on.then_some(name)).collect()
+    }
+}
+
+/// Flags set for tenants over the API, over those in their config. Kept in memory, so they're
+/// back to the config's when the server restarts
+#[derive(Default)]
+pub struct TenantFeatures {
+    flags: Mutex<HashMap<String, FeatureFlags>>,
+}
+
+impl TenantFeatures {
+    pub fn get(&self, tenant: &str) -> FeatureFlags {
+        self.flags.lock().unwrap().get(tenant).copied().unwrap_or_default()
+    }
+
+    pub fn set(&self, tenant: &str, flags: FeatureFlags) {
+        let mut all = self.flags.lock().unwrap();
+        match flags.is_empty() {
+            true => all.remove(tenant),
+            false => all.insert(tenant.to_string(), flags),
+        };
+    }
+
+    /// A tenant's flags, from the API or else its config
+    fn of(&self, tenant: &Tenant) -> FeatureFlags {
+        self.get(tenant.id).or(tenant.config.features)
+    }
+
+    /// What the hub room `room_id` with its own `room` flags may do
+    pub fn resolve(&self, config: &Config, room_id: &str, room: FeatureFlags) -> Features {
+        let tenant = tenants::tenant_of(config, room_id).map_or_else(FeatureFlags::default, |(tenant, _)| self.of(&tenant));
+        let flags = room.or(tenant).or(config.features);
+        Features {
+            dms: flags.dms.unwrap_or(true),
+            binary: flags.binary.unwrap_or(true),
+            notes: flags.notes.unwrap_or(true),
+            history_limit: flags.history_limit.map_or(config.rooms.history_limit, |limit| limit.min(config.rooms.history_limit)),
+        }
+    }
+}
+
+/// Flags set at one level, and what they come to there
+#[derive(Serialize, ToSchema)]
+pub struct FeatureState {
+    flags: FeatureFlags,
+    /// For a tenant, what its rooms get unless they set their own
+    effective: Features,
+}
+
+/// `GET` and `PUT` on `/api/rooms/<id>/features` for admins, and on `/api/tenants/<id>/features`
+/// and `/api/tenants/<id>/rooms/<room>/features` for the tenant's admins too
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = api::admin(config.clone());
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+    let flags = || warp::body::content_length_limit(MAX_FLAGS_BYTES).and(warp::body::json());
+
+    let room = warp::path!("api" / "rooms" / String / "features").and(admin);
+    let get_room = room.clone().and(warp::get()).and(hub.clone()).and(config.clone()).then(room_features);
+    let put_room = room.and(warp::put()).and(flags()).and(hub.clone()).and(config.clone()).then(set_room_features);
+
+    let tenant = warp::path!("api" / "tenants" / String / "features").and(warp::header::optional::<String>("authorization"));
+    let get_tenant = tenant.and(warp::get()).and(hub.clone()).and(config.clone()).then(tenant_features);
+    let put_tenant = tenant.and(warp::put()).and(flags()).and(hub.clone()).and(config.clone()).then(set_tenant_features);
+
+    let tenant_room = warp::path!("api" / "tenants" / String / "rooms" / String / "features").and(warp::header::optional::<String>("authorization"));
+    let get_tenant_room = tenant_room.and(warp::get()).and(hub.clone()).and(config.clone()).then(tenant_room_features);
+    let put_tenant_room = tenant_room.and(warp::put()).and(flags()).and(hub).and(config).then(set_tenant_room_features);
+
+    get_room.or(put_room).or(get_tenant).or(put_tenant).or(get_tenant_room).or(put_tenant_room)
+}
+
+// A few flags, anything longer isn't one
+const MAX_FLAGS_BYTES: u64 = 4 * 1024;
+
+/// A room's own flags, and what it ends up with
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/features",
+    tag = "admin",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 200, description = "OK", body = FeatureState),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn room_features(id: String, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    match valid_room_id(&id) {
+        true => update_room(format!("api/rooms/{}/features", id), id, None, &hub, &config).await,
+        false => error(StatusCode::BAD_REQUEST, "invalid room id"),
+    }
+}
+
+/// Replace a room's own flags, saved with its info
+#[utoipa::path(
+    put,
+    path = "/api/rooms/{id}/features",
+    tag = "admin",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = FeatureFlags,
+    responses(
+        (status = 200, description = "Saved", body = FeatureState),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id or flags", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn set_room_features(id: String, flags: FeatureFlags, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    match valid_room_id(&id) {
+        true => update_room(format!("api/rooms/{}/features", id), id, Some(flags), &hub, &config).await,
+        false => error(StatusCode::BAD_REQUEST, "invalid room id"),
+    }
+}
+
+/// A tenant's flags, for its `admin_tokens` or `auth.admin_tokens`
+#[utoipa::path(
+    get,
+    path = "/api/tenants/{id}/features",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id")),
+    responses(
+        (status = 200, description = "OK", body = FeatureState),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn tenant_features(id: String, authorization: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    update_tenant(id, authorization, None, &hub, &config)
+}
+
+/// Replace a tenant's flags, over those in its config until the server restarts. Empty goes
+/// back to the config's
+#[utoipa::path(
+    put,
+    path = "/api/tenants/{id}/features",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id")),
+    request_body = FeatureFlags,
+    responses(
+        (status = 200, description = "Set", body = FeatureState),
+        (status = 400, description = "Invalid flags", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn set_tenant_features(id: String, authorization: Option<String>, flags: FeatureFlags, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    update_tenant(id, authorization, Some(flags), &hub, &config)
+}
+
+/// A tenant's room's own flags, and what it ends up with
+#[utoipa::path(
+    get,
+    path = "/api/tenants/{id}/rooms/{room}/features",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id"), ("room" = String, Path, description = "Room id within the tenant")),
+    responses(
+        (status = 200, description = "OK", body = FeatureState),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn tenant_room_features(id: String, room: String, authorization: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    update_tenant_room(id, room, authorization, None, &hub, &config).await
+}
+
+/// Replace a tenant's room's own flags, saved with its info
+#[utoipa::path(
+    put,
+    path = "/api/tenants/{id}/rooms/{room}/features",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id"), ("room" = String, Path, description = "Room id within the tenant")),
+    request_body = FeatureFlags,
+    responses(
+        (status = 200, description = "Saved", body = FeatureState),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id or flags", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn set_tenant_room_features(id: String, room: String, authorization: Option<String>, flags: FeatureFlags, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    update_tenant_room(id, room, authorization, Some(flags), &hub, &config).await
+}
+
+fn update_tenant(id: String, authorization: Option<String>, flags: Option<FeatureFlags>, hub: &Hub, config: &ConfigHandle) -> Box<dyn Reply> {
+    let current = config.borrow().clone();
+    let Some((id, tenant)) = current.tenants.get_key_value(&id) else {
+        return error(StatusCode::NOT_FOUND, "unknown tenant");
+    };
+    let tenant = Tenant { id, config: tenant };
+    if !tenants::is_admin(&current, &tenant, authorization.as_deref()) {
+        return error(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    if let Some(flags) = flags {
+        match flags.validate() {
+            Ok(flags) => hub.tenant_features.set(id, flags),
+            Err(reason) => return error(StatusCode::BAD_REQUEST, &reason),
+        }
+        log::info!("Set the feature flags of tenant {} to {:?}", id, flags);
+    }
+    let flags = hub.tenant_features.of(&tenant);
+    // A room in the tenant with no flags of its own
+    let effective = hub.tenant_features.resolve(&current, &tenant.scope("_"), FeatureFlags::default());
+    Box::new(warp::reply::json(&FeatureState { flags, effective }))
+}
+
+async fn update_tenant_room(id: String, room: String, authorization: Option<String>, flags: Option<FeatureFlags>, hub: &Hub, config: &ConfigHandle) -> Box<dyn Reply> {
+    let path = format!("api/tenants/{}/rooms/{}/features", id, room);
+    let scoped = {
+        let current = config.borrow();
+        let Some((id, tenant)) = current.tenants.get_key_value(&id) else {
+            return error(StatusCode::NOT_FOUND, "unknown tenant");
+        };
+        let tenant = Tenant { id, config: tenant };
+        if !tenants::is_admin(&current, &tenant, authorization.as_deref()) {
+            return error(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+        if !valid_room_id(&room) {
+            return error(StatusCode::BAD_REQUEST, "invalid room id");
+        }
+        tenant.scope(&room)
+    };
+    update_room(path, scoped, flags, hub, config).await
+}
+
+// `id` is the hub's, scoped to its tenant if it's in one, and `path` where the request went, for
+// redirecting it to the cluster node that owns the room
+async fn update_room(path: String, id: String, flags: Option<FeatureFlags>, hub: &Hub, config: &ConfigHandle) -> Box<dyn Reply> {
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&id, dead_after) {
+        return cluster::moved(&owner, &path, &HashMap::new());
+    }
+    let room = match hub.open(&id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", id, e);
+            return error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable");
+        }
+    };
+    if let Some(flags) = flags {
+        let flags = match flags.validate() {
+            Ok(flags) => flags,
+            Err(reason) => return error(StatusCode::BAD_REQUEST, &reason),
+        };
+        let info = RoomInfo { features: flags, ..room.read().await.info.clone() };
+        if let Err(e) = hub.set_info(&room, info.clone()).await {
+            log::error!("Could not save the info of room {}: {}", id, e);
+            return error(StatusCode::SERVICE_UNAVAILABLE, "could not save the room's info");
+        }
+        log::info!("Set the feature flags of room {} to {:?}", id, flags);
+        socket::broadcast(&*room.read().await, &ServerMessage::Room(info));
+    }
+    let flags = room.read().await.info.features;
+    let effective = hub.tenant_features.resolve(&config.borrow(), &id, flags);
+    Box::new(warp::reply::json(&FeatureState { flags, effective }))
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TenantConfig;
+
+    #[test]
+    fn rooms_flags_win_over_their_tenants_over_the_configs() {
+        let mut config = Config::default();
+        config.rooms.history_limit = 1000;
+        config.features = FeatureFlags { dms: Some(false), history_limit: Some(500), ..FeatureFlags::default() };
+        let acme = TenantConfig { features: FeatureFlags { binary: Some(false), ..FeatureFlags::default() }, ..TenantConfig::default() };
+        config.tenants.insert("acme".to_string(), acme);
+        let tenants = TenantFeatures::default();
+
+        let plain = tenants.resolve(&config, "sketch", FeatureFlags::default());
+        assert_eq!(plain, Features { dms: false, binary: true, notes: true, history_limit: 500 });
+        let tenant = tenants.resolve(&config, "acme.sketch", FeatureFlags::default());
+        assert_eq!(tenant, Features { dms: false, binary: false, notes: true, history_limit: 500 });
+
+        tenants.set("acme", FeatureFlags { dms: Some(true), ..FeatureFlags::default() });
+        let room = FeatureFlags { binary: Some(true), history_limit: Some(5000), ..FeatureFlags::default() };
+        // Never past `rooms.history_limit`
+        assert_eq!(tenants.resolve(&config, "acme.sketch", room), Features { dms: true, binary: true, notes: true, history_limit: 1000 });
+        // Flags set over the API leave the rest of the tenant's config as it is
+        assert_eq!(tenants.resolve(&config, "acme.sketch", FeatureFlags::default()), Features { dms: true, binary: false, notes: true, history_limit: 500 });
+
+        tenants.set("acme", FeatureFlags::default());
+        assert!(!tenants.resolve(&config, "acme.sketch", FeatureFlags::default()).dms);
+    }
+
+    #[test]
+    fn capabilities_leave_out_what_is_off() {
+        let mut config = Config::default();
+        config.rooms.checksum_interval_secs = 0;
+        let features = Features { dms: false, binary: true, notes: true, history_limit: 500 };
+        assert_eq!(features.capabilities(&config, "websocket", false), ["binary", "notes", "undo_clear"]);
+        config.rooms.clear_undo_secs = 0;
+        assert_eq!(features.capabilities(&config, "longpoll", true), ["notes", "relay"]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::connection::Peer;
+use crate::protocol::{Role, ServerMessage};
+use crate::room::Room;
+
+/// A frame on its way to one connection
+pub struct Outbound<'a> {
+    pub message: &'a ServerMessage,
+    /// `Peer::identity` of the connection it's from, None for the server's own frames
+    pub from: Option<&'a str>,
+}
+
+/// One stage a frame passes through just before it's sent to a connection, which can hold it
+/// back from that connection. Ops on the board and answers to a connection's own frames don't
+/// go through filters. Filters run under the room's lock for every recipient, so they must be quick
+pub trait OutboundFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether `to` gets `outbound`
+    fn allows(&self, room: &Room, to: &Peer, outbound: &Outbound) -> bool;
+}
+
+/// Holds back everything from someone the recipient blocked, see `ControlMessage::Block`
+pub struct BlockList;
+
+impl OutboundFilter for BlockList {
+    fn name(&self) -> &'static str {
+        "block_list"
+    }
+
+    fn allows(&self, room: &Room, to: &Peer, outbound: &Outbound) -> bool {
+        outbound.from.is_none_or(|from| !room.blocks(to, from))
+    }
+}
+
+/// Sends someone's cursor and viewport only to whoever follows them, see `ControlMessage::Follow`
+pub struct Interest;
+
+impl OutboundFilter for Interest {
+    fn name(&self) -> &'static str {
+        "interest"
+    }
+
+    fn allows(&self, room: &Room, to: &Peer, outbound: &Outbound) -> bool {
+        match (outbound.message, outbound.from) {
+            (ServerMessage::Cursor { .. } | ServerMessage::Viewport { .. }, Some(from)) => room.follows.is_follower(&to.identity(), from),
+            _ => true,
+        }
+    }
+}
+
+/// Keeps frames of some `type`s from a connection, `filters.hidden_from_viewers` for viewers
+pub struct Redaction {
+    kinds: Vec<String>,
+}
+
+impl OutboundFilter for Redaction {
+    fn name(&self) -> &'static str {
+        "redaction"
+    }
+
+    fn allows(&self, _room: &Room, _to: &Peer, outbound: &Outbound) -> bool {
+        !self.kinds.iter().any(|kind| kind == outbound.message.kind())
+    }
+}
+
+/// The filters of one connection, run in order until one holds a frame back
+#[derive(Clone)]
+pub struct Filters(Arc<Vec<Box<dyn OutboundFilter>>>);
+
+impl Default for Filters {
+    /// Block lists and following, which every connection has
+    fn default() -> Self {
+        Filters::new(vec![Box::new(BlockList), Box::new(Interest)])
+    }
+}
+
+impl Filters {
+    pub fn new(filters: Vec<Box<dyn OutboundFilter>>) -> Self {
+        Filters(Arc::new(filters))
+    }
+
+    /// The default filters, and for a viewer what `filters.hidden_from_viewers` keeps from them
+    pub fn for_connection(config: &Config, role: Role) -> Self {
+        let hidden = &config.filters.hidden_from_viewers;
+        if role != Role::Viewer || hidden.is_empty() {
+            return Filters::default();
+        }
+        Filters::new(vec![Box::new(BlockList), Box::new(Interest), Box::new(Redaction { kinds: hidden.clone() })])
+    }
+
+    pub fn allows(&self, room: &Room, to: &Peer, outbound: &Outbound) -> bool {
+        self.0.iter().all(|filter| {
+            let allowed = filter.allows(room, to, outbound);
+            if !allowed {
+                log::trace!("Filter {} held a {} frame back from user {} in room {}", filter.name(), outbound.message.kind(), to.stats.user_id, room.id);
+            }
+            allowed
+        })
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How long someone can be gone before their follows are dropped, enough for a reload or a network blip
+const RECONNECT_GRACE: Duration = Duration::from_secs(60);
+
+/// Who follows whom in a room. Keyed by `Peer::identity` rather than user id, which a reconnect changes
+#[derive(Default)]
+pub struct Follows {
+    // Follower to followed
+    following: HashMap<String, String>,
+    // Identities of people who left, and when
+    gone: HashMap<String, Instant>,
+}
+
+impl Follows {
+    /// Follow someone, replacing whoever `follower` followed before
+    pub fn follow(&mut self, follower: &str, followed: &str) {
+        self.following.insert(follower.to_string(), followed.to_string());
+    }
+
+    pub fn unfollow(&mut self, follower: &str) {
+        self.following.remove(follower);
+    }
+
+    pub fn following(&self, follower: &str) -> Option<&str> {
+        self.following.get(follower).map(String::as_str)
+    }
+
+    pub fn is_follower(&self, follower: &str, followed: &str) -> bool {
+        self.following(follower) == Some(followed)
+    }
+
+    /// Someone's last connection closed. Their follows are kept for a while in case they come back
+    pub fn left(&mut self, identity: &str) {
+        self.prune();
+        self.gone.insert(identity.to_string(), Instant::now());
+    }
+
+    /// Someone joined, who may have left recently
+    pub fn returned(&mut self, identity: &str) {
+        self.prune();
+        self.gone.remove(identity);
+    }
+
+    /// Someone's identity changed from `from` to `to`, e.g. a guest who signed in, keeping their follows
+    pub fn rename(&mut self, from: &str, to: &str) {
+        self.following = self.following.drain().map(|(follower, followed)| (swap(follower, from, to), swap(followed, from, to))).collect();
+        if let Some(left_at) = self.gone.remove(from) {
+            self.gone.insert(to.to_string(), left_at);
+        }
+    }
+
+    fn prune(&mut self) {
+        let expired: Vec<String> = self.gone.iter().filter(|(_, left_at)| left_at.elapsed() >= RECONNECT_GRACE).map(|(identity, _)| identity.clone()).collect();
+        for identity in expired {
+            self.gone.remove(&identity);
+            self.following.retain(|follower, followed| *follower != identity && *followed != identity);
+        }
+    }
+}
+
+fn swap(identity: String, from: &str, to: &str) -> String {
+    match identity == from {
+        true => to.to_string(),
+        false => identity,
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Component, Path, PathBuf};
+
+use warp::http::header::{CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use warp::http::Response;
+use warp::path::Tail;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ConfigHandle;
+
+const INDEX: &str = "index.html";
+
+/// Serves the built Angular app from `frontend.dir`. Unknown paths requested by a browser get
+/// `index.html` so client-side routes survive a reload, and `.br`/`.gz` siblings are sent when accepted
+pub fn routes(config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::any().map(move || config.clone()))
+        .and_then(serve_file)
+}
+
+async fn serve_file(
+    tail: Tail,
+    accept: Option<String>,
+    accept_encoding: Option<String>,
+    config: ConfigHandle,
+) -> Result<Response<Vec<u8>>, Rejection> {
+    let frontend = config.borrow().frontend.clone();
+    let Some(root) = frontend.dir else {
+        return Err(warp::reject::not_found());
+    };
+    let Some(relative) = sanitize(tail.as_str()) else {
+        return Err(warp::reject::not_found());
+    };
+
+    let mut path = root.join(&relative);
+    if relative.as_os_str().is_empty() || is_dir(&path).await {
+        path = path.join(INDEX);
+    }
+    if !is_file(&path).await {
+        // Only page navigations fall back, a missing script or API typo should still 404
+        let navigation = accept.is_some_and(|a| a.contains("text/html"));
+        if !navigation {
+            return Err(warp::reject::not_found());
+        }
+        path = root.join(INDEX);
+    }
+
+    let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+    let cache_control = if path.file_name().is_some_and(|name| name == INDEX) {
+        // Revalidate the entry point so a deploy is picked up, the hashed bundles it names can be cached
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={}", frontend.cache_max_age_secs)
+    };
+
+    let (body, encoding) = read_encoded(&path, accept_encoding.as_deref().unwrap_or_default()).await
+        .map_err(|_| warp::reject::not_found())?;
+
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, content_type.as_ref())
+        .header(CACHE_CONTROL, cache_control)
+        .header(VARY, "Accept-Encoding");
+    if let Some(encoding) = encoding {
+        response = response.header(CONTENT_ENCODING, encoding);
+    }
+    response.body(body).map_err(|_| warp::reject::not_found())
+}
+
+/// The request path as a relative path under the root, refusing anything that could climb out of it
+fn sanitize(tail: &str) -> Option<PathBuf> {
+    let path = Path::new(tail);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(part) if !part.to_string_lossy().starts_with('.')))
+        .then(|| path.to_path_buf())
+}
+
+/// The pre-compressed variant of `path` the client accepts, if one was built, else the file itself
+async fn read_encoded(path: &Path, accept_encoding: &str) -> std::io::Result<(Vec<u8>, Option<&'static str>)> {
+    let accepts = |coding: &str| accept_encoding.split(',').any(|c| c.split(';').next().unwrap_or_default().trim() == coding);
+    for (coding, extension) in [("br", "br"), ("gzip", "gz")] {
+        if !accepts(coding) {
+            continue;
+        }
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".");
+        compressed.push(extension);
+        if let Ok(body) = tokio::fs::read(&compressed).await {
+            return Ok((body, Some(coding)));
+        }
+    }
+    Ok((tokio::fs::read(path).await?, None))
+}
+
+async fn is_file(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok_and(|m| m.is_file())
+}
+
+async fn is_dir(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok_and(|m| m.is_dir())
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_graphql::futures_util::stream::{self, Stream, StreamExt};
+use async_graphql::{Context, Data, EmptyMutation, Enum, Error, Object, ID, Result, Schema, SimpleObject, Subscription};
+use async_graphql_warp::{graphql, graphql_protocol, GraphQLResponse, GraphQLWebSocket};
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ConfigHandle;
+use crate::connection::ConnectionSnapshot;
+use crate::events::OpRecord;
+use crate::hub::{valid_room_id, Hub};
+use crate::ids;
+use crate::protocol::MessageType;
+use crate::room::SharedRoom;
+
+type WhiteboardSchema = Schema<Query, EmptyMutation, SubscriptionRoot>;
+
+/// What the caller proved, from `?key=` and the bearer token over HTTP, or from `{key, token}`
+/// in the `connection_init` payload of a subscription socket
+#[derive(Clone, Copy)]
+struct Access {
+    // Passed an access key, or none are configured
+    key: bool,
+    // Passed one of `auth.admin_tokens`, needed for users
+    admin: bool,
+}
+
+impl Access {
+    fn new(config: &ConfigHandle, key: Option<&str>, token: Option<&str>) -> Self {
+        let current = config.borrow();
+        let keys = &current.auth.access_keys;
+        let admin = token.is_some_and(|t| ids::is_one_of(&current.auth.admin_tokens, t));
+        Access { key: keys.is_empty() || key.is_some_and(|k| keys.iter().any(|a| a == k)) || admin, admin }
+    }
+}
+
+fn not_found() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": "not found" })), StatusCode::NOT_FOUND)
+}
+
+// GraphQL has no login sessions, so rooms with an access list are only visible to admins
+fn visible(ctx: &Context<'_>, hub: &Hub, room: &str) -> Result<bool> {
+    Ok(ctx.data::<Access>()?.admin || hub.may_access(room, None))
+}
+
+fn check_key(ctx: &Context<'_>) -> Result<()> {
+    match ctx.data::<Access>()?.key {
+        true => Ok(()),
+        false => Err(Error::new("missing or invalid key")),
+    }
+}
+
+/// `/graphql`: queries over POST or GET, and subscriptions over a `graphql-transport-ws` (or legacy `graphql-ws`) socket
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let schema = Schema::build(Query, EmptyMutation, SubscriptionRoot).data(hub).finish();
+
+    // Answered rather than rejected while disabled, warp would turn a rejection here into a 405 from another route
+    let enabled_config = config.clone();
+    let disabled = warp::any().and_then(move || {
+        let enabled = enabled_config.borrow().graphql.enabled;
+        async move {
+            match enabled {
+                true => Err(warp::reject::not_found()),
+                false => Ok(not_found()),
+            }
+        }
+    });
+
+    let socket_schema = schema.clone();
+    let socket_config = config.clone();
+    let subscriptions = warp::ws().and(graphql_protocol()).map(move |ws: warp::ws::Ws, protocol: async_graphql::http::WebSocketProtocols| {
+        let (schema, config) = (socket_schema.clone(), socket_config.clone());
+        let reply = ws.on_upgrade(move |socket| {
+            GraphQLWebSocket::new(socket, schema, protocol)
+                .on_connection_init(move |payload: Value| async move {
+                    let field = |name: &str| payload.get(name).and_then(Value::as_str).map(str::to_string);
+                    let mut data = Data::default();
+                    data.insert(Access::new(&config, field("key").as_deref(), field("token").as_deref()));
+                    Ok(data)
+                })
+                .serve()
+        });
+        warp::reply::with_header(reply, "Sec-WebSocket-Protocol", protocol.sec_websocket_protocol())
+    });
+
+    let queries = graphql(schema)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || config.clone()))
+        .then(|(schema, request): (WhiteboardSchema, async_graphql::Request), header: Option<String>, query: HashMap<String, String>, config: ConfigHandle| async move {
+            let token = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+            let access = Access::new(&config, query.get("key").map(String::as_str), token);
+            GraphQLResponse::from(schema.execute(request.data(access)).await)
+        });
+
+    warp::path("graphql")
+        .and(warp::path::end())
+        .and(disabled.or(subscriptions).or(queries))
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// Rooms resident in memory
+    async fn rooms(&self, ctx: &Context<'_>) -> Result<Vec<Room>> {
+        check_key(ctx)?;
+        let hub = ctx.data::<Arc<Hub>>()?;
+        let mut rooms = Vec::new();
+        for room in hub.rooms().await {
+            let id = room.read().await.id.clone();
+            if visible(ctx, hub, &id)? {
+                rooms.push(Room { id, room });
+            }
+        }
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(rooms)
+    }
+
+    /// A room resident in memory, rooms aren't loaded just to be queried
+    async fn room(&self, ctx: &Context<'_>, id: String) -> Result<Option<Room>> {
+        check_key(ctx)?;
+        let hub = ctx.data::<Arc<Hub>>()?;
+        if !visible(ctx, hub, &id)? {
+            return Ok(None);
+        }
+        Ok(hub.get(&id).await.map(|room| Room { id, room }))
+    }
+}
+
+struct Room {
+    id: String,
+    room: SharedRoom,
+}
+
+#[Object]
+impl Room {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn name(&self) -> Option<String> {
+        self.room.read().await.info.name.clone()
+    }
+
+    async fn description(&self) -> Option<String> {
+        self.room.read().await.info.description.clone()
+    }
+
+    async fn tags(&self) -> Vec<String> {
+        self.room.read().await.info.tags.clone()
+    }
+
+    /// People in the room, a signed-in user's tabs count once
+    async fn participants(&self) -> usize {
+        self.room.read().await.participants()
+    }
+
+    /// Draw and erase ops since the room was loaded, this survives clears
+    async fn total_strokes(&self) -> u64 {
+        self.room.read().await.total_strokes
+    }
+
+    /// Everyone connected, needs an admin token
+    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<User>> {
+        if !ctx.data::<Access>()?.admin {
+            return Err(Error::new("unauthorized"));
+        }
+        let mut snapshots: Vec<ConnectionSnapshot> = self.room.read().await.users.values().map(|peer| peer.stats.snapshot()).collect();
+        snapshots.sort_by_key(|s| s.user_id);
+        Ok(snapshots.into_iter().map(User::from).collect())
+    }
+
+    /// The board as joiners get it, oldest op first
+    async fn strokes(&self, #[graphql(default = 0)] offset: usize, limit: Option<usize>) -> Vec<Op> {
+        let room = self.room.read().await;
+        room.history.iter().skip(offset).take(limit.unwrap_or(usize::MAX)).map(Op::from).collect()
+    }
+}
+
+#[derive(SimpleObject)]
+struct User {
+    user_id: ID,
+    /// Unix seconds
+    connected_at: u64,
+    messages_in: u64,
+    messages_out: u64,
+    rtt_ms: Option<f64>,
+}
+
+impl From<ConnectionSnapshot> for User {
+    fn from(snapshot: ConnectionSnapshot) -> Self {
+        User {
+            user_id: snapshot.user_id.to_string().into(),
+            connected_at: snapshot.connected_at,
+            messages_in: snapshot.messages_in,
+            messages_out: snapshot.messages_out,
+            rtt_ms: snapshot.rtt_ms,
+        }
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+enum OpKind {
+    Draw,
+    Clear,
+    Erase,
+}
+
+/// One op, flattened since GraphQL has no tagged unions of plain values. Fields an op doesn't have are null
+#[derive(SimpleObject)]
+struct Op {
+    kind: OpKind,
+    prev: Option<Vec<f64>>,
+    cur: Option<Vec<f64>>,
+    color: Option<String>,
+    brush_size: Option<u32>,
+}
+
+impl From<&MessageType> for Op {
+    fn from(op: &MessageType) -> Self {
+        match op {
+            MessageType::Draw(draw) if draw.composite.erases() => Op {
+                kind: OpKind::Erase,
+                prev: Some(draw.prev.to_vec()),
+                cur: Some(draw.cur.to_vec()),
+                color: None,
+                brush_size: Some(draw.brush_size),
+            },
+            MessageType::Draw(draw) => Op {
+                kind: OpKind::Draw,
+                prev: Some(draw.prev.to_vec()),
+                cur: Some(draw.cur.to_vec()),
+                color: Some(draw.color.clone()),
+                brush_size: Some(draw.brush_size),
+            },
+            MessageType::Clear => Op { kind: OpKind::Clear, prev: None, cur: None, color: None, brush_size: None },
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct OpEvent {
+    room: String,
+    user_id: ID,
+    /// The room's sequence number for the op
+    seq: u64,
+    /// Unix milliseconds
+    timestamp: u64,
+    op: Op,
+}
+
+impl From<OpRecord> for OpEvent {
+    fn from(record: OpRecord) -> Self {
+        OpEvent { op: Op::from(&record.op), room: record.room, user_id: record.user_id.to_string().into(), seq: record.seq, timestamp: record.timestamp }
+    }
+}
+
+struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every op accepted into a room from now on. A subscriber that falls too far behind misses ops
+    async fn room_ops(&self, ctx: &Context<'_>, room: String) -> Result<impl Stream<Item = OpEvent>> {
+        check_key(ctx)?;
+        if !valid_room_id(&room) {
+            return Err(Error::new("invalid room id"));
+        }
+        let hub = ctx.data::<Arc<Hub>>()?;
+        if !visible(ctx, hub, &room)? {
+            return Err(Error::new("not on the room's access list"));
+        }
+        let rx = hub.ops.subscribe();
+        let records = stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(record) => return Some((record, rx)),
+                    Err(RecvError::Lagged(skipped)) => log::warn!("GraphQL subscriber fell behind, {} ops skipped", skipped),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(records.filter(move |record| std::future::ready(record.room == room)).map(OpEvent::from))
+    }
+}
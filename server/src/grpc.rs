@@ -0,0 +1,238 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+use warp::ws::Message;
+
+use crate::config::{Config, ConfigHandle};
+use crate::connection::{ConnectionStats, DisconnectReason, Peer};
+use crate::hub::{Hub, DEFAULT_ROOM};
+use crate::ids::UserId;
+use crate::protocol::{Composite, DrawCommand, EraseCommand, MessageType};
+use crate::room::SharedRoom;
+use crate::socket::{self, Inbound};
+use crate::trial;
+
+pub mod proto {
+    tonic::include_proto!("whiteboard.v1");
+}
+
+use proto::whiteboard_server::{Whiteboard, WhiteboardServer};
+use proto::{client_frame, op, server_frame, ClientFrame, Op, Point, ServerFrame};
+
+// Frames waiting for a slow gRPC client, beyond this the writer waits and the room's queue grows instead
+const STREAM_BUFFER: usize = 64;
+
+/// Binds `grpc.bind:grpc.port` so a taken port fails startup like the HTTP listeners
+pub fn bind(config: &Config) -> Result<TcpIncoming, Box<dyn std::error::Error + Send + Sync>> {
+    let addr = SocketAddr::new(config.grpc.bind.unwrap_or(config.server.bind), config.grpc.port);
+    TcpIncoming::new(addr, true, None)
+}
+
+pub async fn serve(incoming: TcpIncoming, hub: Arc<Hub>, config: ConfigHandle) {
+    let service = WhiteboardServer::new(Service { hub, config });
+    if let Err(e) = tonic::transport::Server::builder().add_service(service).serve_with_incoming(incoming).await {
+        log::error!("gRPC server error: {}", e);
+    }
+}
+
+struct Service {
+    hub: Arc<Hub>,
+    config: ConfigHandle,
+}
+
+#[tonic::async_trait]
+impl Whiteboard for Service {
+    type SessionStream = ReceiverStream<Result<ServerFrame, Status>>;
+
+    /// Checks the join frame the way `socket::upgrade` checks a WebSocket, then hands the call to a connection task
+    async fn session(&self, request: Request<Streaming<ClientFrame>>) -> Result<Response<Self::SessionStream>, Status> {
+        let remote_addr = request.remote_addr().map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+        let mut frames = request.into_inner();
+        let join = match frames.message().await? {
+            Some(ClientFrame { frame: Some(client_frame::Frame::Join(join)) }) => join,
+            _ => return Err(Status::invalid_argument("the first frame must be join")),
+        };
+        let current = self.config.borrow().clone();
+
+        let room = if join.room.is_empty() { DEFAULT_ROOM } else { join.room.as_str() };
+        let Some(room_id) = self.hub.room_id(room) else {
+            return Err(Status::invalid_argument("invalid room id"));
+        };
+        // The owner checks everything else, its HTTP URL is in the `location` metadata
+        if let Some(owner) = self.hub.cluster.remote_owner(&room_id, Duration::from_secs(current.cluster.dead_after_secs)) {
+            let mut status = Status::unavailable(format!("room is on cluster node {}", owner.node_id));
+            if let Ok(url) = owner.url.parse() {
+                status.metadata_mut().insert("location", url);
+            }
+            return Err(status);
+        }
+        let keys = &current.auth.access_keys;
+        if !keys.is_empty() && !keys.contains(&join.key) {
+            return Err(Status::unauthenticated("missing or invalid key"));
+        }
+        if !self.hub.may_access(&room_id, Some(join.token.as_str()).filter(|t| !t.is_empty())) {
+            return Err(Status::permission_denied("not on the room's access list"));
+        }
+        if let Err(e) = self.hub.quotas.check_join(&room_id, &self.hub.usage.room(&room_id)) {
+            log::info!("Refused gRPC join to room {}: {}", room_id, e);
+            return Err(Status::resource_exhausted(e.to_string()));
+        }
+        let room = match self.hub.open(&room_id).await {
+            Ok(room) => room,
+            Err(e) => {
+                log::error!("Could not load room {}: {}", room_id, e);
+                return Err(Status::unavailable("room unavailable"));
+            }
+        };
+        if let Some(opens_at) = room.read().await.info.opens_later() {
+            return Err(Status::failed_precondition(format!("not yet open, opens at {}", opens_at)));
+        }
+        if socket::is_full(&self.hub, &room, trial::capacity_of(&current, &room_id)).await {
+            return Err(Status::resource_exhausted("room is full"));
+        }
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(connect_user(frames, tx, self.hub.clone(), room, remote_addr, self.config.clone()));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+async fn connect_user(
+    mut frames: Streaming<ClientFrame>,
+    tx: mpsc::Sender<Result<ServerFrame, Status>>,
+    hub: Arc<Hub>,
+    room: SharedRoom,
+    remote_addr: Option<SocketAddr>,
+    config: ConfigHandle,
+) {
+    let current_user_id = UserId::random();
+    let connected_at = Instant::now();
+    let room_id = room.read().await.id.clone();
+    let stats = Arc::new(ConnectionStats::new(current_user_id, room_id.clone(), remote_addr, "grpc"));
+
+    let (message_sender, mut rx) = mpsc::unbounded_channel::<Message>();
+    let writer_stats = stats.clone();
+    let mut writer = tokio::task::spawn(socket::isolated(hub.clone(), "writer", current_user_id, room_id.clone(), async move {
+        while let Some(message) = rx.recv().await {
+            writer_stats.sent();
+            if message.is_close() {
+                break;
+            }
+            let Some(frame) = server_frame(&message) else {
+                continue;
+            };
+            // Only fails once the client has gone
+            if tx.send(Ok(frame)).await.is_err() {
+                break;
+            }
+        }
+    }));
+
+    let capabilities = socket::capabilities(&hub, &room, &config, stats.transport).await;
+    socket::join(&hub, &room, Peer::new(message_sender.clone(), stats.clone()).with_capabilities(capabilities)).await;
+
+    let reader = async {
+        let mut inbound = Inbound::new(&config.borrow().limits);
+        loop {
+            let frame = tokio::select! {
+                frame = frames.message() => match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(status) => {
+                        log::warn!("Could not receive message from user {}: {}", current_user_id, status.message());
+                        stats.closing(DisconnectReason::Error);
+                        break;
+                    }
+                },
+                _ = &mut writer => break,
+            };
+            let Some(client_frame::Frame::Op(op)) = frame.frame else {
+                log::debug!("Ignored a gRPC frame from user {} that isn't an op", current_user_id);
+                continue;
+            };
+            let Some(op) = op.op.map(message_type) else {
+                continue;
+            };
+            let serialized = match serde_json::to_string(&op) {
+                Ok(serialized) => serialized,
+                Err(e) => {
+                    log::error!("Serialization error: {}", e);
+                    continue;
+                }
+            };
+            // Rejections are already logged and counted
+            let _ = inbound.handle(Message::text(serialized), &hub, &room, &stats, &config).await;
+        }
+    };
+    if socket::isolated(hub.clone(), "reader", current_user_id, room_id, reader).await.is_none() {
+        stats.closing(DisconnectReason::Error);
+    }
+    drop(message_sender);
+
+    socket::leave(&hub, &room, &stats, connected_at).await;
+}
+
+fn point(point: Option<Point>) -> [f64; 2] {
+    point.map(|p| [p.x, p.y]).unwrap_or_default()
+}
+
+fn message_type(op: op::Op) -> MessageType {
+    match op {
+        op::Op::Draw(draw) => MessageType::Draw(DrawCommand {
+            prev: point(draw.prev),
+            cur: point(draw.cur),
+            color: draw.color,
+            brush_size: draw.brush_size,
+            composite: Composite::SourceOver,
+        }),
+        op::Op::Clear(_) => MessageType::Clear,
+        op::Op::Erase(erase) => MessageType::Draw(
+            EraseCommand {
+                prev: point(erase.prev),
+                cur: point(erase.cur),
+                brush_size: erase.brush_size,
+            }
+            .into(),
+        ),
+    }
+}
+
+/// A frame queued for a WebSocket, as the typed gRPC frame
+fn server_frame(message: &Message) -> Option<ServerFrame> {
+    let json: Value = serde_json::from_str(message.to_str().ok()?).ok()?;
+    if json.get("type").and_then(Value::as_str) == Some("Error") {
+        let data = json.get("data")?;
+        let field = |name: &str| data.get(name).and_then(Value::as_str).unwrap_or_default().to_string();
+        return Some(ServerFrame {
+            frame: Some(server_frame::Frame::Error(proto::Error {
+                code: field("code"),
+                message: field("message"),
+                correlation_id: field("correlation_id"),
+            })),
+        });
+    }
+
+    let point = |[x, y]: [f64; 2]| Some(Point { x, y });
+    let op = match serde_json::from_value(json).ok()? {
+        // The proto keeps its own Erase, so typed clients don't need to know about composites
+        MessageType::Draw(draw) if draw.composite.erases() => op::Op::Erase(proto::Erase {
+            prev: point(draw.prev),
+            cur: point(draw.cur),
+            brush_size: draw.brush_size,
+        }),
+        MessageType::Draw(draw) => op::Op::Draw(proto::Draw {
+            prev: point(draw.prev),
+            cur: point(draw.cur),
+            color: draw.color,
+            brush_size: draw.brush_size,
+        }),
+        MessageType::Clear => op::Op::Clear(proto::Clear {}),
+    };
+    Some(ServerFrame { frame: Some(server_frame::Frame::Op(Op { op: Some(op) })) })
+}
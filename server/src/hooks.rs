@@ -0,0 +1,96 @@
+use serde::Deserialize;
+
+use crate::ids::UserId;
+use crate::protocol::MessageType;
+
+/// What a hook made of an op a user sent
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Outcome {
+    /// Replaces the op, e.g. a recoloured stroke
+    pub op: Option<MessageType>,
+    /// Drops the op, the sender is sent an error frame with this reason
+    pub reject: Option<String>,
+    /// Drawn into the room after the op by the room's bot
+    pub emit: Vec<MessageType>,
+}
+
+/// Extension points in message processing that operators can fill without recompiling,
+/// e.g. with WASM plugins. Hooks run under the room's lock, so they must be quick
+pub trait Hook: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn on_message(&self, room: &str, user_id: UserId, op: &MessageType) -> Result<Outcome, String>;
+
+    /// Ops to draw when a user joins, after they were sent the board
+    fn on_join(&self, room: &str, user_id: UserId) -> Result<Vec<MessageType>, String>;
+
+    /// Ops to draw into a room that was just loaded
+    fn on_room_create(&self, room: &str) -> Result<Vec<MessageType>, String>;
+
+    /// Ops to draw into a resident room, called about once a minute, e.g. to clear boards on a schedule
+    fn on_tick(&self, _room: &str) -> Result<Vec<MessageType>, String> {
+        Ok(Vec::new())
+    }
+}
+
+/// Every configured hook, run in order. A hook that fails is logged and skipped rather than
+/// blocking the room
+pub struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Box<dyn Hook>>) -> Self {
+        Hooks { hooks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// The op to apply, or why it was rejected, and the ops to draw after it.
+    /// Each hook sees the op as the previous one left it
+    pub fn on_message(&self, room: &str, user_id: UserId, mut op: MessageType) -> (Result<MessageType, String>, Vec<MessageType>) {
+        let mut emit = Vec::new();
+        for hook in &self.hooks {
+            match hook.on_message(room, user_id, &op) {
+                Ok(outcome) => {
+                    emit.extend(outcome.emit);
+                    if let Some(reason) = outcome.reject {
+                        log::debug!("Hook {} rejected an op from user {} in room {}: {}", hook.name(), user_id, room, reason);
+                        return (Err(reason), emit);
+                    }
+                    if let Some(replacement) = outcome.op {
+                        op = replacement;
+                    }
+                }
+                Err(e) => log::warn!("Hook {} on_message failed in room {}: {}", hook.name(), room, e),
+            }
+        }
+        (Ok(op), emit)
+    }
+
+    pub fn on_join(&self, room: &str, user_id: UserId) -> Vec<MessageType> {
+        self.collect("on_join", room, |hook| hook.on_join(room, user_id))
+    }
+
+    pub fn on_room_create(&self, room: &str) -> Vec<MessageType> {
+        self.collect("on_room_create", room, |hook| hook.on_room_create(room))
+    }
+
+    pub fn on_tick(&self, room: &str) -> Vec<MessageType> {
+        self.collect("on_tick", room, |hook| hook.on_tick(room))
+    }
+
+    fn collect(&self, name: &str, room: &str, call: impl Fn(&dyn Hook) -> Result<Vec<MessageType>, String>) -> Vec<MessageType> {
+        let mut emit = Vec::new();
+        for hook in &self.hooks {
+            match call(hook.as_ref()) {
+                Ok(ops) => emit.extend(ops),
+                Err(e) => log::warn!("Hook {} {} failed in room {}: {}", hook.name(), name, room, e),
+            }
+        }
+        emit
+    }
+}
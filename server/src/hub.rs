@@ -0,0 +1,914 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Notify, RwLock};
+use warp::ws::Message;
+
+use crate::accounts::{Account, Accounts};
+use crate::backup::{self, Backup};
+use crate::bot::Bots;
+use crate::cluster::Cluster;
+use crate::connection::DisconnectReason;
+use crate::events::{now_millis, EventBus, OpFeed, ServerEvent};
+use crate::features::TenantFeatures;
+use crate::hooks::Hooks;
+use crate::links::Links;
+use crate::metrics::Metrics;
+use crate::moderation::Moderation;
+use crate::notes::{self, Notes};
+use crate::notices::Notices;
+use crate::profiles::Profiles;
+use crate::public_ids::{self, Plain, PublicIds};
+use crate::protocol::{MessageType, ServerMessage};
+use crate::render::{self, Thumbnails};
+use crate::reporting;
+use crate::reports::Report;
+use crate::socket;
+use crate::room::{Room, RoomInfo, SessionSummary, SharedRoom, Stroke, Template, Visibility};
+use crate::schedule::{self, Action, Cron};
+use crate::shared::SharedState;
+use crate::storage::Storage;
+use crate::sync::SyncSnapshot;
+use crate::trial;
+use crate::usage::{Metering, QuotaProvider};
+
+// Where clients connecting to plain `/room` end up
+pub const DEFAULT_ROOM: &str = "default";
+
+const MAX_ROOM_ID_LEN: usize = 64;
+
+// Ops `refresh_sync` copies off a board per hold of its read lock
+const SYNC_COPY_OPS: usize = 1000;
+
+/// Room ids end up in storage keys and file names, so keep them to a safe alphabet
+pub fn valid_room_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ROOM_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// What the history takes up serialized, the same for every storage backend
+pub fn stored_size(history: &[crate::protocol::MessageType]) -> u64 {
+    serde_json::to_vec(history).map(|b| b.len() as u64).unwrap_or_default()
+}
+
+/// What `Hub::open` fails with for archived rooms, see `is_archived`
+#[derive(Debug)]
+struct Archived;
+
+impl std::fmt::Display for Archived {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "room is archived")
+    }
+}
+
+impl std::error::Error for Archived {}
+
+/// Whether opening a room failed because it's archived, rather than storage failing
+pub fn is_archived(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Archived>())
+}
+
+/// Every room resident in memory, loading them from storage on first join
+pub struct Hub {
+    rooms: RwLock<HashMap<String, SharedRoom>>,
+    storage: Arc<dyn Storage>,
+    pub events: EventBus,
+    pub ops: OpFeed,
+    pub usage: Metering,
+    pub metrics: Metrics,
+    pub bots: Bots,
+    pub thumbnails: Thumbnails,
+    pub profiles: Profiles,
+    pub quotas: Arc<dyn QuotaProvider>,
+    pub hooks: Hooks,
+    /// `None` unless `accounts.enabled` is set
+    pub accounts: Option<Arc<Accounts>>,
+    /// `None` unless `shared_state.enabled` is set
+    pub shared: Option<Arc<dyn SharedState>>,
+    pub links: Links,
+    /// How rooms are named in URLs, see `Hub::room_id`
+    pub public_ids: Arc<dyn PublicIds>,
+    pub notices: Notices,
+    pub moderation: Moderation,
+    pub cluster: Cluster,
+    pub tenant_features: TenantFeatures,
+    /// WebSockets that haven't sent a valid frame yet, see `limits.max_pending_connections`
+    pub pending: AtomicUsize,
+    /// Woken whenever a room's board or notes change, for `storage.durability = "fsync"` to
+    /// save them right away
+    pub changes: Notify,
+    // Reports are read, changed and written back, one at a time so none are lost
+    report_writes: Mutex<()>,
+    // Rooms `open` is loading, each by one caller while any others wait for it, and rooms `import`
+    // is restoring, which nobody loads until it's done
+    loading: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl Hub {
+    pub fn new(storage: Arc<dyn Storage>, quotas: Arc<dyn QuotaProvider>, hooks: Hooks, accounts: Option<Arc<Accounts>>, shared: Option<Arc<dyn SharedState>>) -> Self {
+        Hub {
+            rooms: RwLock::new(HashMap::new()),
+            storage,
+            events: EventBus::default(),
+            ops: OpFeed::default(),
+            usage: Metering::default(),
+            metrics: Metrics::default(),
+            bots: Bots::default(),
+            thumbnails: Thumbnails::default(),
+            profiles: Profiles::default(),
+            quotas,
+            hooks,
+            accounts,
+            shared,
+            links: Links::default(),
+            public_ids: Arc::new(Plain),
+            notices: Notices::default(),
+            moderation: Moderation::default(),
+            cluster: Cluster::default(),
+            tenant_features: TenantFeatures::default(),
+            pending: AtomicUsize::new(0),
+            changes: Notify::new(),
+            report_writes: Mutex::new(()),
+            loading: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Name rooms in URLs with `public_ids` instead of their own ids
+    pub fn with_public_ids(self, public_ids: Arc<dyn PublicIds>) -> Self {
+        Hub { public_ids, ..self }
+    }
+
+    /// The id a room has here for the one it went by in a URL, see `public_ids`. None if that
+    /// isn't a room's public id. The default room, which `/room` joins, goes by its own
+    pub fn room_id(&self, public: &str) -> Option<String> {
+        if public == DEFAULT_ROOM {
+            return Some(DEFAULT_ROOM.to_string());
+        }
+        self.public_ids.decode(public).filter(|id| valid_room_id(id))
+    }
+
+    /// What the room goes by in URLs, see `public_ids`
+    pub fn public_id(&self, room_id: &str) -> String {
+        public_ids::public_id(&*self.public_ids, room_id)
+    }
+
+    /// Whether the holder of a login session token, if any, may join or read the room. Rooms
+    /// without an access list, and every room when accounts are off, are open to guests
+    pub fn may_access(&self, room_id: &str, token: Option<&str>) -> bool {
+        let Some(accounts) = &self.accounts else {
+            return true;
+        };
+        accounts.may_join(room_id, token).unwrap_or_else(|e| {
+            log::error!("Could not check access to room {}: {}", room_id, e);
+            false
+        })
+    }
+
+    /// The account signed in with a login session token, None for guests and while accounts are off
+    pub fn account(&self, token: Option<&str>) -> Option<Account> {
+        let (accounts, token) = (self.accounts.as_ref()?, token?);
+        accounts.session(token).unwrap_or_else(|e| {
+            log::error!("Could not look up a session: {}", e);
+            None
+        })
+    }
+
+    /// The summaries of a room's past sessions, newest first
+    pub async fn sessions(&self, id: &str) -> io::Result<Vec<SessionSummary>> {
+        let mut sessions = self.storage.summaries(id).await?;
+        sessions.reverse();
+        Ok(sessions)
+    }
+
+    /// Keep an abuse report, see `reports::submit`
+    pub async fn save_report(&self, report: &Report) -> io::Result<()> {
+        let _writing = self.report_writes.lock().await;
+        self.storage.save_report(report).await
+    }
+
+    /// Every report kept, or only `room`'s, newest first
+    pub async fn reports(&self, room: Option<&str>) -> io::Result<Vec<Report>> {
+        let rooms = match room {
+            Some(room) => vec![room.to_string()],
+            None => self.storage.rooms().await?,
+        };
+        let mut reports = Vec::new();
+        for room in rooms {
+            reports.extend(self.storage.reports(&room).await?);
+        }
+        reports.sort_by_key(|report| std::cmp::Reverse(report.reported_at));
+        Ok(reports)
+    }
+
+    /// Remove a report a moderator dealt with, false if there's none with that id
+    pub async fn dismiss_report(&self, id: &str) -> io::Result<bool> {
+        let _writing = self.report_writes.lock().await;
+        for room in self.storage.rooms().await? {
+            let mut reports = self.storage.reports(&room).await?;
+            let before = reports.len();
+            reports.retain(|report| report.id != id);
+            if reports.len() != before {
+                self.storage.replace_reports(&room, &reports).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub async fn template(&self, name: &str) -> io::Result<Option<Template>> {
+        self.storage.load_template(name).await
+    }
+
+    /// Every saved template, sorted by name
+    pub async fn templates(&self) -> io::Result<Vec<Template>> {
+        let mut templates = self.storage.templates().await?;
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    pub async fn get(&self, id: &str) -> Option<SharedRoom> {
+        self.rooms.read().await.get(id).cloned()
+    }
+
+    pub async fn rooms(&self) -> Vec<SharedRoom> {
+        self.rooms.read().await.values().cloned().collect()
+    }
+
+    /// The room with this id, loading its history if it isn't resident yet
+    pub async fn open(&self, id: &str) -> io::Result<SharedRoom> {
+        if let Some(room) = self.get(id).await {
+            return Ok(room);
+        }
+
+        // Loaded off the rooms lock so rooms already resident can still be looked up, one load per room
+        let gate = self.gate(id);
+        let loaded = {
+            let _loading = gate.lock().await;
+            match self.get(id).await {
+                Some(room) => Ok(room),
+                // Boxed, it's a big future to hold in every caller's
+                None => Box::pin(self.load(id)).await,
+            }
+        };
+        self.ungate(id, gate);
+        loaded
+    }
+
+    // What loading room `id` waits on, see `loading`
+    fn gate(&self, id: &str) -> Arc<Mutex<()>> {
+        self.loading.lock().unwrap().entry(id.to_string()).or_default().clone()
+    }
+
+    // Done with `gate`, forgotten if nobody else is waiting on it
+    fn ungate(&self, id: &str, gate: Arc<Mutex<()>>) {
+        let mut loading = self.loading.lock().unwrap();
+        if loading.get(id).is_some_and(|current| Arc::ptr_eq(current, &gate)) && Arc::strong_count(&gate) == 2 {
+            loading.remove(id);
+        }
+    }
+
+    // A room from storage, made resident. Only `open` calls it, for a room that isn't
+    async fn load(&self, id: &str) -> io::Result<SharedRoom> {
+        if self.storage.archived(id).await?.is_some() {
+            return Err(io::Error::other(Archived));
+        }
+        let history = self.storage.load(id).await?;
+        let info = self.storage.load_info(id).await?.unwrap_or_default();
+        let strokes = self.storage.load_strokes(id).await?;
+        let notes = match self.storage.load_notes(id).await? {
+            Some(state) => Notes::load(&state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => Notes::default(),
+        };
+        // Its window closed while it wasn't loaded
+        if info.has_closed() {
+            self.storage.archive(id, &history, now_millis() / 1000).await?;
+            log::info!("Archived room {}, its schedule closed it", id);
+            self.events.emit(ServerEvent::RoomArchived { room: id.to_string(), info });
+            return Err(io::Error::other(Archived));
+        }
+        self.usage.stored(id, stored_size(&history));
+        // Other nodes may have drawn on it since storage last had it
+        let (history, seq, epoch) = match &self.shared {
+            Some(shared) => shared.load(id, &history).await?,
+            None => (history, 0, 0),
+        };
+        log::info!("Loaded room {} with {} ops", id, history.len());
+        let mut room = Room::new(id.to_string(), history);
+        if let Some(strokes) = strokes {
+            room.attribute(strokes);
+        }
+        (room.seq, room.epoch, room.synced_seq) = (seq, epoch, seq);
+        // What fell due on its schedule while it wasn't loaded runs on the next pass, going by
+        // when the board was last drawn on
+        if let Some(drawn_at) = room.strokes.iter().filter_map(|stroke| stroke.drawn_at).max() {
+            room.schedule_checked = room.schedule_checked.min(drawn_at / 1000);
+        }
+        room.info = info;
+        room.notes = notes;
+        let emit = self.hooks.on_room_create(id);
+        // Trimmed to `rooms.history_limit` by the room's next op
+        socket::draw_as_bot(self, &mut room, emit, usize::MAX).await;
+        let info = room.info.clone();
+        let room = Arc::new(RwLock::new(room));
+        // Nothing else makes rooms resident, but keep whatever got there first all the same
+        let room = self.rooms.write().await.entry(id.to_string()).or_insert(room).clone();
+        self.events.emit(ServerEvent::RoomCreated { room: id.to_string(), info });
+        Ok(room)
+    }
+
+    /// Save a new room's info and open it, so `room_created` already carries the info. The board
+    /// starts out as `history`, from a template
+    pub async fn create(&self, id: &str, info: RoomInfo, history: Vec<MessageType>) -> io::Result<SharedRoom> {
+        self.store_info(id, &info).await?;
+        let room = self.open(id).await?;
+        if trial::is_trial(id) {
+            // Not stored, so `open` found none
+            room.write().await.info = info;
+        }
+        if !history.is_empty() {
+            {
+                let mut room = room.write().await;
+                room.replace_board(history);
+                room.dirty = true;
+            }
+            self.save(&room).await;
+        }
+        Ok(room)
+    }
+
+    /// Save a room's board and info as template `name`, loading the room if it isn't
+    pub async fn save_template(&self, id: &str, name: &str, saved_by: Option<String>) -> io::Result<Template> {
+        let room = self.open(id).await?;
+        let template = {
+            let room = room.read().await;
+            Template {
+                name: name.to_string(),
+                info: RoomInfo { opens_at: None, closes_at: None, ..room.info.clone() },
+                history: room.history.clone(),
+                saved_by,
+                saved_at: now_millis() / 1000,
+            }
+        };
+        self.storage.save_template(&template).await?;
+        log::info!("Saved room {} as template {} with {} ops", id, name, template.history.len());
+        Ok(template)
+    }
+
+    /// Replace a room's info and save it straight away, it changes too rarely to wait for the next save
+    pub async fn set_info(&self, room: &SharedRoom, info: RoomInfo) -> io::Result<()> {
+        let id = room.read().await.id.clone();
+        self.store_info(&id, &info).await?;
+        room.write().await.info = info.clone();
+        self.events.emit(ServerEvent::RoomUpdated { room: id, info });
+        Ok(())
+    }
+
+    async fn store_info(&self, id: &str, info: &RoomInfo) -> io::Result<()> {
+        if trial::is_trial(id) {
+            return Ok(());
+        }
+        self.storage.save_info(id, info).await?;
+        if let Some(accounts) = &self.accounts {
+            accounts.set_private(id, info.visibility == Visibility::Private).map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// The username of a room's owner, None for rooms without one and while accounts are off
+    pub fn owner(&self, room_id: &str) -> Option<String> {
+        self.accounts.as_ref()?.owner_name(room_id).unwrap_or_else(|e| {
+            log::error!("Could not look up the owner of room {}: {}", room_id, e);
+            None
+        })
+    }
+
+    pub async fn save(&self, room: &SharedRoom) {
+        self.save_notes(room).await;
+        // Snapshot under the lock, write outside it so slow disks don't stall broadcasts
+        let (id, history, strokes, last_correlation_id) = {
+            let mut room = room.write().await;
+            if !room.dirty {
+                return;
+            }
+            room.dirty = false;
+            // Trial rooms live in memory only
+            if trial::is_trial(&room.id) {
+                return;
+            }
+            (room.id.clone(), room.history.clone(), room.strokes.clone(), room.last_correlation_id)
+        };
+
+        let saved = match self.storage.save(&id, &history).await {
+            Ok(()) => self.storage.save_strokes(&id, &strokes).await,
+            Err(e) => Err(e),
+        };
+        match saved {
+            Ok(()) => {
+                if let Some(correlation_id) = last_correlation_id {
+                    log::debug!("[{}] Saved room {} with {} ops", correlation_id, id, history.len());
+                }
+                self.usage.stored(&id, stored_size(&history));
+                self.events.emit(ServerEvent::SnapshotSaved { room: id, ops: history.len() });
+            }
+            Err(e) => {
+                log::error!("Could not save room {}: {}", id, e);
+                self.events.emit(ServerEvent::Error { room: Some(id.clone()), user_id: None, message: format!("save failed: {}", e) });
+                reporting::capture("storage", format!("save failed: {}", e), [("room", id)]);
+                room.write().await.dirty = true;
+            }
+        }
+    }
+
+    async fn save_notes(&self, room: &SharedRoom) {
+        let (id, state) = {
+            let mut room = room.write().await;
+            if !room.notes_dirty {
+                return;
+            }
+            room.notes_dirty = false;
+            if trial::is_trial(&room.id) {
+                return;
+            }
+            (room.id.clone(), room.notes.snapshot())
+        };
+        if let Err(e) = self.storage.save_notes(&id, &state).await {
+            log::error!("Could not save the notes of room {}: {}", id, e);
+            room.write().await.notes_dirty = true;
+        }
+    }
+
+    pub async fn save_all(&self) {
+        for room in self.rooms().await {
+            self.save(&room).await;
+        }
+    }
+
+    /// Save and unload rooms nobody has been in for `ttl`, with who did what in them on the
+    /// `room_closed` event when `summary` is set
+    pub async fn expire_idle(&self, ttl: Duration, summary: bool) {
+        for room in self.rooms().await {
+            let idle = room.read().await.empty_since.is_some_and(|since| since.elapsed() >= ttl);
+            if !idle {
+                continue;
+            }
+            self.save(&room).await;
+
+            let session = {
+                let mut rooms = self.rooms.write().await;
+                let room = room.read().await;
+                // Someone may have joined while we were saving, or be switching in
+                if !room.users.is_empty() || room.reserved > 0 || room.reserved_bots > 0 || room.dirty || room.notes_dirty {
+                    continue;
+                }
+                log::info!("Room {} idle for {:?}, unloading", room.id, ttl);
+                rooms.remove(&room.id);
+                let contributions = summary.then(|| room.contributions());
+                self.events.emit(ServerEvent::RoomClosed { room: room.id.clone(), info: room.info.clone(), contributions });
+                // Rooms only ever opened for the API, e.g. to render them, had no session
+                (room.joined > 0).then(|| room.summary())
+            };
+            if let Some(session) = session {
+                self.save_summary(&session).await;
+            }
+        }
+    }
+
+    /// Save a room's board for good and unload it, closing everyone's connection to it. It can't
+    /// be joined or opened again until it's restored
+    pub async fn archive(&self, id: &str) -> io::Result<()> {
+        let shared = self.open(id).await?;
+        let mut room = shared.write().await;
+        // Stored under the room's lock, unlike regular saves, so nothing is drawn after the board
+        // was taken. Only this room waits on the disk, the others and joins elsewhere carry on
+        self.storage.archive(id, &room.history, now_millis() / 1000).await?;
+        if room.notes_dirty {
+            self.storage.save_notes(id, &room.notes.snapshot()).await?;
+        }
+        room.archived = true;
+        room.dirty = false;
+        // Waiters leave the waitlist once their socket closes
+        for peer in room.users.values().chain(room.waitlist.iter().map(|waiter| &waiter.peer)) {
+            peer.disconnect(DisconnectReason::Kicked, 1001, "room archived");
+        }
+        log::info!("Archived room {} with {} ops", id, room.history.len());
+        self.events.emit(ServerEvent::RoomArchived { room: id.to_string(), info: room.info.clone() });
+        let session = (room.joined > 0).then(|| room.summary());
+        drop(room);
+        // Anyone who opened it meanwhile got it archived, and is turned away on joining
+        let mut rooms = self.rooms.write().await;
+        if rooms.get(id).is_some_and(|resident| Arc::ptr_eq(resident, &shared)) {
+            rooms.remove(id);
+        }
+        drop(rooms);
+        if let Some(session) = session {
+            self.save_summary(&session).await;
+        }
+        Ok(())
+    }
+
+    async fn save_summary(&self, session: &SessionSummary) {
+        if trial::is_trial(&session.room) {
+            return;
+        }
+        if let Err(e) = self.storage.save_summary(session).await {
+            log::error!("Could not save the session summary of room {}: {}", session.room, e);
+        }
+    }
+
+    /// Unload trial rooms loaded longer than `ttl`, closing everyone's connection to them. What
+    /// was drawn in them is gone
+    pub async fn expire_trials(&self, ttl: Duration) {
+        let mut rooms = self.rooms.write().await;
+        let mut expired = Vec::new();
+        for (id, room) in rooms.iter() {
+            if !trial::is_trial(id) {
+                continue;
+            }
+            let room = room.read().await;
+            if room.created_at.elapsed() < ttl {
+                continue;
+            }
+            for peer in room.users.values().chain(room.waitlist.iter().map(|waiter| &waiter.peer)) {
+                peer.disconnect(DisconnectReason::Kicked, 1001, "trial ended");
+            }
+            self.events.emit(ServerEvent::RoomClosed { room: id.clone(), info: room.info.clone(), contributions: None });
+            expired.push(id.clone());
+        }
+        for id in expired {
+            log::info!("Trial room {} reached its end, unloading", id);
+            rooms.remove(&id);
+        }
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    /// Everything stored, with resident rooms as they are in memory, see `backup`
+    pub async fn export(&self) -> io::Result<Backup> {
+        self.save_all().await;
+        let mut backup = backup::collect(&*self.storage).await?;
+        for room in self.rooms().await {
+            let room = room.read().await;
+            if trial::is_trial(&room.id) {
+                continue;
+            }
+            backup.resident(&room.id, &room.history, &room.strokes, &room.info, &room.notes);
+        }
+        backup.rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(backup)
+    }
+
+    /// Put a backup in storage. Its rooms that are resident are unloaded without saving, closing
+    /// everyone's connection with 1012, so they load what was restored on the next join
+    pub async fn import(&self, backup: &Backup) -> io::Result<()> {
+        backup.check()?;
+        // Only the rooms restored are held, so nobody loads one halfway through and everything
+        // else carries on. Always taken in the same order, so two restores can't deadlock
+        let ids: BTreeSet<&str> = backup.rooms.iter().map(|room| room.id.as_str()).collect();
+        let mut held = Vec::new();
+        for id in ids {
+            let gate = self.gate(id);
+            let guard = gate.clone().lock_owned().await;
+            held.push((id, gate, guard));
+        }
+        let resident: Vec<SharedRoom> = {
+            let mut rooms = self.rooms.write().await;
+            held.iter().filter_map(|(id, _, _)| rooms.remove(*id)).collect()
+        };
+        for room in resident {
+            let mut room = room.write().await;
+            (room.dirty, room.notes_dirty) = (false, false);
+            for peer in room.users.values().chain(room.waitlist.iter().map(|waiter| &waiter.peer)) {
+                peer.disconnect(DisconnectReason::Kicked, 1012, "room restored from a backup");
+            }
+        }
+        let applied = backup::apply(&*self.storage, backup).await;
+        for (id, gate, guard) in held {
+            drop(guard);
+            self.ungate(id, gate);
+        }
+        applied?;
+        for room in &backup.rooms {
+            if let Some(info) = &room.info {
+                self.store_info(&room.id, info).await?;
+            }
+            // Storage that doesn't keep boards and notes has them resident instead
+            if room.archived_at.is_none() && (!room.history.is_empty() || room.notes.is_some()) && !self.keeps(&room.id).await? {
+                let resident = self.create(&room.id, room.info.clone().unwrap_or_default(), room.history.clone()).await?;
+                if let Some(state) = &room.notes {
+                    let notes = notes::decode(state).and_then(|state| Notes::load(&state)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    resident.write().await.notes = notes;
+                }
+                resident.write().await.attribute(room.strokes.clone());
+            }
+        }
+        log::info!("Restored {} rooms and {} templates from a backup", backup.rooms.len(), backup.templates.len());
+        Ok(())
+    }
+
+    // Whether storage has a board or notes for the room
+    async fn keeps(&self, id: &str) -> io::Result<bool> {
+        Ok(!self.storage.load(id).await?.is_empty() || self.storage.load_notes(id).await?.is_some())
+    }
+
+    /// Make an archived room joinable again, false if it wasn't archived. It's loaded on next join
+    pub async fn restore(&self, id: &str) -> io::Result<bool> {
+        let restored = self.storage.restore(id).await?;
+        if restored {
+            log::info!("Restored room {}", id);
+            self.events.emit(ServerEvent::RoomRestored { room: id.to_string() });
+        }
+        Ok(restored)
+    }
+
+    /// Archive resident rooms whose `closes_at` has passed
+    pub async fn close_scheduled(&self) {
+        for room in self.rooms().await {
+            let (id, closed) = {
+                let room = room.read().await;
+                (room.id.clone(), room.info.has_closed())
+            };
+            if !closed {
+                continue;
+            }
+            log::info!("Room {} reached its closing time", id);
+            if let Err(e) = self.archive(&id).await {
+                // Archived some other way meanwhile, or storage failed and the next pass tries again
+                if !is_archived(&e) {
+                    log::error!("Could not archive room {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    /// Run what's due on each resident room's schedule, and warn everyone in a room of what's
+    /// coming within `warning`
+    pub async fn run_scheduled(&self, warning: Duration, history_limit: usize) {
+        let now = now_millis() / 1000;
+        for shared in self.rooms().await {
+            let mut room = shared.write().await;
+            // A frozen room's actions wait until it's unfrozen, like a room that wasn't loaded
+            if room.info.schedule.is_empty() || room.archived || room.frozen.is_some() {
+                continue;
+            }
+            let mut due = Vec::new();
+            let mut upcoming = Vec::new();
+            for recurring in &room.info.schedule {
+                // Checked when it was set
+                let Ok(cron) = Cron::parse(&recurring.cron) else {
+                    continue;
+                };
+                if let Some(at) = cron.next_after(room.schedule_checked).filter(|&at| at <= now) {
+                    due.push((recurring.action, at));
+                }
+                if let Some(at) = cron.next_after(now).filter(|&at| at <= now + warning.as_secs() && at > room.schedule_warned) {
+                    upcoming.push((at, recurring.action));
+                }
+            }
+            upcoming.sort_by_key(|&(at, _)| at);
+            for (at, action) in upcoming {
+                socket::broadcast(&room, &ServerMessage::Scheduled { action, at, in_secs: at.saturating_sub(now) });
+                room.schedule_warned = at;
+            }
+            // Nothing to clear or keep
+            if due.is_empty() || room.history.is_empty() {
+                room.schedule_checked = now;
+                continue;
+            }
+            // Archiving clears the board too, so one clear for both. The board is written with the
+            // room unlocked and only cleared if nothing was drawn on it meanwhile, otherwise it's
+            // written again on the next pass, under the same id as it's named for the occurrence
+            let archive = due.iter().filter(|&&(action, _)| action == Action::Archive).map(|&(_, at)| at).max();
+            if let Some(at) = archive.filter(|_| !trial::is_trial(&room.id)) {
+                let drawn = (room.epoch, room.strokes.last().map(|stroke| stroke.stroke_id));
+                let (id, info, history, strokes) = (room.id.clone(), room.info.clone(), room.history.clone(), room.strokes.clone());
+                drop(room);
+                if let Err(e) = self.archive_board(&id, info, &history, &strokes, at).await {
+                    log::error!("Could not archive the board of room {} on its schedule, trying again: {}", id, e);
+                    continue;
+                }
+                room = shared.write().await;
+                if drawn != (room.epoch, room.strokes.last().map(|stroke| stroke.stroke_id)) {
+                    log::info!("Room {} was drawn on while its board was archived, archiving it again next time", id);
+                    continue;
+                }
+            }
+            log::info!("Clearing room {} on its schedule", room.id);
+            let bot = self.bots.get(&room.id).user_id;
+            // Neither is done for this occurrence until both are, what failed runs on the next pass
+            match socket::draw_as(self, &mut room, bot, MessageType::Clear, history_limit).await {
+                Ok(_) => room.schedule_checked = now,
+                Err(e) => log::error!("Could not clear room {} on its schedule, trying again: {}", room.id, e),
+            }
+        }
+    }
+
+    // Keep a room's board as an archived room of its own, see `schedule::archived_id`
+    async fn archive_board(&self, room: &str, info: RoomInfo, history: &[MessageType], strokes: &[Stroke], at: u64) -> io::Result<()> {
+        let id = schedule::archived_id(room, at);
+        let info = RoomInfo { schedule: Vec::new(), opens_at: None, closes_at: None, ..info };
+        self.storage.save_info(&id, &info).await?;
+        self.storage.save_strokes(&id, strokes).await?;
+        self.storage.archive(&id, history, at).await?;
+        log::info!("Archived the board of room {} as {} with {} ops", room, id, history.len());
+        self.events.emit(ServerEvent::RoomArchived { room: id, info });
+        Ok(())
+    }
+
+    /// Run the `on_tick` hooks for every resident room
+    pub async fn tick_hooks(&self, history_limit: usize) {
+        for room in self.rooms().await {
+            let mut room = room.write().await;
+            let emit = self.hooks.on_tick(&room.id);
+            socket::draw_as_bot(self, &mut room, emit, history_limit).await;
+        }
+    }
+
+    /// Update who's idle or away in each resident room
+    pub async fn update_presence(&self, idle_after: Duration, away_after: Duration, share_latency: bool) {
+        for room in self.rooms().await {
+            socket::update_presence(&mut *room.write().await, idle_after, away_after, share_latency);
+        }
+    }
+
+    /// Send a `Checksum` to everyone in each room whose board changed since its last one
+    pub async fn send_checksums(&self) {
+        for room in self.rooms().await {
+            socket::send_checksum(&mut *room.write().await);
+        }
+    }
+
+    /// Drop the boards kept for `UndoClear` once their clear is `grace` old
+    pub async fn forget_clears(&self, grace: Duration) {
+        for room in self.rooms().await {
+            room.write().await.forget_cleared(grace);
+        }
+    }
+
+    /// Snapshot the board of each resident room that's long enough for joiners, see `SyncSnapshot`,
+    /// and let go of snapshots of boards that got short again
+    pub async fn refresh_sync(&self, min_ops: usize) {
+        'rooms: for room in self.rooms().await {
+            let epoch = {
+                let current = room.read().await;
+                if current.sync.is_some() && (min_ops == 0 || current.history.len() < min_ops) {
+                    drop(current);
+                    room.write().await.sync = None;
+                    continue;
+                }
+                if !SyncSnapshot::is_stale(current.sync.as_deref(), &current, min_ops) {
+                    continue;
+                }
+                current.epoch
+            };
+            // Copied `SYNC_COPY_OPS` at a time so nobody drawing waits on the whole board. Ops that
+            // fall off its front meanwhile stay in the snapshot, `covering` skips them
+            let (mut history, mut stroke_ids) = (Vec::new(), Vec::new());
+            loop {
+                let current = room.read().await;
+                if current.epoch != epoch {
+                    continue 'rooms;
+                }
+                let from = stroke_ids.last().map_or(0, |&last| current.strokes.partition_point(|stroke| stroke.stroke_id <= last));
+                let to = current.history.len().min(from + SYNC_COPY_OPS);
+                history.extend_from_slice(&current.history[from..to]);
+                stroke_ids.extend(current.strokes[from..to].iter().map(|stroke| stroke.stroke_id));
+                if to == current.history.len() {
+                    break;
+                }
+            }
+            // Serializing a long board is CPU-bound, keep it off the runtime's workers
+            let snapshot = match tokio::task::spawn_blocking(move || SyncSnapshot::build(epoch, &history, stroke_ids)).await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    log::warn!("Snapshot for joiners panicked: {}", e);
+                    continue;
+                }
+            };
+            // Ops drawn meanwhile are the tail joiners get serialized live, until the next one
+            let mut room = room.write().await;
+            if snapshot.covering(&room).is_some() {
+                room.sync = Some(Arc::new(snapshot));
+            }
+        }
+    }
+
+    /// Re-render the thumbnail of each resident room that changed since its last one
+    pub async fn render_thumbnails(&self, size: u32) {
+        let mut resident = Vec::new();
+        for room in self.rooms().await {
+            let (id, seq, history) = {
+                let room = room.read().await;
+                resident.push(room.id.clone());
+                if self.thumbnails.is_current(&room.id, room.seq, size) {
+                    continue;
+                }
+                (room.id.clone(), room.seq, room.history.clone())
+            };
+            // Rasterizing is CPU-bound, keep it off the runtime's workers
+            match tokio::task::spawn_blocking(move || render::thumbnail(&history, size)).await {
+                Ok(Ok(png)) => self.thumbnails.insert(id, seq, size, png),
+                Ok(Err(e)) => log::warn!("Could not render a thumbnail of room {}: {}", id, e),
+                Err(e) => log::warn!("Thumbnail of room {} panicked: {}", id, e),
+            }
+        }
+        self.thumbnails.retain(&resident);
+    }
+
+    /// Unload a room another cluster node owns now and save its board, sending everyone in it to
+    /// `url`, where it's loaded from storage again
+    pub async fn hand_off(&self, room: &SharedRoom, url: &str) {
+        let redirect = serde_json::to_string(&ServerMessage::Redirect { url: url.to_string() }).unwrap_or_default();
+        {
+            let mut rooms = self.rooms.write().await;
+            let room = room.read().await;
+            for peer in room.users.values().chain(room.waitlist.iter().map(|waiter| &waiter.peer)) {
+                peer.send(Message::text(redirect.clone()));
+                peer.disconnect(DisconnectReason::Kicked, socket::REDIRECT_CODE, "redirect");
+            }
+            rooms.remove(&room.id);
+        }
+        // After they were sent off, so what they drew on the way out is saved too
+        self.save(room).await;
+    }
+
+    /// Ask every connected socket to close, used on shutdown
+    pub async fn close_all(&self) {
+        for room in self.rooms().await {
+            for peer in room.read().await.users.values() {
+                peer.disconnect(DisconnectReason::ServerShutdown, 1001, "server shutting down");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::MemoryStorage;
+    use crate::usage::ConfigQuotas;
+
+    #[tokio::test]
+    async fn a_room_opened_by_many_at_once_is_loaded_once() {
+        let (_, config) = tokio::sync::watch::channel(Arc::new(Config::default()));
+        let hub = Hub::new(Arc::new(MemoryStorage::default()), Arc::new(ConfigQuotas::new(config)), Hooks::new(Vec::new()), None, None);
+        let mut events = hub.events.subscribe();
+        let opened = futures_util::future::join_all((0..8).map(|_| hub.open("busy"))).await;
+        let first = opened[0].as_ref().unwrap();
+        assert!(opened.iter().all(|room| Arc::ptr_eq(room.as_ref().unwrap(), first)));
+        assert!(matches!(events.try_recv().unwrap().event, ServerEvent::RoomCreated { .. }));
+        assert!(events.try_recv().is_err());
+        assert!(hub.loading.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn archiving_a_room_holds_up_no_other() {
+        let (_, config) = tokio::sync::watch::channel(Arc::new(Config::default()));
+        let hub = Arc::new(Hub::new(Arc::new(MemoryStorage::default()), Arc::new(ConfigQuotas::new(config)), Hooks::new(Vec::new()), None, None));
+        let busy = hub.open("busy").await.unwrap();
+        // The archive waits for this room, and only this room
+        let held = busy.read().await;
+        let archiving = tokio::spawn({
+            let hub = hub.clone();
+            async move { hub.archive("busy").await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let other = tokio::time::timeout(Duration::from_secs(1), hub.open("other")).await;
+        assert!(other.expect("opening another room waited on the archive").is_ok());
+        drop(held);
+        archiving.await.unwrap().unwrap();
+        assert!(hub.get("busy").await.is_none());
+        assert!(hub.storage.archived("busy").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn restoring_a_backup_holds_up_only_its_rooms() {
+        let (_, config) = tokio::sync::watch::channel(Arc::new(Config::default()));
+        let hub = Arc::new(Hub::new(Arc::new(MemoryStorage::default()), Arc::new(ConfigQuotas::new(config)), Hooks::new(Vec::new()), None, None));
+        let room = |id: &str| backup::RoomBackup { id: id.to_string(), ..backup::RoomBackup::default() };
+        let escaping = Backup { rooms: vec![room("../x")], ..Backup::default() };
+        assert_eq!(hub.import(&escaping).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Someone's loading the restored room, the restore waits for them and only that
+        let gate = hub.gate("restored");
+        let loading = gate.lock().await;
+        let restoring = tokio::spawn({
+            let hub = hub.clone();
+            async move { hub.import(&Backup { rooms: vec![room("restored")], ..Backup::default() }).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let other = tokio::time::timeout(Duration::from_secs(1), hub.open("other")).await;
+        assert!(other.expect("opening another room waited on the restore").is_ok());
+        assert!(!restoring.is_finished());
+        drop(loading);
+        hub.ungate("restored", gate);
+        restoring.await.unwrap().unwrap();
+        assert!(hub.loading.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+use utoipa::openapi::schema::{ObjectBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+
+/// A participant, random so ids can't be guessed and don't repeat across restarts.
+/// Written as 16 hex digits, in JSON as a string since JavaScript numbers can't hold 64 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UserId(u64);
+
+impl UserId {
+    pub fn random() -> Self {
+        UserId(rand::thread_rng().gen())
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 16 {
+            return Err("expected 16 hex digits".to_string());
+        }
+        u64::from_str_radix(s, 16).map(UserId).map_err(|e| e.to_string())
+    }
+}
+
+impl Serialize for UserId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UserId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl utoipa::PartialSchema for UserId {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new().schema_type(Type::String).description(Some("16 hex digits")).examples(["3f9c0a51d27e84b6"]).into()
+    }
+}
+
+impl utoipa::ToSchema for UserId {}
+
+/// A fresh room id nobody could guess, within `valid_room_id`'s alphabet
+pub fn random_room_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// A secret handed to a connection, like a resume token
+pub fn random_token() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// Whether `given` is one of `secrets`, like `auth.admin_tokens`. Compared in constant time, so
+/// how long it takes says nothing about how much of a secret a guess got right or how long it is
+pub fn is_one_of<'a>(secrets: impl IntoIterator<Item = &'a String>, given: &str) -> bool {
+    // Both sides MAC'd to the same length first, `verify_slice` then compares in constant time
+    let mac = |secret: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret comparison").expect("HMAC accepts any key length");
+        mac.update(secret.as_bytes());
+        mac
+    };
+    let given = mac(given).finalize().into_bytes();
+    secrets.into_iter().fold(false, |found, secret| mac(secret).verify_slice(&given).is_ok() | found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_match_only_in_full() {
+        let secrets = vec!["secret".to_string(), "other".to_string()];
+        assert!(is_one_of(&secrets, "secret"));
+        assert!(is_one_of(&secrets, "other"));
+        for guess in ["", "s", "secre", "secrets", "SECRET"] {
+            assert!(!is_one_of(&secrets, guess), "{}", guess);
+        }
+        assert!(!is_one_of(&Vec::new(), ""));
+    }
+}
@@ -0,0 +1,49 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::events::now_millis;
+use crate::ids::random_token;
+use crate::protocol::Role;
+
+/// Signs and checks join links, `<role>.<expires_at>.<signature>` passed as `?link=` on the
+/// WebSocket upgrade. Nothing is stored, so a link can't be revoked before it expires except by
+/// changing `links.secret`, which revokes them all
+pub struct Links {
+    // Used while `links.secret` is empty, links then stop working when the server restarts
+    fallback: String,
+}
+
+impl Default for Links {
+    fn default() -> Self {
+        Links { fallback: random_token() }
+    }
+}
+
+impl Links {
+    /// A link into `room` with `role`, good until `expires_at` in unix seconds
+    pub fn sign(&self, secret: &str, room: &str, role: Role, expires_at: u64) -> String {
+        let signature = hex::encode(self.mac(secret, room, role, expires_at).finalize().into_bytes());
+        format!("{}.{}.{}", role.name(), expires_at, signature)
+    }
+
+    /// The role a link grants, none if it's for another room, expired or wasn't signed with `secret`
+    pub fn verify(&self, secret: &str, room: &str, link: &str) -> Option<Role> {
+        let mut parts = link.splitn(3, '.');
+        let role = Role::parse(parts.next()?)?;
+        let expires_at: u64 = parts.next()?.parse().ok()?;
+        let signature = hex::decode(parts.next()?).ok()?;
+        if expires_at <= now_millis() / 1000 {
+            return None;
+        }
+        // Compared in constant time
+        self.mac(secret, room, role, expires_at).verify_slice(&signature).ok()?;
+        Some(role)
+    }
+
+    fn mac(&self, secret: &str, room: &str, role: Role, expires_at: u64) -> Hmac<Sha256> {
+        let key = if secret.is_empty() { &self.fallback } else { secret };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(format!("{}\n{}\n{}", room, role.name(), expires_at).as_bytes());
+        mac
+    }
+}
@@ -0,0 +1,361 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Request, Response};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::ReceiverStream;
+use warp::{Filter, Rejection};
+
+use crate::config::{ConfigHandle, ServerConfig};
+use crate::proxy;
+
+// How long to back off when accepting fails, e.g. when we're out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+// Accepted connections waiting for the HTTP server to pick them up
+const ACCEPT_QUEUE: usize = 128;
+
+// Hold off on connections that haven't sent their PROXY header by then
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peer address of the connection a request arrived on, or the client a PROXY header named
+#[derive(Clone, Copy, Debug)]
+struct ClientAddr(SocketAddr);
+
+/// Like `warp::addr::remote`, for connections accepted by `serve`. Requests from `server.trusted_proxies`
+/// are attributed to the client in `X-Forwarded-For` instead. Unix socket clients have no address
+pub fn remote(config: ConfigHandle) -> impl Filter<Extract = (Option<SocketAddr>,), Error = Rejection> + Clone {
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |addr: Option<ClientAddr>, forwarded_for: Option<String>| {
+            let addr = addr?.0;
+            let trusted = &config.borrow().server.trusted_proxies;
+            match proxy::forwarded_client(addr.ip(), forwarded_for.as_deref(), trusted) {
+                ip if ip == addr.ip() => Some(addr),
+                // Proxies don't forward the client's port
+                ip => Some(SocketAddr::new(ip, 0)),
+            }
+        })
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// `path` is set when we created the socket file, and so should remove it again
+    #[cfg(unix)]
+    Unix { listener: UnixListener, path: Option<PathBuf> },
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => Ok(Stream::Unix(listener.accept().await?.0)),
+        }
+    }
+
+    fn socket_file(&self) -> Option<PathBuf> {
+        match self {
+            Listener::Tcp(_) => None,
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => path.clone(),
+        }
+    }
+
+    /// The address a TCP listener ended up on, with its port picked if it was 0
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_owned())) {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix socket"),
+            },
+        }
+    }
+}
+
+/// Sockets handed to us by systemd socket activation, or else the TCP and/or Unix listeners from config
+pub async fn bind(config: &ServerConfig) -> io::Result<Vec<Listener>> {
+    if let Some(listeners) = inherited()? {
+        log::info!("Using {} socket(s) from systemd", listeners.len());
+        return Ok(listeners);
+    }
+
+    let mut listeners = Vec::new();
+    if config.tcp {
+        listeners.push(Listener::Tcp(bind_tcp(SocketAddr::new(config.bind, config.port), config.v6_only)?));
+    }
+    for extra in &config.listeners {
+        listeners.push(Listener::Tcp(bind_tcp(SocketAddr::new(extra.bind, extra.port), extra.v6_only)?));
+    }
+    if let Some(path) = &config.unix_socket {
+        listeners.push(bind_unix(path.clone())?);
+    }
+    if listeners.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "server.tcp is off and no other listeners are configured"));
+    }
+    Ok(listeners)
+}
+
+// Set IPV6_V6ONLY ourselves, the OS default for `::` differs between platforms
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Same as `TcpListener::bind`, so restarts don't wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into()).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: PathBuf) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left behind by a previous run would make bind fail, anything else we leave alone
+    if let Ok(meta) = std::fs::symlink_metadata(&path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    let listener = UnixListener::bind(&path)?;
+    Ok(Listener::Unix { listener, path: Some(path) })
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: PathBuf) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+}
+
+#[cfg(unix)]
+fn inherited() -> io::Result<Option<Vec<Listener>>> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    // See sd_listen_fds(3): the sockets are numbered from 3, and are only ours if LISTEN_PID matches
+    const LISTEN_FDS_START: i32 = 3;
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+    if count <= 0 {
+        return Ok(None);
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).map(|fd| {
+        // Safety: systemd passes these descriptors to us alone, and nothing else in the process claims them
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+        }
+
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        unix.local_addr().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("inherited fd {} is not a TCP or Unix socket: {}", fd, e))
+        })?;
+        unix.set_nonblocking(true)?;
+        // systemd owns the socket file
+        Ok(Listener::Unix { listener: UnixListener::from_std(unix)?, path: None })
+    }).collect::<io::Result<_>>().map(Some)
+}
+
+#[cfg(not(unix))]
+fn inherited() -> io::Result<Option<Vec<Listener>>> {
+    Ok(None)
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            // Dual-stack sockets report IPv4 clients as `::ffff:a.b.c.d`
+            Stream::Tcp(stream) => stream.peer_addr().ok().map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port())),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+/// A connection accepted from any of our listeners, with the client it's on behalf of
+pub struct Conn {
+    stream: Stream,
+    client: Option<SocketAddr>,
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.stream {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Serve `service` on every listener until `shutdown` resolves, tagging requests with their `ClientAddr`.
+/// With `server.proxy_protocol` every connection from `server.trusted_proxies` must open with a
+/// PROXY v1/v2 header naming the real client
+pub async fn serve<S>(service: S, listeners: Vec<Listener>, config: ConfigHandle, shutdown: impl Future<Output = ()>) -> hyper::Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let socket_files: Vec<PathBuf> = listeners.iter().filter_map(Listener::socket_file).collect();
+    {
+        let server = &config.borrow().server;
+        if server.proxy_protocol && server.trusted_proxies.is_empty() {
+            log::warn!("server.proxy_protocol is on but server.trusted_proxies is empty, PROXY headers are only read on Unix sockets");
+        }
+    }
+
+    let (tx, rx) = mpsc::channel::<Conn>(ACCEPT_QUEUE);
+    let accepting: Vec<_> = listeners.into_iter()
+        .map(|listener| tokio::spawn(accept(listener, config.clone(), tx.clone())))
+        .collect();
+    let incoming = ReceiverStream::new(rx).map(Ok::<_, Infallible>);
+
+    let make_service = make_service_fn(move |conn: &Conn| {
+        let addr = conn.client;
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                if let Some(addr) = addr {
+                    req.extensions_mut().insert(ClientAddr(addr));
+                }
+                service.clone().call(req)
+            }))
+        }
+    });
+
+    let result = hyper::Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await;
+
+    for task in accepting {
+        task.abort();
+    }
+    for path in socket_files {
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Could not remove {}: {}", path.display(), e);
+        }
+    }
+    result
+}
+
+async fn accept(listener: Listener, config: ConfigHandle, tx: mpsc::Sender<Conn>) {
+    // Restart only
+    let proxy_protocol = config.borrow().server.proxy_protocol;
+    loop {
+        let mut conn = match listener.accept().await {
+            Ok(stream) => Conn { client: stream.peer_addr(), stream },
+            Err(e) => {
+                log::warn!("Could not accept connection on {}: {}", listener, e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        // Anyone could send a header naming whoever they like, so it's only believed from a proxy
+        // we trust. Whoever can reach a Unix socket was let in by its file permissions
+        let trusted = conn.client.is_none_or(|client| config.borrow().server.trusted_proxies.iter().any(|cidr| cidr.contains(client.ip())));
+        if !proxy_protocol || !trusted {
+            if tx.send(conn).await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        // Read the header off to the side, so a slow proxy connection doesn't hold up the rest
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut conn)).await {
+                Ok(Ok(client)) => {
+                    conn.client = client.or(conn.client);
+                    let _ = tx.send(conn).await;
+                }
+                Ok(Err(e)) => log::warn!("Dropping connection from {:?}: {}", conn.client, e),
+                Err(_) => log::warn!("Dropping connection from {:?}: no PROXY header after {:?}", conn.client, PROXY_HEADER_TIMEOUT),
+            }
+        });
+    }
+}
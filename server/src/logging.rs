@@ -0,0 +1,44 @@
+use std::sync::RwLock;
+
+use log::{Log, Metadata, Record};
+
+/// env_logger behind a lock so the filter can be swapped on config reload
+pub struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+static LOGGER: std::sync::OnceLock<ReloadableLogger> = std::sync::OnceLock::new();
+
+fn build(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+pub fn init(filter: &str) {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: RwLock::new(build(filter)),
+    });
+    log::set_max_level(logger.inner.read().unwrap().filter());
+    log::set_logger(logger).expect("logger initialised twice");
+}
+
+pub fn set_filter(filter: &str) {
+    if let Some(logger) = LOGGER.get() {
+        let rebuilt = build(filter);
+        log::set_max_level(rebuilt.filter());
+        *logger.inner.write().unwrap() = rebuilt;
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
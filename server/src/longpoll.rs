@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::ws::Message;
+use warp::{Filter, Rejection, Reply};
+
+use crate::cluster;
+use crate::config::ConfigHandle;
+use crate::connection::{ConnectionStats, DisconnectReason, Peer};
+use crate::events::now_millis;
+use crate::hub::Hub;
+use crate::ids::UserId;
+use crate::listener;
+use crate::openapi::ApiError;
+use crate::protocol::MessageType;
+use crate::room::SharedRoom;
+use crate::socket::{self, Inbound, Rejected};
+use crate::trial;
+
+// How often abandoned sessions are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A participant that polls over HTTP instead of holding a WebSocket. The room sees an ordinary peer,
+/// whose queued broadcasts wait here until the next poll drains them
+struct Session {
+    room: SharedRoom,
+    stats: Arc<ConnectionStats>,
+    connected_at: Instant,
+    queue: Mutex<mpsc::UnboundedReceiver<Message>>,
+    inbound: Mutex<Inbound>,
+    last_seen: std::sync::Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_seen.lock().unwrap().elapsed()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionOpened {
+    /// Passed as `?session=` to the other long-poll endpoints
+    session: String,
+    user_id: UserId,
+}
+
+/// Long-poll sessions by token
+#[derive(Default)]
+pub struct Sessions {
+    by_token: RwLock<HashMap<String, Arc<Session>>>,
+}
+
+impl Sessions {
+    async fn get(&self, room_id: &str, token: Option<&String>) -> Option<Arc<Session>> {
+        let session = self.by_token.read().await.get(token?).cloned()?;
+        (session.stats.room_id() == room_id).then_some(session)
+    }
+
+    async fn remove(&self, token: &str) -> Option<Arc<Session>> {
+        self.by_token.write().await.remove(token)
+    }
+}
+
+pub fn routes(hub: Arc<Hub>, sessions: Arc<Sessions>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+    let sessions = warp::any().map(move || sessions.clone());
+    let remote = listener::remote(config.clone());
+    let config = warp::any().map(move || config.clone());
+
+    let open = warp::path!("api" / "rooms" / String / "sessions")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(remote)
+        .and(hub.clone())
+        .and(sessions.clone())
+        .and(config.clone())
+        .and_then(open_session);
+
+    let close = warp::path!("api" / "rooms" / String / "sessions" / String)
+        .and(warp::delete())
+        .and(hub.clone())
+        .and(sessions.clone())
+        .and_then(close_session);
+
+    let send = warp::path!("api" / "rooms" / String / "messages")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::bytes())
+        .and(hub)
+        .and(sessions.clone())
+        .and(config.clone())
+        .and_then(send_message);
+
+    let poll = warp::path!("api" / "rooms" / String / "messages")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(sessions)
+        .and(config)
+        .and_then(poll_messages);
+
+    open.or(close).or(send).or(poll)
+}
+
+fn error(message: &str, status: StatusCode) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+fn session_not_found() -> Box<dyn Reply> {
+    error("session not found", StatusCode::NOT_FOUND)
+}
+
+/// `POST /api/rooms/<id>/sessions`, joins the room like a WebSocket would, subject to the same key and quota checks
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/sessions",
+    tag = "long-polling",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 201, description = "Joined", body = SessionOpened),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list, or the room isn't open yet, with `opens_at` and `opens_in_secs`", body = ApiError),
+        (status = 429, description = "Over the room's join quota", body = ApiError),
+    ),
+)]
+async fn open_session(
+    room_id: String,
+    query: HashMap<String, String>,
+    remote_addr: Option<SocketAddr>,
+    hub: Arc<Hub>,
+    sessions: Arc<Sessions>,
+    config: ConfigHandle,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let current = config.borrow().clone();
+
+    let path = format!("api/rooms/{}/sessions", room_id);
+    let Some(room_id) = hub.room_id(&room_id) else {
+        return Ok(error("invalid room id", StatusCode::BAD_REQUEST));
+    };
+    // The owner checks everything else, and the session lives there
+    if let Some(owner) = hub.cluster.remote_owner(&room_id, Duration::from_secs(current.cluster.dead_after_secs)) {
+        return Ok(cluster::moved(&owner, &path, &query));
+    }
+    let keys = &current.auth.access_keys;
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(error("missing or invalid key", StatusCode::UNAUTHORIZED));
+    }
+    if !hub.may_access(&room_id, query.get("token").map(String::as_str)) {
+        return Ok(error("not on the room's access list", StatusCode::FORBIDDEN));
+    }
+    if let Err(e) = hub.quotas.check_join(&room_id, &hub.usage.room(&room_id)) {
+        log::info!("Refused long-poll join to room {}: {}", room_id, e);
+        return Ok(error(&e.to_string(), StatusCode::TOO_MANY_REQUESTS));
+    }
+    let room = match hub.open(&room_id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", room_id, e);
+            return Ok(error("room unavailable", StatusCode::SERVICE_UNAVAILABLE));
+        }
+    };
+    if let Some(opens_at) = room.read().await.info.opens_later() {
+        let opens_in_secs = opens_at.saturating_sub(now_millis() / 1000);
+        let body = serde_json::json!({ "error": "not yet open", "opens_at": opens_at, "opens_in_secs": opens_in_secs });
+        return Ok(Box::new(warp::reply::with_status(warp::reply::json(&body), StatusCode::FORBIDDEN)));
+    }
+    if socket::is_full(&hub, &room, trial::capacity_of(&current, &room_id)).await {
+        return Ok(error("room is full", StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let user_id = UserId::random();
+    let stats = Arc::new(ConnectionStats::new(user_id, room_id, remote_addr, "longpoll"));
+    let (tx, rx) = mpsc::unbounded_channel();
+    let token = format!("{:032x}", rand::random::<u128>());
+    let session = Arc::new(Session {
+        room: room.clone(),
+        stats: stats.clone(),
+        connected_at: Instant::now(),
+        queue: Mutex::new(rx),
+        inbound: Mutex::new(Inbound::new(&current.limits)),
+        last_seen: std::sync::Mutex::new(Instant::now()),
+    });
+    sessions.by_token.write().await.insert(token.clone(), session);
+    let capabilities = socket::capabilities(&hub, &room, &config, stats.transport).await;
+    socket::join(&hub, &room, Peer::new(tx, stats).with_capabilities(capabilities)).await;
+
+    let body = SessionOpened { session: token, user_id };
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&body), StatusCode::CREATED)))
+}
+
+/// `DELETE /api/rooms/<id>/sessions/<token>`, leaves the room
+#[utoipa::path(
+    delete,
+    path = "/api/rooms/{id}/sessions/{token}",
+    tag = "long-polling",
+    params(("id" = String, Path, description = "Room id"), ("token" = String, Path, description = "Session token")),
+    responses((status = 204, description = "Left the room"), (status = 404, description = "No such session in this room", body = ApiError)),
+)]
+async fn close_session(room_id: String, token: String, hub: Arc<Hub>, sessions: Arc<Sessions>) -> Result<Box<dyn Reply>, Infallible> {
+    if sessions.get(&room_id, Some(&token)).await.is_none() {
+        return Ok(session_not_found());
+    }
+    if let Some(session) = sessions.remove(&token).await {
+        socket::leave(&hub, &session.room, &session.stats, session.connected_at).await;
+    }
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+/// `POST /api/rooms/<id>/messages?session=<token>`, one frame in the WebSocket format
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/messages",
+    tag = "long-polling",
+    params(("id" = String, Path, description = "Room id"), ("session" = String, Query, description = "Session token")),
+    request_body = MessageType,
+    responses(
+        (status = 202, description = "Applied, or refused with an error frame waiting for the next poll"),
+        (status = 400, description = "Invalid message", body = ApiError),
+        (status = 404, description = "No such session in this room", body = ApiError),
+        (status = 413, description = "Over `limits.max_message_bytes`", body = ApiError),
+        (status = 429, description = "Rate limited", body = ApiError),
+    ),
+)]
+async fn send_message(
+    room_id: String,
+    query: HashMap<String, String>,
+    body: Bytes,
+    hub: Arc<Hub>,
+    sessions: Arc<Sessions>,
+    config: ConfigHandle,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(session) = sessions.get(&room_id, query.get("session")).await else {
+        return Ok(session_not_found());
+    };
+    session.touch();
+
+    if body.len() > config.borrow().limits.max_message_bytes {
+        return Ok(error("message too large", StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    let Ok(text) = String::from_utf8(body.to_vec()) else {
+        return Ok(error("message is not UTF-8", StatusCode::BAD_REQUEST));
+    };
+
+    let result = session.inbound.lock().await.handle(Message::text(text), &hub, &session.room, &session.stats, &config).await;
+    match result {
+        Ok(()) => Ok(Box::new(StatusCode::ACCEPTED)),
+        Err(Rejected::RateLimited) => Ok(error("rate limited", StatusCode::TOO_MANY_REQUESTS)),
+        Err(Rejected::Invalid(e)) => Ok(error(&format!("invalid message: {}", e), StatusCode::BAD_REQUEST)),
+        // Reported like it is to sockets, by the error frame waiting in the session's queue
+        Err(Rejected::OverQuota(_) | Rejected::Refused(_)) => Ok(Box::new(StatusCode::ACCEPTED)),
+    }
+}
+
+/// `GET /api/rooms/<id>/messages?session=<token>`, waits up to `longpoll.poll_timeout_secs` for broadcasts
+/// and returns every one queued since the last poll, oldest first. The first poll gets the room history
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/messages",
+    tag = "long-polling",
+    params(("id" = String, Path, description = "Room id"), ("session" = String, Query, description = "Session token")),
+    responses(
+        (status = 200, description = "Frames in the WebSocket format, ops and error frames, empty if the wait timed out", body = Vec<MessageType>),
+        (status = 404, description = "No such session in this room", body = ApiError),
+        (status = 410, description = "The server is shutting down", body = ApiError),
+    ),
+)]
+async fn poll_messages(room_id: String, query: HashMap<String, String>, sessions: Arc<Sessions>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(session) = sessions.get(&room_id, query.get("session")).await else {
+        return Ok(session_not_found());
+    };
+    session.touch();
+    let timeout = Duration::from_secs(config.borrow().longpoll.poll_timeout_secs);
+
+    // A second poll on the same session waits its turn rather than splitting the queue
+    let mut queue = session.queue.lock().await;
+    let mut messages = Vec::new();
+    if let Ok(Some(first)) = tokio::time::timeout(timeout, queue.recv()).await {
+        messages.push(first);
+        while let Ok(next) = queue.try_recv() {
+            messages.push(next);
+        }
+    }
+    session.touch();
+
+    let mut frames = Vec::with_capacity(messages.len());
+    let mut closed = false;
+    for message in messages {
+        session.stats.sent();
+        if message.is_close() {
+            closed = true;
+        } else if let Some(frame) = message.to_str().ok().and_then(|s| serde_json::from_str::<Value>(s).ok()) {
+            frames.push(frame);
+        }
+    }
+    // The server is shutting down, the session's second poll would find it gone anyway
+    if closed && frames.is_empty() {
+        return Ok(error("session closed", StatusCode::GONE));
+    }
+    Ok(Box::new(warp::reply::json(&frames)))
+}
+
+/// Leave rooms on behalf of sessions that stopped polling
+pub async fn expire_sessions(sessions: Arc<Sessions>, hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ttl = Duration::from_secs(config.borrow().longpoll.session_ttl_secs);
+        let expired: Vec<String> = sessions.by_token.read().await.iter()
+            .filter(|(_, session)| session.idle_for() > ttl)
+            .map(|(token, _)| token.clone())
+            .collect();
+        for token in expired {
+            if let Some(session) = sessions.remove(&token).await {
+                log::info!("Long-poll session for user {} expired", session.stats.user_id);
+                session.stats.closing(DisconnectReason::Timeout);
+                socket::leave(&hub, &session.room, &session.stats, session.connected_at).await;
+            }
+        }
+    }
+}
@@ -1,115 +1,662 @@
+// Warp's filter types nest one level per route, the default limit is too shallow for all of them
+#![recursion_limit = "256"]
+
+mod accounts;
+mod admin;
+mod api;
+mod backup;
+mod bot;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cli;
+mod cluster;
+mod codec;
+mod config;
+mod connection;
+mod cors;
+mod events;
+mod export;
+mod features;
+mod filters;
+mod follows;
+mod frontend;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod hooks;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hub;
+mod ids;
+mod links;
+mod listener;
+mod logging;
+mod longpoll;
+mod metrics;
+mod moderation;
+mod mqtt;
+mod notes;
+mod notices;
+mod notify;
+mod openapi;
+mod permissions;
+#[cfg(feature = "plugins")]
+mod plugins;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod profiles;
+mod protocol;
+mod proxy;
+mod public_ids;
+mod render;
+mod replay;
+mod replica;
+mod reports;
+mod reporting;
+mod retention;
+mod room;
+mod schedule;
+mod sealing;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod shared;
+mod socket;
+mod socketio;
+mod sse;
+mod status;
+mod storage;
+mod sync;
+mod tenants;
+mod trial;
+mod usage;
+mod webhooks;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use tokio::sync::{mpsc, RwLock};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use clap::Parser;
+use tokio::sync::watch;
 use warp::Filter;
-use warp::ws::{Message, WebSocket};
-use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
 
-type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
+use config::{Config, ConfigHandle};
+use hooks::Hooks;
+use hub::{Hub, DEFAULT_ROOM};
+use storage::{Durability, Storage};
 
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
+// How often dirty room history is written to storage, and idle rooms are checked for
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+// How often `on_tick` hooks run for each resident room
+const HOOK_TICK_INTERVAL: Duration = Duration::from_secs(60);
+// How often people are checked for going idle, so it shows up to this late
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(5);
+// How often scheduled rooms are checked for closing time
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+// How often each room's message rate is sampled for its histogram, the seconds it counts in
+const METRICS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// How often rooms are checked for a board that needs a new snapshot for joiners
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", content = "data")]
-enum MessageType {
-    Draw(DrawCommand),
-    Clear,
-    Erase(EraseCommand),
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct DrawCommand {
-    prev: [f64; 2],
-    cur: [f64; 2],
-    color: String,
-    brush_size: u32,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct EraseCommand {
-    prev: [f64; 2],
-    cur: [f64; 2],
-    brush_size: u32,
+#[derive(Default)]
+struct Health {
+    draining: AtomicBool,
 }
 
 #[tokio::main]
 async fn main() {
-    let users = Users::default();
+    let args = cli::Args::parse();
+    let config = Config::load(&args).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    logging::init(&config.log_level);
+    reporting::init(&config.reporting);
+
+    let keyring = sealing::Keyring::new(&config.storage.encryption).map(|keyring| keyring.map(Arc::new)).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+    if let Some(command) = &args.command {
+        match backup::run(command, &config, keyring).await {
+            Ok(done) => log::info!("{}", done),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.reseal {
+        let Some(keyring) = &keyring else {
+            eprintln!("--reseal needs storage.encryption.key");
+            std::process::exit(1);
+        };
+        match sealing::reseal(keyring, config.storage.backend.dir(), config.export.file.as_deref()).await {
+            Ok(resealed) => log::info!("Resealed {} files with the current key", resealed),
+            Err(e) => {
+                log::error!("Could not reseal storage: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let backend = &config.storage.backend;
+    let storage = backend.open(keyring.clone(), config.storage.durability).await.unwrap_or_else(|e| {
+        log::error!("Could not open storage {:?}: {}", backend, e);
+        std::process::exit(1);
+    });
+    log::info!("Using {:?} storage, {:?} durability", backend, config.storage.durability);
+
+    let replaying = args.replay.clone().map(|path| (path, args.replay_speed.unwrap_or(1.0)));
+    if replaying.as_ref().is_some_and(|(_, speed)| !(speed.is_finite() && *speed >= 0.0)) {
+        eprintln!("--replay-speed has to be 0 or more");
+        std::process::exit(1);
+    }
+
+    let (config_tx, config) = watch::channel(Arc::new(config));
+    let current = config.borrow().clone();
+
+    let quotas = Arc::new(usage::ConfigQuotas::new(config.clone()));
+    let accounts = current.accounts.enabled.then(|| {
+        let database = &current.accounts.database;
+        let accounts = accounts::Accounts::open(database).unwrap_or_else(|e| {
+            log::error!("Could not open the accounts database {}: {}", database.display(), e);
+            std::process::exit(1);
+        });
+        Arc::new(accounts)
+    });
+    let shared = match current.shared_state.enabled {
+        true => Some(open_shared(&current.shared_state).await),
+        false => None,
+    };
+    // Whatever hooks drew is in the op log already, and replayed ops shouldn't go out as new ones.
+    // A replica's ops were the primary's, hooked and exported there
+    let following = replaying.is_some() || current.replica.nats_url.is_some();
+    let hooks = match (&replaying, &current.replica.nats_url) {
+        (Some(_), _) => {
+            log::info!("Replaying, hooks, op export and the MQTT bridge are off and rooms aren't unloaded");
+            Hooks::new(Vec::new())
+        }
+        (None, Some(url)) => {
+            log::info!("Read replica of the primary exporting to {}, hooks, op export, the MQTT bridge and live rooms are off and rooms aren't unloaded", url);
+            Hooks::new(Vec::new())
+        }
+        (None, None) => load_hooks(&current),
+    };
+    warn_about_chaos(&current);
+    let hub = Arc::new(Hub::new(storage.clone(), quotas, hooks, accounts, shared.clone()).with_public_ids(public_ids::from_config(&current.public_ids)));
+    webhooks::spawn(&current.webhooks, &hub.events);
+    notify::spawn(&current.notifiers, hub.clone(), config.clone());
+    if !following {
+        export::spawn(&current.export, &hub.ops, keyring.clone());
+        mqtt::spawn(hub.clone(), config.clone());
+    }
+    let health = Arc::new(Health::default());
+
+    tokio::spawn(config::reload_on_hangup(args, config_tx));
+    tokio::spawn(save_periodically(hub.clone(), config.clone()));
+    // With memory storage an unloaded room would lose what was replayed into it
+    if !following {
+        tokio::spawn(expire_idle_rooms(hub.clone(), config.clone()));
+    }
+    if !hub.hooks.is_empty() {
+        tokio::spawn(tick_hooks(hub.clone(), config.clone()));
+    }
+    tokio::spawn(render_thumbnails(hub.clone(), config.clone()));
+    tokio::spawn(update_presence(hub.clone(), config.clone()));
+    tokio::spawn(sample_room_traffic(hub.clone(), config.clone()));
+    tokio::spawn(forget_clears(hub.clone(), config.clone()));
+    tokio::spawn(send_checksums(hub.clone(), config.clone()));
+    tokio::spawn(refresh_sync(hub.clone(), config.clone()));
+    tokio::spawn(close_scheduled_rooms(hub.clone()));
+    // The primary's clears reach a replica in its op export
+    if !following {
+        tokio::spawn(run_schedules(hub.clone(), config.clone()));
+    }
+    tokio::spawn(expire_trial_rooms(hub.clone(), config.clone()));
+    tokio::spawn(retention::janitor(hub.clone(), config.clone()));
+    if let Some(shared) = shared {
+        tokio::spawn(shared::sync(hub.clone(), shared, config.clone()));
+    }
+    let sessions = Arc::new(longpoll::Sessions::default());
+    tokio::spawn(longpoll::expire_sessions(sessions.clone(), hub.clone(), config.clone()));
+
+    let shutdown_hub = hub.clone();
+    let shutdown_health = health.clone();
+    let shutdown_config = config.clone();
 
-    let users = warp::any().map(move || users.clone()); // This applies users, almost like middleware to each path
+    let api = api::routes(hub.clone(), config.clone());
+    let accounts = accounts::routes(hub.clone(), config.clone());
+    let openapi = openapi::routes(config.clone());
+    let graphql = graphql_routes(hub.clone(), config.clone());
+    let longpoll = longpoll::routes(hub.clone(), sessions, config.clone());
+    let cluster = cluster::routes(hub.clone(), config.clone());
+    let tenants = tenants::routes(hub.clone(), config.clone());
+    let features = features::routes(hub.clone(), config.clone());
+    let backups = backup::routes(hub.clone(), config.clone());
+    let retention = retention::routes(hub.clone(), config.clone());
+    let status = status::routes(hub.clone(), config.clone());
+    let notes = notes::routes(hub.clone(), config.clone());
+    let reports = reports::routes(hub.clone(), config.clone());
+    let notices = notices::routes(hub.clone(), config.clone());
+    let remote = listener::remote(config.clone());
+    let socketio = socketio::routes(hub.clone(), config.clone());
+    let frontend = frontend::routes(config.clone());
+    let cors_config = config.clone();
 
-    let routes = warp::path("room")
+    let hub = warp::any().map(move || hub.clone()); // This applies the hub, almost like middleware to each path
+    let config = warp::any().map(move || config.clone());
+
+    // `/room` joins the default room, `/room/<id>` a named one
+    let room_id = warp::path::param::<String>()
+        .or(warp::any().map(|| DEFAULT_ROOM.to_string()))
+        .unify();
+
+    // `/t/<tenant>` in front is a room of that tenant's
+    let tenant = warp::path("t")
+        .and(warp::path::param::<String>())
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
+
+    let room = tenant
+        .and(warp::path("room"))
+        .and(room_id)
+        .and(warp::path::end())
         .and(warp::ws())
-        .and(users)
-        .map(|ws: warp::ws::Ws, users| {
-            ws.on_upgrade(move |socket| connect_user(socket, users))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(remote)
+        .and(hub)
+        .and(config)
+        .and_then(socket::upgrade);
+
+    let healthz = warp::path("healthz")
+        .and(warp::get())
+        .map(|| "ok");
+
+    let readyz = warp::path("readyz")
+        .and(warp::get())
+        .and_then(move || {
+            let health = health.clone();
+            let storage = storage.clone();
+            async move { Ok::<_, Infallible>(readiness(&health, storage.as_ref()).await) }
+        });
+
+    let routes = room
+        .or(api)
+        .or(accounts)
+        .or(openapi)
+        .or(graphql)
+        .or(longpoll)
+        .or(socketio)
+        .or(cluster)
+        .or(tenants)
+        .or(features)
+        .or(backups)
+        .or(retention)
+        .or(status)
+        .or(notes)
+        .or(reports)
+        .or(notices)
+        .or(healthz)
+        .or(readyz)
+        .or(frontend);
+    // A replica only serves reads
+    let routes = cors::preflight()
+        .or(replica::read_only(cors_config.clone()).and(routes))
+        .recover(api::handle_rejection);
+    let routes = cors::wrap(routes, cors_config);
+
+    let listeners = listener::bind(&current.server).await.unwrap_or_else(|e| {
+        log::error!("Could not bind listeners: {}", e);
+        std::process::exit(1);
+    });
+    for listener in &listeners {
+        log::info!("Listening on {}", listener);
+    }
+    cluster::start(shutdown_hub.clone(), shutdown_config.clone(), &listeners);
+    if current.replica.nats_url.is_some() && (current.webtransport.enabled || current.grpc.enabled) {
+        log::warn!("WebTransport and gRPC are off on a read replica");
+    }
+    if current.webtransport.enabled && current.replica.nats_url.is_none() {
+        spawn_webtransport(&current, shutdown_hub.clone(), shutdown_config.clone()).await;
+    }
+    if current.grpc.enabled && current.replica.nats_url.is_none() {
+        spawn_grpc(&current, shutdown_hub.clone(), shutdown_config.clone());
+    }
+    replica::spawn(shutdown_hub.clone(), shutdown_config.clone());
+    if let Some((path, speed)) = replaying {
+        let (hub, config) = (shutdown_hub.clone(), shutdown_config.clone());
+        tokio::spawn(async move {
+            log::info!("Replaying {} at {}x", path.display(), speed);
+            if let Err(e) = replay::replay(&hub, &path, speed, keyring.as_deref(), &config).await {
+                log::error!("Could not replay {}: {}", path.display(), e);
+            }
         });
+    }
+
+    let serve_config = shutdown_config.clone();
+    let shutdown = async move {
+        shutdown_signal().await;
+        let shutdown_grace = Duration::from_secs(shutdown_config.borrow().server.shutdown_grace_secs);
+        log::info!("Shutdown requested, draining for {:?}", shutdown_grace);
+        shutdown_health.draining.store(true, Ordering::Relaxed);
+        tokio::time::sleep(shutdown_grace).await;
+
+        // Upgraded sockets aren't tracked by the HTTP server, so close them ourselves
+        shutdown_hub.close_all().await;
+        shutdown_hub.save_all().await;
+    };
+
+    if let Err(e) = listener::serve(warp::service(routes), listeners, serve_config, shutdown).await {
+        log::error!("Server error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+#[cfg(feature = "webtransport")]
+async fn spawn_webtransport(current: &Config, hub: Arc<Hub>, config: ConfigHandle) {
+    let endpoint = webtransport::bind(current).await.unwrap_or_else(|e| {
+        log::error!("Could not start the WebTransport listener: {}", e);
+        std::process::exit(1);
+    });
+    if let Ok(addr) = endpoint.local_addr() {
+        log::info!("Listening for WebTransport on udp {}", addr);
+    }
+    tokio::spawn(webtransport::serve(endpoint, hub, config));
 }
 
-async fn connect_user(ws: WebSocket, users: Users){
-    let current_user_id = NEXT_USER_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+#[cfg(not(feature = "webtransport"))]
+async fn spawn_webtransport(_current: &Config, _hub: Arc<Hub>, _config: ConfigHandle) {
+    log::warn!("webtransport.enabled is set but this build has no WebTransport support, rebuild with --features webtransport");
+}
 
-    let (mut user_ws_sender, mut user_ws_receiver) = ws.split();
+#[cfg(feature = "grpc")]
+fn spawn_grpc(current: &Config, hub: Arc<Hub>, config: ConfigHandle) {
+    let incoming = grpc::bind(current).unwrap_or_else(|e| {
+        log::error!("Could not start the gRPC listener: {}", e);
+        std::process::exit(1);
+    });
+    log::info!("Listening for gRPC on {}:{}", current.grpc.bind.unwrap_or(current.server.bind), current.grpc.port);
+    tokio::spawn(grpc::serve(incoming, hub, config));
+}
 
-    let (message_sender, message_receiver) = mpsc::unbounded_channel();
-    let mut rx = UnboundedReceiverStream::new(message_receiver);
+#[cfg(not(feature = "grpc"))]
+fn spawn_grpc(_current: &Config, _hub: Arc<Hub>, _config: ConfigHandle) {
+    log::warn!("grpc.enabled is set but this build has no gRPC support, rebuild with --features grpc");
+}
 
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            user_ws_sender
-                .send(message)
-                .unwrap_or_else(|e| {
-                    eprintln!("WebSocket send error: {}", e)
-                }).await;
-        }
+#[cfg(feature = "graphql")]
+fn graphql_routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    graphql::routes(hub, config)
+}
+
+#[cfg(not(feature = "graphql"))]
+fn graphql_routes(_hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    if config.borrow().graphql.enabled {
+        log::warn!("graphql.enabled is set but this build has no GraphQL support, rebuild with --features graphql");
+    }
+    // Answered rather than rejected, warp would turn a rejection here into a 405 from another route
+    warp::path("graphql").map(|| warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": "not found" })), StatusCode::NOT_FOUND))
+}
+
+fn warn_about_chaos(current: &Config) {
+    match (cfg!(feature = "chaos"), current.chaos.is_enabled()) {
+        (true, true) => log::warn!("Injecting latency, drops and reordering into every socket: {:?}", current.chaos),
+        (false, true) => log::warn!("chaos is set but this build has no chaos mode, rebuild with --features chaos"),
+        _ => {}
+    }
+}
+
+fn open_sqlite(config: &config::SharedStateConfig) -> Arc<dyn shared::SharedState> {
+    let database = &config.database;
+    let shared = shared::SqliteState::open(database).unwrap_or_else(|e| {
+        log::error!("Could not open the shared state database {}: {}", database.display(), e);
+        std::process::exit(1);
     });
+    Arc::new(shared)
+}
 
-    users.write().await.insert(current_user_id, message_sender);
+#[cfg(feature = "postgres")]
+async fn open_shared(config: &config::SharedStateConfig) -> Arc<dyn shared::SharedState> {
+    let Some(url) = &config.postgres_url else {
+        return open_sqlite(config);
+    };
+    match postgres::PostgresState::connect(url).await {
+        Ok(shared) => Arc::new(shared),
+        Err(e) => {
+            log::error!("Could not connect to the shared state database: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    while let Some(result) = user_ws_receiver.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Could not send user message {}", e);
-                break;
-            } 
+// Falling back to SQLite would leave each node on a database of its own
+#[cfg(not(feature = "postgres"))]
+async fn open_shared(config: &config::SharedStateConfig) -> Arc<dyn shared::SharedState> {
+    if config.postgres_url.is_some() {
+        log::error!("shared_state.postgres_url is set but this build has no PostgreSQL support, rebuild with --features postgres");
+        std::process::exit(1);
+    }
+    open_sqlite(config)
+}
+
+/// WASM plugins first, then the script
+fn load_hooks(current: &Config) -> Hooks {
+    let mut hooks = load_plugins(current);
+    hooks.extend(load_script(current));
+    Hooks::new(hooks)
+}
+
+#[cfg(feature = "plugins")]
+fn load_plugins(current: &Config) -> Vec<Box<dyn hooks::Hook>> {
+    plugins::load(&current.plugins).unwrap_or_else(|e| {
+        log::error!("Could not load plugin {}", e);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_plugins(current: &Config) -> Vec<Box<dyn hooks::Hook>> {
+    if !current.plugins.modules.is_empty() {
+        log::warn!("plugins.modules is set but this build has no plugin support, rebuild with --features plugins");
+    }
+    Vec::new()
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(current: &Config) -> Option<Box<dyn hooks::Hook>> {
+    let path = current.scripting.script.as_ref()?;
+    match scripting::load(path, &current.scripting) {
+        Ok(script) => Some(Box::new(script)),
+        Err(e) => {
+            log::error!("Could not load script {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_script(current: &Config) -> Option<Box<dyn hooks::Hook>> {
+    if current.scripting.script.is_some() {
+        log::warn!("scripting.script is set but this build has no scripting support, rebuild with --features scripting");
+    }
+    None
+}
+
+async fn readiness(health: &Health, storage: &dyn Storage) -> impl warp::Reply {
+    if health.draining.load(Ordering::Relaxed) {
+        return warp::reply::with_status("draining", StatusCode::SERVICE_UNAVAILABLE);
+    }
+    if let Err(e) = storage.ping().await {
+        log::warn!("Storage not reachable: {}", e);
+        return warp::reply::with_status("storage unavailable", StatusCode::SERVICE_UNAVAILABLE);
+    }
+    warp::reply::with_status("ready", StatusCode::OK)
+}
+
+// Saves rooms that changed as often as `storage.durability` says
+async fn save_periodically(hub: Arc<Hub>, config: ConfigHandle) {
+    loop {
+        let (durability, commit_interval_ms) = {
+            let current = config.borrow();
+            (current.storage.durability, current.storage.commit_interval_ms)
         };
-        send_user_message(current_user_id, msg, &users).await;
-    }
-
-    user_disconnected(current_user_id, &users).await;
-}
-
-async fn send_user_message(user_id: usize, msg: Message, users: &Users){
-   if let Ok(s) = msg.to_str() {
-    let parsed: Result<MessageType, serde_json::Error> = serde_json::from_str(s);
-    match parsed {
-        Ok(msg) => {
-            let serialized = serde_json::to_string(&msg).unwrap_or_else(|e| {
-                eprintln!("Serialization error: {}", e);
-                String::new()
-            });
-            for (&uid, tx) in users.read().await.iter() {
-                if user_id != uid {
-                    if let Err(_disconnected) = tx.send(Message::text(&serialized)){
-                        println!("User disconnected");
-                    }
-                }
-            }
-        },
-        Err(e) => eprintln!("Whoops, could not serialize: {:?}", e)
+        match durability {
+            Durability::Async => tokio::time::sleep(SAVE_INTERVAL).await,
+            Durability::Batched => tokio::time::sleep(Duration::from_millis(commit_interval_ms.max(1))).await,
+            Durability::Fsync => hub.changes.notified().await,
+        }
+        hub.save_all().await;
+    }
+}
+
+async fn tick_hooks(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(HOOK_TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let history_limit = config.borrow().rooms.history_limit;
+        hub.tick_hooks(history_limit).await;
+    }
+}
+
+async fn update_presence(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(PRESENCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Re-read every tick so reloaded thresholds take effect without a restart
+        let (idle_after, away_after, share_latency) = {
+            let presence = &config.borrow().presence;
+            (Duration::from_secs(presence.idle_after_secs), Duration::from_secs(presence.away_after_secs), presence.share_latency)
+        };
+        hub.update_presence(idle_after, away_after, share_latency).await;
+    }
+}
+
+async fn sample_room_traffic(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(METRICS_SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let top_rooms = config.borrow().metrics.top_rooms;
+        metrics::sample(&hub, top_rooms).await;
+    }
+}
+
+async fn forget_clears(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let grace = Duration::from_secs(config.borrow().rooms.clear_undo_secs);
+        hub.forget_clears(grace).await;
+    }
+}
+
+async fn send_checksums(hub: Arc<Hub>, config: ConfigHandle) {
+    loop {
+        // Re-read every pass so a reloaded interval takes effect without a restart
+        let interval = config.borrow().rooms.checksum_interval_secs;
+        if interval != 0 {
+            hub.send_checksums().await;
+        }
+        tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+    }
+}
+
+async fn refresh_sync(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        let min_ops = config.borrow().rooms.sync_snapshot_ops;
+        hub.refresh_sync(min_ops).await;
+    }
+}
+
+async fn close_scheduled_rooms(hub: Arc<Hub>) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        hub.close_scheduled().await;
+    }
+}
+
+async fn run_schedules(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (warning, history_limit) = {
+            let rooms = &config.borrow().rooms;
+            (rooms.schedule_warning_secs, rooms.history_limit)
+        };
+        hub.run_scheduled(Duration::from_secs(warning), history_limit).await;
     }
-   };
 }
 
-async fn user_disconnected(my_id: usize, users: &Users) {
-    eprintln!("good bye user: {}", my_id);
+async fn expire_trial_rooms(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ttl = config.borrow().trial.ttl_secs;
+        if ttl > 0 {
+            hub.expire_trials(Duration::from_secs(ttl)).await;
+        }
+    }
+}
+
+async fn render_thumbnails(hub: Arc<Hub>, config: ConfigHandle) {
+    loop {
+        // Re-read every pass so a reloaded interval or size takes effect without a restart
+        let (interval, size) = {
+            let render = &config.borrow().render;
+            (render.thumbnail_interval_secs, render.thumbnail_size)
+        };
+        match interval {
+            0 => hub.thumbnails.retain(&[]),
+            _ => hub.render_thumbnails(size).await,
+        }
+        tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+    }
+}
+
+async fn expire_idle_rooms(hub: Arc<Hub>, config: ConfigHandle) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Re-read every tick so a reloaded TTL takes effect without a restart
+        let (ttl, summary) = {
+            let rooms = &config.borrow().rooms;
+            (rooms.idle_ttl_secs, rooms.contribution_summary)
+        };
+        if ttl == 0 {
+            continue;
+        }
+        hub.expire_idle(Duration::from_secs(ttl), summary).await;
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
 
-    // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
-}
\ No newline at end of file
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
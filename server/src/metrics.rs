@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::hub::Hub;
+use crate::protocol::Invalid;
+
+// Messages a room got in one second
+const MESSAGE_RATE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+// Seconds from an op reaching its room to it being queued for everyone there
+const BROADCAST_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+// The `room` label of everything outside the busiest rooms. A room with this id never gets its own
+const OTHER: &str = "other";
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Observations counted into buckets, each bucket only counting what's above the one before until
+/// it's rendered
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, room: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{room=\"{room}\",le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{room=\"{room}\",le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{room=\"{room}\"}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{room=\"{room}\"}} {}", self.count);
+    }
+}
+
+struct Traffic {
+    message_rate: Histogram,
+    broadcast: Histogram,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Traffic { message_rate: Histogram::new(MESSAGE_RATE_BUCKETS), broadcast: Histogram::new(BROADCAST_BUCKETS) }
+    }
+}
+
+/// Message rate and broadcast latency by room. Only the `metrics.top_rooms` busiest rooms have a
+/// `room` label of their own, everything else is counted together as `other`, so there are as
+/// many series however many rooms come and go. A room that falls out of the busiest loses its
+/// series rather than moving its counts into `other`, which only ever goes up
+#[derive(Default)]
+pub struct RoomTraffic {
+    rooms: HashMap<String, Traffic>,
+    other: Traffic,
+}
+
+impl RoomTraffic {
+    /// Label the busiest `top` of `rates`, loaded rooms' ids and messages per second lately
+    pub fn rank(&mut self, rates: &[(String, f64)], top: usize) {
+        let mut busiest: Vec<&(String, f64)> = rates.iter().filter(|(id, rate)| *rate > 0.0 && id != OTHER).collect();
+        busiest.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        busiest.truncate(top);
+        self.rooms.retain(|id, _| busiest.iter().any(|(busy, _)| busy == id));
+        for (id, _) in busiest {
+            self.rooms.entry(id.clone()).or_default();
+        }
+    }
+
+    fn of(&mut self, room: &str) -> &mut Traffic {
+        match self.rooms.contains_key(room) {
+            true => self.rooms.get_mut(room).unwrap(),
+            false => &mut self.other,
+        }
+    }
+
+    /// What a room got over a second
+    pub fn message_rate(&mut self, room: &str, messages: u32) {
+        self.of(room).message_rate.observe(messages as f64);
+    }
+
+    /// How long an op took from reaching `room` to being queued for everyone in it
+    pub fn broadcast(&mut self, room: &str, took: Duration) {
+        self.of(room).broadcast.observe(took.as_secs_f64());
+    }
+
+    fn render(&self, out: &mut String) {
+        let mut rooms: Vec<(&String, &Traffic)> = self.rooms.iter().collect();
+        rooms.sort_by_key(|(id, _)| *id);
+        let labeled = || rooms.iter().map(|(id, traffic)| (id.as_str(), *traffic)).chain([(OTHER, &self.other)]);
+        out.push_str("# HELP whiteboard_room_messages_per_second Messages an occupied room got in each second, busiest rooms by id\n# TYPE whiteboard_room_messages_per_second histogram\n");
+        for (room, traffic) in labeled() {
+            traffic.message_rate.render(out, "whiteboard_room_messages_per_second", room);
+        }
+        out.push_str("# HELP whiteboard_room_broadcast_seconds From an op reaching its room to it being queued for everyone there, busiest rooms by id\n# TYPE whiteboard_room_broadcast_seconds histogram\n");
+        for (room, traffic) in labeled() {
+            traffic.broadcast.render(out, "whiteboard_room_broadcast_seconds", room);
+        }
+    }
+}
+
+/// Process-wide counters, served in the Prometheus text format at `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_opened: Counter,
+    /// Connection tasks that panicked and were closed on their own
+    pub connection_panics: Counter,
+    /// Frames refused before they got to a room, indexed like `Invalid::CODES`
+    pub invalid_frames: [Counter; Invalid::CODES.len()],
+    /// Joiners sent the start of the board from its `SyncSnapshot`
+    pub snapshot_joins: Counter,
+    pub room_traffic: Mutex<RoomTraffic>,
+}
+
+/// Put each occupied room's messages over the last second into its rate histogram, after labeling
+/// the `top_rooms` busiest
+pub async fn sample(hub: &Hub, top_rooms: usize) {
+    let mut rates = Vec::new();
+    let mut messages = Vec::new();
+    for room in hub.rooms().await {
+        let room = room.read().await;
+        if room.participants() > 0 {
+            rates.push((room.id.clone(), room.messages_in.per_second()));
+            messages.push(room.messages_in.last_second());
+        }
+    }
+    let mut traffic = hub.metrics.room_traffic.lock().unwrap();
+    traffic.rank(&rates, top_rooms);
+    for ((room, _), messages) in rates.iter().zip(messages) {
+        traffic.message_rate(room, messages);
+    }
+}
+
+/// Counters plus gauges read off the hub at scrape time
+pub async fn render(hub: &Hub) -> String {
+    let rooms = hub.rooms().await;
+    let (mut connections, mut snapshots) = (0, 0);
+    for room in &rooms {
+        let room = room.read().await;
+        connections += room.users.len();
+        snapshots += room.sync.is_some() as u64;
+    }
+
+    let metrics = &hub.metrics;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+    };
+    metric("whiteboard_rooms_loaded", "gauge", "Rooms resident in memory", rooms.len() as u64);
+    metric("whiteboard_connections", "gauge", "Open WebSocket connections", connections as u64);
+    metric("whiteboard_pending_connections", "gauge", "WebSocket connections yet to send a valid frame", hub.pending.load(Ordering::Relaxed) as u64);
+    metric("whiteboard_connections_opened_total", "counter", "WebSocket connections accepted", metrics.connections_opened.get());
+    metric("whiteboard_connection_panics_total", "counter", "Connection tasks that panicked", metrics.connection_panics.get());
+    metric("whiteboard_sync_snapshots", "gauge", "Resident rooms with their board serialized for joiners", snapshots);
+    metric("whiteboard_snapshot_joins_total", "counter", "Joins sent the start of the board from its snapshot", metrics.snapshot_joins.get());
+    out.push_str("# HELP whiteboard_invalid_frames_total Frames refused before they got to a room, by error code\n# TYPE whiteboard_invalid_frames_total counter\n");
+    for (code, counter) in Invalid::CODES.iter().zip(&metrics.invalid_frames) {
+        let _ = writeln!(out, "whiteboard_invalid_frames_total{{code=\"{}\"}} {}", code, counter.get());
+    }
+    metrics.room_traffic.lock().unwrap().render(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_busiest_rooms_get_their_own_series() {
+        let mut traffic = RoomTraffic::default();
+        let rates = |busy: &[(&str, f64)]| busy.iter().map(|(id, rate)| (id.to_string(), *rate)).collect::<Vec<_>>();
+        traffic.rank(&rates(&[("quiet", 0.5), ("busy", 40.0), ("idle", 0.0), ("other", 90.0)]), 1);
+        traffic.message_rate("busy", 40);
+        traffic.message_rate("quiet", 1);
+        traffic.broadcast("quiet", Duration::from_micros(300));
+
+        let mut out = String::new();
+        traffic.render(&mut out);
+        assert!(out.contains("whiteboard_room_messages_per_second_bucket{room=\"busy\",le=\"50\"} 1\n"), "{}", out);
+        assert!(out.contains("whiteboard_room_messages_per_second_bucket{room=\"other\",le=\"1\"} 1\n"), "{}", out);
+        assert!(out.contains("whiteboard_room_broadcast_seconds_bucket{room=\"other\",le=\"0.0005\"} 1\n"), "{}", out);
+        assert!(!out.contains("room=\"quiet\""));
+
+        // Busy went quiet, so its series goes and what it counted stays out of `other`
+        traffic.rank(&rates(&[("quiet", 0.5)]), 1);
+        let mut out = String::new();
+        traffic.render(&mut out);
+        assert!(!out.contains("room=\"busy\""));
+        assert!(out.contains("whiteboard_room_messages_per_second_count{room=\"quiet\"} 0\n"), "{}", out);
+        assert!(out.contains("whiteboard_room_messages_per_second_count{room=\"other\"} 1\n"), "{}", out);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ModerationConfig;
+use crate::room::RoomInfo;
+
+/// What a piece of text is, so the service can hold names to a different standard than messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Dm,
+    RoomName,
+    RoomDescription,
+    RoomTag,
+}
+
+/// A room's name, description and tags, for `Moderation::check`
+pub fn room_texts(info: &RoomInfo) -> Vec<(Kind, &str)> {
+    let name = info.name.as_deref().map(|name| (Kind::RoomName, name));
+    let description = info.description.as_deref().map(|description| (Kind::RoomDescription, description));
+    name.into_iter().chain(description).chain(info.tags.iter().map(|tag| (Kind::RoomTag, tag.as_str()))).collect()
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    kind: Kind,
+    room: &'a str,
+    text: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Verdict {
+    allowed: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Why text was refused, as an error code and message
+#[derive(Debug)]
+pub struct Refused {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Text checked with `moderation.url` before anyone else sees it. Verdicts are kept by kind and
+/// text for `moderation.cache_ttl_secs`, so a name set again or a message sent twice is only asked
+/// about once; what the service couldn't answer isn't kept
+#[derive(Default)]
+pub struct Moderation {
+    client: reqwest::Client,
+    verdicts: Mutex<HashMap<(Kind, String), (Verdict, Instant)>>,
+}
+
+impl Moderation {
+    /// Ok if every piece of `texts` may be shown, asking about each in turn until one is refused
+    pub async fn check(&self, config: &ModerationConfig, room: &str, texts: &[(Kind, &str)]) -> Result<(), Refused> {
+        if config.url.is_empty() {
+            return Ok(());
+        }
+        for &(kind, text) in texts {
+            let verdict = match self.cached(config, kind, text) {
+                Some(verdict) => verdict,
+                None => match self.ask(config, &Request { kind, room, text }).await {
+                    Ok(verdict) => {
+                        self.keep(config, kind, text, verdict.clone());
+                        verdict
+                    }
+                    Err(e) if config.fail_closed => {
+                        log::warn!("Refused text in room {}, the moderation service failed: {}", room, e);
+                        return Err(Refused { code: "unavailable", message: "could not check the text, try again later".to_string() });
+                    }
+                    Err(e) => {
+                        log::warn!("Let text through in room {} unchecked, the moderation service failed: {}", room, e);
+                        continue;
+                    }
+                },
+            };
+            if !verdict.allowed {
+                return Err(Refused { code: "moderated", message: verdict.reason.unwrap_or_else(|| "refused by moderation".to_string()) });
+            }
+        }
+        Ok(())
+    }
+
+    async fn ask(&self, config: &ModerationConfig, request: &Request<'_>) -> reqwest::Result<Verdict> {
+        let mut post = self.client.post(&config.url).json(request).timeout(Duration::from_millis(config.timeout_ms));
+        if !config.token.is_empty() {
+            post = post.bearer_auth(&config.token);
+        }
+        post.send().await?.error_for_status()?.json().await
+    }
+
+    fn cached(&self, config: &ModerationConfig, kind: Kind, text: &str) -> Option<Verdict> {
+        let ttl = Duration::from_secs(config.cache_ttl_secs);
+        let verdicts = self.verdicts.lock().unwrap();
+        verdicts.get(&(kind, text.to_string())).filter(|(_, at)| at.elapsed() < ttl).map(|(verdict, _)| verdict.clone())
+    }
+
+    // Making room by dropping expired verdicts, then the oldest
+    fn keep(&self, config: &ModerationConfig, kind: Kind, text: &str, verdict: Verdict) {
+        if config.cache_entries == 0 || config.cache_ttl_secs == 0 {
+            return;
+        }
+        let ttl = Duration::from_secs(config.cache_ttl_secs);
+        let mut verdicts = self.verdicts.lock().unwrap();
+        if verdicts.len() >= config.cache_entries {
+            verdicts.retain(|_, (_, at)| at.elapsed() < ttl);
+        }
+        while verdicts.len() >= config.cache_entries {
+            let Some(oldest) = verdicts.iter().min_by_key(|(_, (_, at))| *at).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            verdicts.remove(&oldest);
+        }
+        verdicts.insert((kind, text.to_string()), (verdict, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_as_many_verdicts_are_kept_as_configured() {
+        let config = ModerationConfig { url: "http://moderation.invalid".to_string(), cache_entries: 2, ..ModerationConfig::default() };
+        let moderation = Moderation::default();
+        let allowed = |allowed| Verdict { allowed, reason: None };
+        moderation.keep(&config, Kind::Dm, "one", allowed(true));
+        moderation.keep(&config, Kind::Dm, "two", allowed(false));
+        assert!(moderation.cached(&config, Kind::Dm, "one").unwrap().allowed);
+        assert!(moderation.cached(&config, Kind::RoomName, "one").is_none());
+
+        // The oldest makes way
+        moderation.keep(&config, Kind::Dm, "three", allowed(true));
+        assert!(moderation.cached(&config, Kind::Dm, "one").is_none());
+        assert!(!moderation.cached(&config, Kind::Dm, "two").unwrap().allowed);
+        assert!(moderation.cached(&config, Kind::Dm, "three").is_some());
+
+        let uncached = ModerationConfig { cache_ttl_secs: 0, ..config };
+        moderation.keep(&uncached, Kind::Dm, "four", allowed(true));
+        assert!(moderation.cached(&uncached, Kind::Dm, "four").is_none());
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use warp::ws::Message;
+
+use crate::config::{ConfigHandle, MqttConfig};
+use crate::events::OpRecord;
+use crate::hub::{valid_room_id, Hub};
+use crate::socket::Inbound;
+
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+// MQTT 3.1.1 control packet types, the high nibble of the first byte
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+
+// Nothing we subscribe to should come close, commands are capped at `limits.max_message_bytes`
+const MAX_PACKET_BYTES: usize = 1 << 20;
+
+// Commands are read from `<prefix>/<room>/commands`
+const COMMANDS: &str = "commands";
+
+/// Mirror every accepted op to `<topic_prefix>/<room>/<op>` and apply commands published to
+/// `<topic_prefix>/<room>/commands`, if an MQTT url is configured
+pub fn spawn(hub: Arc<Hub>, config: ConfigHandle) {
+    let settings = config.borrow().mqtt.clone();
+    let Some(url) = settings.url.clone() else {
+        return;
+    };
+    let mut rx = hub.ops.subscribe();
+
+    tokio::spawn(async move {
+        // Each room's commands are drawn by its bot, so they share a rate limit
+        let mut bots = Bots::default();
+        loop {
+            match bridge(&url, &settings, &mut rx, &hub, &config, &mut bots).await {
+                Ok(()) => break,
+                Err(e) => log::warn!("MQTT bridge interrupted, reconnecting in {:?}: {}", RECONNECT_BACKOFF, e),
+            }
+            tokio::time::sleep(RECONNECT_BACKOFF).await;
+        }
+    });
+}
+
+/// One MQTT session speaking just enough of 3.1.1 to publish at QoS 0, subscribe to commands and keep alive
+async fn bridge(
+    url: &str,
+    settings: &MqttConfig,
+    rx: &mut broadcast::Receiver<OpRecord>,
+    hub: &Hub,
+    config: &ConfigHandle,
+    bots: &mut Bots,
+) -> io::Result<()> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let host = parsed.host_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "MQTT url has no host"))?;
+    let addr = format!("{}:{}", host, parsed.port().unwrap_or(1883));
+
+    let (mut reader, mut writer) = TcpStream::connect(&addr).await?.into_split();
+    let username = (!parsed.username().is_empty()).then(|| parsed.username());
+    writer.write_all(&connect_packet(&settings.client_id, username, parsed.password(), settings.keep_alive_secs)).await?;
+
+    match read_packet(&mut reader).await? {
+        (CONNACK, body) if body.get(1) == Some(&0) => {}
+        (CONNACK, body) => {
+            let code = body.get(1).copied().unwrap_or_default();
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("MQTT broker refused the connection, code {}", code)));
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK from MQTT broker")),
+    }
+    let prefix = &settings.topic_prefix;
+    if settings.accept_commands {
+        writer.write_all(&subscribe_packet(1, &format!("{}/+/{}", prefix, COMMANDS))).await?;
+    }
+    log::info!("Bridging rooms to MQTT at {} under {}/", addr, prefix);
+
+    // Reads get their own task, a packet half read when an op arrives would otherwise be lost
+    let (packets_tx, mut packets) = mpsc::channel(64);
+    let read_task = tokio::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if packets_tx.send(packet).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    // Aborted however this session ends
+    let _read_task = AbortOnDrop(read_task);
+
+    let keep_alive = Duration::from_secs(settings.keep_alive_secs.max(1));
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + keep_alive, keep_alive);
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let (kind, body) = match packet {
+                    Some(packet) => packet?,
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "MQTT broker closed the connection")),
+                };
+                match kind & 0xf0 {
+                    PUBLISH => {
+                        let Some((topic, packet_id, payload)) = parse_publish(kind, &body) else {
+                            log::warn!("Malformed PUBLISH from MQTT broker");
+                            continue;
+                        };
+                        if let Some(packet_id) = packet_id {
+                            writer.write_all(&[PUBACK, 2, (packet_id >> 8) as u8, packet_id as u8]).await?;
+                        }
+                        command(topic, payload, prefix, hub, config, bots).await;
+                    }
+                    SUBACK if body.get(2) == Some(&0x80) => log::warn!("MQTT broker refused the commands subscription"),
+                    _ => {}
+                }
+            }
+            record = rx.recv() => {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("MQTT bridge fell behind, {} ops not published", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                let payload = serde_json::to_vec(&record).map_err(io::Error::other)?;
+                let topic = format!("{}/{}/{}", prefix, record.room, record.op.name());
+                writer.write_all(&publish_packet(&topic, &payload)).await?;
+            }
+            // The broker drops clients it hasn't heard from in 1.5 keep-alive periods
+            _ = ping.tick() => writer.write_all(&[PINGREQ, 0]).await?,
+        }
+    }
+}
+
+/// Rate limits for each room's commands
+#[derive(Default)]
+struct Bots {
+    by_room: HashMap<String, Inbound>,
+}
+
+/// Apply a frame published to `<prefix>/<room>/commands` as the room's bot, in the WebSocket format
+async fn command(topic: &str, payload: &[u8], prefix: &str, hub: &Hub, config: &ConfigHandle, bots: &mut Bots) {
+    let room_id = match topic.strip_prefix(prefix).and_then(|t| t.strip_prefix('/')).and_then(|t| t.strip_suffix(COMMANDS)) {
+        Some(room_id) => room_id.trim_end_matches('/'),
+        None => return,
+    };
+    if !valid_room_id(room_id) {
+        log::debug!("Ignored MQTT command for invalid room {:?}", room_id);
+        return;
+    }
+    let Ok(text) = std::str::from_utf8(payload) else {
+        log::debug!("Ignored non UTF-8 MQTT command for room {}", room_id);
+        return;
+    };
+    if payload.len() > config.borrow().limits.max_message_bytes {
+        log::debug!("Ignored oversized MQTT command for room {}", room_id);
+        return;
+    }
+    // Every node of a cluster gets the command, the room's owner applies it
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    if hub.cluster.remote_owner(room_id, dead_after).is_some() {
+        return;
+    }
+    let room = match hub.open(room_id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", room_id, e);
+            return;
+        }
+    };
+
+    let inbound = bots.by_room.entry(room_id.to_string()).or_insert_with(|| Inbound::new(&config.borrow().limits));
+    // Rejections are already logged and counted
+    let _ = inbound.handle(Message::text(text), hub, &room, &hub.bots.get(room_id), config).await;
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>, keep_alive_secs: u64) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(keep_alive_secs.min(u16::MAX as u64) as u16).to_be_bytes());
+    put_str(&mut body, client_id);
+    for field in [username, password].into_iter().flatten() {
+        put_str(&mut body, field);
+    }
+    packet(CONNECT, &body)
+}
+
+fn subscribe_packet(packet_id: u16, filter: &str) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    put_str(&mut body, filter);
+    body.push(0); // QoS 0
+    packet(SUBSCRIBE, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    put_str(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH, &body)
+}
+
+/// Topic, packet id for QoS 1 and 2, and payload of a PUBLISH
+fn parse_publish(kind: u8, body: &[u8]) -> Option<(&str, Option<u16>, &[u8])> {
+    let len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + len)?).ok()?;
+    let mut rest = &body[2 + len..];
+    let mut packet_id = None;
+    if (kind >> 1) & 0x03 > 0 {
+        packet_id = Some(u16::from_be_bytes([*rest.first()?, *rest.get(1)?]));
+        rest = &rest[2..];
+    }
+    Some((topic, packet_id, rest))
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    // Remaining length, 7 bits per byte with the high bit set on all but the last
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let kind = reader.read_u8().await?;
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            if len > MAX_PACKET_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte MQTT packet", len)));
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).await?;
+            return Ok((kind, body));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "malformed MQTT remaining length"))
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, GetString, ReadTxn, StateVector, TextRef, Transact, Update};
+
+use crate::cluster;
+use crate::config::ConfigHandle;
+use crate::hub::{is_archived, Hub};
+use crate::openapi::ApiError;
+
+// The Yjs text clients edit, `doc.getText("notes")` on their side
+const TEXT: &str = "notes";
+
+/// A room's shared notes, a Yjs document with one text in it. Clients send Yjs v1 updates,
+/// which are merged here without the server knowing what they say, and joiners get the merged
+/// state, so concurrent edits converge however they interleave
+pub struct Notes {
+    doc: Doc,
+    text: TextRef,
+    // What the state would take up encoded, at most. Updates overlap, so this only ever overcounts
+    size: usize,
+}
+
+impl Default for Notes {
+    fn default() -> Self {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text(TEXT);
+        Notes { doc, text, size: 0 }
+    }
+}
+
+impl Notes {
+    /// What `state` returned before
+    pub fn load(state: &[u8]) -> Result<Notes, String> {
+        let mut notes = Notes::default();
+        notes.apply(state)?;
+        Ok(notes)
+    }
+
+    /// Merge a Yjs v1 update in
+    pub fn apply(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let update = Update::decode_v1(bytes).map_err(|e| format!("not a Yjs update: {}", e))?;
+        self.doc.transact_mut().apply_update(update).map_err(|e| format!("could not apply the update: {}", e))?;
+        self.size += bytes.len();
+        Ok(())
+    }
+
+    /// At most what `state` would take up
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Everything, as one update
+    pub fn state(&self) -> Vec<u8> {
+        self.doc.transact().encode_state_as_update_v1(&StateVector::default())
+    }
+
+    /// `state`, to be saved, which also tells `size` what it really is again
+    pub fn snapshot(&mut self) -> Vec<u8> {
+        let state = self.state();
+        self.size = state.len();
+        state
+    }
+
+    /// What a client with this Yjs v1 state vector is missing, as one update
+    pub fn diff(&self, state_vector: &[u8]) -> Result<Vec<u8>, String> {
+        let state_vector = StateVector::decode_v1(state_vector).map_err(|e| format!("not a Yjs state vector: {}", e))?;
+        Ok(self.doc.transact().encode_diff_v1(&state_vector))
+    }
+
+    pub fn text(&self) -> String {
+        self.text.get_string(&self.doc.transact())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc.transact().state_vector().is_empty()
+    }
+}
+
+/// Yjs updates and state vectors travel in JSON frames as standard base64
+pub fn decode(base64: &str) -> Result<Vec<u8>, String> {
+    STANDARD.decode(base64).map_err(|e| format!("not base64: {}", e))
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+#[derive(Serialize, ToSchema)]
+struct NotesText {
+    id: String,
+    /// The notes as plain text, as they are merged now
+    text: String,
+}
+
+/// `GET /api/rooms/<id>/notes`, a room's shared notes as plain text
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+    warp::path!("api" / "rooms" / String / "notes")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub)
+        .and(config)
+        .and_then(room_notes)
+}
+
+/// A room's shared notes as plain text, loading the room if it isn't resident
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/notes",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "OK", body = NotesText),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 410, description = "The room is archived", body = ApiError),
+    ),
+)]
+async fn room_notes(id: String, query: HashMap<String, String>, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let path = format!("api/rooms/{}/notes", id);
+    let Some(id) = hub.room_id(&id) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid room id"));
+    };
+    // The owner checks everything else
+    let dead_after = Duration::from_secs(config.borrow().cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&id, dead_after) {
+        return Ok(cluster::moved(&owner, &path, &query));
+    }
+    let authorized = {
+        let keys = &config.borrow().auth.access_keys;
+        keys.is_empty() || query.get("key").is_some_and(|k| keys.contains(k))
+    };
+    if !authorized {
+        return Ok(error(StatusCode::UNAUTHORIZED, "missing or invalid key"));
+    }
+    if !hub.may_access(&id, query.get("token").map(String::as_str)) {
+        return Ok(error(StatusCode::FORBIDDEN, "not on the room's access list"));
+    }
+    let room = match hub.open(&id).await {
+        Ok(room) => room,
+        Err(e) if is_archived(&e) => return Ok(error(StatusCode::GONE, "room is archived")),
+        Err(e) => {
+            log::error!("Could not load room {}: {}", id, e);
+            return Ok(error(StatusCode::SERVICE_UNAVAILABLE, "room unavailable"));
+        }
+    };
+    let text = room.read().await.notes.text();
+    Ok(Box::new(warp::reply::json(&NotesText { id, text })))
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use yrs::updates::encoder::Encode;
+    use yrs::Text;
+
+    use super::*;
+
+    // What a client's Yjs document sends after typing `text` at `index`
+    fn edit(client: &Notes, index: u32, text: &str) -> Vec<u8> {
+        let before = client.doc.transact().state_vector();
+        client.text.insert(&mut client.doc.transact_mut(), index, text);
+        client.doc.transact().encode_diff_v1(&before)
+    }
+
+    #[test]
+    fn concurrent_edits_converge_and_late_joiners_catch_up() {
+        let mut server = Notes::default();
+        let alice = Notes::default();
+        let mut bob = Notes::default();
+        let hello = edit(&alice, 0, "hello");
+        server.apply(&hello).unwrap();
+        bob.apply(&hello).unwrap();
+
+        // Both edit at once, each without the other's edit
+        let world = edit(&alice, 5, " world");
+        let greeting = edit(&bob, 0, "> ");
+        server.apply(&greeting).unwrap();
+        server.apply(&world).unwrap();
+        assert_eq!(server.text(), "> hello world");
+
+        let mut late = Notes::load(&server.snapshot()).unwrap();
+        assert_eq!(late.text(), "> hello world");
+        let missing = server.diff(&bob.doc.transact().state_vector().encode_v1()).unwrap();
+        bob.apply(&missing).unwrap();
+        assert_eq!(bob.text(), server.text());
+        late.apply(&world).unwrap();
+        assert_eq!(late.text(), server.text());
+        assert!(server.apply(b"not an update").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::config::ConfigHandle;
+use crate::hub::{valid_room_id, Hub};
+use crate::openapi::ApiError;
+use crate::protocol::ServerMessage;
+use crate::socket;
+
+const MAX_TEXT_CHARS: usize = 500;
+
+const DEFAULT_TTL_SECS: u64 = 10 * 60;
+const MAX_TTL_SECS: u64 = 24 * 60 * 60;
+
+// Text, a room id and a few small fields
+const MAX_NOTICE_BYTES: u64 = 4 * 1024;
+
+/// How a client shows a `Notice`, e.g. the color of its banner
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// A message from the server's admins, e.g. "maintenance in 10 minutes", for every room or one
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Notice {
+    pub text: String,
+    pub severity: Severity,
+    /// None for every room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Unix seconds, joiners get it until then
+    pub expires_at: u64,
+}
+
+impl Notice {
+    pub fn message(&self) -> ServerMessage {
+        ServerMessage::Notice { text: self.text.clone(), severity: self.severity, expires_at: self.expires_at }
+    }
+}
+
+/// Notices that haven't expired, sent to everyone who joins a room they're for. Kept in memory,
+/// so they're gone after a restart and each node of a cluster has its own
+#[derive(Default)]
+pub struct Notices {
+    active: Mutex<Vec<Notice>>,
+}
+
+impl Notices {
+    pub fn post(&self, notice: Notice) {
+        let mut active = self.active.lock().unwrap();
+        let now = now_secs();
+        active.retain(|notice| notice.expires_at > now);
+        active.push(notice);
+    }
+
+    /// What someone joining `room` is shown, oldest first
+    pub fn for_room(&self, room: &str) -> Vec<Notice> {
+        let now = now_secs();
+        let active = self.active.lock().unwrap();
+        active.iter().filter(|notice| notice.expires_at > now && notice.room.as_deref().is_none_or(|for_room| for_room == room)).cloned().collect()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+// Trimmed, without control characters
+fn normalize_text(text: &str) -> Result<String, String> {
+    let text = text.chars().filter(|c| !c.is_control()).collect::<String>().trim().to_string();
+    if text.is_empty() {
+        return Err("text is empty".to_string());
+    }
+    if text.chars().count() > MAX_TEXT_CHARS {
+        return Err(format!("text is longer than {} characters", MAX_TEXT_CHARS));
+    }
+    Ok(text)
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct NoticeRequest {
+    text: String,
+    #[serde(default)]
+    severity: Severity,
+    /// Only this room, `<prefix>.<room>` for a tenant's. Every room without it
+    room: Option<String>,
+    /// How long joiners get it for, 10 minutes without it and at most a day
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct NoticePosted {
+    notice: Notice,
+    /// Loaded rooms it was sent to
+    rooms: usize,
+}
+
+/// `POST /api/admin/notices`
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "admin" / "notices")
+        .and(warp::post())
+        .and(api::admin(config))
+        .and(warp::body::content_length_limit(MAX_NOTICE_BYTES))
+        .and(warp::body::json())
+        .and(warp::any().map(move || hub.clone()))
+        .and_then(post_notice)
+}
+
+/// Send a notice to everyone in every room, or in one, as a `Notice` frame. Anyone joining those
+/// rooms gets it too until it expires
+#[utoipa::path(
+    post,
+    path = "/api/admin/notices",
+    tag = "admin",
+    request_body = NoticeRequest,
+    responses(
+        (status = 201, description = "Sent", body = NoticePosted),
+        (status = 400, description = "Invalid text, room id or ttl", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn post_notice(request: NoticeRequest, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let text = match normalize_text(&request.text) {
+        Ok(text) => text,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e)),
+    };
+    // A tenant's rooms are `<prefix>.<room>` in the hub
+    if request.room.as_deref().is_some_and(|room| !room.split('.').all(valid_room_id)) {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid room id"));
+    }
+    let ttl_secs = request.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    if ttl_secs == 0 || ttl_secs > MAX_TTL_SECS {
+        return Ok(error(StatusCode::BAD_REQUEST, &format!("ttl_secs has to be 1-{}", MAX_TTL_SECS)));
+    }
+    let notice = Notice { text, severity: request.severity, room: request.room, expires_at: now_secs() + ttl_secs };
+
+    let message = notice.message();
+    let mut rooms = 0;
+    for room in hub.rooms().await {
+        let room = room.read().await;
+        if notice.room.as_ref().is_none_or(|id| *id == room.id) {
+            socket::broadcast(&room, &message);
+            rooms += 1;
+        }
+    }
+    match &notice.room {
+        Some(room) => log::info!("Notice sent to room {} until {}: {}", room, notice.expires_at, notice.text),
+        None => log::info!("Notice sent to {} rooms until {}: {}", rooms, notice.expires_at, notice.text),
+    }
+    hub.notices.post(notice.clone());
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&NoticePosted { notice, rooms }), StatusCode::CREATED)))
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_are_for_their_room_until_they_expire() {
+        let notices = Notices::default();
+        let later = now_secs() + 60;
+        notices.post(Notice { text: "everyone".to_string(), severity: Severity::Info, room: None, expires_at: later });
+        notices.post(Notice { text: "class".to_string(), severity: Severity::Warning, room: Some("class".to_string()), expires_at: later });
+        notices.post(Notice { text: "gone".to_string(), severity: Severity::Critical, room: None, expires_at: now_secs() - 1 });
+        let texts = |room| notices.for_room(room).into_iter().map(|notice| notice.text).collect::<Vec<_>>();
+        assert_eq!(texts("class"), ["everyone", "class"]);
+        assert_eq!(texts("lobby"), ["everyone"]);
+    }
+}
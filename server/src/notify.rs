@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::config::{ConfigHandle, NotifierConfig, NotifierKind};
+use crate::events::ServerEvent;
+use crate::hub::Hub;
+use crate::render;
+
+// Posts waiting per notifier before new ones are dropped, chat webhooks are rate limited anyway
+const QUEUE_SIZE: usize = 64;
+
+const EVENTS: [&str; 3] = ["room_created", "first_join", "snapshot_saved"];
+
+struct Notification {
+    room: String,
+    /// What the room goes by in URLs, see `Hub::public_id`, the only id chat messages show
+    public: String,
+    text: String,
+}
+
+/// Post room activity to every configured Slack or Discord incoming webhook
+pub fn spawn(notifiers: &[NotifierConfig], hub: Arc<Hub>, config: ConfigHandle) {
+    if notifiers.is_empty() {
+        return;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("failed to build HTTP client");
+
+    let mut queues = Vec::new();
+    for notifier in notifiers {
+        if let Some(unknown) = notifier.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            log::warn!("Notifier {} has unknown event {}, expected one of {}", notifier.url, unknown, EVENTS.join(", "));
+        }
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(post(client.clone(), notifier.clone(), rx, hub.clone(), config.clone()));
+        queues.push((notifier.clone(), tx));
+    }
+
+    let mut rx = hub.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event.event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Notifiers fell behind, {} events skipped", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let (name, room) = match &event {
+                ServerEvent::RoomCreated { room, .. } => ("room_created", room.clone()),
+                ServerEvent::UserJoined { room, first: true, .. } => ("first_join", room.clone()),
+                ServerEvent::SnapshotSaved { room, .. } => ("snapshot_saved", room.clone()),
+                _ => continue,
+            };
+            let public = hub.public_id(&room);
+            let text = match event {
+                ServerEvent::RoomCreated { info, .. } => match info.name {
+                    Some(name) => format!("Room \"{}\" (`{}`) was opened", name, public),
+                    None => format!("Room `{}` was opened", public),
+                },
+                ServerEvent::UserJoined { user_id, .. } => format!("User {} joined the empty room `{}`", user_id, public),
+                ServerEvent::SnapshotSaved { ops, .. } => format!("Room `{}` was saved with {} ops", public, ops),
+                _ => continue,
+            };
+            for (notifier, tx) in &queues {
+                if !notifier.events.is_empty() && !notifier.events.iter().any(|e| e == name) {
+                    continue;
+                }
+                if tx.try_send(Notification { room: room.clone(), public: public.clone(), text: text.clone() }).is_err() {
+                    log::warn!("Notifier {} queue full, dropping {}", notifier.url, name);
+                }
+            }
+        }
+    });
+}
+
+/// One notifier's posts in order. Chat notifications aren't worth retrying, failures are only logged
+async fn post(client: reqwest::Client, notifier: NotifierConfig, mut rx: mpsc::Receiver<Notification>, hub: Arc<Hub>, config: ConfigHandle) {
+    while let Some(notification) = rx.recv().await {
+        let request = match notifier.kind {
+            NotifierKind::Slack => client.post(&notifier.url).json(&slack_message(&notifier, &notification)),
+            NotifierKind::Discord => {
+                let payload = json!({ "content": notification.text }).to_string();
+                let thumbnail = match notifier.thumbnail {
+                    true => thumbnail(&hub, &config, &notification.room).await,
+                    false => None,
+                };
+                match thumbnail {
+                    // Discord shows attached images inline
+                    Some(png) => {
+                        let file = reqwest::multipart::Part::bytes(png).file_name("thumbnail.png").mime_str("image/png").expect("valid mime type");
+                        let form = reqwest::multipart::Form::new().text("payload_json", payload).part("files[0]", file);
+                        client.post(&notifier.url).multipart(form)
+                    }
+                    None => client.post(&notifier.url).header("Content-Type", "application/json").body(payload),
+                }
+            }
+        };
+
+        match request.send().await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => log::warn!("Notifier {} answered {}", notifier.url, res.status()),
+            Err(e) => log::warn!("Notifier {} failed: {}", notifier.url, e),
+        }
+    }
+}
+
+// Slack can't take uploads on incoming webhooks, so it fetches the board from `public_url` itself.
+// It refuses messages whose image won't load, and a room that was just opened has no thumbnail yet
+// to point at, so this links the full render. By the room's public id, the only one that route takes
+fn slack_message(notifier: &NotifierConfig, notification: &Notification) -> Value {
+    let Some(base) = notifier.public_url.as_deref().filter(|_| notifier.thumbnail) else {
+        return json!({ "text": notification.text });
+    };
+    let image_url = format!("{}/api/rooms/{}/render.png", base.trim_end_matches('/'), notification.public);
+    json!({
+        "text": notification.text,
+        "blocks": [
+            { "type": "section", "text": { "type": "mrkdwn", "text": notification.text } },
+            { "type": "image", "image_url": image_url, "alt_text": format!("Room {}", notification.public) },
+        ],
+    })
+}
+
+// Rendered now rather than taken from the thumbnail cache, a room that was just opened has none yet
+async fn thumbnail(hub: &Hub, config: &ConfigHandle, room_id: &str) -> Option<Vec<u8>> {
+    let history = hub.get(room_id).await?.read().await.history.clone();
+    let size = config.borrow().render.thumbnail_size;
+    match tokio::task::spawn_blocking(move || render::thumbnail(&history, size)).await {
+        Ok(Ok(png)) => Some(png),
+        Ok(Err(e)) => {
+            log::warn!("Could not render a thumbnail of room {}: {}", room_id, e);
+            None
+        }
+        Err(e) => {
+            log::warn!("Thumbnail of room {} panicked: {}", room_id, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::public_ids::{PublicIds, Signed};
+
+    #[test]
+    fn slack_links_the_render_by_public_id() {
+        let ids = Signed::new("secret");
+        let public = ids.encode("class-7b");
+        let notifier = NotifierConfig { kind: NotifierKind::Slack, url: String::new(), events: Vec::new(), thumbnail: true, public_url: Some("https://board.example.com/".to_string()) };
+        let notification = Notification { room: "class-7b".to_string(), public: public.clone(), text: format!("Room `{}` was opened", public) };
+        let message = slack_message(&notifier, &notification).to_string();
+        assert!(message.contains(&format!("https://board.example.com/api/rooms/{}/render.png", public)), "{}", message);
+        assert!(!message.contains("class-7b"), "{}", message);
+        assert_eq!(ids.decode(&public).as_deref(), Some("class-7b"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ConfigHandle;
+use crate::{accounts, api, backup, cluster, features, longpoll, notes, notices, reports, retention, sse, tenants};
+
+// Assets are loaded from a CDN rather than bundled into the binary
+const SWAGGER_UI_VERSION: &str = "5.17.14";
+
+/// The JSON body of every error response
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct ApiError {
+    error: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Whiteboard server",
+        description = "The HTTP API. Rooms are joined over WebSocket at `/room/{id}`, this covers the REST and long-polling endpoints around them",
+    ),
+    paths(
+        api::create_room,
+        api::lobby,
+        api::archive_room,
+        api::restore_room,
+        api::save_template,
+        api::templates,
+        api::room_stats,
+        api::room_contributions,
+        api::user_timeline,
+        api::room_sessions,
+        sse::room_events,
+        notes::room_notes,
+        api::render_room,
+        api::export_svg,
+        api::replay_gif,
+        api::room_thumbnail,
+        longpoll::open_session,
+        longpoll::close_session,
+        longpoll::send_message,
+        longpoll::poll_messages,
+        api::inject_commands,
+        api::list_connections,
+        api::get_connection,
+        api::usage_report,
+        reports::report_user,
+        reports::list_reports,
+        reports::dismiss_report,
+        notices::post_notice,
+        backup::export_backup,
+        backup::import_backup,
+        retention::preview_retention,
+        retention::enforce_retention,
+        cluster::cluster_status,
+        accounts::register,
+        accounts::login,
+        accounts::logout,
+        accounts::current_account,
+        accounts::room_members,
+        accounts::add_member,
+        accounts::remove_member,
+        accounts::create_link,
+        tenants::tenant_info,
+        tenants::tenant_rooms,
+        features::room_features,
+        features::set_room_features,
+        features::tenant_features,
+        features::set_tenant_features,
+        features::tenant_room_features,
+        features::set_tenant_room_features,
+    ),
+    modifiers(&AdminToken),
+    tags(
+        (name = "rooms"),
+        (name = "long-polling", description = "For networks that block WebSockets"),
+        (name = "admin", description = "Needs one of `auth.admin_tokens` as a bearer token"),
+        (name = "accounts", description = "Registered users and room access lists, when `accounts.enabled` is set"),
+        (name = "tenants", description = "Organizations whose rooms are kept apart, see `tenants` in the config"),
+    ),
+)]
+struct ApiDoc;
+
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("admin_token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+            components.add_security_scheme("session_token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        }
+    }
+}
+
+/// `GET /api/openapi.json`, plus Swagger UI at `/api/docs` when `openapi.swagger_ui` is set
+pub fn routes(config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Generated once, it only changes with the binary
+    let document = Arc::new(ApiDoc::openapi().to_json().unwrap_or_default());
+
+    let spec = warp::path!("api" / "openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::with_header(document.to_string(), "content-type", "application/json"));
+
+    let docs = warp::path!("api" / "docs")
+        .and(warp::get())
+        .then(move || {
+            let enabled = config.borrow().openapi.swagger_ui;
+            async move {
+                // Answered rather than rejected, warp would turn a rejection here into a 405 from another route
+                let reply: Box<dyn Reply> = match enabled {
+                    true => Box::new(warp::reply::html(swagger_ui())),
+                    false => Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": "not found" })), StatusCode::NOT_FOUND)),
+                };
+                reply
+            }
+        });
+
+    spec.or(docs)
+}
+
+fn swagger_ui() -> String {
+    format!(
+        r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Whiteboard server API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: "/api/openapi.json", dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##,
+        version = SWAGGER_UI_VERSION
+    )
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::protocol::{MessageType, Role};
+
+/// Who may use a tool in a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Grant {
+    /// Viewers too
+    Everyone,
+    /// Everyone but viewers, what every tool is unless it's set
+    Editors,
+    /// Only a connection signed in as the room's owner, nobody in rooms without one
+    Owner,
+}
+
+impl Grant {
+    /// Whether someone with `role` may, `owner` being whether they're signed in as the room's owner
+    pub fn allows(self, role: Role, owner: bool) -> bool {
+        match self {
+            Grant::Everyone => true,
+            Grant::Editors => owner || role != Role::Viewer,
+            Grant::Owner => owner,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Grant::Everyone => "everyone",
+            Grant::Editors => "editors",
+            Grant::Owner => "the room's owner",
+        }
+    }
+}
+
+/// What someone needs a grant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Draw,
+    Erase,
+    Clear,
+    UndoClear,
+    /// Editing the shared notes, reading them is for everyone
+    Notes,
+    /// Frames of a `type` the server doesn't know, e.g. sticky notes from newer clients
+    Relayed,
+}
+
+impl Tool {
+    pub fn of(op: &MessageType) -> Tool {
+        match op {
+            MessageType::Draw(draw) if draw.composite.erases() => Tool::Erase,
+            MessageType::Draw(_) => Tool::Draw,
+            MessageType::Clear => Tool::Clear,
+        }
+    }
+
+    /// As it's named in `Permissions`
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Draw => "draw",
+            Tool::Erase => "erase",
+            Tool::Clear => "clear",
+            Tool::UndoClear => "undo_clear",
+            Tool::Notes => "notes",
+            Tool::Relayed => "relayed",
+        }
+    }
+}
+
+/// Who may use each tool, on top of the connection's role. Set for every room in `[permissions]`
+/// and for one room by its owner with `UpdateRoom`; a tool left unset is whatever the level above
+/// it has, and `editors` at the top
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Permissions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draw: Option<Grant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub erase: Option<Grant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clear: Option<Grant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub undo_clear: Option<Grant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<Grant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relayed: Option<Grant>,
+}
+
+impl Permissions {
+    pub fn is_empty(&self) -> bool {
+        *self == Permissions::default()
+    }
+
+    /// These, with any left unset taken from `fallback`
+    pub fn or(self, fallback: Permissions) -> Permissions {
+        Permissions {
+            draw: self.draw.or(fallback.draw),
+            erase: self.erase.or(fallback.erase),
+            clear: self.clear.or(fallback.clear),
+            undo_clear: self.undo_clear.or(fallback.undo_clear),
+            notes: self.notes.or(fallback.notes),
+            relayed: self.relayed.or(fallback.relayed),
+        }
+    }
+
+    pub fn grant(&self, tool: Tool) -> Grant {
+        let grant = match tool {
+            Tool::Draw => self.draw,
+            Tool::Erase => self.erase,
+            Tool::Clear => self.clear,
+            Tool::UndoClear => self.undo_clear,
+            Tool::Notes => self.notes,
+            Tool::Relayed => self.relayed,
+        };
+        grant.unwrap_or(Grant::Editors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Composite, DrawCommand};
+
+    #[test]
+    fn tools_fall_back_to_the_server_then_to_editors() {
+        let server = Permissions { clear: Some(Grant::Owner), relayed: Some(Grant::Everyone), ..Permissions::default() };
+        let room = Permissions { relayed: Some(Grant::Editors), draw: Some(Grant::Everyone), ..Permissions::default() }.or(server);
+        assert_eq!(room.grant(Tool::Draw), Grant::Everyone);
+        assert_eq!(room.grant(Tool::Relayed), Grant::Editors);
+        assert_eq!(room.grant(Tool::Clear), Grant::Owner);
+        assert_eq!(room.grant(Tool::Erase), Grant::Editors);
+
+        let erase = MessageType::Draw(DrawCommand { prev: [0.0, 0.0], cur: [1.0, 1.0], color: "#fff".to_string(), brush_size: 1, composite: Composite::DestinationOut });
+        assert_eq!(Tool::of(&erase), Tool::Erase);
+        assert!(room.grant(Tool::Draw).allows(Role::Viewer, false));
+        assert!(!room.grant(Tool::Erase).allows(Role::Viewer, false));
+        assert!(!room.grant(Tool::Clear).allows(Role::Editor, false));
+        assert!(room.grant(Tool::Clear).allows(Role::Viewer, true));
+    }
+}
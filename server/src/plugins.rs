@@ -0,0 +1,129 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use wasmtime::{Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::config::PluginsConfig;
+use crate::hooks::{Hook, Outcome};
+use crate::ids::UserId;
+use crate::protocol::MessageType;
+
+const HOOKS: [&str; 4] = ["on_message", "on_join", "on_room_create", "on_tick"];
+
+/// Load every module in `plugins.modules`, failing on the first that can't be used.
+///
+/// A module imports nothing and exports `memory`, `alloc(len: i32) -> i32` and any of the hooks
+/// as `(ptr: i32, len: i32) -> i64`. Each hook is passed JSON in a buffer from `alloc`:
+/// `{"room", "user_id", "op"}` for `on_message`, `{"room", "user_id"}` for `on_join` and `{"room"}`
+/// for `on_room_create` and `on_tick`. It returns `ptr << 32 | len` of a JSON reply in its memory, or 0 to leave
+/// things as they are. The reply may carry `op` to replace the op, `reject` with a reason to drop
+/// it, and `emit` with ops to draw into the room
+pub fn load(config: &PluginsConfig) -> Result<Vec<Box<dyn Hook>>, String> {
+    let mut engine_config = wasmtime::Config::new();
+    engine_config.consume_fuel(true);
+    let engine = Engine::new(&engine_config).map_err(|e| e.to_string())?;
+
+    let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+    for path in &config.modules {
+        let plugin = Plugin::load(&engine, path, config).map_err(|e| format!("{}: {}", path.display(), e))?;
+        log::info!("Loaded plugin {} with {}", plugin.name, HOOKS.iter().filter(|h| plugin.has(h)).copied().collect::<Vec<_>>().join(", "));
+        hooks.push(Box::new(plugin));
+    }
+    Ok(hooks)
+}
+
+struct State {
+    limits: StoreLimits,
+}
+
+struct Instantiated {
+    store: Store<State>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    hooks: Vec<Option<TypedFunc<(i32, i32), i64>>>,
+}
+
+struct Plugin {
+    name: String,
+    fuel: u64,
+    // One instance per module, so a plugin can keep state between calls
+    instance: Mutex<Instantiated>,
+}
+
+impl Plugin {
+    fn load(engine: &Engine, path: &Path, config: &PluginsConfig) -> wasmtime::Result<Self> {
+        // `.wat` text works as well as binary modules
+        let module = Module::from_file(engine, path)?;
+        let limits = StoreLimitsBuilder::new().memory_size(config.max_memory_bytes).build();
+        let mut store = Store::new(engine, State { limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(config.fuel)?;
+
+        let instance: Instance = Linker::new(engine).instantiate(&mut store, &module)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| wasmtime::Error::msg("no exported memory"))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let hooks = HOOKS.iter().map(|hook| instance.get_typed_func(&mut store, hook).ok()).collect();
+
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(Plugin { name, fuel: config.fuel, instance: Mutex::new(Instantiated { store, memory, alloc, hooks }) })
+    }
+
+    fn has(&self, hook: &str) -> bool {
+        let index = HOOKS.iter().position(|h| *h == hook);
+        index.is_some_and(|i| self.instance.lock().unwrap().hooks[i].is_some())
+    }
+
+    /// Run one hook, `None` if the module doesn't export it or returned 0
+    fn call(&self, hook: &str, input: Value) -> Result<Option<Outcome>, String> {
+        let index = HOOKS.iter().position(|h| *h == hook).expect("known hook");
+        let mut guard = self.instance.lock().unwrap_or_else(|e| e.into_inner());
+        let instance = &mut *guard;
+        let Some(func) = instance.hooks[index].clone() else {
+            return Ok(None);
+        };
+        let store = &mut instance.store;
+        // A runaway hook traps without fuel rather than stalling the room
+        store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+
+        let input = serde_json::to_vec(&input).map_err(|e| e.to_string())?;
+        let ptr = instance.alloc.call(&mut *store, input.len() as i32).map_err(trap)?;
+        instance.memory.write(&mut *store, ptr as u32 as usize, &input).map_err(|e| e.to_string())?;
+        let packed = func.call(&mut *store, (ptr, input.len() as i32)).map_err(trap)?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let mut reply = vec![0; len];
+        instance.memory.read(&*store, ptr, &mut reply).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&reply).map(Some).map_err(|e| format!("invalid reply: {}", e))
+    }
+}
+
+// The wasm backtrace wasmtime attaches spans lines, the cause is enough for the log
+fn trap(e: wasmtime::Error) -> String {
+    e.root_cause().to_string()
+}
+
+impl Hook for Plugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_message(&self, room: &str, user_id: UserId, op: &MessageType) -> Result<Outcome, String> {
+        Ok(self.call("on_message", json!({ "room": room, "user_id": user_id, "op": op }))?.unwrap_or_default())
+    }
+
+    fn on_join(&self, room: &str, user_id: UserId) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_join", json!({ "room": room, "user_id": user_id }))?.map(|o| o.emit).unwrap_or_default())
+    }
+
+    fn on_room_create(&self, room: &str) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_room_create", json!({ "room": room }))?.map(|o| o.emit).unwrap_or_default())
+    }
+
+    fn on_tick(&self, room: &str) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_tick", json!({ "room": room }))?.map(|o| o.emit).unwrap_or_default())
+    }
+}
@@ -0,0 +1,234 @@
+use std::io;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, NoTls};
+
+use crate::events::now_millis;
+use crate::ids::{random_token, UserId};
+use crate::protocol::{Member, MessageType};
+use crate::shared::{SharedOp, SharedState};
+
+// How long until someone a node stopped saying is in a room is taken to have left, as for SQLite
+const MEMBER_TTL_MS: i64 = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        id TEXT PRIMARY KEY,
+        seq BIGINT NOT NULL,
+        epoch BIGINT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS ops (
+        room TEXT NOT NULL,
+        seq BIGINT NOT NULL,
+        epoch BIGINT NOT NULL,
+        node TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        op TEXT NOT NULL,
+        at BIGINT NOT NULL,
+        PRIMARY KEY (room, seq)
+    );
+    CREATE TABLE IF NOT EXISTS members (
+        room TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        node TEXT NOT NULL,
+        member TEXT NOT NULL,
+        seen_at BIGINT NOT NULL,
+        PRIMARY KEY (room, user_id)
+    );
+";
+
+/// Shared state in a PostgreSQL database, for nodes on any machine that can reach it. A room's
+/// row is locked while an op is appended to it, so its seqs are handed out one at a time
+pub struct PostgresState {
+    url: String,
+    client: Mutex<Client>,
+    // Picked per process, rows a node wrote are told apart by it
+    node: String,
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+async fn connect(url: &str) -> io::Result<Client> {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await.map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::warn!("Lost the connection to the shared state database: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+impl PostgresState {
+    pub async fn connect(url: &str) -> io::Result<Self> {
+        let client = connect(url).await?;
+        client.batch_execute(SCHEMA).await.map_err(io::Error::other)?;
+        Ok(PostgresState { url: url.to_string(), client: Mutex::new(client), node: random_token()[..16].to_string() })
+    }
+
+    // The client, connected again if the last connection dropped, e.g. when the database restarted.
+    // What was tried on the dropped one failed and went back to whoever asked
+    async fn client(&self) -> io::Result<tokio::sync::MutexGuard<'_, Client>> {
+        let mut client = self.client.lock().await;
+        if client.is_closed() {
+            *client = connect(&self.url).await?;
+        }
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl SharedState for PostgresState {
+    async fn load(&self, room: &str, history: &[MessageType]) -> io::Result<(Vec<MessageType>, u64, u64)> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(io::Error::other)?;
+        // Whichever node gets here first for a room puts in what its storage had, the others wait
+        // on its row and take the board from there
+        let created = tx
+            .execute("INSERT INTO rooms (id, seq, epoch) VALUES ($1, $2, 0) ON CONFLICT (id) DO NOTHING", &[&room, &(history.len() as i64)])
+            .await
+            .map_err(io::Error::other)?;
+        if created == 1 {
+            let at = now_millis() as i64;
+            for (n, op) in history.iter().enumerate() {
+                let op = serde_json::to_string(op)?;
+                tx.execute(
+                    // Nobody in particular drew what storage had
+                    "INSERT INTO ops (room, seq, epoch, node, user_id, op, at) VALUES ($1, $2, 0, $3, '0000000000000000', $4, $5)",
+                    &[&room, &(n as i64 + 1), &self.node, &op, &at],
+                )
+                .await
+                .map_err(io::Error::other)?;
+            }
+        }
+        let row = tx.query_one("SELECT seq, epoch FROM rooms WHERE id = $1 FOR SHARE", &[&room]).await.map_err(io::Error::other)?;
+        let (seq, epoch) = (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64);
+        let rows = tx.query("SELECT op FROM ops WHERE room = $1 AND epoch = $2 ORDER BY seq", &[&room, &(epoch as i64)]).await.map_err(io::Error::other)?;
+        let mut board = Vec::new();
+        for row in rows {
+            match serde_json::from_str(row.get(0)).map_err(invalid)? {
+                // The clear that started the epoch
+                MessageType::Clear => {}
+                op => board.push(op),
+            }
+        }
+        tx.commit().await.map_err(io::Error::other)?;
+        Ok((board, seq, epoch))
+    }
+
+    async fn append(&self, room: &str, op: &MessageType, user_id: UserId, history_limit: usize) -> io::Result<(u64, u64)> {
+        let json = serde_json::to_string(op)?;
+        let clears = matches!(op, MessageType::Clear) as i64;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(io::Error::other)?;
+        let row = tx
+            .query_one(
+                "INSERT INTO rooms (id, seq, epoch) VALUES ($1, 1, $2)
+                 ON CONFLICT (id) DO UPDATE SET seq = rooms.seq + 1, epoch = rooms.epoch + $2
+                 RETURNING seq, epoch",
+                &[&room, &clears],
+            )
+            .await
+            .map_err(io::Error::other)?;
+        let (seq, epoch) = (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64);
+        tx.execute(
+            "INSERT INTO ops (room, seq, epoch, node, user_id, op, at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[&room, &(seq as i64), &(epoch as i64), &self.node, &user_id.to_string(), &json, &(now_millis() as i64)],
+        )
+        .await
+        .map_err(io::Error::other)?;
+        tx.execute(
+            "DELETE FROM ops WHERE room = $1 AND (epoch < $2 OR seq <= $3)",
+            &[&room, &(epoch as i64), &(seq.saturating_sub(history_limit as u64) as i64)],
+        )
+        .await
+        .map_err(io::Error::other)?;
+        tx.commit().await.map_err(io::Error::other)?;
+        Ok((seq, epoch))
+    }
+
+    async fn since(&self, room: &str, seq: u64) -> io::Result<Vec<SharedOp>> {
+        let rows = self
+            .client()
+            .await?
+            .query("SELECT seq, epoch, node, user_id, op, at FROM ops WHERE room = $1 AND seq > $2 ORDER BY seq", &[&room, &(seq as i64)])
+            .await
+            .map_err(io::Error::other)?;
+        rows.iter()
+            .map(|row| {
+                Ok(SharedOp {
+                    seq: row.get::<_, i64>(0) as u64,
+                    epoch: row.get::<_, i64>(1) as u64,
+                    user_id: row.get::<_, &str>(3).parse().map_err(invalid)?,
+                    op: serde_json::from_str(row.get(4)).map_err(invalid)?,
+                    at: row.get::<_, i64>(5) as u64,
+                    own: row.get::<_, &str>(2) == self.node,
+                })
+            })
+            .collect()
+    }
+
+    async fn share_members(&self, room: &str, members: &[Member]) -> io::Result<Vec<Member>> {
+        let now = now_millis() as i64;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(io::Error::other)?;
+        tx.execute("DELETE FROM members WHERE room = $1 AND node = $2", &[&room, &self.node]).await.map_err(io::Error::other)?;
+        for member in members {
+            let json = serde_json::to_string(member)?;
+            tx.execute(
+                "INSERT INTO members (room, user_id, node, member, seen_at) VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (room, user_id) DO UPDATE SET node = $3, member = $4, seen_at = $5",
+                &[&room, &member.user_id.to_string(), &self.node, &json, &now],
+            )
+            .await
+            .map_err(io::Error::other)?;
+        }
+        tx.execute("DELETE FROM members WHERE seen_at < $1", &[&(now - MEMBER_TTL_MS)]).await.map_err(io::Error::other)?;
+        let rows = tx
+            .query("SELECT member FROM members WHERE room = $1 AND node != $2 ORDER BY user_id", &[&room, &self.node])
+            .await
+            .map_err(io::Error::other)?;
+        let others = rows.iter().map(|row| serde_json::from_str(row.get(0)).map_err(invalid)).collect::<io::Result<Vec<Member>>>()?;
+        tx.commit().await.map_err(io::Error::other)?;
+        Ok(others)
+    }
+
+    async fn forget(&self, room: &str) -> io::Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(io::Error::other)?;
+        for statement in ["DELETE FROM ops WHERE room = $1", "DELETE FROM members WHERE room = $1", "DELETE FROM rooms WHERE id = $1"] {
+            tx.execute(statement, &[&room]).await.map_err(io::Error::other)?;
+        }
+        tx.commit().await.map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::tests::{see_each_others_members, share_seqs_and_boards};
+
+    // Needs a PostgreSQL server of its own, e.g. WS_DEMO_TEST_POSTGRES=postgres://postgres@localhost/ws_demo_test,
+    // whose tables are emptied first
+    async fn nodes() -> Option<(PostgresState, PostgresState)> {
+        let Ok(url) = std::env::var("WS_DEMO_TEST_POSTGRES") else {
+            eprintln!("WS_DEMO_TEST_POSTGRES is not set, skipping");
+            return None;
+        };
+        let a = PostgresState::connect(&url).await.unwrap();
+        a.client().await.unwrap().batch_execute("TRUNCATE rooms, ops, members").await.unwrap();
+        Some((a, PostgresState::connect(&url).await.unwrap()))
+    }
+
+    // Both in one test, they'd empty the tables under each other
+    #[tokio::test]
+    async fn nodes_share_seqs_boards_and_members() {
+        let Some((a, b)) = nodes().await else {
+            return;
+        };
+        share_seqs_and_boards(&a, &b).await;
+        see_each_others_members(&a, &b).await;
+    }
+}
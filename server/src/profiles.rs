@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::protocol::Profile;
+
+pub const MAX_NAME_CHARS: usize = 32;
+pub const MAX_DM_CHARS: usize = 2000;
+// Enough for an emoji with skin tone and ZWJ sequences, not enough for a message
+const MAX_EMOJI_CHARS: usize = 8;
+/// How long a reaction shows in the roster after it's sent
+pub const REACTION_TTL: Duration = Duration::from_secs(10);
+// How long a profile is kept for its resume token after the connection closes
+const RESUME_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Clean up a `SetProfile` from a client. Names are trimmed, and an empty one clears it. Colors
+/// are anything CSS takes, stored normalized to hex so they're safe to hand to other clients
+pub fn normalize(profile: Profile) -> Result<Profile, String> {
+    let name = profile.name.map(|name| name.chars().filter(|c| !c.is_control()).collect::<String>().trim().to_string()).filter(|name| !name.is_empty());
+    if name.as_ref().is_some_and(|name| name.chars().count() > MAX_NAME_CHARS) {
+        return Err(format!("name is longer than {} characters", MAX_NAME_CHARS));
+    }
+    let avatar_color = match profile.avatar_color.filter(|color| !color.trim().is_empty()) {
+        Some(color) => Some(csscolorparser::parse(&color).map_err(|_| format!("invalid avatar color {:?}", color))?.to_css_hex().to_string()),
+        None => None,
+    };
+    Ok(Profile { name, avatar_color })
+}
+
+/// Check a `React` from a client: a short run of non-alphanumeric characters, since it's meant to
+/// be an emoji rather than a way around the chat
+pub fn normalize_emoji(emoji: &str) -> Result<String, String> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_CHARS || emoji.chars().any(|c| c.is_control() || c.is_whitespace() || c.is_alphanumeric()) {
+        return Err(format!("invalid reaction {:?}", emoji));
+    }
+    Ok(emoji.to_string())
+}
+
+/// Clean up the text of a `Dm`: control characters other than newlines are dropped and it's
+/// trimmed, leaving nothing is an error
+pub fn normalize_text(text: &str) -> Result<String, String> {
+    let text = text.chars().filter(|&c| c == '\n' || !c.is_control()).collect::<String>().trim().to_string();
+    if text.is_empty() {
+        return Err("message is empty".to_string());
+    }
+    if text.chars().count() > MAX_DM_CHARS {
+        return Err(format!("message is longer than {} characters", MAX_DM_CHARS));
+    }
+    Ok(text)
+}
+
+/// `name`, or with a " (2)", " (3)"... suffix if someone in `taken` already goes by it, ignoring case
+pub fn unique_name<'a>(name: &str, taken: impl Iterator<Item = &'a str> + Clone) -> String {
+    let is_taken = |candidate: &str| taken.clone().any(|t| t.to_lowercase() == candidate.to_lowercase());
+    if !is_taken(name) {
+        return name.to_string();
+    }
+    (2..).map(|n| format!("{} ({})", name, n)).find(|candidate| !is_taken(candidate)).expect("some suffix is free")
+}
+
+/// Whether a `?resume=` token looks like one `random_token` made, anything else gets a fresh one
+pub fn valid_token(token: &str) -> bool {
+    token.len() == 32 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Profiles of connections that have closed, by resume token, so reconnecting clients get theirs back
+#[derive(Default)]
+pub struct Profiles {
+    by_token: Mutex<HashMap<String, (Profile, Instant)>>,
+}
+
+impl Profiles {
+    pub fn remember(&self, token: String, profile: Profile) {
+        let mut by_token = self.by_token.lock().unwrap();
+        by_token.retain(|_, (_, left_at)| left_at.elapsed() < RESUME_TTL);
+        by_token.insert(token, (profile, Instant::now()));
+    }
+
+    pub fn take(&self, token: &str) -> Option<Profile> {
+        let (profile, left_at) = self.by_token.lock().unwrap().remove(token)?;
+        (left_at.elapsed() < RESUME_TTL).then_some(profile)
+    }
+}
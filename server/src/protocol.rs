@@ -0,0 +1,813 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::connection::DisconnectReason;
+use crate::ids::UserId;
+use crate::notices::Severity;
+use crate::room::{RoomInfo, RoomUpdate, Stroke};
+use crate::schedule::Action;
+
+// Past anything a client draws, low enough that rendering a stroke stays cheap
+const MAX_BRUSH_SIZE: u32 = 500;
+const MAX_COORDINATE: f64 = 1_000_000.0;
+// Enough for any CSS color syntax
+const MAX_COLOR_BYTES: usize = 64;
+// Well past any frame this server knows, whose op points are three levels down, and still room
+// for a newer client's relayed frames
+const MAX_DEPTH: usize = 32;
+// The `color` of an erase, the board's background
+const ERASE_COLOR: &str = "#ffffff";
+
+/// An op on the board. Erasing is drawing with `Composite::DestinationOut`, older clients' `Erase`
+/// frames are read as that
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(tag = "type", content = "data", from = "WireOp")]
+pub enum MessageType {
+    Draw(DrawCommand),
+    Clear,
+}
+
+// What an op can come as, the `Erase` of clients from before `composite`
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data")]
+enum WireOp {
+    Draw(DrawCommand),
+    Clear,
+    Erase(EraseCommand),
+}
+
+impl From<WireOp> for MessageType {
+    fn from(op: WireOp) -> Self {
+        match op {
+            WireOp::Draw(draw) => MessageType::Draw(draw),
+            WireOp::Clear => MessageType::Clear,
+            WireOp::Erase(erase) => MessageType::Draw(erase.into()),
+        }
+    }
+}
+
+impl MessageType {
+    /// Every `type` an op can come as
+    pub const TYPES: &'static [&'static str] = &["Draw", "Clear", "Erase"];
+
+    /// Brush sizes and coordinates within what a board can show, and colors of a sane length
+    pub fn check(&self) -> Result<(), Invalid> {
+        let draw = match self {
+            MessageType::Draw(draw) => draw,
+            MessageType::Clear => return Ok(()),
+        };
+        if draw.color.len() > MAX_COLOR_BYTES {
+            return Err(Invalid::OutOfRange(format!("color is over {} bytes", MAX_COLOR_BYTES)));
+        }
+        let (points, brush_size) = ([draw.prev, draw.cur], draw.brush_size);
+        if !(1..=MAX_BRUSH_SIZE).contains(&brush_size) {
+            return Err(Invalid::OutOfRange(format!("brush_size has to be 1 to {}", MAX_BRUSH_SIZE)));
+        }
+        if points.iter().flatten().any(|v| !(v.is_finite() && v.abs() <= MAX_COORDINATE)) {
+            return Err(Invalid::OutOfRange(format!("coordinates have to be within {}", MAX_COORDINATE)));
+        }
+        Ok(())
+    }
+
+    /// Lowercase name of the op, e.g. for topics. Erasing keeps its own name there
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Draw(draw) if draw.composite.erases() => "erase",
+            MessageType::Draw(_) => "draw",
+            MessageType::Clear => "clear",
+        }
+    }
+}
+
+// Ops are strict about their fields, a field from a newer client would otherwise be dropped from
+// history without it knowing
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DrawCommand {
+    pub prev: [f64; 2],
+    pub cur: [f64; 2],
+    /// What clients that don't know `composite` draw with, white for an erase on the white board
+    pub color: String,
+    pub brush_size: u32,
+    #[serde(default, skip_serializing_if = "Composite::is_default")]
+    pub composite: Composite,
+}
+
+/// How a stroke goes onto the board, named like the canvas `globalCompositeOperation`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Composite {
+    /// Painted over what's there in its color
+    #[default]
+    SourceOver,
+    /// Takes away what's under it, its color doesn't matter
+    DestinationOut,
+}
+
+impl Composite {
+    pub fn is_default(&self) -> bool {
+        *self == Composite::SourceOver
+    }
+
+    pub fn erases(&self) -> bool {
+        *self == Composite::DestinationOut
+    }
+}
+
+/// The `data` of an `Erase` from an older client, the same as a `Draw` with `destination-out`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EraseCommand {
+    pub prev: [f64; 2],
+    pub cur: [f64; 2],
+    pub brush_size: u32,
+}
+
+impl From<EraseCommand> for DrawCommand {
+    fn from(erase: EraseCommand) -> Self {
+        DrawCommand { prev: erase.prev, cur: erase.cur, color: ERASE_COLOR.to_string(), brush_size: erase.brush_size, composite: Composite::DestinationOut }
+    }
+}
+
+/// Frames clients send about themselves rather than the board, these never go into history
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "data")]
+pub enum ControlMessage {
+    /// Does nothing, for a client's first frame when it has nothing else to send, see `limits.handshake_timeout_secs`
+    Hello,
+    SetProfile(Profile),
+    RaiseHand,
+    LowerHand,
+    React { emoji: String },
+    /// Get `user_id`'s viewport and cursor from now on
+    Follow { user_id: UserId },
+    Unfollow,
+    /// Your own viewport and cursor, only relayed to your followers
+    Viewport(Viewport),
+    Cursor { x: f64, y: f64 },
+    /// A private message, only `to_user_id` and the sender see it
+    Dm { to_user_id: UserId, text: String },
+    /// WebRTC signaling, an SDP offer or answer or an ICE candidate, passed to `to_user_id` as it
+    /// is. The server never looks inside `payload`
+    Signal { to_user_id: UserId, payload: Value },
+    /// Join the room's voice channel, or say you muted, unmuted, started or stopped talking in it.
+    /// The audio itself goes peer to peer, see `Signal`
+    VoiceState(VoiceState),
+    LeaveVoice,
+    /// A Yjs v1 update to the room's shared notes, in base64, see `notes`
+    NotesUpdate { update: String },
+    /// Send me what a Yjs document with this v1 state vector, in base64, is missing of the notes
+    NotesSync { state_vector: String },
+    /// Stop getting someone's cursor, viewport, reactions and messages, their ops still arrive
+    Block { user_id: UserId },
+    Unblock { user_id: UserId },
+    /// The room's name, description, tags or visibility, from its owner only
+    UpdateRoom(RoomUpdate),
+    /// Bring back the board from before the last `Clear`, within `rooms.clear_undo_secs` of it
+    UndoClear,
+    /// Send me the whole board again, e.g. when a `Checksum` doesn't match mine
+    Resync,
+    /// Who drew the op on the board with this `stroke_id`, from an echo or an export
+    Inspect { stroke_id: u64 },
+    /// Ask for the server's clock, `client_time` is yours in whatever unit you like and comes back as it is
+    TimeSync { client_time: f64 },
+    /// Report `user_id` to the server's moderators, see `reports`
+    Report { user_id: UserId, reason: String },
+    /// Sign this guest connection in with a login session `token`, keeping what it did as a guest
+    SignIn { token: String },
+    /// Make the whole room read-only until `Unfreeze`, from its owner only
+    Freeze,
+    Unfreeze,
+    /// Leave this room for `room_id` without reconnecting, WebSockets only. The socket is then
+    /// caught up on the new room like any joiner, starting with its `Welcome`
+    SwitchRoom { room_id: String },
+}
+
+impl ControlMessage {
+    /// Every `type` of these, a frame is told apart from an op by its `type` alone
+    pub const TYPES: &'static [&'static str] = &["Hello", "SetProfile", "RaiseHand", "LowerHand", "React", "Follow", "Unfollow", "Viewport", "Cursor", "Dm", "Signal", "VoiceState", "LeaveVoice", "NotesUpdate", "NotesSync", "Block", "Unblock", "UpdateRoom", "UndoClear", "Resync", "Inspect", "TimeSync", "Report", "SignIn", "Freeze", "Unfreeze", "SwitchRoom"];
+}
+
+/// A text frame from a client, an op for the board or a control frame. Fields next to `type` and
+/// `data` are ignored, fields inside an op's `data` aren't
+#[derive(Debug)]
+pub enum ClientFrame {
+    /// With the `epoch` next to its `type` if it had one, the board as the sender last saw it
+    Op { op: MessageType, epoch: Option<u64> },
+    Control(ControlMessage),
+    /// A `type` this server doesn't know, e.g. from a newer client. The room's `unknown_frames`
+    /// decides whether it's refused as `unknown_type` or relayed to everyone else as it came
+    Unknown { kind: String, frame: Value },
+}
+
+impl ClientFrame {
+    /// Told apart by `type` first, so a malformed control frame is reported as that rather than as
+    /// a bad op. Ops are also checked against the limits on their values
+    pub fn parse(text: &str) -> Result<Self, Invalid> {
+        screen(text)?;
+        #[derive(Deserialize)]
+        struct Tag {
+            #[serde(rename = "type")]
+            kind: Option<String>,
+        }
+        let tag: Tag = serde_json::from_str(text).map_err(|e| Invalid::Malformed(e.to_string()))?;
+        let kind = tag.kind.ok_or_else(|| Invalid::Malformed("missing field `type`".to_string()))?;
+        if ControlMessage::TYPES.contains(&kind.as_str()) {
+            return serde_json::from_str(text).map(ClientFrame::Control).map_err(Invalid::from);
+        }
+        if !MessageType::TYPES.contains(&kind.as_str()) {
+            return Ok(ClientFrame::Unknown { frame: serde_json::from_str(text)?, kind });
+        }
+        #[derive(Deserialize)]
+        struct Epoch {
+            epoch: Option<u64>,
+        }
+        let op: MessageType = serde_json::from_str(text)?;
+        op.check()?;
+        let Epoch { epoch } = serde_json::from_str(text)?;
+        Ok(ClientFrame::Op { op, epoch })
+    }
+}
+
+/// An op as it goes out to clients, with the epoch the room is in once it's applied
+#[derive(Serialize, Clone, Copy)]
+pub struct Stamped<'a> {
+    #[serde(flatten)]
+    pub op: &'a MessageType,
+    pub epoch: u64,
+    /// Its id on the board, for `Inspect`, for anything but a clear. Only for connections that
+    /// asked for echoes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stroke_id: Option<u64>,
+}
+
+/// An op as it goes out to connections that asked for echoes, their own ops included, with where
+/// it landed in the room
+#[derive(Serialize)]
+pub struct Echoed<'a> {
+    #[serde(flatten)]
+    pub op: Stamped<'a>,
+    pub seq: u64,
+    /// Unix milliseconds, when it was applied
+    pub timestamp: u64,
+    /// Who sent it, as everyone sees them
+    pub user_id: UserId,
+}
+
+/// One pass over a frame before serde sees it, so nothing that would be refused anyway is parsed
+/// into values first, up to three times in `ClientFrame::parse`. JSON has no raw control characters
+/// other than whitespace, a frame is an object, and nesting is held to `MAX_DEPTH`
+fn screen(text: &str) -> Result<(), Invalid> {
+    if !text.trim_start_matches([' ', '\t', '\n', '\r']).starts_with('{') {
+        return Err(Invalid::Malformed("a frame has to be a JSON object".to_string()));
+    }
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for (i, &b) in text.as_bytes().iter().enumerate() {
+        if b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r') {
+            return Err(Invalid::Malformed(format!("control character {:#04x} at byte {}", b, i)));
+        }
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(Invalid::Malformed(format!("nested more than {} deep", MAX_DEPTH)));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Why a client frame was refused before it got to the room, sent back as an error frame with `code()`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalid {
+    /// Not JSON, or not an object with a string `type`, or past what `screen` lets through
+    Malformed(String),
+    UnknownType(String),
+    /// A field an op or `UpdateRoom` doesn't have
+    UnknownField(String),
+    MissingField(String),
+    /// A field of the wrong type, or a value it can't have such as an unknown visibility
+    BadField(String),
+    /// A number too large for its field, or a value past `MessageType::check`'s limits
+    OutOfRange(String),
+    /// Over `limits.max_message_bytes`
+    Oversized { bytes: usize, limit: usize },
+}
+
+impl Invalid {
+    /// Every `code()`, in a fixed order for metrics
+    pub const CODES: [&'static str; 7] = ["malformed", "unknown_type", "unknown_field", "missing_field", "invalid_field", "out_of_range", "oversized"];
+
+    pub fn code(&self) -> &'static str {
+        Self::CODES[self.index()]
+    }
+
+    /// Where `code()` is in `CODES`
+    pub fn index(&self) -> usize {
+        match self {
+            Invalid::Malformed(_) => 0,
+            Invalid::UnknownType(_) => 1,
+            Invalid::UnknownField(_) => 2,
+            Invalid::MissingField(_) => 3,
+            Invalid::BadField(_) => 4,
+            Invalid::OutOfRange(_) => 5,
+            Invalid::Oversized { .. } => 6,
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::UnknownType(kind) => write!(f, "unknown type `{}`", kind),
+            Invalid::Malformed(e) | Invalid::UnknownField(e) | Invalid::MissingField(e) | Invalid::BadField(e) | Invalid::OutOfRange(e) => write!(f, "{}", e),
+            Invalid::Oversized { bytes, limit } => write!(f, "frame is {} bytes, the limit is {}", bytes, limit),
+        }
+    }
+}
+
+// serde_json only says what went wrong in its messages
+impl From<serde_json::Error> for Invalid {
+    fn from(e: serde_json::Error) -> Self {
+        let message = e.to_string();
+        match e.classify() {
+            Category::Data if message.starts_with("unknown field") => Invalid::UnknownField(message),
+            Category::Data if message.starts_with("missing field") => Invalid::MissingField(message),
+            Category::Data if message.starts_with("invalid value: integer") => Invalid::OutOfRange(message),
+            Category::Data => Invalid::BadField(message),
+            Category::Io | Category::Syntax | Category::Eof => Invalid::Malformed(message),
+        }
+    }
+}
+
+/// What a participant shows others, both parts optional
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_color: Option<String>,
+}
+
+/// The part of the board someone is looking at: the board point at the top left of their screen, and their zoom
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: f64,
+    pub y: f64,
+    pub zoom: f64,
+}
+
+impl Viewport {
+    pub fn is_valid(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.zoom.is_finite() && self.zoom > 0.0
+    }
+}
+
+/// Whether someone has sent anything lately, see `presence` in the config
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    #[default]
+    Active,
+    Idle,
+    Away,
+}
+
+impl Presence {
+    pub fn is_active(&self) -> bool {
+        *self == Presence::Active
+    }
+}
+
+/// Someone in the room's voice channel, as they last said
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VoiceState {
+    pub muted: bool,
+    pub speaking: bool,
+}
+
+/// What a connection may do in its room, from the join link it came in with. Everyone else edits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Editor,
+    /// Sees the board and everyone on it but can't change it
+    Viewer,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "editor" => Some(Role::Editor),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+}
+
+/// A participant in roster and profile frames, read back from shared state
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Member {
+    pub user_id: UserId,
+    #[serde(flatten)]
+    pub profile: Profile,
+    /// Unix milliseconds, so a facilitator can take questions in order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hand_raised_at: Option<u64>,
+    /// Their latest reaction, for a few seconds after they sent it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reaction: Option<String>,
+    #[serde(default, skip_serializing_if = "Presence::is_active")]
+    pub presence: Presence,
+    /// Ping round trip in milliseconds, with `presence.share_latency`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u32>,
+    /// Set while they're in the voice channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<VoiceState>,
+    /// The account they're signed in with, guests have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// The client they said they're using, `?agent=` on the WebSocket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// They said they're a bot with `?bot=1`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_bot: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Frames only the server sends
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerMessage {
+    Error {
+        code: String,
+        message: String,
+        /// The frame that caused this, when `server.echo_correlation_ids` is on
+        #[serde(skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<String>,
+    },
+    /// First frame on every connection, pass `resume_token` as `?resume=` when reconnecting to keep your profile.
+    /// `epoch` is what to tag ops with until the next `Clear`. `capabilities` are what's on for
+    /// this connection in this room, see `Features::capabilities`, so clients can leave out the rest
+    Welcome { user_id: UserId, resume_token: String, role: Role, epoch: u64, capabilities: Vec<&'static str> },
+    /// Everyone in the room, including you, sent on join before the history, and to everyone again
+    /// when someone signs in with `SignIn`
+    Roster { users: Vec<Member> },
+    Joined(Member),
+    /// Someone's profile changed, sent to them as well since the server may have changed their name
+    Profile(Member),
+    /// Someone's last tab in the room closed, and why, see `DisconnectReason`. Without a reason for
+    /// someone who was on another node
+    Left {
+        user_id: UserId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DisconnectReason>,
+    },
+    /// Someone went idle or away, or came back, or their round trip changed
+    Presence {
+        user_id: UserId,
+        presence: Presence,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latency_ms: Option<u32>,
+    },
+    /// Someone raised (`raised_at` set) or lowered their hand
+    Hand {
+        user_id: UserId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        raised_at: Option<u64>,
+    },
+    Reaction { user_id: UserId, emoji: String },
+    /// Someone joined the voice channel or changed their state in it, or left it (`voice` missing)
+    VoiceState {
+        user_id: UserId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        voice: Option<VoiceState>,
+    },
+    /// Who you're following, sent when that changes and again if they reconnect under a new user id
+    Following { user_id: Option<UserId> },
+    /// The viewport and cursor of someone you follow
+    Viewport {
+        user_id: UserId,
+        #[serde(flatten)]
+        viewport: Viewport,
+    },
+    Cursor { user_id: UserId, x: f64, y: f64 },
+    /// Sent to the blocker's tabs when `Block` or `Unblock` took effect
+    Blocking { user_id: UserId, blocked: bool },
+    /// A private message, to its recipient and echoed to its sender. `sent_at` is unix milliseconds
+    Dm { from_user_id: UserId, to_user_id: UserId, text: String, sent_at: u64 },
+    /// Someone's edit to the room's shared notes, a Yjs v1 update in base64, to everyone else
+    NotesUpdate { user_id: UserId, update: String },
+    /// The room's shared notes as one Yjs v1 update in base64, all of them to a joiner, or what
+    /// they were missing in answer to `NotesSync`
+    Notes { update: String },
+    /// A `Signal` from `from_user_id`, to its recipient only
+    Signal { from_user_id: UserId, payload: Value },
+    /// The room is full, you're `position` in line (from 1) and get `Welcome` once you're in
+    Waitlisted { position: usize },
+    /// The room's info changed, sent to everyone in it
+    Room(RoomInfo),
+    /// The only frame before the socket closes when the room's `opens_at` is still to come
+    NotYetOpen { opens_at: u64, opens_in_secs: u64 },
+    /// The room lives on another cluster node, join it at `url` with the same query instead. The
+    /// socket closes with 4307 after this
+    Redirect { url: String },
+    /// A frame of a `type` the server doesn't know, from `user_id`, in a room that relays those
+    Relayed { user_id: UserId, frame: Value },
+    /// The board as of op `seq`: `ops` on it since the last clear and their `Room::checksum` as 16
+    /// hex digits. A client with another board sends `Resync`
+    Checksum { epoch: u64, seq: u64, ops: usize, hash: String },
+    /// Answers `Inspect`: an op on the board and who drew it
+    Stroke { stroke: Stroke, op: MessageType },
+    /// Answers `Resync`: clear your board, the server's follows as ops and then a `Checksum`
+    Resync { epoch: u64 },
+    /// Answers `TimeSync`, with when the server got it and sent this in unix milliseconds
+    TimeSync { client_time: f64, received_at: u64, sent_at: u64 },
+    /// Answers `Report`, it was kept for moderators as `report_id`
+    Reported { report_id: String },
+    /// The room's schedule does `action` at `at`, unix seconds, `in_secs` from now. Sent to
+    /// everyone in it `rooms.schedule_warning_secs` before
+    Scheduled { action: Action, at: u64, in_secs: u64 },
+    /// The room's owner, `user_id`, froze or unfroze it. While it's frozen every op, undo, notes
+    /// edit and relayed frame is refused as `room_frozen`. Joiners get it after the board
+    Frozen { frozen: bool, user_id: UserId },
+    /// From the server's admins, e.g. maintenance coming up, for clients to show as a banner until
+    /// `expires_at`, unix seconds. Joiners get the ones still up after the board
+    Notice { text: String, severity: Severity, expires_at: u64 },
+}
+
+impl ServerMessage {
+    /// The `type` it's sent as
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerMessage::Error { .. } => "Error",
+            ServerMessage::Welcome { .. } => "Welcome",
+            ServerMessage::Roster { .. } => "Roster",
+            ServerMessage::Joined(_) => "Joined",
+            ServerMessage::Profile(_) => "Profile",
+            ServerMessage::Left { .. } => "Left",
+            ServerMessage::Presence { .. } => "Presence",
+            ServerMessage::Hand { .. } => "Hand",
+            ServerMessage::Reaction { .. } => "Reaction",
+            ServerMessage::VoiceState { .. } => "VoiceState",
+            ServerMessage::Following { .. } => "Following",
+            ServerMessage::Viewport { .. } => "Viewport",
+            ServerMessage::Cursor { .. } => "Cursor",
+            ServerMessage::Blocking { .. } => "Blocking",
+            ServerMessage::Dm { .. } => "Dm",
+            ServerMessage::NotesUpdate { .. } => "NotesUpdate",
+            ServerMessage::Notes { .. } => "Notes",
+            ServerMessage::Signal { .. } => "Signal",
+            ServerMessage::Waitlisted { .. } => "Waitlisted",
+            ServerMessage::Room(_) => "Room",
+            ServerMessage::NotYetOpen { .. } => "NotYetOpen",
+            ServerMessage::Redirect { .. } => "Redirect",
+            ServerMessage::Relayed { .. } => "Relayed",
+            ServerMessage::Checksum { .. } => "Checksum",
+            ServerMessage::Stroke { .. } => "Stroke",
+            ServerMessage::Resync { .. } => "Resync",
+            ServerMessage::TimeSync { .. } => "TimeSync",
+            ServerMessage::Reported { .. } => "Reported",
+            ServerMessage::Scheduled { .. } => "Scheduled",
+            ServerMessage::Frozen { .. } => "Frozen",
+            ServerMessage::Notice { .. } => "Notice",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const OP_TYPES: &[&str] = &["Draw", "Clear", "Erase"];
+    const CONTROL_TYPES: &[&str] = &["Hello", "SetProfile", "RaiseHand", "LowerHand", "React", "Follow", "Unfollow", "Viewport", "Cursor", "Dm", "Signal", "VoiceState", "LeaveVoice", "NotesUpdate", "NotesSync", "Block", "Unblock", "UpdateRoom", "UndoClear", "Resync", "Inspect", "TimeSync", "Report", "SignIn", "Freeze", "Unfreeze", "SwitchRoom"];
+
+    // Any JSON, a few levels deep
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            ".*".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                prop::collection::hash_map(".*", inner, 0..8).prop_map(|fields| Value::Object(fields.into_iter().collect())),
+            ]
+        })
+    }
+
+    // Shaped like a real frame, with a known or made up `type` and anything as `data`
+    fn frame() -> impl Strategy<Value = Value> {
+        let kind = prop_oneof![
+            prop::sample::select(OP_TYPES).prop_map(str::to_string),
+            prop::sample::select(CONTROL_TYPES).prop_map(str::to_string),
+            ".*",
+        ];
+        (kind, prop::option::of(json_value())).prop_map(|(kind, data)| match data {
+            Some(data) => json!({ "type": kind, "data": data }),
+            None => json!({ "type": kind }),
+        })
+    }
+
+    fn coordinate() -> impl Strategy<Value = f64> {
+        -MAX_COORDINATE..=MAX_COORDINATE
+    }
+
+    // Up to 4 bytes a character, so within `MAX_COLOR_BYTES`
+    fn color() -> impl Strategy<Value = String> {
+        ".{0,16}"
+    }
+
+    fn op() -> impl Strategy<Value = MessageType> {
+        let point = || [coordinate(), coordinate()];
+        let brush_size = || 1..=MAX_BRUSH_SIZE;
+        prop_oneof![
+            (point(), point(), color(), brush_size()).prop_map(|(prev, cur, color, brush_size)| MessageType::Draw(DrawCommand { prev, cur, color, brush_size, composite: Composite::SourceOver })),
+            (point(), point(), brush_size()).prop_map(|(prev, cur, brush_size)| MessageType::Draw(EraseCommand { prev, cur, brush_size }.into())),
+            Just(MessageType::Clear),
+        ]
+    }
+
+    // Drawn ops with one value past its limit, JSON has no infinities or NaN
+    fn out_of_range_op() -> impl Strategy<Value = MessageType> {
+        let far = prop_oneof![MAX_COORDINATE + 1.0..f64::MAX, f64::MIN..-MAX_COORDINATE - 1.0];
+        prop_oneof![
+            (prop_oneof![Just(0), MAX_BRUSH_SIZE + 1..=u32::MAX], coordinate()).prop_map(|(brush_size, x)| MessageType::Draw(EraseCommand { prev: [x, 0.0], cur: [0.0, 0.0], brush_size }.into())),
+            (far, 1..=MAX_BRUSH_SIZE).prop_map(|(x, brush_size)| MessageType::Draw(DrawCommand { prev: [0.0, 0.0], cur: [0.0, x], color: "#000".to_string(), brush_size, composite: Composite::SourceOver })),
+            ".{65,100}".prop_map(|color| MessageType::Draw(DrawCommand { prev: [0.0, 0.0], cur: [1.0, 1.0], color, brush_size: 1, composite: Composite::SourceOver })),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn any_text_parses_or_is_rejected(text in ".*") {
+            let _ = ClientFrame::parse(&text);
+        }
+
+        // Bytes that happen to be UTF-8 still mustn't get further, binary frames go to `codec`
+        #[test]
+        fn any_bytes_parse_or_are_rejected(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            if let Ok(text) = std::str::from_utf8(&bytes) {
+                let _ = ClientFrame::parse(text);
+            }
+        }
+
+        #[test]
+        fn frames_are_what_their_type_says_or_rejected(frame in frame()) {
+            let kind = frame["type"].as_str().unwrap_or_default().to_string();
+            let known = OP_TYPES.contains(&kind.as_str()) || CONTROL_TYPES.contains(&kind.as_str());
+            let text = frame.to_string();
+            match ClientFrame::parse(&text) {
+                Ok(ClientFrame::Op { op, .. }) => prop_assert_eq!(op.name(), kind.to_lowercase()),
+                Ok(ClientFrame::Control(_)) => prop_assert!(CONTROL_TYPES.contains(&kind.as_str())),
+                Ok(ClientFrame::Unknown { kind: unknown, frame: parsed }) => {
+                    // Compared with the text reparsed, floats don't always survive a round trip
+                    prop_assert!(!known && unknown == kind && parsed == serde_json::from_str::<Value>(&text).unwrap());
+                }
+                // Well-formed JSON with a known type, so only ever refused for what's in it
+                Err(e) => prop_assert!(known && !matches!(e, Invalid::Malformed(_) | Invalid::Oversized { .. }), "{:?}", e),
+            }
+        }
+
+        #[test]
+        fn out_of_range_values_are_refused(op in out_of_range_op()) {
+            let text = serde_json::to_string(&op).unwrap();
+            prop_assert!(matches!(ClientFrame::parse(&text), Err(Invalid::OutOfRange(_))));
+        }
+
+        #[test]
+        fn missing_fields_are_named(op in op(), field in any::<prop::sample::Index>()) {
+            let mut frame = serde_json::to_value(&op).unwrap();
+            let Some(data) = frame.get_mut("data").and_then(Value::as_object_mut) else {
+                return Ok(());
+            };
+            // `composite` is optional, the rest are required
+            data.remove("composite");
+            let name = data.keys().nth(field.index(data.len())).unwrap().clone();
+            data.remove(&name);
+            match ClientFrame::parse(&frame.to_string()) {
+                Err(Invalid::MissingField(message)) => prop_assert!(message.contains(&name)),
+                parsed => prop_assert!(false, "{} parsed as {:?}", frame, parsed),
+            }
+        }
+
+        #[test]
+        fn unknown_fields_in_ops_are_refused(op in op(), name in "[a-z_]{1,16}") {
+            let mut frame = serde_json::to_value(&op).unwrap();
+            let Some(data) = frame.get_mut("data").and_then(Value::as_object_mut) else {
+                return Ok(());
+            };
+            if data.contains_key(&name) {
+                return Ok(());
+            }
+            data.insert(name.clone(), Value::from(1));
+            match ClientFrame::parse(&frame.to_string()) {
+                Err(Invalid::UnknownField(message)) => prop_assert!(message.contains(&name)),
+                parsed => prop_assert!(false, "{} parsed as {:?}", frame, parsed),
+            }
+        }
+
+        #[test]
+        fn valid_ops_are_accepted(op in op()) {
+            let text = serde_json::to_string(&op).unwrap();
+            match (ClientFrame::parse(&text), op) {
+                (Ok(ClientFrame::Op { op: MessageType::Draw(parsed), .. }), MessageType::Draw(sent)) => {
+                    prop_assert_eq!(parsed.color, sent.color);
+                    prop_assert_eq!(parsed.brush_size, sent.brush_size);
+                    prop_assert_eq!(parsed.composite, sent.composite);
+                }
+                (Ok(ClientFrame::Op { op: MessageType::Clear, .. }), MessageType::Clear) => {}
+                (parsed, sent) => prop_assert!(false, "{:?} parsed as {:?}", sent, parsed),
+            }
+        }
+
+        #[test]
+        fn old_erases_are_destination_out_draws(prev in [coordinate(), coordinate()], cur in [coordinate(), coordinate()], brush_size in 1..=MAX_BRUSH_SIZE) {
+            let text = json!({ "type": "Erase", "data": { "prev": prev, "cur": cur, "brush_size": brush_size } }).to_string();
+            let parsed = ClientFrame::parse(&text);
+            prop_assert!(matches!(&parsed, Ok(ClientFrame::Op { op: MessageType::Draw(draw), .. }) if draw.composite.erases() && draw.brush_size == brush_size), "{:?}", parsed);
+            let Ok(ClientFrame::Op { op, .. }) = parsed else { unreachable!() };
+            let sent = serde_json::to_value(&op).unwrap();
+            prop_assert_eq!(&sent["type"], "Draw");
+            prop_assert_eq!(&sent["data"]["composite"], "destination-out");
+        }
+
+        // What the server sends can be sent back as it is
+        #[test]
+        fn stamped_ops_keep_their_epoch(op in op(), epoch in any::<u64>()) {
+            let text = serde_json::to_string(&Stamped { op: &op, epoch, stroke_id: None }).unwrap();
+            match ClientFrame::parse(&text) {
+                Ok(ClientFrame::Op { op: parsed, epoch: parsed_epoch }) => {
+                    prop_assert_eq!(parsed.name(), op.name());
+                    prop_assert_eq!(parsed_epoch, Some(epoch));
+                }
+                parsed => prop_assert!(false, "{} parsed as {:?}", text, parsed),
+            }
+        }
+
+        #[test]
+        fn truncated_frames_are_rejected(op in op(), cut in any::<prop::sample::Index>()) {
+            let text = serde_json::to_string(&op).unwrap();
+            let boundaries: Vec<usize> = (0..text.len()).filter(|&i| text.is_char_boundary(i)).collect();
+            let truncated = &text[..*cut.get(&boundaries)];
+            prop_assert!(ClientFrame::parse(truncated).is_err());
+        }
+
+        // Turned away before serde_json's recursion limit, let alone a stack overflow
+        #[test]
+        fn deeply_nested_data_is_rejected(depth in 1usize..10_000, kind in prop::sample::select([OP_TYPES, CONTROL_TYPES].concat())) {
+            let text = format!(r#"{{"type":"{}","data":{}{}}}"#, kind, "[".repeat(depth), "]".repeat(depth));
+            match ClientFrame::parse(&text) {
+                Err(Invalid::Malformed(_)) => prop_assert!(depth >= MAX_DEPTH),
+                parsed => prop_assert!(depth < MAX_DEPTH && parsed.is_err()),
+            }
+        }
+
+        // Brackets and quotes in strings aren't nesting
+        #[test]
+        fn brackets_in_strings_are_not_counted(text in r#"[\[\]{}"\\a]{0,200}"#) {
+            let frame = json!({ "type": "Scribble", "data": { "text": text } });
+            let parsed = ClientFrame::parse(&frame.to_string());
+            prop_assert!(matches!(parsed, Ok(ClientFrame::Unknown { .. })), "{:?}", parsed);
+        }
+
+        #[test]
+        fn raw_control_characters_are_malformed(frame in frame(), at in any::<prop::sample::Index>(), c in (0u8..0x20).prop_filter("JSON whitespace", |c| !matches!(c, b'\t' | b'\n' | b'\r'))) {
+            let mut text = frame.to_string();
+            let boundaries: Vec<usize> = (1..text.len()).filter(|&i| text.is_char_boundary(i)).collect();
+            text.insert(*at.get(&boundaries), c as char);
+            prop_assert!(matches!(ClientFrame::parse(&text), Err(Invalid::Malformed(_))));
+        }
+    }
+}
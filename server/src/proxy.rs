@@ -0,0 +1,264 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+/// An address or `addr/prefix` network that's allowed to set `X-Forwarded-For`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network `{}`, expected an address or `addr/prefix`", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        match prefix.unwrap_or(max) {
+            prefix if prefix <= max => Ok(Cidr { addr, prefix }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The client behind a trusted proxy: walk `X-Forwarded-For` back from the proxy we're talking to,
+/// stopping at the first hop we don't trust. Untrusted peers can't override their own address
+pub fn forwarded_client(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    let mut client = peer;
+    let Some(forwarded_for) = forwarded_for else {
+        return client;
+    };
+    for hop in forwarded_for.rsplit(',') {
+        if !is_trusted(client) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Read a PROXY protocol v1 or v2 header from the start of `conn`, returning the client it describes.
+/// `None` means the proxy connected on its own behalf (`LOCAL`/`UNKNOWN`), so the peer address stands
+pub async fn read_header<R: AsyncRead + Unpin>(conn: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Shorter than either header, so we never read past it into the HTTP request
+    let mut start = [0u8; 8];
+    conn.read_exact(&mut start).await?;
+
+    if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(conn.read_u8().await?);
+        }
+        return parse_v1(&line[..line.len() - 2]);
+    }
+    if start[..] != V2_SIGNATURE[..8] {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    let mut rest = [0u8; 8];
+    conn.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] || rest[4] >> 4 != 2 {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    let mut body = vec![0u8; len];
+    conn.read_exact(&mut body).await?;
+
+    // LOCAL commands are health checks from the proxy itself
+    if rest[4] & 0x0f == 0 {
+        return Ok(None);
+    }
+    let too_short = || Err(invalid("PROXY v2 header too short for its addresses"));
+    match rest[5] >> 4 {
+        1 if len < 12 => too_short(),
+        2 if len < 36 => too_short(),
+        1 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))))
+        }
+        2 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]]))))
+        }
+        // AF_UNIX or unspecified, there's no address to report
+        _ => Ok(None),
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src.parse::<IpAddr>().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            let port = src_port.parse::<u16>().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+
+    // A v2 header with `command` (0 LOCAL, 1 PROXY), `family` (high nibble) and `body`, its length
+    // field saying `len`
+    fn v2(command: u8, family: u8, len: u16, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family, len.to_be_bytes()[0], len.to_be_bytes()[1]]);
+        header.extend(body);
+        header
+    }
+
+    // The client a header names, and what's left for HTTP after it
+    async fn read(header: &[u8]) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let bytes = [header, REQUEST].concat();
+        let mut conn = &bytes[..];
+        let client = read_header(&mut conn).await?;
+        Ok((client, conn.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn v1_headers_name_the_client() {
+        let (client, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\n").await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, REQUEST);
+        let (client, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 8080\r\n").await.unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:51234".parse().unwrap()));
+        let (client, rest) = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!((client, rest), (None, REQUEST.to_vec()));
+
+        for bad in [&b"PROXY TCP4 nowhere 10.0.0.1 51234 8080\r\n"[..], b"PROXY TCP4 203.0.113.7 10.0.0.1 port 8080\r\n", b"PROXY SCTP\r\n"] {
+            assert_eq!(read(bad).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_headers_name_the_client() {
+        let mut tcp4 = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        tcp4.extend(51234u16.to_be_bytes());
+        tcp4.extend(8080u16.to_be_bytes());
+        let (client, rest) = read(&v2(1, 0x11, 12, &tcp4)).await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, REQUEST);
+
+        let mut tcp6 = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        tcp6.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6.extend(51234u16.to_be_bytes());
+        tcp6.extend(8080u16.to_be_bytes());
+        let (client, rest) = read(&v2(1, 0x21, 36, &tcp6)).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:51234".parse().unwrap()));
+        assert_eq!(rest, REQUEST);
+
+        // UNSPEC, and LOCAL health checks from the proxy itself, even with an address, name nobody
+        let (client, rest) = read(&v2(1, 0x00, 0, &[])).await.unwrap();
+        assert_eq!((client, rest), (None, REQUEST.to_vec()));
+        let (client, rest) = read(&v2(0, 0x11, 12, &tcp4)).await.unwrap();
+        assert_eq!((client, rest), (None, REQUEST.to_vec()));
+        // TLVs after the addresses are skipped
+        let with_tlv = [&tcp4[..], &[0x04, 0x00, 0x01, 0xff]].concat();
+        let (client, rest) = read(&v2(1, 0x11, 16, &with_tlv)).await.unwrap();
+        assert_eq!((client, rest), (Some("203.0.113.7:51234".parse().unwrap()), REQUEST.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_refused() {
+        for truncated in [&b"PROXY TCP4 203.0.113.7"[..], b"PROX", &V2_SIGNATURE[..10]] {
+            let mut conn = truncated;
+            assert_eq!(read_header(&mut conn).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{:?}", truncated);
+        }
+        let header = v2(1, 0x11, 12, &[203, 0, 113, 7]);
+        let mut conn = &header[..];
+        assert_eq!(read_header(&mut conn).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn oversized_lengths_are_refused() {
+        // A v1 line runs to 107 bytes at most
+        let long = format!("PROXY TCP6 {} {} 51234 8080\r\n", "f".repeat(60), "f".repeat(60));
+        assert_eq!(read(long.as_bytes()).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // A v2 length past what was sent doesn't read on into nothing
+        let header = v2(1, 0x11, u16::MAX, &[0; 12]);
+        let mut conn = &header[..];
+        assert_eq!(read_header(&mut conn).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // And one too short for its family's addresses would have them read from the request
+        assert_eq!(read(&v2(1, 0x11, 4, &[203, 0, 113, 7])).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(&v2(1, 0x21, 12, &[0; 12])).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let trusted = ["10.0.0.0/8".parse::<Cidr>().unwrap()];
+        let proxy: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(forwarded_client(proxy, Some("203.0.113.7"), &trusted), "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(forwarded_client("192.0.2.1".parse().unwrap(), Some("203.0.113.7"), &trusted), "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(forwarded_client(proxy, Some("198.51.100.1, 203.0.113.7, 10.9.9.9"), &trusted), "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::PublicIdsConfig;
+use crate::hub::DEFAULT_ROOM;
+use crate::ids::random_token;
+
+// Bytes of the signature in front of a signed id, guessing one takes 2^64 tries
+const TAG_BYTES: usize = 8;
+
+/// How rooms are named in the URLs handed out, `/room/<id>`, join links and the lobby, and turned
+/// back into the id they have in the hub and storage
+pub trait PublicIds: Send + Sync {
+    fn encode(&self, room: &str) -> String;
+
+    /// The room behind a public id, None if it isn't one
+    fn decode(&self, public: &str) -> Option<String>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    /// Rooms go by their own ids
+    #[default]
+    Plain,
+    /// Rooms go by ids signed with `public_ids.secret`, see `Signed`
+    Signed,
+}
+
+/// The `PublicIds` for `public_ids` in the config
+pub fn from_config(config: &PublicIdsConfig) -> Arc<dyn PublicIds> {
+    match config.scheme {
+        Scheme::Plain => Arc::new(Plain),
+        Scheme::Signed => Arc::new(Signed::new(&config.secret)),
+    }
+}
+
+/// What `room` goes by in URLs. The default room, which `/room` joins, goes by its own id
+pub fn public_id(ids: &dyn PublicIds, room: &str) -> String {
+    match room {
+        DEFAULT_ROOM => DEFAULT_ROOM.to_string(),
+        _ => ids.encode(room),
+    }
+}
+
+pub struct Plain;
+
+impl PublicIds for Plain {
+    fn encode(&self, room: &str) -> String {
+        room.to_string()
+    }
+
+    fn decode(&self, public: &str) -> Option<String> {
+        Some(public.to_string())
+    }
+}
+
+/// The room id encrypted under a key only the server has, in the URL-safe base64 alphabet that
+/// `valid_room_id` takes. A signature of the room id goes first and seeds the keystream, so the
+/// same room always gets the same public id, nothing needs to be kept to map it back, and ids
+/// can't be made up or counted through: anything the server didn't sign decodes to nothing
+pub struct Signed {
+    key: Vec<u8>,
+}
+
+impl Signed {
+    /// Empty uses a key picked at startup, public ids then change on every restart
+    pub fn new(secret: &str) -> Self {
+        let key = if secret.is_empty() { random_token() } else { secret.to_string() };
+        Signed { key: key.into_bytes() }
+    }
+
+    fn mac(&self, label: &[u8], parts: &[&[u8]]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(label);
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+
+    // XORs `bytes` with a keystream drawn from `tag`, which both encrypts and decrypts
+    fn apply_keystream(&self, tag: &[u8], bytes: &mut [u8]) {
+        for (block, chunk) in bytes.chunks_mut(32).enumerate() {
+            let stream = self.mac(b"stream\n", &[tag, &(block as u64).to_be_bytes()]).finalize().into_bytes();
+            for (byte, key) in chunk.iter_mut().zip(stream) {
+                *byte ^= key;
+            }
+        }
+    }
+}
+
+impl PublicIds for Signed {
+    fn encode(&self, room: &str) -> String {
+        let tag = self.mac(b"tag\n", &[room.as_bytes()]).finalize().into_bytes();
+        let mut bytes = tag[..TAG_BYTES].to_vec();
+        let mut sealed = room.as_bytes().to_vec();
+        self.apply_keystream(&tag[..TAG_BYTES], &mut sealed);
+        bytes.extend(sealed);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn decode(&self, public: &str) -> Option<String> {
+        let bytes = URL_SAFE_NO_PAD.decode(public).ok()?;
+        if bytes.len() <= TAG_BYTES {
+            return None;
+        }
+        let (tag, sealed) = bytes.split_at(TAG_BYTES);
+        let mut room = sealed.to_vec();
+        self.apply_keystream(tag, &mut room);
+        // Compared in constant time
+        self.mac(b"tag\n", &[&room]).verify_truncated_left(tag).ok()?;
+        String::from_utf8(room).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::valid_room_id;
+
+    #[test]
+    fn signed_ids_map_back_and_cant_be_made_up() {
+        let ids = Signed::new("secret");
+        let public = ids.encode("class-7b");
+        assert!(valid_room_id(&public) && !public.contains("class"), "{}", public);
+        assert_eq!(ids.encode("class-7b"), public);
+        assert_eq!(ids.decode(&public).as_deref(), Some("class-7b"));
+
+        assert_eq!(Signed::new("other").decode(&public), None);
+        let mut tampered = public.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert_eq!(ids.decode(std::str::from_utf8(&tampered).unwrap()), None);
+        assert_eq!(ids.decode("class-7b"), None);
+    }
+}
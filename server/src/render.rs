@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use tiny_skia::{Color, LineCap, Paint, PathBuilder, Pixmap, Stroke, Transform};
+
+use crate::protocol::MessageType;
+use crate::room;
+
+// The size of a <canvas> without one set, for boards with nothing on them
+const EMPTY_SIZE: (u32, u32) = (300, 150);
+const REPLAY_FPS: u16 = 10;
+// How long a replay shows the finished board before looping, in hundredths of a second
+const REPLAY_HOLD_CS: u16 = 200;
+
+/// The part of the board an image shows, `width` x `height` board pixels from `x`, `y` at its top left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    // Board to image pixels
+    fn transform(&self) -> Transform {
+        Transform::from_translate(-self.x as f32, -self.y as f32)
+    }
+
+    // Whether any of a segment this thick falls inside, strokes that don't are left out rather than
+    // drawn off the image
+    fn touches(&self, prev: [f64; 2], cur: [f64; 2], brush_size: u32) -> bool {
+        let radius = brush_size as f64 / 2.0;
+        prev[0].max(cur[0]) + radius >= self.x
+            && prev[0].min(cur[0]) - radius <= self.x + self.width as f64
+            && prev[1].max(cur[1]) + radius >= self.y
+            && prev[1].min(cur[1]) - radius <= self.y + self.height as f64
+    }
+}
+
+/// The smallest size from the origin that fits every stroke, in board pixels
+pub fn extent(history: &[MessageType]) -> (u32, u32) {
+    let mut size: Option<(f64, f64)> = None;
+    for op in history {
+        let (prev, cur, brush_size) = match op {
+            MessageType::Draw(draw) => (draw.prev, draw.cur, draw.brush_size),
+            MessageType::Clear => continue,
+        };
+        let radius = brush_size as f64 / 2.0;
+        let (width, height) = size.get_or_insert((0.0, 0.0));
+        *width = width.max(prev[0].max(cur[0]) + radius);
+        *height = height.max(prev[1].max(cur[1]) + radius);
+    }
+    match size {
+        Some((width, height)) => (width.ceil().max(1.0) as u32, height.ceil().max(1.0) as u32),
+        None => EMPTY_SIZE,
+    }
+}
+
+/// Rasterize a board the way the web client draws it: round-capped segments on white, erases
+/// painted white. Anything outside `region` is cut off
+pub fn png(history: &[MessageType], region: Region) -> Result<Vec<u8>, String> {
+    let mut pixmap = Pixmap::new(region.width, region.height).ok_or("invalid image size")?;
+    pixmap.fill(Color::WHITE);
+    for op in history {
+        draw(&mut pixmap, op, Some(&region), region.transform());
+    }
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+/// The whole board scaled down to fit in `max_side` x `max_side`, boards smaller than that aren't scaled up
+pub fn thumbnail(history: &[MessageType], max_side: u32) -> Result<Vec<u8>, String> {
+    let (width, height) = extent(history);
+    let scale = (max_side as f32 / width.max(height) as f32).min(1.0);
+    let scaled = |side: u32| ((side as f32 * scale).ceil() as u32).max(1);
+    let mut pixmap = Pixmap::new(scaled(width), scaled(height)).ok_or("invalid image size")?;
+    pixmap.fill(Color::WHITE);
+    for op in history {
+        draw(&mut pixmap, op, None, Transform::from_scale(scale, scale));
+    }
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+/// The latest thumbnail of each resident room, see `Hub::render_thumbnails`
+#[derive(Default)]
+pub struct Thumbnails {
+    by_room: Mutex<HashMap<String, Thumbnail>>,
+}
+
+struct Thumbnail {
+    // The room's seq and the size it was rendered at
+    seq: u64,
+    size: u32,
+    png: Vec<u8>,
+}
+
+impl Thumbnails {
+    pub fn get(&self, room_id: &str) -> Option<Vec<u8>> {
+        self.by_room.lock().unwrap().get(room_id).map(|t| t.png.clone())
+    }
+
+    pub fn has(&self, room_id: &str) -> bool {
+        self.by_room.lock().unwrap().contains_key(room_id)
+    }
+
+    /// Whether the room's thumbnail was rendered at this seq and size
+    pub fn is_current(&self, room_id: &str, seq: u64, size: u32) -> bool {
+        self.by_room.lock().unwrap().get(room_id).is_some_and(|t| t.seq == seq && t.size == size)
+    }
+
+    pub fn insert(&self, room_id: String, seq: u64, size: u32, png: Vec<u8>) {
+        self.by_room.lock().unwrap().insert(room_id, Thumbnail { seq, size, png });
+    }
+
+    /// Drop the thumbnails of rooms that aren't resident anymore
+    pub fn retain(&self, resident: &[String]) {
+        self.by_room.lock().unwrap().retain(|id, _| resident.contains(id));
+    }
+}
+
+/// A looping GIF of the board being drawn op by op, spread evenly over `duration_secs` and then
+/// held on the finished board for a moment. Only what's in `region` is shown
+pub fn replay_gif(history: &[MessageType], region: Region, duration_secs: f64) -> Result<Vec<u8>, String> {
+    let mut pixmap = Pixmap::new(region.width, region.height).ok_or("invalid image size")?;
+    pixmap.fill(Color::WHITE);
+    let (gif_width, gif_height) = (u16::try_from(region.width).map_err(|e| e.to_string())?, u16::try_from(region.height).map_err(|e| e.to_string())?);
+
+    let mut out = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut out, gif_width, gif_height, &[]).map_err(|e| e.to_string())?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+
+    let frames = ((duration_secs * REPLAY_FPS as f64).ceil() as usize).clamp(1, history.len().max(1));
+    let mut drawn = 0;
+    for frame in 1..=frames {
+        let upto = history.len() * frame / frames;
+        for op in &history[drawn..upto] {
+            draw(&mut pixmap, op, Some(&region), region.transform());
+        }
+        drawn = upto;
+
+        // The background is opaque, so the premultiplied pixels are plain RGBA
+        let mut pixels = pixmap.data().to_vec();
+        let mut gif_frame = gif::Frame::from_rgba_speed(gif_width, gif_height, &mut pixels, 10);
+        gif_frame.delay = if frame == frames { REPLAY_HOLD_CS } else { 100 / REPLAY_FPS };
+        encoder.write_frame(&gif_frame).map_err(|e| e.to_string())?;
+    }
+    drop(encoder);
+    Ok(out)
+}
+
+// Segments outside `region`, when there is one, are skipped
+fn draw(pixmap: &mut Pixmap, op: &MessageType, region: Option<&Region>, transform: Transform) {
+    let (prev, cur, brush_size, color) = match op {
+        MessageType::Draw(draw) if draw.composite.erases() => (draw.prev, draw.cur, draw.brush_size, Color::WHITE),
+        MessageType::Draw(draw) => {
+            let c = parse_color(&draw.color);
+            (draw.prev, draw.cur, draw.brush_size, Color::from_rgba(c.r, c.g, c.b, c.a).unwrap_or(Color::BLACK))
+        }
+        MessageType::Clear => {
+            pixmap.fill(Color::WHITE);
+            return;
+        }
+    };
+    if region.is_some_and(|region| !region.touches(prev, cur, brush_size)) {
+        return;
+    }
+    let mut path = PathBuilder::new();
+    path.move_to(prev[0] as f32, prev[1] as f32);
+    path.line_to(cur[0] as f32, cur[1] as f32);
+    // A segment with no length has nothing to stroke
+    let Some(path) = path.finish() else {
+        return;
+    };
+
+    let mut paint = Paint::default();
+    paint.set_color(color);
+    paint.anti_alias = true;
+    let stroke = Stroke { width: brush_size as f32, line_cap: LineCap::Round, ..Stroke::default() };
+    pixmap.stroke_path(&path, &paint, &stroke, transform, None);
+}
+
+/// The same board as SVG, one path per run of joined segments in the same color and brush size
+/// by the same person. Erases are white paths, so the document stays a flat list of strokes. The
+/// viewport is `region`, and segments wholly outside it are left out. `title` and `description`,
+/// the room's name and description, go in `<title>` and `<desc>`. `strokes`, one for each op, say
+/// who drew each path in `data-` attributes, as its first segment was drawn
+pub fn svg(history: &[MessageType], strokes: &[room::Stroke], region: Region, title: Option<&str>, description: Option<&str>) -> String {
+    let mut paths: Vec<(String, u32, String, Option<&room::Stroke>)> = Vec::new();
+    let mut last: Option<[f64; 2]> = None;
+    for (index, op) in history.iter().enumerate() {
+        let stroke = strokes.get(index);
+        let (prev, cur, brush_size, color) = match op {
+            MessageType::Draw(draw) if draw.composite.erases() => (draw.prev, draw.cur, draw.brush_size, "#ffffff".to_string()),
+            MessageType::Draw(draw) => (draw.prev, draw.cur, draw.brush_size, parse_color(&draw.color).to_css_hex().to_string()),
+            MessageType::Clear => {
+                paths.clear();
+                last = None;
+                continue;
+            }
+        };
+        if !region.touches(prev, cur, brush_size) {
+            last = None;
+            continue;
+        }
+        let author = |s: Option<&room::Stroke>| s.and_then(|s| s.user_id);
+        match paths.last_mut() {
+            // Clients send a stroke as segments that each start where the last one ended
+            Some((c, size, data, first)) if *c == color && *size == brush_size && last == Some(prev) && author(*first) == author(stroke) => {
+                let _ = write!(data, " L{} {}", cur[0], cur[1]);
+            }
+            _ => paths.push((color, brush_size, format!("M{} {} L{} {}", prev[0], prev[1], cur[0], cur[1]), stroke)),
+        }
+        last = Some(cur);
+    }
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"{x} {y} {w} {h}\">\n",
+        x = region.x,
+        y = region.y,
+        w = region.width,
+        h = region.height
+    );
+    if let Some(title) = title {
+        let _ = writeln!(svg, "<title>{}</title>", escape_xml(title));
+    }
+    if let Some(description) = description {
+        let _ = writeln!(svg, "<desc>{}</desc>", escape_xml(description));
+    }
+    let _ = writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>", region.x, region.y);
+    for (color, brush_size, data, stroke) in paths {
+        let _ = writeln!(
+            svg,
+            "<path d=\"{}\" stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"round\" stroke-linejoin=\"round\" fill=\"none\"{}/>",
+            data,
+            color,
+            brush_size,
+            stroke.map(attribution).unwrap_or_default()
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// A path's `data-` attributes, for what's known of who drew it
+fn attribution(stroke: &room::Stroke) -> String {
+    let mut attributes = format!(" data-stroke-id=\"{}\"", stroke.stroke_id);
+    if let Some(user_id) = stroke.user_id {
+        let _ = write!(attributes, " data-user-id=\"{}\"", user_id);
+    }
+    if let Some(name) = &stroke.name {
+        let _ = write!(attributes, " data-name=\"{}\"", escape_xml(name));
+    }
+    if let Some(account) = &stroke.account {
+        let _ = write!(attributes, " data-account=\"{}\"", escape_xml(account));
+    }
+    if let Some(drawn_at) = stroke.drawn_at {
+        let _ = write!(attributes, " data-drawn-at=\"{}\"", drawn_at);
+    }
+    if let Some(device) = &stroke.device {
+        let _ = write!(attributes, " data-device=\"{}\"", escape_xml(device));
+    }
+    attributes
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Any CSS color a browser would take, strokes with one it wouldn't are drawn black. Colors are
+// only ever written back out normalized, never as the client sent them
+fn parse_color(color: &str) -> csscolorparser::Color {
+    csscolorparser::parse(color).unwrap_or(csscolorparser::Color::new(0.0, 0.0, 0.0, 1.0))
+}
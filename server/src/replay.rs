@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::config::ConfigHandle;
+use crate::hub::Hub;
+use crate::ids::UserId;
+use crate::protocol::MessageType;
+use crate::sealing::Keyring;
+use crate::socket;
+
+/// A line of the op log, see `export.file`, or an op exported to NATS. Its correlation id belonged
+/// to the recording server
+#[derive(Deserialize)]
+pub struct Recorded {
+    pub room: String,
+    pub user_id: UserId,
+    pub seq: u64,
+    pub timestamp: u64,
+    pub op: MessageType,
+}
+
+/// Apply an op log to the rooms it was recorded in, in the order it was written, which is the order
+/// each room applied them, so every board ends up as recorded. The gaps between ops are waited out
+/// divided by `speed`, not at all at 0. Ops keep their user ids and are relayed like any other.
+/// Sealed lines need the `keyring` they were sealed with
+pub async fn replay(hub: &Hub, path: &Path, speed: f64, keyring: Option<&Keyring>, config: &ConfigHandle) -> io::Result<()> {
+    let mut lines = BufReader::new(tokio::fs::File::open(path).await?).lines();
+    // Recorded minus replayed seq for each room, this only changes where the log is missing ops
+    let mut offsets: BTreeMap<String, i128> = BTreeMap::new();
+    let mut last_timestamp = None;
+    let mut replayed = 0;
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = match keyring {
+            Some(keyring) => keyring.open_line(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_number, e)))?,
+            None => line,
+        };
+        let record: Recorded = serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_number, e)))?;
+        if let Some(last_timestamp) = last_timestamp.filter(|_| speed > 0.0) {
+            let gap = record.timestamp.saturating_sub(last_timestamp);
+            tokio::time::sleep(Duration::from_secs_f64(gap as f64 / 1000.0 / speed)).await;
+        }
+        last_timestamp = Some(record.timestamp);
+
+        let room = hub.open(&record.room).await?;
+        let history_limit = config.borrow().rooms.history_limit;
+        let mut room = room.write().await;
+        if !offsets.contains_key(&record.room) && room.seq > 0 {
+            log::warn!("Room {} already had {} ops before the replay, its board won't match the recording", record.room, room.seq);
+        }
+        let seq = socket::draw_as(hub, &mut room, record.user_id, record.op, history_limit).await?;
+        let offset = i128::from(record.seq) - i128::from(seq);
+        match offsets.insert(record.room.clone(), offset) {
+            None if offset != 0 => log::warn!("The recording of room {} starts at seq {}, the board before it is missing", record.room, record.seq),
+            Some(previous) if previous != offset => log::warn!("The recording of room {} is missing ops before seq {}", record.room, record.seq),
+            _ => {}
+        }
+        replayed += 1;
+    }
+
+    log::info!("Replayed {} ops into {} rooms", replayed, offsets.len());
+    for id in offsets.keys() {
+        if let Some(room) = hub.get(id).await {
+            let room = room.read().await;
+            log::info!("Room {} is at seq {} with {} ops on its board", id, room.seq, room.history.len());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use warp::http::Method;
+use warp::{Filter, Rejection};
+
+use crate::config::ConfigHandle;
+use crate::export;
+use crate::hub::Hub;
+use crate::replay::Recorded;
+use crate::socket;
+
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Whether this server follows a primary, see `replica.nats_url`
+pub fn is_replica(config: &ConfigHandle) -> bool {
+    config.borrow().replica.nats_url.is_some()
+}
+
+#[derive(Debug)]
+pub struct ReadOnly;
+
+impl warp::reject::Reject for ReadOnly {}
+
+/// In front of every route on a replica: reads go through, anything that would change a room or
+/// join one interactively is refused with `ReadOnly`, a 503. Rooms only change by following the primary
+pub fn read_only(config: ConfigHandle) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and_then(move |method: Method, path: warp::path::FullPath| {
+            let refused = is_replica(&config) && (!matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) || interactive(path.as_str()));
+            async move {
+                match refused {
+                    true => Err(warp::reject::custom(ReadOnly)),
+                    false => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// The WebSocket rooms and the transports standing in for them, which are all GETs to start with
+fn interactive(path: &str) -> bool {
+    path == "/room" || path.starts_with("/room/") || path.starts_with("/t/") || path.starts_with("/socket.io")
+}
+
+/// Subscribe to the primary's op export and apply every op to the same room here, reconnecting when
+/// the session drops. Ops published while it's down are missed, NATS doesn't keep them
+pub fn spawn(hub: Arc<Hub>, config: ConfigHandle) {
+    let (url, prefix) = {
+        let replica = &config.borrow().replica;
+        let Some(url) = replica.nats_url.clone() else {
+            return;
+        };
+        (url, replica.subject_prefix.clone())
+    };
+    tokio::spawn(async move {
+        // The primary's last seq for each room, to notice what went missing
+        let mut seqs = HashMap::new();
+        loop {
+            if let Err(e) = follow(&url, &prefix, &hub, &config, &mut seqs).await {
+                log::warn!("Lost the primary's op stream, reconnecting in {:?}: {}", RECONNECT_BACKOFF, e);
+            }
+            tokio::time::sleep(RECONNECT_BACKOFF).await;
+        }
+    });
+}
+
+/// One NATS session: SUB to every room under `prefix`, then a MSG per op, answering PING
+async fn follow(url: &str, prefix: &str, hub: &Hub, config: &ConfigHandle, seqs: &mut HashMap<String, u64>) -> io::Result<()> {
+    let (mut lines, mut writer, addr) = export::connect_nats(url).await?;
+    writer.write_all(format!("SUB {}.> 1\r\n", prefix).as_bytes()).await?;
+    log::info!("Following the primary's ops on {}.> at NATS {}", prefix, addr);
+
+    while let Some(line) = lines.next_line().await? {
+        if line.starts_with("PING") {
+            writer.write_all(b"PONG\r\n").await?;
+            continue;
+        }
+        if line.starts_with("-ERR") {
+            log::warn!("NATS error: {}", line);
+            continue;
+        }
+        if !line.starts_with("MSG ") {
+            continue;
+        }
+        // Ops are exported as one line of JSON, so the payload is the next line
+        let Some(payload) = lines.next_line().await? else {
+            break;
+        };
+        let record: Recorded = match serde_json::from_str(&payload) {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn!("Skipping an op from the primary that doesn't parse: {}", e);
+                continue;
+            }
+        };
+        apply(hub, config, record, seqs).await?;
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "NATS server closed the connection"))
+}
+
+async fn apply(hub: &Hub, config: &ConfigHandle, record: Recorded, seqs: &mut HashMap<String, u64>) -> io::Result<()> {
+    if let Some(last) = seqs.insert(record.room.clone(), record.seq).filter(|last| record.seq != last + 1) {
+        log::warn!("Missed {} ops of room {} from the primary, its board here won't match", record.seq.saturating_sub(last + 1), record.room);
+    }
+    let room = hub.open(&record.room).await?;
+    let history_limit = config.borrow().rooms.history_limit;
+    let mut room = room.write().await;
+    socket::draw_as(hub, &mut room, record.user_id, record.op, history_limit).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_rooms_themselves_are_interactive() {
+        assert!(interactive("/room"));
+        assert!(interactive("/room/sketch"));
+        assert!(interactive("/t/acme/room/sketch"));
+        assert!(interactive("/socket.io/"));
+        assert!(!interactive("/api/rooms/sketch/render.png"));
+        assert!(!interactive("/roomy"));
+    }
+}
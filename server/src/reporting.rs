@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::config::ReportingConfig;
+
+// Reports beyond this many in flight are dropped rather than blocking the caller
+const QUEUE_SIZE: usize = 64;
+
+static REPORTS: OnceLock<mpsc::Sender<Report>> = OnceLock::new();
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub kind: &'static str,
+    pub message: String,
+    pub context: BTreeMap<&'static str, String>,
+    pub timestamp: u64,
+}
+
+enum Target {
+    Sentry { store_url: String, auth: String },
+    Webhook(String),
+}
+
+/// Start the background sender and hook panics, does nothing unless reporting is enabled
+pub fn init(config: &ReportingConfig) {
+    if !config.enabled {
+        return;
+    }
+
+    let mut targets = Vec::new();
+    if let Some(dsn) = &config.sentry_dsn {
+        match parse_dsn(dsn) {
+            Some(target) => targets.push(target),
+            None => log::error!("Invalid Sentry DSN, Sentry reporting disabled"),
+        }
+    }
+    if let Some(url) = &config.webhook_url {
+        targets.push(Target::Webhook(url.clone()));
+    }
+    if targets.is_empty() {
+        log::warn!("Error reporting enabled but no sentry_dsn or webhook_url configured");
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    if REPORTS.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(send_reports(rx, targets, config.environment.clone()));
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => info.payload().downcast_ref::<String>().cloned().unwrap_or_else(|| "panic".to_string()),
+        };
+        let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+        let thread = std::thread::current().name().unwrap_or("unnamed").to_string();
+        capture("panic", message, [("location", location), ("thread", thread)]);
+        default_hook(info);
+    }));
+}
+
+/// Queue a report if reporting is enabled, never blocks
+pub fn capture<const N: usize>(kind: &'static str, message: impl Into<String>, context: [(&'static str, String); N]) {
+    let Some(tx) = REPORTS.get() else {
+        return;
+    };
+    let report = Report {
+        kind,
+        message: message.into(),
+        context: context.into_iter().collect(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+    };
+    if tx.try_send(report).is_err() {
+        log::debug!("Error report dropped, queue full");
+    }
+}
+
+fn parse_dsn(dsn: &str) -> Option<Target> {
+    // https://<key>@<host>/<project>
+    let url = reqwest::Url::parse(dsn).ok()?;
+    let key = url.username();
+    let project = url.path().trim_matches('/');
+    if key.is_empty() || project.is_empty() {
+        return None;
+    }
+    let host = url.host_str()?;
+    let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+    Some(Target::Sentry {
+        store_url: format!("{}://{}{}/api/{}/store/", url.scheme(), host, port, project),
+        auth: format!("Sentry sentry_version=7, sentry_client=ws-demo/{}, sentry_key={}", env!("CARGO_PKG_VERSION"), key),
+    })
+}
+
+async fn send_reports(mut rx: mpsc::Receiver<Report>, targets: Vec<Target>, environment: String) {
+    let client = reqwest::Client::new();
+
+    while let Some(report) = rx.recv().await {
+        for target in &targets {
+            let request = match target {
+                Target::Sentry { store_url, auth } => client
+                    .post(store_url)
+                    .header("X-Sentry-Auth", auth)
+                    .json(&json!({
+                        "timestamp": report.timestamp,
+                        "level": "error",
+                        "platform": "other",
+                        "logger": report.kind,
+                        "environment": environment,
+                        "message": { "formatted": report.message },
+                        "tags": report.context,
+                    })),
+                Target::Webhook(url) => client.post(url).json(&json!({
+                    "environment": environment,
+                    "report": report,
+                })),
+            };
+
+            // Logged at warn, not reported, so a broken endpoint can't feed itself
+            match request.send().await {
+                Ok(res) if !res.status().is_success() => log::warn!("Error report rejected: {}", res.status()),
+                Err(e) => log::warn!("Could not send error report: {}", e),
+                Ok(_) => {}
+            }
+        }
+    }
+}
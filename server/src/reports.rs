@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::config::ConfigHandle;
+use crate::events::{now_millis, ServerEvent};
+use crate::hub::{valid_room_id, Hub};
+use crate::ids::{random_token, UserId};
+use crate::openapi::ApiError;
+use crate::protocol::MessageType;
+use crate::room::{Room, Stroke};
+
+// Ops at the end of the board kept with a report, enough to see what was going on
+const CONTEXT_OPS: usize = 50;
+
+const MAX_REASON_CHARS: usize = 500;
+
+// A room id, a user id and a reason
+const MAX_REPORT_BYTES: u64 = 4 * 1024;
+
+/// Someone reported for abuse in a room, with the end of the board as it was then, kept by the
+/// storage backend until a moderator dismisses it
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Report {
+    pub id: String,
+    pub room: String,
+    /// Who was reported, as everyone in the room saw them
+    pub user_id: UserId,
+    /// Their name at the time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The account they were signed in with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Who reported them, None when it came over the REST API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_by: Option<UserId>,
+    /// The reporter's account, if they were signed in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_by_account: Option<String>,
+    pub reason: String,
+    /// Unix milliseconds
+    pub reported_at: u64,
+    /// The last ops on the board and who drew them, oldest first
+    pub context: Vec<ReportedOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReportedOp {
+    pub stroke: Stroke,
+    pub op: MessageType,
+}
+
+/// Why a report was refused, as an error code and message
+pub struct Refused {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// A report of `user_id` in `room` by `reported_by`, if it came from someone in the room. The
+/// reported user has to be in the room, on another node in it, or have an op on its board
+pub fn file(room: &Room, user_id: UserId, reason: &str, reported_by: Option<UserId>, reported_by_account: Option<String>) -> Result<Report, Refused> {
+    let reason = normalize_reason(reason).map_err(|message| Refused { code: "invalid_report", message })?;
+    let known = match room.tabs(user_id).next() {
+        Some(peer) => Some((peer.profile.name.clone(), peer.account.clone())),
+        None => match room.remote_members.get(&user_id) {
+            Some(member) => Some((member.profile.name.clone(), None)),
+            None => room.strokes.iter().rev().find(|stroke| stroke.user_id == Some(user_id)).map(|stroke| (stroke.name.clone(), stroke.account.clone())),
+        },
+    };
+    let Some((name, account)) = known.filter(|_| reported_by != Some(user_id)) else {
+        return Err(Refused { code: "unknown_user", message: format!("no one else in the room is user {}", user_id) });
+    };
+    let from = room.history.len().saturating_sub(CONTEXT_OPS);
+    let context = room.history[from..].iter().zip(&room.strokes[from..]).map(|(op, stroke)| ReportedOp { stroke: stroke.clone(), op: op.clone() }).collect();
+    Ok(Report {
+        id: random_token(),
+        room: room.id.clone(),
+        user_id,
+        name,
+        account,
+        reported_by,
+        reported_by_account,
+        reason,
+        reported_at: now_millis(),
+        context,
+    })
+}
+
+/// Keep a report and tell webhooks and `/admin/events` about it
+pub async fn submit(hub: &Hub, report: &Report) -> std::io::Result<()> {
+    hub.save_report(report).await?;
+    log::info!("User {} was reported in room {}, report {}", report.user_id, report.room, report.id);
+    hub.events.emit(ServerEvent::UserReported { room: report.room.clone(), user_id: report.user_id, report_id: report.id.clone(), reason: report.reason.clone() });
+    Ok(())
+}
+
+// Trimmed, without control characters other than newlines
+fn normalize_reason(reason: &str) -> Result<String, String> {
+    let reason = reason.chars().filter(|&c| c == '\n' || !c.is_control()).collect::<String>().trim().to_string();
+    if reason.is_empty() {
+        return Err("reason is empty".to_string());
+    }
+    if reason.chars().count() > MAX_REASON_CHARS {
+        return Err(format!("reason is longer than {} characters", MAX_REASON_CHARS));
+    }
+    Ok(reason)
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReportRequest {
+    room: String,
+    user_id: UserId,
+    reason: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReportFiled {
+    id: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Reports {
+    reports: Vec<Report>,
+}
+
+/// `POST /api/reports` for anyone who can get into the room, and `GET /api/admin/reports` and
+/// `DELETE /api/admin/reports/<id>` for moderators with an admin token
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = api::admin(config.clone());
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+
+    let report = warp::path!("api" / "reports")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::content_length_limit(MAX_REPORT_BYTES))
+        .and(warp::body::json())
+        .and(hub.clone())
+        .and(config)
+        .and_then(report_user);
+
+    let list = warp::path!("api" / "admin" / "reports")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(hub.clone())
+        .and_then(list_reports);
+
+    let dismiss = warp::path!("api" / "admin" / "reports" / String)
+        .and(warp::delete())
+        .and(admin)
+        .and(hub)
+        .and_then(dismiss_report);
+
+    report.or(list).or(dismiss)
+}
+
+/// Report someone in a room for moderators to look at. The room has to be loaded, and the report
+/// keeps the last ops on its board for context
+#[utoipa::path(
+    post,
+    path = "/api/reports",
+    tag = "rooms",
+    params(
+        ("key" = Option<String>, Query, description = "One of `auth.access_keys`, when any are set"),
+        ("token" = Option<String>, Query, description = "A login session token, for rooms with an access list, and kept as who reported"),
+    ),
+    request_body = ReportRequest,
+    responses(
+        (status = 201, description = "Kept for moderators", body = ReportFiled),
+        (status = 400, description = "Invalid room id or reason", body = ApiError),
+        (status = 401, description = "Missing or invalid key", body = ApiError),
+        (status = 403, description = "Not on the room's access list", body = ApiError),
+        (status = 404, description = "The room isn't loaded or the user was never in it", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+)]
+async fn report_user(query: HashMap<String, String>, request: ReportRequest, hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    let Some(room_id) = hub.room_id(&request.room) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid room id"));
+    };
+    let keys = config.borrow().auth.access_keys.clone();
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(error(StatusCode::UNAUTHORIZED, "missing or invalid key"));
+    }
+    let token = query.get("token").map(String::as_str);
+    if !hub.may_access(&room_id, token) {
+        return Ok(error(StatusCode::FORBIDDEN, "not on the room's access list"));
+    }
+    // Only rooms someone is using, so reports can't be made up for any room id
+    let Some(room) = hub.get(&room_id).await else {
+        return Ok(error(StatusCode::NOT_FOUND, "room not found"));
+    };
+    let account = hub.account(token).map(|account| account.username);
+    let filed = file(&*room.read().await, request.user_id, &request.reason, None, account);
+    let report = match filed {
+        Ok(report) => report,
+        Err(refused) if refused.code == "unknown_user" => return Ok(error(StatusCode::NOT_FOUND, &refused.message)),
+        Err(refused) => return Ok(error(StatusCode::BAD_REQUEST, &refused.message)),
+    };
+    if let Err(e) = submit(&hub, &report).await {
+        log::error!("Could not keep a report in room {}: {}", report.room, e);
+        return Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"));
+    }
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&ReportFiled { id: report.id }), StatusCode::CREATED)))
+}
+
+/// Reports waiting for a moderator, newest first
+#[utoipa::path(
+    get,
+    path = "/api/admin/reports",
+    tag = "admin",
+    params(("room" = Option<String>, Query, description = "Only this room's, `<prefix>.<room>` for a tenant's")),
+    responses(
+        (status = 200, description = "OK", body = Reports),
+        (status = 400, description = "Invalid room id", body = ApiError),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn list_reports(query: HashMap<String, String>, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    let room = query.get("room").map(String::as_str);
+    // A tenant's rooms are `<prefix>.<room>` in the hub
+    if room.is_some_and(|room| !room.split('.').all(valid_room_id)) {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid room id"));
+    }
+    match hub.reports(room).await {
+        Ok(reports) => Ok(Box::new(warp::reply::json(&Reports { reports }))),
+        Err(e) => {
+            log::error!("Could not list reports: {}", e);
+            Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"))
+        }
+    }
+}
+
+/// Remove a report a moderator dealt with
+#[utoipa::path(
+    delete,
+    path = "/api/admin/reports/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "Report id")),
+    responses(
+        (status = 204, description = "Dismissed"),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such report", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn dismiss_report(id: String, hub: Arc<Hub>) -> Result<Box<dyn Reply>, Infallible> {
+    match hub.dismiss_report(&id).await {
+        Ok(true) => {
+            log::info!("Report {} was dismissed", id);
+            Ok(Box::new(StatusCode::NO_CONTENT))
+        }
+        Ok(false) => Ok(error(StatusCode::NOT_FOUND, "report not found")),
+        Err(e) => {
+            log::error!("Could not dismiss report {}: {}", id, e);
+            Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"))
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_are_trimmed_and_capped() {
+        assert_eq!(normalize_reason("  spam\u{7}\n").unwrap(), "spam");
+        assert!(normalize_reason(" \n ").is_err());
+        assert!(normalize_reason(&"a".repeat(MAX_REASON_CHARS + 1)).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::convert::Infallible;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::config::{Config, ConfigHandle};
+use crate::events::now_millis;
+use crate::hub::Hub;
+use crate::openapi::ApiError;
+use crate::storage::Storage;
+use crate::tenants;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// How long what's stored is kept, in days. Unset or 0 keeps it forever; a tenant's policy
+/// overrides `[retention]` one setting at a time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Session summaries are deleted this long after the session ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessions_days: Option<u64>,
+    /// Session summaries lose who did what, keeping the room's totals, this long after the session ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compact_sessions_days: Option<u64>,
+    /// Boards lose the ops drawn on them this long ago, from the front, so a board drawn on for
+    /// months only keeps its recent ops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_history_days: Option<u64>,
+    /// Archived rooms are deleted, board, info and all, this long after they were archived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// This, with any setting left unset taken from `fallback`
+    pub fn or(self, fallback: RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            sessions_days: self.sessions_days.or(fallback.sessions_days),
+            compact_sessions_days: self.compact_sessions_days.or(fallback.compact_sessions_days),
+            trim_history_days: self.trim_history_days.or(fallback.trim_history_days),
+            archived_days: self.archived_days.or(fallback.archived_days),
+        }
+    }
+
+    // Unix seconds before which something `days` old is past it, None for forever
+    fn cutoff(days: Option<u64>, now: u64) -> Option<u64> {
+        days.filter(|&days| days > 0).map(|days| now.saturating_sub(days.saturating_mul(DAY_SECS)))
+    }
+}
+
+/// What a janitor run did, or with `dry_run` would have done
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub sessions_deleted: usize,
+    pub sessions_compacted: usize,
+    /// Ops trimmed off the front of boards
+    pub ops_trimmed: usize,
+    /// Archived rooms deleted
+    pub rooms_purged: Vec<String>,
+}
+
+/// Apply each stored room's policy, its tenant's over `[retention]`, as of `now` in unix seconds.
+/// A purged room is forgotten by accounts and shared state too. Boards of open rooms are left for
+/// a run after they're unloaded, saving them would put back what was trimmed
+pub async fn enforce(hub: &Hub, config: &Config, now: u64, dry_run: bool) -> io::Result<RetentionReport> {
+    let storage = hub.storage();
+    let mut report = RetentionReport { dry_run, ..RetentionReport::default() };
+    let mut rooms = storage.rooms().await?;
+    rooms.sort();
+    for room in rooms {
+        let policy = match tenants::tenant_of(config, &room) {
+            Some((tenant, _)) => tenant.config.retention.or(config.retention.policy),
+            None => config.retention.policy,
+        };
+        if let Some(cutoff) = RetentionPolicy::cutoff(policy.archived_days, now) {
+            if storage.archived(&room).await?.is_some_and(|archived_at| archived_at < cutoff) {
+                if !dry_run {
+                    purge(hub, &room).await?;
+                }
+                report.rooms_purged.push(room);
+                continue;
+            }
+        }
+        if let Some(cutoff) = RetentionPolicy::cutoff(policy.trim_history_days, now) {
+            if storage.archived(&room).await?.is_none() && hub.get(&room).await.is_none() {
+                report.ops_trimmed += trim_history(storage, &room, cutoff, dry_run).await?;
+            }
+        }
+
+        let summaries = storage.summaries(&room).await?;
+        let delete_before = RetentionPolicy::cutoff(policy.sessions_days, now);
+        let compact_before = RetentionPolicy::cutoff(policy.compact_sessions_days, now);
+        let (mut deleted, mut compacted) = (0, 0);
+        let mut kept = Vec::with_capacity(summaries.len());
+        for mut summary in summaries {
+            if delete_before.is_some_and(|cutoff| summary.ended_at < cutoff) {
+                deleted += 1;
+                continue;
+            }
+            if compact_before.is_some_and(|cutoff| summary.ended_at < cutoff) && !summary.contributions.is_empty() {
+                summary.contributions.clear();
+                compacted += 1;
+            }
+            kept.push(summary);
+        }
+        if (deleted > 0 || compacted > 0) && !dry_run {
+            storage.replace_summaries(&room, &kept).await?;
+        }
+        report.sessions_deleted += deleted;
+        report.sessions_compacted += compacted;
+    }
+    Ok(report)
+}
+
+// Delete everything kept about a room, in storage, its owner and members, and in shared state
+async fn purge(hub: &Hub, room: &str) -> io::Result<()> {
+    hub.storage().delete(room).await?;
+    if let Some(accounts) = &hub.accounts {
+        accounts.forget(room).map_err(|e| io::Error::other(e.to_string()))?;
+    }
+    if let Some(shared) = &hub.shared {
+        shared.forget(room).await?;
+    }
+    Ok(())
+}
+
+// Drop the ops drawn before `cutoff` (unix seconds) off the front of a stored board, returning how
+// many. A board whose ops don't say when they were drawn is left alone
+async fn trim_history(storage: &dyn Storage, room: &str, cutoff: u64, dry_run: bool) -> io::Result<usize> {
+    let Some(mut strokes) = storage.load_strokes(room).await? else {
+        return Ok(0);
+    };
+    let mut history = storage.load(room).await?;
+    if strokes.len() != history.len() {
+        return Ok(0);
+    }
+    let old = strokes.iter().take_while(|stroke| stroke.drawn_at.is_some_and(|at| at / 1000 < cutoff)).count();
+    if old > 0 && !dry_run {
+        history.drain(..old);
+        strokes.drain(..old);
+        storage.save(room, &history).await?;
+        storage.save_strokes(room, &strokes).await?;
+    }
+    Ok(old)
+}
+
+/// Enforce retention every `retention.interval_secs`
+pub async fn janitor(hub: Arc<Hub>, config: ConfigHandle) {
+    loop {
+        let interval = config.borrow().retention.interval_secs;
+        // Checked again in a while, it can be turned on by a reload
+        tokio::time::sleep(Duration::from_secs(if interval == 0 { 60 } else { interval })).await;
+        if interval == 0 {
+            continue;
+        }
+        let current = config.borrow().clone();
+        match enforce(&hub, &current, now_millis() / 1000, current.retention.dry_run).await {
+            Ok(report) if report.dry_run => log::info!("Retention dry run: would delete {} session summaries, compact {}, trim {} ops off boards, purge archived rooms {:?}", report.sessions_deleted, report.sessions_compacted, report.ops_trimmed, report.rooms_purged),
+            Ok(report) => {
+                if report.sessions_deleted + report.sessions_compacted + report.ops_trimmed + report.rooms_purged.len() > 0 {
+                    log::info!("Retention deleted {} session summaries, compacted {}, trimmed {} ops off boards, purged archived rooms {:?}", report.sessions_deleted, report.sessions_compacted, report.ops_trimmed, report.rooms_purged);
+                }
+            }
+            Err(e) => log::error!("Retention janitor failed: {}", e),
+        }
+    }
+}
+
+/// `GET /api/admin/retention` for what the janitor would do now, `POST` to have it done
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = api::admin(config.clone());
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+    let path = warp::path!("api" / "admin" / "retention").and(admin);
+
+    let preview = path.clone().and(warp::get()).and(hub.clone()).and(config.clone()).and_then(preview_retention);
+    let enforce = path.and(warp::post()).and(hub).and(config).and_then(enforce_retention);
+    preview.or(enforce)
+}
+
+/// What the janitor would delete, compact and trim if it ran now
+#[utoipa::path(
+    get,
+    path = "/api/admin/retention",
+    tag = "admin",
+    responses(
+        (status = 200, description = "OK", body = RetentionReport),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn preview_retention(hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    run(hub, config, true).await
+}
+
+/// Enforce retention now, whatever `retention.dry_run` says
+#[utoipa::path(
+    post,
+    path = "/api/admin/retention",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Done", body = RetentionReport),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 503, description = "Storage failed", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn enforce_retention(hub: Arc<Hub>, config: ConfigHandle) -> Result<Box<dyn Reply>, Infallible> {
+    run(hub, config, false).await
+}
+
+async fn run(hub: Arc<Hub>, config: ConfigHandle, dry_run: bool) -> Result<Box<dyn Reply>, Infallible> {
+    let current = config.borrow().clone();
+    match enforce(&hub, &current, now_millis() / 1000, dry_run).await {
+        Ok(report) => Ok(Box::new(warp::reply::json(&report))),
+        Err(e) => {
+            log::error!("Could not enforce retention: {}", e);
+            Ok(error(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable"))
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::config::TenantConfig;
+    use crate::hooks::Hooks;
+    use crate::protocol::{Composite, DrawCommand, MessageType};
+    use crate::room::{SessionSummary, SnapshotRef, Stroke, UserContribution};
+    use crate::shared::{SharedState, SqliteState};
+    use crate::storage::{Durability, MemoryStorage, StorageSpec};
+    use crate::usage::ConfigQuotas;
+
+    const NOW: u64 = 100 * DAY_SECS;
+
+    fn summary(room: &str, days_ago: u64) -> SessionSummary {
+        let ended_at = NOW - days_ago * DAY_SECS;
+        let contribution = UserContribution { user_id: crate::ids::UserId::random(), contribution: Default::default() };
+        SessionSummary {
+            room: room.to_string(),
+            started_at: ended_at,
+            ended_at,
+            duration_secs: 0,
+            participants: 1,
+            peak_participants: 1,
+            total_strokes: 0,
+            contributions: vec![contribution],
+            snapshot: SnapshotRef { seq: 0, ops: 0 },
+        }
+    }
+
+    fn database(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ws-demo-retention-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn hub(storage: Arc<dyn Storage>, accounts: Option<Accounts>, shared: Option<SqliteState>) -> Hub {
+        let (_, config) = tokio::sync::watch::channel(Arc::new(Config::default()));
+        let shared = shared.map(|shared| Arc::new(shared) as Arc<dyn SharedState>);
+        Hub::new(storage, Arc::new(ConfigQuotas::new(config)), Hooks::new(Vec::new()), accounts.map(Arc::new), shared)
+    }
+
+    fn draw(n: u64) -> MessageType {
+        MessageType::Draw(DrawCommand { prev: [0.0, 0.0], cur: [n as f64, 0.0], color: "#000000".to_string(), brush_size: 1, composite: Composite::SourceOver })
+    }
+
+    #[tokio::test]
+    async fn old_sessions_are_compacted_then_deleted_and_old_archives_purged() {
+        let hub = hub(Arc::new(MemoryStorage::default()), None, None);
+        let storage = hub.storage();
+        for (room, days_ago) in [("sketch", 40), ("sketch", 10), ("sketch", 1), ("acme.sketch", 10)] {
+            storage.save_summary(&summary(room, days_ago)).await.unwrap();
+        }
+        storage.archive("old", &[], NOW - 20 * DAY_SECS).await.unwrap();
+        storage.archive("acme.old", &[], NOW - 20 * DAY_SECS).await.unwrap();
+        let mut config = Config::default();
+        config.retention.policy = RetentionPolicy { sessions_days: Some(30), compact_sessions_days: Some(7), archived_days: Some(14), ..RetentionPolicy::default() };
+        let acme = TenantConfig { retention: RetentionPolicy { archived_days: Some(0), compact_sessions_days: Some(30), ..RetentionPolicy::default() }, ..TenantConfig::default() };
+        config.tenants.insert("acme".to_string(), acme);
+
+        let preview = enforce(&hub, &config, NOW, true).await.unwrap();
+        assert_eq!((preview.sessions_deleted, preview.sessions_compacted, preview.rooms_purged.clone()), (1, 1, vec!["old".to_string()]));
+        assert_eq!(storage.summaries("sketch").await.unwrap().len(), 3);
+
+        enforce(&hub, &config, NOW, false).await.unwrap();
+        let kept = storage.summaries("sketch").await.unwrap();
+        assert_eq!(kept.iter().map(|s| s.contributions.len()).collect::<Vec<_>>(), [0, 1]);
+        // The tenant compacts later and keeps its archives
+        assert_eq!(storage.summaries("acme.sketch").await.unwrap()[0].contributions.len(), 1);
+        assert!(storage.archived("old").await.unwrap().is_none());
+        assert!(storage.archived("acme.old").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn old_ops_are_trimmed_off_boards_left_alone_while_open() {
+        // Memory storage keeps no boards
+        let dir = std::env::temp_dir().join(format!("ws-demo-retention-{}", crate::ids::random_token()));
+        let hub = hub(StorageSpec::File(dir.clone()).open(None, Durability::Async).await.unwrap(), None, None);
+        let storage = hub.storage();
+        let strokes: Vec<Stroke> = [20, 10, 1].iter().enumerate().map(|(n, days_ago)| Stroke { stroke_id: n as u64, drawn_at: Some((NOW - days_ago * DAY_SECS) * 1000), ..Stroke::default() }).collect();
+        for room in ["board", "open"] {
+            storage.save(room, &[draw(1), draw(2), draw(3)]).await.unwrap();
+            storage.save_strokes(room, &strokes).await.unwrap();
+        }
+        hub.open("open").await.unwrap();
+        let mut config = Config::default();
+        config.retention.policy.trim_history_days = Some(7);
+
+        assert_eq!(enforce(&hub, &config, NOW, true).await.unwrap().ops_trimmed, 2);
+        assert_eq!(storage.load("board").await.unwrap().len(), 3);
+        enforce(&hub, &config, NOW, false).await.unwrap();
+        assert_eq!(serde_json::to_value(storage.load("board").await.unwrap()).unwrap(), serde_json::to_value([draw(3)]).unwrap());
+        assert_eq!(storage.load_strokes("board").await.unwrap().unwrap(), strokes[2..]);
+        assert_eq!(storage.load("open").await.unwrap().len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn purged_rooms_are_forgotten_by_accounts_and_shared_state() {
+        let (accounts_path, shared_path) = (database("accounts"), database("shared"));
+        let accounts = Accounts::open(&accounts_path).unwrap();
+        let owner = accounts.register("ann", "correct horse", 8).unwrap();
+        accounts.claim("old", owner.id).unwrap();
+        accounts.set_private("old", true).unwrap();
+        let hub = hub(Arc::new(MemoryStorage::default()), Some(accounts), Some(SqliteState::open(&shared_path).unwrap()));
+        let shared = hub.shared.clone().unwrap();
+        shared.load("old", &[draw(1)]).await.unwrap();
+        hub.storage().archive("old", &[draw(1)], NOW - 20 * DAY_SECS).await.unwrap();
+        let mut config = Config::default();
+        config.retention.policy.archived_days = Some(14);
+
+        enforce(&hub, &config, NOW, false).await.unwrap();
+        let accounts = hub.accounts.as_ref().unwrap();
+        assert_eq!(accounts.owner("old").unwrap(), None);
+        assert!(accounts.may_join("old", None).unwrap());
+        // Starts over from what storage has, nothing
+        assert_eq!(shared.load("old", &[]).await.unwrap().1, 0);
+        let _ = (std::fs::remove_file(&accounts_path), std::fs::remove_file(&shared_path));
+    }
+}
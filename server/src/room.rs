@@ -0,0 +1,773 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, RwLock};
+use utoipa::ToSchema;
+
+use crate::codec;
+use crate::connection::Peer;
+use crate::events::{now_millis, CorrelationId};
+use crate::features::FeatureFlags;
+use crate::follows::Follows;
+use crate::ids::UserId;
+use crate::notes::Notes;
+use crate::permissions::Permissions;
+use crate::protocol::{DrawCommand, Member, MessageType};
+use crate::schedule::{self, Recurring};
+use crate::sync::SyncSnapshot;
+use crate::usage::Drawn;
+
+pub type SharedRoom = Arc<RwLock<Room>>;
+
+// Width of the window message rates are averaged over
+const RATE_WINDOW_SECS: u64 = 60;
+
+const MAX_ROOM_NAME_CHARS: usize = 64;
+const MAX_DESCRIPTION_CHARS: usize = 500;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 32;
+
+pub struct Room {
+    pub id: String,
+    pub users: HashMap<UserId, Peer>,
+    pub history: Vec<MessageType>,
+    // Who drew each op in `history`, in the same order. Saved with it
+    pub strokes: Vec<Stroke>,
+    // What the next op on the board is given as its `Stroke::stroke_id`
+    pub next_stroke_id: u64,
+    // Set when history changed since the last save
+    pub dirty: bool,
+    // When the last user left, None while anyone is connected
+    pub empty_since: Option<Instant>,
+    // When this room was loaded into memory
+    pub created_at: Instant,
+    // Draw and Erase ops accepted since load, unlike history this survives clears
+    pub total_strokes: u64,
+    // Sequence number of the last accepted op
+    pub seq: u64,
+    // Clears since load, an op tagged with another epoch was sent before the latest clear reached its sender
+    pub epoch: u64,
+    // The board before the last clear, who drew it, and when that was, until `rooms.clear_undo_secs` passes
+    pub cleared: Option<(Vec<MessageType>, Vec<Stroke>, Instant)>,
+    // `seq` as of the last `Checksum` sent, so an unchanged board isn't checked again
+    pub checksummed_seq: u64,
+    // With shared state, the last seq taken in from it, and who's in the room on other nodes
+    pub synced_seq: u64,
+    pub remote_members: HashMap<UserId, Member>,
+    // The frame behind the last accepted op, logged when the room is saved
+    pub last_correlation_id: Option<CorrelationId>,
+    pub messages_in: RateCounter,
+    pub messages_out: RateCounter,
+    // WebSocket joiners waiting for a slot, admitted in order as people leave
+    pub waitlist: VecDeque<Waiter>,
+    // `rooms.max_participants` for this room as of the last join, 0 is unlimited
+    pub capacity: usize,
+    // Slots held for people on their way in from another room, see `socket::move_to_room`
+    pub reserved: usize,
+    // Bots on their way in from another room, whether or not they hold a slot, see `bots()`
+    pub reserved_bots: usize,
+    pub follows: Follows,
+    // By participant, what they have on the board, see `drawn_with`
+    tallies: HashMap<UserId, Tally>,
+    // By participant, since the room was loaded
+    pub contributions: HashMap<UserId, Contribution>,
+    // Participants who joined since the room was loaded, and the most there were at once
+    pub joined: usize,
+    pub peak_participants: usize,
+    // Who each participant blocked, by `Peer::identity` so it holds across tabs and reconnects
+    pub blocked: HashMap<String, HashSet<String>>,
+    // Kept by storage next to the history, see `Hub::set_info`
+    pub info: RoomInfo,
+    // Set once `Hub::archive` took the board, anyone who still gets to join is turned away
+    pub archived: bool,
+    // Kept by storage next to the history, saved with it when `notes_dirty`
+    pub notes: Notes,
+    pub notes_dirty: bool,
+    // Unix seconds up to which `info.schedule` was run, and the latest action everyone was warned of
+    pub schedule_checked: u64,
+    pub schedule_warned: u64,
+    // Who froze the room with `Freeze`, None while anyone may change it. Not saved
+    pub frozen: Option<UserId>,
+    // The board serialized for joiners, see `SyncSnapshot`, refreshed by `Hub::refresh_sync`
+    pub sync: Option<Arc<SyncSnapshot>>,
+}
+
+/// Who can find a room. Public and unlisted rooms can be joined by anyone with the id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed in `GET /api/lobby`
+    Public,
+    #[default]
+    Unlisted,
+    /// Only its owner, members and join links get in, even while it has no members
+    Private,
+}
+
+/// What happens to frames of a `type` the server doesn't know, see `ClientFrame::Unknown`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownFrames {
+    /// Refused with an `unknown_type` error frame
+    #[default]
+    Reject,
+    /// Sent on to everyone else in the room as a `Relayed` frame, without being looked at or kept.
+    /// For rooms whose clients are newer than the server
+    Relay,
+}
+
+impl UnknownFrames {
+    pub fn is_reject(&self) -> bool {
+        *self == UnknownFrames::Reject
+    }
+}
+
+/// What a room is besides its board
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RoomInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Lowercase, for finding rooms about something
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+    /// Unix seconds, joins before this are turned away with how long is left
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opens_at: Option<u64>,
+    /// Unix seconds, when the room is archived on its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closes_at: Option<u64>,
+    #[serde(skip_serializing_if = "UnknownFrames::is_reject")]
+    pub unknown_frames: UnknownFrames,
+    /// Set by admins, see `features`
+    #[serde(skip_serializing_if = "FeatureFlags::is_empty")]
+    pub features: FeatureFlags,
+    /// Set by its owner, over `[permissions]`
+    #[serde(skip_serializing_if = "Permissions::is_empty")]
+    pub permissions: Permissions,
+    /// Recurring clears and archives, run while the room is loaded, see `Hub::run_scheduled`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<Recurring>,
+}
+
+/// Changes to a room's info from its owner, see `ControlMessage::UpdateRoom`. Fields left out stay
+/// as they are, an empty name or description removes it. Fields it doesn't have are refused
+/// rather than left as they are without the owner knowing
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RoomUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub visibility: Option<Visibility>,
+    pub unknown_frames: Option<UnknownFrames>,
+    /// Replaces the room's permissions as a whole, `{}` goes back to the server's
+    pub permissions: Option<Permissions>,
+    /// Replaces the room's schedule as a whole, `[]` ends it
+    pub schedule: Option<Vec<Recurring>>,
+}
+
+impl RoomUpdate {
+    pub fn apply(self, info: RoomInfo) -> RoomInfo {
+        RoomInfo {
+            name: self.name.or(info.name),
+            description: self.description.or(info.description),
+            tags: self.tags.unwrap_or(info.tags),
+            visibility: self.visibility.unwrap_or(info.visibility),
+            unknown_frames: self.unknown_frames.unwrap_or(info.unknown_frames),
+            permissions: self.permissions.unwrap_or(info.permissions),
+            schedule: self.schedule.unwrap_or(info.schedule),
+            ..info
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+// Trimmed and without control characters, None if that leaves nothing
+fn clean(text: Option<String>, keep_newlines: bool) -> Option<String> {
+    let text = text?.chars().filter(|&c| (keep_newlines && c == '\n') || !c.is_control()).collect::<String>();
+    Some(text.trim().to_string()).filter(|text| !text.is_empty())
+}
+
+impl RoomInfo {
+    /// Cleaned up like `clean`, tags lowercased and deduplicated, or why it can't be used
+    pub fn normalize(self) -> Result<Self, String> {
+        let name = clean(self.name, false);
+        if name.as_ref().is_some_and(|name| name.chars().count() > MAX_ROOM_NAME_CHARS) {
+            return Err(format!("room names are at most {} characters", MAX_ROOM_NAME_CHARS));
+        }
+        let description = clean(self.description, true);
+        if description.as_ref().is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_CHARS) {
+            return Err(format!("descriptions are at most {} characters", MAX_DESCRIPTION_CHARS));
+        }
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags.into_iter().filter_map(|tag| clean(Some(tag.to_lowercase()), false)) {
+            if tag.chars().count() > MAX_TAG_CHARS {
+                return Err(format!("tags are at most {} characters", MAX_TAG_CHARS));
+            }
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(format!("rooms have at most {} tags", MAX_TAGS));
+        }
+        if let (Some(opens_at), Some(closes_at)) = (self.opens_at, self.closes_at) {
+            if closes_at <= opens_at {
+                return Err("closes_at has to be after opens_at".to_string());
+            }
+        }
+        let schedule = schedule::normalize(self.schedule)?;
+        Ok(RoomInfo { name, description, tags, schedule, ..self })
+    }
+
+    /// When the room opens, if that's still to come
+    pub fn opens_later(&self) -> Option<u64> {
+        self.opens_at.filter(|&opens_at| opens_at > now_secs())
+    }
+
+    pub fn has_closed(&self) -> bool {
+        self.closes_at.is_some_and(|closes_at| closes_at <= now_secs())
+    }
+}
+
+/// What one participant did in a room since it was loaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct Contribution {
+    /// Their name when they last did something
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The account they were signed in with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    pub strokes: u64,
+    pub erases: u64,
+    pub clears: u64,
+    /// Direct messages sent, only counted
+    pub messages: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UserContribution {
+    pub user_id: UserId,
+    #[serde(flatten)]
+    pub contribution: Contribution,
+}
+
+/// Someone waiting to get into a full room, see `socket::join_or_wait`
+pub struct Waiter {
+    pub peer: Peer,
+    pub admit: oneshot::Sender<()>,
+}
+
+/// Who put an op on the board and how, kept next to it for as long as it's there so "who drew
+/// this?" can be answered later, see `ControlMessage::Inspect`. Ops from before the server kept
+/// these only have a `stroke_id`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Stroke {
+    /// Unique in its room and kept when it's saved, unlike the seq, which starts over on each load
+    pub stroke_id: u64,
+    /// Who drew it, as everyone in the room saw them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<UserId>,
+    /// Their name at the time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The account they were signed in with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Unix milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drawn_at: Option<u64>,
+    /// What it came in over: `websocket`, `longpoll`, `socketio`, `grpc` or `webtransport`, and
+    /// `bot` for the room's bot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// A room's board and info saved under a name, for rooms that start out the same every time.
+/// Kept by the storage backend, see `Hub::save_template`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Template {
+    pub name: String,
+    /// Without `opens_at` and `closes_at`, rooms made from it get their own
+    pub info: RoomInfo,
+    pub history: Vec<MessageType>,
+    /// The account that saved it, None if it was saved with an admin token
+    pub saved_by: Option<String>,
+    /// Unix seconds
+    pub saved_at: u64,
+}
+
+/// The record of a room from when it was loaded until it was unloaded, kept by the storage backend
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SessionSummary {
+    pub room: String,
+    /// Unix seconds
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub duration_secs: u64,
+    /// Everyone who joined, a signed-in user's tabs count once
+    pub participants: usize,
+    pub peak_participants: usize,
+    pub total_strokes: u64,
+    pub contributions: Vec<UserContribution>,
+    pub snapshot: SnapshotRef,
+}
+
+/// The board as the session left it: the room's stored snapshot, until someone draws in it again
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SnapshotRef {
+    /// The room's sequence number at the last op
+    pub seq: u64,
+    pub ops: usize,
+}
+
+impl Room {
+    pub fn new(id: String, history: Vec<MessageType>) -> Self {
+        let strokes = (1..=history.len() as u64).map(|stroke_id| Stroke { stroke_id, ..Stroke::default() }).collect();
+        Room {
+            id,
+            users: HashMap::new(),
+            next_stroke_id: history.len() as u64 + 1,
+            history,
+            strokes,
+            dirty: false,
+            empty_since: Some(Instant::now()),
+            created_at: Instant::now(),
+            total_strokes: 0,
+            seq: 0,
+            epoch: 0,
+            cleared: None,
+            checksummed_seq: 0,
+            synced_seq: 0,
+            remote_members: HashMap::new(),
+            last_correlation_id: None,
+            messages_in: RateCounter::default(),
+            messages_out: RateCounter::default(),
+            waitlist: VecDeque::new(),
+            capacity: 0,
+            reserved: 0,
+            reserved_bots: 0,
+            tallies: HashMap::new(),
+            follows: Follows::default(),
+            contributions: HashMap::new(),
+            joined: 0,
+            peak_participants: 0,
+            blocked: HashMap::new(),
+            info: RoomInfo::default(),
+            archived: false,
+            notes: Notes::default(),
+            notes_dirty: false,
+            schedule_checked: now_secs(),
+            schedule_warned: 0,
+            frozen: None,
+            sync: None,
+        }
+    }
+
+    /// Whether a joiner has to wait, which they also do behind anyone already waiting
+    pub fn is_full(&self) -> bool {
+        self.capacity > 0 && (self.slots_taken() >= self.capacity || !self.waitlist.is_empty())
+    }
+
+    /// Participants taking up one of `capacity`, see `Peer::takes_slot`, and slots held for
+    /// people switching in
+    pub fn slots_taken(&self) -> usize {
+        self.count_participants(|peer| peer.takes_slot) + self.reserved
+    }
+
+    /// Connections in the room that said they're bots, and bots on their way in from another room
+    pub fn bots(&self) -> usize {
+        self.users.values().filter(|peer| peer.stats.is_bot).count() + self.reserved_bots
+    }
+
+    /// People in the room, counting a signed-in user's tabs once
+    pub fn participants(&self) -> usize {
+        self.count_participants(|_| true)
+    }
+
+    // Participants with a connection that `counts`, a signed-in user's tabs being one
+    fn count_participants(&self, counts: impl Fn(&Peer) -> bool) -> usize {
+        let mut participants: Vec<UserId> = self.users.values().filter(|peer| counts(peer)).map(|peer| peer.participant).collect();
+        participants.sort();
+        participants.dedup();
+        participants.len()
+    }
+
+    /// What a connection is seen as by everyone else, see `Peer::participant`
+    pub fn participant_of(&self, user_id: UserId) -> Option<UserId> {
+        self.users.get(&user_id).map(|peer| peer.participant)
+    }
+
+    /// Every connection of a participant, more than one when a signed-in user has several tabs open
+    pub fn tabs(&self, participant: UserId) -> impl Iterator<Item = &Peer> {
+        self.users.values().filter(move |peer| peer.participant == participant)
+    }
+
+    pub fn tabs_mut(&mut self, participant: UserId) -> impl Iterator<Item = &mut Peer> {
+        self.users.values_mut().filter(move |peer| peer.participant == participant)
+    }
+
+    /// Whether `peer` blocked whoever has `identity`, whose cursors, reactions and messages it then doesn't get
+    pub fn blocks(&self, peer: &Peer, identity: &str) -> bool {
+        !self.blocked.is_empty() && self.blocked.get(&peer.identity()).is_some_and(|blocked| blocked.contains(identity))
+    }
+
+    /// A connection already in the room, signed in to `account`
+    pub fn signed_in(&self, account: &str) -> Option<&Peer> {
+        self.users.values().find(|peer| peer.account.as_deref() == Some(account))
+    }
+
+    /// Count something a connection did towards its participant's contribution
+    pub fn contribute(&mut self, user_id: UserId, count: impl FnOnce(&mut Contribution)) {
+        let Some(peer) = self.users.get(&user_id).filter(|peer| peer.contributes) else {
+            return;
+        };
+        let contribution = self.contributions.entry(peer.participant).or_default();
+        contribution.name.clone_from(&peer.profile.name);
+        contribution.account.clone_from(&peer.account);
+        count(contribution);
+    }
+
+    /// Everyone's contributions, most strokes first
+    pub fn contributions(&self) -> Vec<UserContribution> {
+        let mut contributions: Vec<UserContribution> =
+            self.contributions.iter().map(|(&user_id, contribution)| UserContribution { user_id, contribution: contribution.clone() }).collect();
+        contributions.sort_by(|a, b| b.contribution.strokes.cmp(&a.contribution.strokes).then(a.user_id.cmp(&b.user_id)));
+        contributions
+    }
+
+    /// What happened in the room since it was loaded, for when it's unloaded
+    pub fn summary(&self) -> SessionSummary {
+        let ended_at = now_secs();
+        let duration_secs = self.created_at.elapsed().as_secs();
+        SessionSummary {
+            room: self.id.clone(),
+            started_at: ended_at.saturating_sub(duration_secs),
+            ended_at,
+            duration_secs,
+            participants: self.joined,
+            peak_participants: self.peak_participants,
+            total_strokes: self.total_strokes,
+            contributions: self.contributions(),
+            snapshot: SnapshotRef { seq: self.seq, ops: self.history.len() },
+        }
+    }
+
+    /// Replace the board, e.g. with a template's, as drawn by nobody in particular
+    pub fn replace_board(&mut self, history: Vec<MessageType>) {
+        self.strokes = history.iter().map(|_| self.next_stroke()).collect();
+        self.history = history;
+        self.tallies.clear();
+        self.sync = None;
+    }
+
+    /// Who drew the ops on the board, as storage had it. Ignored unless there's one for each op,
+    /// the board may have changed on other nodes since
+    pub fn attribute(&mut self, strokes: Vec<Stroke>) {
+        if strokes.len() != self.history.len() {
+            return;
+        }
+        let next = strokes.iter().map(|stroke| stroke.stroke_id + 1).max().unwrap_or(1);
+        self.next_stroke_id = self.next_stroke_id.max(next);
+        self.strokes = strokes;
+        self.recount();
+        // Its stroke ids may not be the board's any more
+        self.sync = None;
+    }
+
+    /// The stroke id `op` was given by the `apply` just before, None for a clear, which isn't on the board
+    pub fn applied_stroke_id(&self, op: &MessageType) -> Option<u64> {
+        match op {
+            MessageType::Draw(_) => self.strokes.last().map(|stroke| stroke.stroke_id),
+            MessageType::Clear => None,
+        }
+    }
+
+    /// The op on the board with this id, and who drew it
+    pub fn stroke(&self, stroke_id: u64) -> Option<(&MessageType, &Stroke)> {
+        // Ids go up along the board
+        let index = self.strokes.binary_search_by_key(&stroke_id, |stroke| stroke.stroke_id).ok()?;
+        Some((&self.history[index], &self.strokes[index]))
+    }
+
+    /// The lines and points `participant` would have on the board with `draw` added, see
+    /// `QuotaConfig::user_strokes`
+    pub fn drawn_with(&self, participant: UserId, draw: &DrawCommand) -> Drawn {
+        let Some(tally) = self.tallies.get(&participant) else {
+            return Drawn { strokes: 1, points: 2 };
+        };
+        match tally.last == Some(draw.prev) {
+            true => Drawn { points: tally.drawn.points + 1, ..tally.drawn },
+            false => Drawn { strokes: tally.drawn.strokes + 1, points: tally.drawn.points + 2 },
+        }
+    }
+
+    // Count everyone's lines and points on the board again, after who drew what changed
+    fn recount(&mut self) {
+        self.tallies.clear();
+        for (op, stroke) in self.history.iter().zip(&self.strokes) {
+            if let (MessageType::Draw(segment), Some(user_id)) = (op, stroke.user_id) {
+                self.tallies.entry(user_id).or_default().push(segment);
+            }
+        }
+    }
+
+    fn next_stroke(&mut self) -> Stroke {
+        let stroke_id = self.next_stroke_id;
+        self.next_stroke_id += 1;
+        Stroke { stroke_id, ..Stroke::default() }
+    }
+
+    /// What is known of `user_id` to attribute an op to them, drawn now
+    pub fn author(&self, user_id: UserId) -> Stroke {
+        let drawn_at = Some(now_millis());
+        match self.users.get(&user_id) {
+            Some(peer) => Stroke {
+                stroke_id: 0,
+                user_id: Some(peer.participant),
+                name: peer.profile.name.clone(),
+                account: peer.account.clone(),
+                drawn_at,
+                device: Some(peer.stats.transport.to_string()),
+            },
+            None => match self.remote_members.get(&user_id) {
+                Some(member) => Stroke { user_id: Some(user_id), name: member.profile.name.clone(), drawn_at, ..Stroke::default() },
+                None => Stroke { user_id: Some(user_id), drawn_at, ..Stroke::default() },
+            },
+        }
+    }
+
+    /// Apply an accepted op drawn by `author`, returning the sequence number it was given. The op
+    /// gets the next stroke id
+    pub fn apply(&mut self, msg: &MessageType, author: Stroke, history_limit: usize) -> u64 {
+        match msg {
+            MessageType::Clear => {
+                self.cleared = Some((std::mem::take(&mut self.history), std::mem::take(&mut self.strokes), Instant::now()));
+                self.tallies.clear();
+                self.epoch += 1;
+            }
+            _ => {
+                self.total_strokes += 1;
+                self.history.push(msg.clone());
+                let stroke_id = self.next_stroke().stroke_id;
+                if let (MessageType::Draw(segment), Some(user_id)) = (msg, author.user_id) {
+                    self.tallies.entry(user_id).or_default().push(segment);
+                }
+                self.strokes.push(Stroke { stroke_id, ..author });
+                if self.history.len() > history_limit {
+                    let overflow = self.history.len() - history_limit;
+                    self.history.drain(..overflow);
+                    for stroke in self.strokes.drain(..overflow) {
+                        let Some(user_id) = stroke.user_id else {
+                            continue;
+                        };
+                        if let Some(tally) = self.tallies.get_mut(&user_id) {
+                            if tally.pop() {
+                                self.tallies.remove(&user_id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.dirty = true;
+        self.seq += 1;
+        self.seq
+    }
+
+    /// FNV-1a over the binary frame of each op on the board in order, see `codec::encode`. Clients
+    /// can work it out from what they drew to tell whether they still have the same board
+    pub fn checksum(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self.history.iter().flat_map(codec::encode) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash
+    }
+
+    /// The board from before the last clear with what was drawn since on top, and who drew each op,
+    /// if that clear was less than `grace` ago. Either way it can't be undone after this
+    pub fn take_cleared(&mut self, grace: Duration) -> Option<(Vec<MessageType>, Vec<Stroke>)> {
+        let (mut ops, mut strokes, _) = self.cleared.take().filter(|(_, _, at)| at.elapsed() < grace)?;
+        ops.extend(self.history.iter().cloned());
+        strokes.extend(self.strokes.iter().cloned());
+        Some((ops, strokes))
+    }
+
+    /// Give ops `take_cleared` gave back, once they're redrawn, back to whoever drew them first
+    /// rather than whoever undid the clear. They keep their new stroke ids
+    pub fn reattribute(&mut self, strokes: Vec<Stroke>) {
+        for (stroke, original) in self.strokes.iter_mut().rev().zip(strokes.into_iter().rev()) {
+            *stroke = Stroke { stroke_id: stroke.stroke_id, ..original };
+        }
+        self.recount();
+    }
+
+    /// Let go of a cleared board once it's past `grace`
+    pub fn forget_cleared(&mut self, grace: Duration) {
+        if self.cleared.as_ref().is_some_and(|(_, _, at)| at.elapsed() >= grace) {
+            self.cleared = None;
+        }
+    }
+}
+
+// One participant's segments on the board, oldest first, counted as `drawn_with` goes
+#[derive(Default)]
+struct Tally {
+    drawn: Drawn,
+    // Whether each segment carried on the one before it rather than starting a line
+    continues: VecDeque<bool>,
+    // Where their last segment ended
+    last: Option<[f64; 2]>,
+}
+
+impl Tally {
+    fn push(&mut self, segment: &DrawCommand) {
+        let continues = self.last == Some(segment.prev);
+        match continues {
+            true => self.drawn.points += 1,
+            false => {
+                self.drawn.strokes += 1;
+                self.drawn.points += 2;
+            }
+        }
+        self.continues.push_back(continues);
+        self.last = Some(segment.cur);
+    }
+
+    // Their oldest segment fell off the board, a line it started now starts at the next one.
+    // True once none are left
+    fn pop(&mut self) -> bool {
+        if self.continues.pop_front().is_some() {
+            self.drawn.strokes -= 1;
+            self.drawn.points -= 2;
+        }
+        if let Some(next) = self.continues.front_mut().filter(|next| **next) {
+            *next = false;
+            self.drawn.strokes += 1;
+            self.drawn.points += 1;
+        }
+        self.continues.is_empty()
+    }
+}
+
+/// Events per second over the last minute, counted in one-second buckets
+pub struct RateCounter {
+    start: Instant,
+    // (second since start, count) so stale buckets can be told apart from current ones
+    buckets: [(u64, u32); RATE_WINDOW_SECS as usize],
+}
+
+impl Default for RateCounter {
+    fn default() -> Self {
+        RateCounter {
+            start: Instant::now(),
+            buckets: [(0, 0); RATE_WINDOW_SECS as usize],
+        }
+    }
+}
+
+impl RateCounter {
+    pub fn record(&mut self, n: u32) {
+        let now = self.start.elapsed().as_secs();
+        let bucket = &mut self.buckets[(now % RATE_WINDOW_SECS) as usize];
+        if bucket.0 != now {
+            *bucket = (now, 0);
+        }
+        bucket.1 += n;
+    }
+
+    /// What was counted in the last whole second
+    pub fn last_second(&self) -> u32 {
+        let Some(last) = self.start.elapsed().as_secs().checked_sub(1) else {
+            return 0;
+        };
+        match self.buckets[(last % RATE_WINDOW_SECS) as usize] {
+            (sec, count) if sec == last => count,
+            _ => 0,
+        }
+    }
+
+    pub fn per_second(&self) -> f64 {
+        let now = self.start.elapsed().as_secs();
+        let total: u32 = self
+            .buckets
+            .iter()
+            .filter(|(sec, _)| now - sec < RATE_WINDOW_SECS)
+            .map(|(_, count)| count)
+            .sum();
+        total as f64 / (now + 1).min(RATE_WINDOW_SECS) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Composite;
+
+    fn segment(prev: [f64; 2], cur: [f64; 2]) -> DrawCommand {
+        DrawCommand { prev, cur, color: "#000".to_string(), brush_size: 2, composite: Composite::SourceOver }
+    }
+
+    // What `drawn_with` counted by going over the whole board
+    fn scanned(room: &Room, participant: UserId, draw: &DrawCommand) -> Drawn {
+        let mut drawn = Drawn::default();
+        let mut last = None;
+        let theirs = room.history.iter().zip(&room.strokes).filter(|(_, stroke)| stroke.user_id == Some(participant));
+        for segment in theirs.filter_map(|(op, _)| match op {
+            MessageType::Draw(segment) => Some(segment),
+            MessageType::Clear => None,
+        }).chain([draw]) {
+            if last == Some(segment.prev) {
+                drawn.points += 1;
+            } else {
+                drawn.strokes += 1;
+                drawn.points += 2;
+            }
+            last = Some(segment.cur);
+        }
+        drawn
+    }
+
+    #[test]
+    fn running_counts_match_the_board() {
+        let (a, b) = (UserId::random(), UserId::random());
+        let mut room = Room::new("room".to_string(), Vec::new());
+        let check = |room: &Room| {
+            for participant in [a, b] {
+                for draw in [segment([0.0, 0.0], [1.0, 0.0]), segment([5.0, 0.0], [6.0, 0.0])] {
+                    assert_eq!(room.drawn_with(participant, &draw), scanned(room, participant, &draw));
+                }
+            }
+        };
+        // Lines of three segments each, interleaved, with the board trimmed to 5 ops
+        for n in 0..12 {
+            let (user_id, x) = match n % 2 {
+                0 => (a, (n / 2 % 3) as f64),
+                _ => (b, 10.0 + (n / 2 % 3) as f64),
+            };
+            let stroke = Stroke { user_id: Some(user_id), ..Stroke::default() };
+            room.apply(&MessageType::Draw(segment([x, 0.0], [x + 1.0, 0.0])), stroke, 5);
+            check(&room);
+        }
+
+        let strokes = room.strokes.iter().map(|stroke| Stroke { user_id: Some(a), ..stroke.clone() }).collect();
+        room.attribute(strokes);
+        check(&room);
+
+        room.apply(&MessageType::Clear, Stroke { user_id: Some(a), ..Stroke::default() }, 5);
+        check(&room);
+        assert_eq!(room.drawn_with(a, &segment([0.0, 0.0], [1.0, 0.0])), Drawn { strokes: 1, points: 2 });
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+const MAX_RECURRING: usize = 10;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+// Far enough ahead for `0 0 29 2 *`, which can skip a leap year at a century
+const LOOKAHEAD_DAYS: u64 = 9 * 366;
+
+/// Something done to a room over and over, when `cron` says, with a `Scheduled` warning to
+/// everyone in it `rooms.schedule_warning_secs` before
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Recurring {
+    /// Minute, hour, day of month, month and day of week in UTC, e.g. `0 9 * * *` every day at 09:00
+    /// or `0 18 * * 5` on Fridays at 18:00. Each is `*`, a number, a range `a-b`, a step `*/n`
+    /// or `a-b/n`, or a list of those with commas; Sunday is 0 or 7
+    pub cron: String,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Clear the board, like a `Clear` from the room's bot
+    Clear,
+    /// Keep the board as an archived room `<room>-<yyyymmdd>-<hhmm>` and clear it
+    Archive,
+}
+
+/// A `Recurring::cron`, as sets of the minutes, hours and so on it fires at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Restricted day of month and day of week fields fire on either, like cron's
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{:?} has to be 5 fields: minute, hour, day of month, month and day of week", spec));
+        };
+        let weekdays = field(weekdays, 0, 7, "day of week")?;
+        Ok(Cron {
+            minutes: field(minutes, 0, 59, "minute")?,
+            hours: field(hours, 0, 23, "hour")?,
+            days: field(days, 1, 31, "day of month")?,
+            months: field(months, 1, 12, "month")?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: days.starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// The first unix second after `after` this fires at, always the start of a minute. None if
+    /// it never does, e.g. on the 31st of February
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start = (after / 60 + 1) * 60;
+        let first_day = start / DAY_SECS;
+        for day in first_day..first_day + LOOKAHEAD_DAYS {
+            if !self.fires_on(day) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                    let at = day * DAY_SECS + hour * 3600 + minute * 60;
+                    if at >= start {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Whether it fires on `day`, in days since 1970-01-01
+    fn fires_on(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = civil(day);
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        // 1970-01-01 was a Thursday
+        let on_day = self.days & 1 << day_of_month != 0;
+        let on_weekday = self.weekdays & 1 << ((day + 4) % 7) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => on_day || on_weekday,
+            _ => on_day && on_weekday,
+        }
+    }
+}
+
+// One comma separated field as a bit per allowed value
+fn field(spec: &str, min: u64, max: u64, name: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid {} {:?}, it takes {}-{}", name, spec, min, max);
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().ok().filter(|&step| step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (from.parse().map_err(|_| invalid())?, to.parse().map_err(|_| invalid())?),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    // `5/15` is 5, 20, 35 and 50
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Year, month and day of the month of `day`, in days since 1970-01-01
+pub fn civil(day: u64) -> (u64, u64, u64) {
+    // From Howard Hinnant's `civil_from_days`, for days on or after the epoch
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day_of_month = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day_of_month)
+}
+
+/// Checked and without duplicates, or why it can't be used
+pub fn normalize(schedule: Vec<Recurring>) -> Result<Vec<Recurring>, String> {
+    let mut normalized: Vec<Recurring> = Vec::new();
+    for recurring in schedule {
+        let cron = recurring.cron.split_whitespace().collect::<Vec<_>>().join(" ");
+        let parsed = Cron::parse(&cron)?;
+        if parsed.next_after(0).is_none() {
+            return Err(format!("{:?} never fires", cron));
+        }
+        let recurring = Recurring { cron, ..recurring };
+        if !normalized.contains(&recurring) {
+            normalized.push(recurring);
+        }
+    }
+    if normalized.len() > MAX_RECURRING {
+        return Err(format!("rooms have at most {} scheduled actions", MAX_RECURRING));
+    }
+    Ok(normalized)
+}
+
+/// Where a board archived by `Action::Archive` at `at` (unix seconds) is kept
+pub fn archived_id(room: &str, at: u64) -> String {
+    let (year, month, day) = civil(at / DAY_SECS);
+    let stamp = format!("-{:04}{:02}{:02}-{:02}{:02}", year, month, day, at % DAY_SECS / 3600, at % 3600 / 60);
+    // Within `hub::valid_room_id`'s length, a tenant's prefix is checked apart from the room
+    let (prefix, name) = match room.rsplit_once('.') {
+        Some((prefix, name)) => (format!("{}.", prefix), name),
+        None => (String::new(), room),
+    };
+    let keep = name.len().min(64 - stamp.len());
+    format!("{}{}{}", prefix, &name[..keep], stamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-15, a Thursday, at 08:30 UTC
+    const THURSDAY: u64 = 1_792_053_000;
+
+    #[test]
+    fn fires_at_the_next_matching_minute() {
+        let daily = Cron::parse("0 9 * * *").unwrap();
+        assert_eq!(daily.next_after(THURSDAY), Some(THURSDAY + 30 * 60));
+        assert_eq!(daily.next_after(THURSDAY + 30 * 60), Some(THURSDAY + 30 * 60 + DAY_SECS));
+
+        let fridays = Cron::parse("0 18 * * 5").unwrap();
+        assert_eq!(fridays.next_after(THURSDAY), Some(THURSDAY + DAY_SECS + 9 * 3600 + 30 * 60));
+        assert_eq!(Cron::parse("0 18 * * 7").unwrap(), Cron::parse("0 18 * * 0").unwrap());
+
+        let quarterly = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(quarterly.next_after(THURSDAY), Some(THURSDAY + 15 * 60));
+    }
+
+    #[test]
+    fn day_of_month_or_weekday() {
+        // The 1st or any Monday, 2026-10-19 is the next Monday
+        let cron = Cron::parse("0 0 1 * 1").unwrap();
+        assert_eq!(cron.next_after(THURSDAY), Some(THURSDAY - 8 * 3600 - 30 * 60 + 4 * DAY_SECS));
+    }
+
+    #[test]
+    fn bad_specs_are_refused() {
+        for spec in ["", "0 9 * *", "60 9 * * *", "0 24 * * *", "0 9 0 * *", "0 9 * 13 *", "0 9 * * 8", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(Cron::parse(spec).is_err(), "{:?}", spec);
+        }
+        assert!(normalize(vec![Recurring { cron: "0 0 31 2 *".to_string(), action: Action::Clear }]).is_err());
+    }
+
+    #[test]
+    fn archived_boards_get_a_dated_id() {
+        assert_eq!(archived_id("class", THURSDAY), "class-20261015-0830");
+        assert_eq!(archived_id("acme.class", THURSDAY), "acme.class-20261015-0830");
+        assert_eq!(archived_id(&"a".repeat(64), THURSDAY).len(), 64);
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Scope, AST, INT};
+
+use crate::config::ScriptingConfig;
+use crate::hooks::{Hook, Outcome};
+use crate::ids::UserId;
+use crate::protocol::MessageType;
+
+const CALLBACKS: [&str; 4] = ["on_message", "on_join", "on_room_create", "on_tick"];
+
+/// A Rhai script defining any of
+/// - `on_message(room, user_id, op)`: return nothing or `true` to keep the op, a changed op to replace
+///   it, or `false` or a reason string to reject it
+/// - `on_join(room, user_id)`, `on_room_create(room)` and `on_tick(room)`, called about once a minute
+///
+/// Every callback can call `emit(op)` to have the room's bot draw an op, ops being maps shaped like
+/// the JSON frames, e.g. `#{type: "Clear"}`. `unix_time()` returns the time in seconds and `print`
+/// logs. Scripts can't import modules, and each callback is stopped after `scripting.max_operations`
+pub fn load(path: &Path, config: &ScriptingConfig) -> Result<Script, String> {
+    let emitted = Arc::new(Mutex::new(Vec::new()));
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(config.max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 << 10)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval");
+    engine.on_print(|s| log::info!("script: {}", s));
+    engine.on_debug(|s, _, pos| log::debug!("script {}: {}", pos, s));
+
+    let queue = emitted.clone();
+    engine.register_fn("emit", move |op: Dynamic| -> Result<(), Box<EvalAltResult>> {
+        queue.lock().unwrap().push(from_dynamic::<MessageType>(&op)?);
+        Ok(())
+    });
+    engine.register_fn("unix_time", || -> INT {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as INT).unwrap_or_default()
+    });
+
+    let ast = engine.compile_file(path.to_path_buf()).map_err(|e| e.to_string())?;
+    // Top-level statements run once, to catch errors at startup
+    engine.run_ast(&ast).map_err(|e| e.to_string())?;
+    emitted.lock().unwrap().clear();
+
+    let defined: Vec<&'static str> = CALLBACKS.into_iter().filter(|name| ast.iter_functions().any(|f| f.name == *name)).collect();
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    log::info!("Loaded script {} with {}", name, defined.join(", "));
+    Ok(Script { name, engine, ast, defined, emitted, calls: Mutex::new(()) })
+}
+
+pub struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    defined: Vec<&'static str>,
+    // What `emit` collected during the current callback
+    emitted: Arc<Mutex<Vec<MessageType>>>,
+    // One callback at a time, so each one's emits are its own
+    calls: Mutex<()>,
+}
+
+impl Script {
+    /// What the callback returned and emitted, unit if the script doesn't define it
+    fn call(&self, name: &str, args: impl FuncArgs) -> Result<(Dynamic, Vec<MessageType>), String> {
+        if !self.defined.contains(&name) {
+            return Ok((Dynamic::UNIT, Vec::new()));
+        }
+        let _call = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, args);
+        let emitted = std::mem::take(&mut *self.emitted.lock().unwrap());
+        result.map(|value| (value, emitted)).map_err(|e| e.to_string())
+    }
+}
+
+impl Hook for Script {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_message(&self, room: &str, user_id: UserId, op: &MessageType) -> Result<Outcome, String> {
+        let op = to_dynamic(op).map_err(|e| e.to_string())?;
+        let (value, emit) = self.call("on_message", (room.to_string(), user_id.to_string(), op))?;
+        let mut outcome = Outcome { emit, ..Outcome::default() };
+        if value.is_string() {
+            outcome.reject = value.into_string().ok();
+        } else if let Ok(keep) = value.as_bool() {
+            outcome.reject = (!keep).then(|| "rejected by script".to_string());
+        } else if !value.is_unit() {
+            outcome.op = Some(from_dynamic(&value).map_err(|e| format!("invalid op returned: {}", e))?);
+        }
+        Ok(outcome)
+    }
+
+    fn on_join(&self, room: &str, user_id: UserId) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_join", (room.to_string(), user_id.to_string()))?.1)
+    }
+
+    fn on_room_create(&self, room: &str) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_room_create", (room.to_string(),))?.1)
+    }
+
+    fn on_tick(&self, room: &str) -> Result<Vec<MessageType>, String> {
+        Ok(self.call("on_tick", (room.to_string(),))?.1)
+    }
+}
@@ -0,0 +1,199 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+
+use crate::config::EncryptionConfig;
+
+// Starts every sealed file, JSON never does
+const MAGIC: &[u8; 4] = b"WBE1";
+// Which key sealed it, the start of the key's SHA-256 so keys need no names
+const KEY_ID_LEN: usize = 4;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+/// AES-256-GCM keys for what's kept on disk, see `storage.encryption`. Seals with the current
+/// key and opens with it or any previous one, so keys can be rotated without rewriting everything
+/// at once. Unsealed data is read as it is, so encryption can be turned on for existing storage
+pub struct Keyring {
+    // The current key first
+    keys: Vec<([u8; KEY_ID_LEN], LessSafeKey)>,
+    random: SystemRandom,
+}
+
+impl Keyring {
+    /// `None` when no key is configured
+    pub fn new(config: &EncryptionConfig) -> Result<Option<Keyring>, String> {
+        if config.key.is_empty() {
+            return match config.previous_keys.is_empty() {
+                true => Ok(None),
+                false => Err("storage.encryption.previous_keys is set without a key".to_string()),
+            };
+        }
+        let keys = std::iter::once(&config.key).chain(&config.previous_keys).map(|key| parse_key(key)).collect::<Result<_, _>>()?;
+        Ok(Some(Keyring { keys, random: SystemRandom::new() }))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let (id, key) = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random.fill(&mut nonce).expect("the system random source works");
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + AES_256_GCM.tag_len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(id);
+        sealed.extend_from_slice(&nonce);
+        let mut body = plaintext.to_vec();
+        // Only fails for inputs far past anything stored
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(MAGIC), &mut body).expect("sealable length");
+        sealed.extend_from_slice(&body);
+        sealed
+    }
+
+    /// What `seal` sealed with any of the keys, or `bytes` as they are if they weren't sealed
+    pub fn open(&self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !is_sealed(&bytes) {
+            return Ok(bytes);
+        }
+        let (header, body) = bytes.split_at(HEADER_LEN);
+        let id = &header[MAGIC.len()..MAGIC.len() + KEY_ID_LEN];
+        let Some((_, key)) = self.keys.iter().find(|(key_id, _)| key_id == id) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("sealed with key {}, which isn't configured", hex::encode(id))));
+        };
+        let nonce = Nonce::try_assume_unique_for_key(&header[MAGIC.len() + KEY_ID_LEN..]).expect("header holds a nonce");
+        let mut body = body.to_vec();
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(MAGIC), &mut body)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "sealed data was changed or is truncated"))?;
+        Ok(plaintext.to_vec())
+    }
+
+    /// Whether `bytes` should be sealed again, they aren't or not with the current key
+    pub fn is_stale(&self, bytes: &[u8]) -> bool {
+        !is_sealed(bytes) || bytes[MAGIC.len()..MAGIC.len() + KEY_ID_LEN] != self.keys[0].0
+    }
+
+    /// A line of the op log, sealed and in base64 so it stays one line
+    pub fn seal_line(&self, line: &[u8]) -> Vec<u8> {
+        STANDARD.encode(self.seal(line)).into_bytes()
+    }
+
+    /// A line `seal_line` wrote, or one of JSON as it is
+    pub fn open_line(&self, line: &str) -> io::Result<String> {
+        if line.starts_with('{') {
+            return Ok(line.to_string());
+        }
+        let sealed = STANDARD.decode(line.trim()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        String::from_utf8(self.open(sealed)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Whether `bytes` were written by `Keyring::seal`
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_LEN && bytes.starts_with(MAGIC)
+}
+
+// 64 hex digits, see `storage.encryption.key`
+fn parse_key(key: &str) -> Result<([u8; KEY_ID_LEN], LessSafeKey), String> {
+    let bytes = hex::decode(key.trim()).ok().filter(|bytes| bytes.len() == AES_256_GCM.key_len());
+    let Some(bytes) = bytes else {
+        return Err("storage.encryption keys are 64 hex digits, e.g. from `openssl rand -hex 32`".to_string());
+    };
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&Sha256::digest(&bytes)[..KEY_ID_LEN]);
+    let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| "invalid AES-256-GCM key".to_string())?;
+    Ok((id, LessSafeKey::new(key)))
+}
+
+/// Seal every file in a storage directory, and the lines of an op log, that isn't sealed with the
+/// current key yet, after a key was added or rotated. Returns how many files were rewritten
+pub async fn reseal(keyring: &Keyring, dir: Option<&Path>, op_log: Option<&Path>) -> io::Result<usize> {
+    let mut resealed = 0;
+    // Tenants' rooms are a directory down, see `FileStorage::file`
+    let mut dirs: Vec<PathBuf> = dir.into_iter().map(Path::to_path_buf).collect();
+    let top = dirs.first().cloned();
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if Some(&dir) == top.as_ref() && entry.file_type().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let bytes = tokio::fs::read(&path).await?;
+            if !keyring.is_stale(&bytes) {
+                continue;
+            }
+            let sealed = keyring.seal(&keyring.open(bytes)?);
+            replace(&path, sealed).await?;
+            resealed += 1;
+        }
+    }
+    if let Some(path) = op_log.filter(|path| path.exists()) {
+        let log = tokio::fs::read_to_string(path).await?;
+        let mut sealed = Vec::with_capacity(log.len());
+        for line in log.lines().filter(|line| !line.trim().is_empty()) {
+            sealed.extend_from_slice(&keyring.seal_line(keyring.open_line(line)?.as_bytes()));
+            sealed.push(b'\n');
+        }
+        replace(path, sealed).await?;
+        resealed += 1;
+    }
+    Ok(resealed)
+}
+
+// Write then rename, as `FileStorage` does
+async fn replace(path: &Path, bytes: Vec<u8>) -> io::Result<()> {
+    let tmp = path.with_extension("sealing.tmp");
+    tokio::fs::write(&tmp, bytes).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff000102030405060708090a0b0c0d0e0f";
+
+    fn keyring(key: &str, previous_keys: &[&str]) -> Keyring {
+        let config = EncryptionConfig { key: key.to_string(), previous_keys: previous_keys.iter().map(|k| k.to_string()).collect() };
+        Keyring::new(&config).unwrap().unwrap()
+    }
+
+    #[test]
+    fn sealed_data_opens_after_a_rotation_but_not_without_its_key() {
+        let board = br#"[{"type":"Draw","data":{"color":"red"}}]"#;
+        let old = keyring(OLD, &[]);
+        let sealed = old.seal(board);
+        assert!(!sealed.windows(3).any(|w| w == b"red"));
+        assert_eq!(old.open(sealed.clone()).unwrap(), board);
+
+        let rotated = keyring(NEW, &[OLD]);
+        assert!(rotated.is_stale(&sealed));
+        assert_eq!(rotated.open(sealed.clone()).unwrap(), board);
+        assert!(!rotated.is_stale(&rotated.seal(board)));
+        assert!(keyring(NEW, &[]).open(sealed.clone()).is_err());
+
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(old.open(tampered).is_err());
+        // Written before encryption was turned on
+        assert_eq!(old.open(board.to_vec()).unwrap(), board);
+    }
+
+    #[test]
+    fn op_log_lines_stay_lines() {
+        let keyring = keyring(OLD, &[]);
+        let line = r#"{"room":"default","seq":1}"#;
+        let sealed = String::from_utf8(keyring.seal_line(line.as_bytes())).unwrap();
+        assert!(!sealed.contains('\n') && !sealed.starts_with('{'));
+        assert_eq!(keyring.open_line(&sealed).unwrap(), line);
+        assert_eq!(keyring.open_line(line).unwrap(), line);
+    }
+}
@@ -0,0 +1,374 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::config::ConfigHandle;
+use crate::events::now_millis;
+use crate::hub::Hub;
+use crate::ids::{random_token, UserId};
+use crate::protocol::{Member, MessageType};
+use crate::room::SharedRoom;
+use crate::socket;
+
+// Other nodes lock the database for as long as a write takes, which is never this long
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// How often each node says who's in its rooms, and how long until someone it stopped saying is
+// there is taken to have left, e.g. when the node went down
+const ROSTER_INTERVAL: Duration = Duration::from_secs(1);
+const MEMBER_TTL_MS: i64 = 10_000;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS rooms (
+        id TEXT PRIMARY KEY,
+        seq INTEGER NOT NULL,
+        epoch INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS ops (
+        room TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        epoch INTEGER NOT NULL,
+        node TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        op TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        PRIMARY KEY (room, seq)
+    );
+    CREATE TABLE IF NOT EXISTS members (
+        room TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        node TEXT NOT NULL,
+        member TEXT NOT NULL,
+        seen_at INTEGER NOT NULL,
+        PRIMARY KEY (room, user_id)
+    );
+";
+
+/// An op as some node appended it
+pub struct SharedOp {
+    pub seq: u64,
+    pub epoch: u64,
+    pub user_id: UserId,
+    pub op: MessageType,
+    /// Unix milliseconds
+    pub at: u64,
+    /// Appended by this node, which applied it already
+    pub own: bool,
+}
+
+/// Each room's seqs and epochs, the ops on its board and who's in it on which node, kept where
+/// every node sees them. Seqs are handed out in the same transaction that appends the op, so two
+/// nodes never give out the same one
+#[async_trait]
+pub trait SharedState: Send + Sync {
+    /// A room's board and where its seq and epoch are at. A room no node had yet starts from
+    /// `history`, what storage had for it
+    async fn load(&self, room: &str, history: &[MessageType]) -> io::Result<(Vec<MessageType>, u64, u64)>;
+
+    /// Give an op the room's next seq and keep it for the other nodes, returning that seq and the
+    /// epoch it's in, the next one for a clear. Ops from before the last clear, and more than
+    /// `history_limit` ago, are let go of
+    async fn append(&self, room: &str, op: &MessageType, user_id: UserId, history_limit: usize) -> io::Result<(u64, u64)>;
+
+    /// Ops in a room after `seq`, in order
+    async fn since(&self, room: &str, seq: u64) -> io::Result<Vec<SharedOp>>;
+
+    /// Say who's in a room on this node, answered with who's in it on the others
+    async fn share_members(&self, room: &str, members: &[Member]) -> io::Result<Vec<Member>>;
+
+    /// Drop everything kept for a room, for one that's gone for good
+    async fn forget(&self, room: &str) -> io::Result<()>;
+}
+
+/// Shared state in a SQLite database, for nodes on one machine or sharing a volume with working
+/// file locks
+pub struct SqliteState {
+    db: Arc<Mutex<Connection>>,
+    // Picked per process, rows a node wrote are told apart by it
+    node: String,
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+}
+
+impl SqliteState {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let db = Connection::open(path)?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        db.execute_batch(SCHEMA)?;
+        Ok(SqliteState { db: Arc::new(Mutex::new(db)), node: random_token()[..16].to_string() })
+    }
+
+    // Waiting on another node's lock blocks for up to `BUSY_TIMEOUT`, so not on the runtime's workers
+    async fn blocking<T: Send + 'static>(&self, work: impl FnOnce(&mut Connection, &str) -> rusqlite::Result<T> + Send + 'static) -> io::Result<T> {
+        let (db, node) = (self.db.clone(), self.node.clone());
+        tokio::task::spawn_blocking(move || work(&mut db.lock().unwrap(), &node)).await.map_err(io::Error::other)?.map_err(io::Error::other)
+    }
+}
+
+#[async_trait]
+impl SharedState for SqliteState {
+    async fn load(&self, room: &str, history: &[MessageType]) -> io::Result<(Vec<MessageType>, u64, u64)> {
+        let (room, history) = (room.to_string(), history.to_vec());
+        self.blocking(move |db, node| {
+            let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let counters: Option<(u64, u64)> = tx
+                .query_row("SELECT seq, epoch FROM rooms WHERE id = ?1", params![room], |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)))
+                .optional()?;
+            let (seq, epoch) = match counters {
+                Some(counters) => counters,
+                None => {
+                    let at = now_millis() as i64;
+                    for (n, op) in history.iter().enumerate() {
+                        let op = serde_json::to_string(op).map_err(invalid)?;
+                        tx.execute(
+                            // Nobody in particular drew what storage had
+                            "INSERT INTO ops (room, seq, epoch, node, user_id, op, at) VALUES (?1, ?2, 0, ?3, '0000000000000000', ?4, ?5)",
+                            params![room, n as i64 + 1, node, op, at],
+                        )?;
+                    }
+                    tx.execute("INSERT INTO rooms (id, seq, epoch) VALUES (?1, ?2, 0)", params![room, history.len() as i64])?;
+                    (history.len() as u64, 0)
+                }
+            };
+            let board = {
+                let mut statement = tx.prepare("SELECT op FROM ops WHERE room = ?1 AND epoch = ?2 ORDER BY seq")?;
+                let ops = statement.query_map(params![room, epoch as i64], |row| row.get::<_, String>(0))?;
+                let ops = ops.map(|op| serde_json::from_str(&op?).map_err(invalid)).collect::<rusqlite::Result<Vec<MessageType>>>()?;
+                // The clear that started the epoch
+                ops.into_iter().filter(|op| !matches!(op, MessageType::Clear)).collect()
+            };
+            tx.commit()?;
+            Ok((board, seq, epoch))
+        })
+        .await
+    }
+
+    async fn append(&self, room: &str, op: &MessageType, user_id: UserId, history_limit: usize) -> io::Result<(u64, u64)> {
+        let json = serde_json::to_string(op)?;
+        let clears = matches!(op, MessageType::Clear) as i64;
+        let room = room.to_string();
+        self.blocking(move |db, node| {
+            let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let (seq, epoch): (u64, u64) = tx.query_row(
+                "INSERT INTO rooms (id, seq, epoch) VALUES (?1, 1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET seq = seq + 1, epoch = epoch + ?2
+                 RETURNING seq, epoch",
+                params![room, clears],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )?;
+            tx.execute(
+                "INSERT INTO ops (room, seq, epoch, node, user_id, op, at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![room, seq as i64, epoch as i64, node, user_id.to_string(), json, now_millis() as i64],
+            )?;
+            tx.execute(
+                "DELETE FROM ops WHERE room = ?1 AND (epoch < ?2 OR seq <= ?3)",
+                params![room, epoch as i64, seq.saturating_sub(history_limit as u64) as i64],
+            )?;
+            tx.commit()?;
+            Ok((seq, epoch))
+        })
+        .await
+    }
+
+    async fn since(&self, room: &str, seq: u64) -> io::Result<Vec<SharedOp>> {
+        let room = room.to_string();
+        self.blocking(move |db, node| {
+            let mut statement = db.prepare("SELECT seq, epoch, node, user_id, op, at FROM ops WHERE room = ?1 AND seq > ?2 ORDER BY seq")?;
+            let rows = statement.query_map(params![room, seq as i64], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?, row.get::<_, i64>(5)? as u64))
+            })?;
+            let mut ops = Vec::new();
+            for row in rows {
+                let (seq, epoch, from, user_id, op, at) = row?;
+                ops.push(SharedOp {
+                    seq,
+                    epoch,
+                    user_id: user_id.parse().map_err(|e: String| invalid(io::Error::other(e)))?,
+                    op: serde_json::from_str(&op).map_err(invalid)?,
+                    at,
+                    own: from == node,
+                });
+            }
+            Ok(ops)
+        })
+        .await
+    }
+
+    async fn share_members(&self, room: &str, members: &[Member]) -> io::Result<Vec<Member>> {
+        let (room, members) = (room.to_string(), members.to_vec());
+        self.blocking(move |db, node| {
+            let now = now_millis() as i64;
+            let tx = db.transaction()?;
+            tx.execute("DELETE FROM members WHERE room = ?1 AND node = ?2", params![room, node])?;
+            for member in &members {
+                let json = serde_json::to_string(member).map_err(invalid)?;
+                tx.execute(
+                    "INSERT INTO members (room, user_id, node, member, seen_at) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (room, user_id) DO UPDATE SET node = ?3, member = ?4, seen_at = ?5",
+                    params![room, member.user_id.to_string(), node, json, now],
+                )?;
+            }
+            tx.execute("DELETE FROM members WHERE seen_at < ?1", params![now - MEMBER_TTL_MS])?;
+            let others = {
+                let mut statement = tx.prepare("SELECT member FROM members WHERE room = ?1 AND node != ?2 ORDER BY user_id")?;
+                let rows = statement.query_map(params![room, node], |row| row.get::<_, String>(0))?;
+                rows.map(|member| serde_json::from_str(&member?).map_err(invalid)).collect::<rusqlite::Result<Vec<Member>>>()?
+            };
+            tx.commit()?;
+            Ok(others)
+        })
+        .await
+    }
+
+    async fn forget(&self, room: &str) -> io::Result<()> {
+        let room = room.to_string();
+        self.blocking(move |db, _| {
+            let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            for table in ["ops", "members"] {
+                tx.execute(&format!("DELETE FROM {} WHERE room = ?1", table), params![room])?;
+            }
+            tx.execute("DELETE FROM rooms WHERE id = ?1", params![room])?;
+            tx.commit()
+        })
+        .await
+    }
+}
+
+/// Every `shared_state.poll_ms`, relay to each resident room what was drawn in it on other nodes,
+/// and every `ROSTER_INTERVAL` who's come and gone there
+pub async fn sync(hub: Arc<Hub>, shared: Arc<dyn SharedState>, config: ConfigHandle) {
+    let mut roster_synced = Instant::now();
+    loop {
+        let (poll, history_limit) = {
+            let config = config.borrow();
+            (Duration::from_millis(config.shared_state.poll_ms.max(1)), config.rooms.history_limit)
+        };
+        tokio::time::sleep(poll).await;
+        let roster = roster_synced.elapsed() >= ROSTER_INTERVAL;
+        if roster {
+            roster_synced = Instant::now();
+        }
+        for room in hub.rooms().await {
+            sync_room(shared.as_ref(), &room, history_limit, roster).await;
+        }
+    }
+}
+
+// The database is read with the room unlocked, so a slow one doesn't hold up drawing in it. Ops
+// another sync already applied are skipped
+async fn sync_room(shared: &dyn SharedState, room: &SharedRoom, history_limit: usize, roster: bool) {
+    let (id, synced_seq) = {
+        let room = room.read().await;
+        (room.id.clone(), room.synced_seq)
+    };
+    match shared.since(&id, synced_seq).await {
+        Ok(ops) => {
+            let mut room = room.write().await;
+            for op in ops {
+                if op.seq <= room.synced_seq {
+                    continue;
+                }
+                room.synced_seq = op.seq;
+                if !op.own {
+                    socket::draw_shared(&mut room, op, history_limit);
+                }
+            }
+        }
+        Err(e) => log::warn!("Could not read shared ops of room {}: {}", id, e),
+    }
+    if !roster {
+        return;
+    }
+    let mut members: Vec<Member> = room.read().await.users.values().map(|peer| peer.member()).collect();
+    members.sort_by_key(|member| member.user_id);
+    members.dedup_by_key(|member| member.user_id);
+    match shared.share_members(&id, &members).await {
+        Ok(others) => socket::remote_members(&mut *room.write().await, others),
+        Err(e) => log::warn!("Could not share the roster of room {}: {}", id, e),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{Composite, DrawCommand, Presence, Profile};
+
+    fn database(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ws-demo-shared-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn draw(n: u32) -> MessageType {
+        MessageType::Draw(DrawCommand { prev: [0.0, 0.0], cur: [n as f64, 0.0], color: "#000000".to_string(), brush_size: 1, composite: Composite::SourceOver })
+    }
+
+    // Ops compare by their JSON
+    async fn load(shared: &dyn SharedState, history: &[MessageType]) -> (serde_json::Value, u64, u64) {
+        let (board, seq, epoch) = shared.load("room", history).await.unwrap();
+        (serde_json::to_value(board).unwrap(), seq, epoch)
+    }
+
+    fn board(ops: &[MessageType]) -> serde_json::Value {
+        serde_json::to_value(ops).unwrap()
+    }
+
+    /// Two nodes on one backend hand out seqs in turn and load the same board
+    pub async fn share_seqs_and_boards(a: &dyn SharedState, b: &dyn SharedState) {
+        let user = UserId::random();
+        assert_eq!(load(a, &[draw(1)]).await, (board(&[draw(1)]), 1, 0));
+        assert_eq!(load(b, &[]).await, (board(&[draw(1)]), 1, 0));
+
+        assert_eq!(a.append("room", &draw(2), user, 100).await.unwrap(), (2, 0));
+        assert_eq!(b.append("room", &draw(3), user, 100).await.unwrap(), (3, 0));
+        let seen: Vec<(u64, bool)> = a.since("room", 1).await.unwrap().iter().map(|op| (op.seq, op.own)).collect();
+        assert_eq!(seen, [(2, true), (3, false)]);
+
+        assert_eq!(b.append("room", &MessageType::Clear, user, 100).await.unwrap(), (4, 1));
+        assert_eq!(a.append("room", &draw(4), user, 100).await.unwrap(), (5, 1));
+        assert_eq!(load(b, &[]).await, (board(&[draw(4)]), 5, 1));
+    }
+
+    /// Two nodes on one backend see who's in a room on the other
+    pub async fn see_each_others_members(a: &dyn SharedState, b: &dyn SharedState) {
+        let member = |name: &str| Member {
+            user_id: UserId::random(),
+            profile: Profile { name: Some(name.to_string()), ..Profile::default() },
+            hand_raised_at: None,
+            reaction: None,
+            presence: Presence::Active,
+            latency_ms: None,
+            voice: None,
+            account: None,
+            agent: None,
+            is_bot: false,
+        };
+        let alice = member("alice");
+        assert!(a.share_members("room", std::slice::from_ref(&alice)).await.unwrap().is_empty());
+        assert_eq!(b.share_members("room", &[]).await.unwrap(), [alice]);
+        assert!(a.share_members("room", &[]).await.unwrap().is_empty());
+        assert!(b.share_members("room", &[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nodes_share_seqs_and_boards() {
+        let path = database("seqs");
+        let (a, b) = (SqliteState::open(&path).unwrap(), SqliteState::open(&path).unwrap());
+        share_seqs_and_boards(&a, &b).await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn nodes_see_each_others_members() {
+        let path = database("members");
+        let (a, b) = (SqliteState::open(&path).unwrap(), SqliteState::open(&path).unwrap());
+        see_each_others_members(&a, &b).await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,1880 @@
+use futures_util::stream::SplitStream;
+use futures_util::{FutureExt, SinkExt, StreamExt, TryFutureExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::http::StatusCode;
+use serde_json::Value;
+use warp::ws::{Message, WebSocket, Ws};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::cluster;
+use crate::codec;
+use crate::config::{BotConfig, ConfigHandle, LimitsConfig, ModerationConfig};
+use crate::connection::{self, ConnectionStats, DisconnectReason, Peer};
+use crate::events::{now_millis, CorrelationId, OpRecord, ServerEvent};
+use crate::features::Features;
+use crate::filters::{Filters, Outbound};
+use crate::hub::{is_archived, Hub};
+use crate::ids::UserId;
+use crate::moderation::{self, Kind};
+use crate::notes;
+use crate::permissions::{Permissions, Tool};
+use crate::profiles::{self, unique_name};
+use crate::protocol::{ClientFrame, ControlMessage, DrawCommand, Echoed, Invalid, Member, MessageType, Presence, Role, ServerMessage, Stamped};
+use crate::reporting;
+use crate::reports;
+use crate::room::{Room, RoomUpdate, SharedRoom, Stroke, Waiter};
+use crate::shared::SharedOp;
+use crate::tenants;
+use crate::trial;
+
+// Segments from one sender further apart in time than this are separate strokes
+const STROKE_PAUSE: Duration = Duration::from_millis(500);
+// How far someone's round trip has to move before everyone is told, so jitter doesn't flood rooms
+const LATENCY_STEP_MS: u32 = 25;
+
+/// Close code after `Redirect`, for a room owned by another cluster node
+pub const REDIRECT_CODE: u16 = 4307;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn upgrade(
+    tenant: Option<String>,
+    room_id: String,
+    ws: Ws,
+    origin: Option<String>,
+    query: HashMap<String, String>,
+    remote_addr: Option<SocketAddr>,
+    hub: Arc<Hub>,
+    config: ConfigHandle,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let current = config.borrow().clone();
+
+    let Some(room_id) = hub.room_id(&room_id) else {
+        return Ok(Box::new(warp::reply::with_status("invalid room id", StatusCode::BAD_REQUEST)));
+    };
+
+    let allowed = &current.server.allowed_origins;
+    if !allowed.is_empty() && !origin.as_ref().is_some_and(|o| allowed.contains(o)) {
+        log::warn!("Rejected upgrade from origin {:?}", origin);
+        return Ok(Box::new(warp::reply::with_status("origin not allowed", StatusCode::FORBIDDEN)));
+    }
+
+    let key = query.get("key").map(String::as_str);
+    let tenant = match tenants::resolve(&current, tenant.as_deref(), key) {
+        Ok(tenant) => tenant,
+        Err(refusal) => return Ok(Box::new(warp::reply::with_status(refusal.message(), refusal.status()))),
+    };
+    // From here on the room is known by the id it has in the hub and storage
+    let room_id = match tenant {
+        Some(tenant) => tenant.scope(&room_id),
+        None => room_id,
+    };
+
+    // The owner checks everything else
+    let dead_after = Duration::from_secs(current.cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&room_id, dead_after) {
+        let url = cluster::join_url(&owner, &tenants::join_path(&current, &*hub.public_ids, &room_id));
+        return Ok(Box::new(ws.on_upgrade(move |socket| redirect(socket, url))));
+    }
+
+    // A join link stands in for both the access key and the access list
+    let role = match query.get("link") {
+        Some(link) => match hub.links.verify(&current.links.secret, &room_id, link) {
+            Some(role) => role,
+            None => return Ok(Box::new(warp::reply::with_status("invalid or expired link", StatusCode::FORBIDDEN))),
+        },
+        None => {
+            // A tenant's keys were checked already, and are the only ones for its rooms
+            let keys = &current.auth.access_keys;
+            if tenant.is_none() && !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+                return Ok(Box::new(warp::reply::with_status("missing or invalid key", StatusCode::UNAUTHORIZED)));
+            }
+            if !hub.may_access(&room_id, query.get("token").map(String::as_str)) {
+                return Ok(Box::new(warp::reply::with_status("not on the room's access list", StatusCode::FORBIDDEN)));
+            }
+            Role::Editor
+        }
+    };
+
+    let ws = ws
+        .max_message_size(current.limits.max_message_bytes)
+        .max_frame_size(current.limits.max_message_bytes);
+
+    if let Err(e) = hub.quotas.check_join(&room_id, &hub.usage.room(&room_id)) {
+        log::info!("Refused join to room {}: {}", room_id, e);
+        return Ok(Box::new(warp::reply::with_status(e.to_string(), StatusCode::TOO_MANY_REQUESTS)));
+    }
+
+    let room = match hub.open(&room_id).await {
+        Ok(room) => room,
+        Err(e) if is_archived(&e) => return Ok(Box::new(warp::reply::with_status("room is archived", StatusCode::GONE))),
+        Err(e) => {
+            log::error!("Could not load room {}: {}", room_id, e);
+            return Ok(Box::new(warp::reply::with_status("room unavailable", StatusCode::SERVICE_UNAVAILABLE)));
+        }
+    };
+    if let Some(opens_at) = room.read().await.info.opens_later() {
+        return Ok(Box::new(ws.on_upgrade(move |socket| not_yet_open(socket, opens_at))));
+    }
+    let is_bot = query.get("bot").is_some_and(|bot| bot == "1" || bot == "true");
+    // Checked again as they're let in, where it holds
+    if is_bot && too_many_bots(&*room.read().await, &current.bots) {
+        return Ok(Box::new(warp::reply::with_status("too many bots in the room", StatusCode::TOO_MANY_REQUESTS)));
+    }
+    let takes_slot = !(is_bot && current.bots.exclude_from_capacity);
+    if !current.rooms.waitlist && takes_slot && is_full(&hub, &room, trial::capacity_of(&current, &room_id)).await {
+        return Ok(Box::new(warp::reply::with_status("room is full", StatusCode::TOO_MANY_REQUESTS)));
+    }
+    let Some(pending) = PendingSlot::take(&hub, current.limits.max_pending_connections) else {
+        log::warn!("Refused an upgrade to room {}, {} sockets have yet to send a frame", room_id, current.limits.max_pending_connections);
+        return Ok(Box::new(warp::reply::with_status("too many pending connections", StatusCode::SERVICE_UNAVAILABLE)));
+    };
+    let resume = query.get("resume").cloned();
+    let token = query.get("token").cloned();
+    let admission = Admission {
+        account: hub.account(token.as_deref()).map(|account| account.username),
+        token,
+        role,
+        via_link: query.contains_key("link"),
+        echo: query.get("echo").is_some_and(|echo| echo == "1" || echo == "true"),
+        agent: query.get("agent").and_then(|agent| connection::normalize_agent(agent)),
+        is_bot,
+    };
+    Ok(Box::new(ws.on_upgrade(move |socket| connect_user(socket, hub, room, remote_addr, resume, admission, pending, config))))
+}
+
+/// What a WebSocket was let in with, checked again for any room it switches to
+struct Admission {
+    token: Option<String>,
+    account: Option<String>,
+    role: Role,
+    // Links are only for the room they were made for
+    via_link: bool,
+    echo: bool,
+    // `?agent=` and `?bot=1`, see `ConnectionStats::identified`
+    agent: Option<String>,
+    is_bot: bool,
+}
+
+/// A WebSocket counted in `Hub::pending` until it sends a valid frame or closes
+struct PendingSlot(Arc<Hub>);
+
+impl PendingSlot {
+    /// None if `limit` sockets are pending already, 0 being no limit
+    fn take(hub: &Arc<Hub>, limit: usize) -> Option<Self> {
+        let pending = hub.pending.fetch_add(1, Ordering::Relaxed);
+        if limit != 0 && pending >= limit {
+            hub.pending.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(PendingSlot(hub.clone()))
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Send a socket to the cluster node that owns its room. Upgraded, like `not_yet_open`, so
+/// browser clients can see where to
+async fn redirect(mut socket: WebSocket, url: String) {
+    if let Ok(text) = serde_json::to_string(&ServerMessage::Redirect { url }) {
+        let _ = socket.send(Message::text(text)).await;
+    }
+    let _ = socket.send(Message::close_with(REDIRECT_CODE, "redirect")).await;
+}
+
+/// Tell a socket how long until the room opens and close it. Upgraded first since browsers don't
+/// show scripts why an upgrade was refused
+async fn not_yet_open(mut socket: WebSocket, opens_at: u64) {
+    let opens_in_secs = opens_at.saturating_sub(now_millis() / 1000);
+    if let Ok(text) = serde_json::to_string(&ServerMessage::NotYetOpen { opens_at, opens_in_secs }) {
+        let _ = socket.send(Message::text(text)).await;
+    }
+    let _ = socket.send(Message::close_with(1008u16, "not yet open")).await;
+}
+
+/// Token bucket applied to each connection's inbound messages, limits are passed in so reloads apply immediately
+struct RateLimiter {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(limits: &LimitsConfig) -> Self {
+        RateLimiter {
+            tokens: limits.burst.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn allow(&mut self, limits: &LimitsConfig) -> bool {
+        if limits.messages_per_second == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let rate = limits.messages_per_second as f64;
+        let burst = limits.burst.max(1) as f64;
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn connect_user(ws: WebSocket, hub: Arc<Hub>, mut room: SharedRoom, remote_addr: Option<SocketAddr>, resume: Option<String>, admission: Admission, pending: PendingSlot, config: ConfigHandle){
+    let current_user_id = UserId::random();
+    let mut joined_at = Instant::now();
+    let room_id = room.read().await.id.clone();
+    let stats = Arc::new(ConnectionStats::new(current_user_id, room_id.clone(), remote_addr, "websocket").identified(admission.agent.clone(), admission.is_bot));
+
+    let (mut user_ws_sender, mut user_ws_receiver) = ws.split();
+
+    let (message_sender, message_receiver) = mpsc::unbounded_channel();
+    let rx = UnboundedReceiverStream::new(message_receiver);
+    #[cfg(feature = "chaos")]
+    let rx = chaos::inject(rx, config.clone(), stats.clone());
+    let mut rx = rx;
+
+    let writer_stats = stats.clone();
+    let writer_hub = hub.clone();
+    let writer_room = room_id.clone();
+    let ping_interval = Duration::from_secs(config.borrow().server.ping_interval_secs.max(1));
+    let mut writer = tokio::task::spawn(isolated(writer_hub, "writer", current_user_id, writer_room, async move {
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+        loop {
+            let message = tokio::select! {
+                message = rx.next() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = ping.tick() => {
+                    // Pongs come back through the reader, which turns them into an RTT sample
+                    writer_stats.ping_sent();
+                    Message::ping(Vec::new())
+                }
+            };
+            let queued = !(message.is_ping() || message.is_pong());
+            user_ws_sender
+                .send(message)
+                .unwrap_or_else(|e| {
+                    log::warn!("WebSocket send error: {}", e)
+                }).await;
+            if queued {
+                writer_stats.sent();
+            }
+        }
+    }));
+
+    let capabilities = capabilities(&hub, &room, &config, stats.transport).await;
+    let role = admission.role;
+    let owner = owns(&hub, admission.account.as_deref(), &room_id);
+    let peer = Peer::new(message_sender.clone(), stats.clone()).resuming(resume.as_ref()).signed_in(admission.account.clone()).owning(owner).with_role(role).echoing(admission.echo).with_capabilities(capabilities).with_filters(Filters::for_connection(&config.borrow(), role)).counted_as(&config.borrow().bots);
+    let (waitlist, capacity, bots) = {
+        let current = config.borrow();
+        (current.rooms.waitlist, trial::capacity_of(&current, &room_id), current.bots.clone())
+    };
+    let waiting = match join_or_wait(&hub, &room, peer, capacity, waitlist, &bots).await {
+        Ok(waiting) => waiting,
+        Err(peer) => {
+            log::info!("Refused bot {} in room {}, it has {} bots already", current_user_id, room_id, bots.max_per_room);
+            send_frame(&peer, &ServerMessage::Error { code: "too_many_bots".to_string(), message: "too many bots in the room".to_string(), correlation_id: None });
+            peer.send(Message::close_with(1008u16, "too many bots"));
+            return;
+        }
+    };
+    if let Some(mut admitted) = waiting {
+        if !wait_for_slot(&mut admitted, &mut user_ws_receiver, &stats).await && unwait(&room, current_user_id).await {
+            log::info!("user {} left the waitlist of room {}", current_user_id, room_id);
+            return;
+        }
+    }
+
+    let reader = read_messages(current_user_id, &mut user_ws_receiver, &mut writer, &message_sender, pending, &hub, (&mut room, &mut joined_at), &admission, &stats, &config);
+    if isolated(hub.clone(), "reader", current_user_id, room_id.clone(), reader).await.is_none() {
+        stats.closing(DisconnectReason::Error);
+        let _ = message_sender.send(Message::close_with(1011u16, "internal error"));
+    }
+    drop(message_sender);
+
+    leave(&hub, &room, &stats, joined_at).await;
+}
+
+/// Catch a new participant up on the room's history and add them to it, for sockets and long-poll sessions alike.
+/// This doesn't look at the room's capacity, see `is_full` and `join_or_wait`
+pub async fn join(hub: &Hub, room: &SharedRoom, peer: Peer) {
+    admit(hub, &mut *room.write().await, peer).await;
+}
+
+/// What a connection over `transport` can do in `room` as of now, for its `Welcome`
+pub async fn capabilities(hub: &Hub, room: &SharedRoom, config: &ConfigHandle, transport: &str) -> Vec<&'static str> {
+    let (room_id, flags, relays) = {
+        let room = room.read().await;
+        (room.id.clone(), room.info.features, !room.info.unknown_frames.is_reject())
+    };
+    let current = config.borrow().clone();
+    hub.tenant_features.resolve(&current, &room_id, flags).capabilities(&current, transport, relays)
+}
+
+/// Join if the room has a free slot, otherwise line up on its waitlist, or join anyway without
+/// `waitlist` since that was checked before. The receiver fires once they're let in. A bot past
+/// `bots.max_per_room` gets its peer back instead
+pub async fn join_or_wait(hub: &Hub, room: &SharedRoom, peer: Peer, capacity: usize, waitlist: bool, bots: &BotConfig) -> Result<Option<oneshot::Receiver<()>>, Peer> {
+    let mut room = room.write().await;
+    if peer.stats.is_bot && too_many_bots(&room, bots) {
+        return Err(peer);
+    }
+    set_capacity(hub, &mut room, capacity).await;
+    // Another tab of someone already in doesn't take a slot, nor do bots with `bots.exclude_from_capacity`
+    if !waitlist || !peer.takes_slot || !room.is_full() || peer.account.as_deref().is_some_and(|account| room.signed_in(account).is_some()) {
+        admit(hub, &mut room, peer).await;
+        return Ok(None);
+    }
+    let (admit, admitted) = oneshot::channel();
+    let position = room.waitlist.len() + 1;
+    log::info!("user {} is waiting to get into room {}, position {}", peer.stats.user_id, room.id, position);
+    send_frame(&peer, &ServerMessage::Waitlisted { position });
+    room.waitlist.push_back(Waiter { peer, admit });
+    Ok(Some(admitted))
+}
+
+/// Whether the room has no slot for someone new, for transports that refuse joiners instead of queueing them
+pub async fn is_full(hub: &Hub, room: &SharedRoom, capacity: usize) -> bool {
+    let mut room = room.write().await;
+    set_capacity(hub, &mut room, capacity).await;
+    room.is_full()
+}
+
+/// Whether `room` has `bots.max_per_room` bots in it already
+fn too_many_bots(room: &Room, bots: &BotConfig) -> bool {
+    bots.max_per_room > 0 && room.bots() >= bots.max_per_room
+}
+
+/// Take someone who gave up waiting off the waitlist, false if they were let in first
+pub async fn unwait(room: &SharedRoom, user_id: UserId) -> bool {
+    let mut room = room.write().await;
+    let Some(index) = room.waitlist.iter().position(|waiter| waiter.peer.stats.user_id == user_id) else {
+        return false;
+    };
+    room.waitlist.remove(index);
+    for (position, waiter) in room.waitlist.iter().enumerate().skip(index) {
+        send_frame(&waiter.peer, &ServerMessage::Waitlisted { position: position + 1 });
+    }
+    true
+}
+
+// The limit is read from the config on every join, and raising it may free slots for people already waiting
+async fn set_capacity(hub: &Hub, room: &mut Room, capacity: usize) {
+    room.capacity = capacity;
+    promote(hub, room).await;
+}
+
+/// Let waitlisted people into free slots in order, and tell the rest where they stand now
+async fn promote(hub: &Hub, room: &mut Room) {
+    let mut promoted = false;
+    while room.capacity == 0 || room.slots_taken() < room.capacity {
+        let Some(waiter) = room.waitlist.pop_front() else {
+            break;
+        };
+        // Nobody to tell if they already gave up
+        if waiter.admit.send(()).is_ok() {
+            admit(hub, room, waiter.peer).await;
+        }
+        promoted = true;
+    }
+    if promoted {
+        for (position, waiter) in room.waitlist.iter().enumerate() {
+            send_frame(&waiter.peer, &ServerMessage::Waitlisted { position: position + 1 });
+        }
+    }
+}
+
+async fn admit(hub: &Hub, room: &mut Room, mut peer: Peer) {
+    // They opened the room just before it was archived
+    if room.archived {
+        peer.disconnect(DisconnectReason::Kicked, 1001, "room archived");
+        return;
+    }
+    let user_id = peer.stats.user_id;
+    let remote_addr = peer.stats.remote_addr;
+    let first = room.users.is_empty();
+
+    // Another tab of a signed-in user joins as the participant they already are
+    let tab = peer.account.as_deref().and_then(|account| room.signed_in(account));
+    let merged = tab.is_some();
+    if let Some(tab) = tab {
+        peer.participant = tab.participant;
+        peer.profile = tab.profile.clone();
+        peer.hand_raised_at = tab.hand_raised_at;
+        peer.presence = tab.presence;
+        peer.latency_ms = tab.latency_ms;
+        peer.voice = tab.voice;
+    } else if let Some(mut profile) = hub.profiles.take(&peer.resume_token) {
+        // Someone else may have taken the name while they were gone
+        profile.name = profile.name.map(|name| unique_name(&name, room.users.values().filter_map(|p| p.profile.name.as_deref())));
+        peer.profile = profile;
+    }
+    let welcome = ServerMessage::Welcome { user_id: peer.participant, resume_token: peer.resume_token.clone(), role: peer.role, epoch: room.epoch, capabilities: peer.capabilities.clone() };
+    send_frame(&peer, &welcome);
+    let mut users: Vec<Member> = room.users.values().map(Peer::member).chain([peer.member()]).chain(room.remote_members.values().cloned()).collect();
+    users.sort_by_key(|member| member.user_id);
+    users.dedup_by_key(|member| member.user_id);
+    send_frame(&peer, &ServerMessage::Roster { users });
+
+    // Catch the new user up before they see any live traffic
+    if send_board(&peer, room) > 0 {
+        hub.metrics.snapshot_joins.inc();
+    }
+    if !room.notes.is_empty() {
+        send_frame(&peer, &ServerMessage::Notes { update: notes::encode(&room.notes.state()) });
+    }
+    if let Some(user_id) = room.frozen {
+        send_frame(&peer, &ServerMessage::Frozen { frozen: true, user_id });
+    }
+    for notice in hub.notices.for_room(&room.id) {
+        send_frame(&peer, &notice.message());
+    }
+    match remote_addr {
+        Some(addr) => log::info!("{} joined room {} from {}, synced {} ops", peer.stats.describe(), room.id, addr, room.history.len()),
+        None => log::info!("{} joined room {}, synced {} ops", peer.stats.describe(), room.id, room.history.len()),
+    }
+
+    // Pick up follows from before a reconnect, in both directions
+    let identity = peer.identity();
+    room.follows.returned(&identity);
+    if let Some(followed) = room.follows.following(&identity).and_then(|followed| room.users.values().find(|p| p.identity() == followed)) {
+        send_frame(&peer, &ServerMessage::Following { user_id: Some(followed.participant) });
+        if let Some(viewport) = room.tabs(followed.participant).find_map(|p| p.viewport) {
+            send_frame(&peer, &ServerMessage::Viewport { user_id: followed.participant, viewport });
+        }
+    }
+    if !merged {
+        for follower in room.users.values().filter(|p| room.follows.is_follower(&p.identity(), &identity)) {
+            send_frame(follower, &ServerMessage::Following { user_id: Some(peer.participant) });
+        }
+        broadcast(room, &ServerMessage::Joined(peer.member()));
+        room.joined += 1;
+    }
+    room.users.insert(user_id, peer);
+    room.empty_since = None;
+    room.peak_participants = room.peak_participants.max(room.participants());
+    // Opening a tab counts as input, for a participant that had gone idle in their others
+    mark_active(room, user_id);
+    let emit = hub.hooks.on_join(&room.id, user_id);
+    // Trimmed to `rooms.history_limit` by the room's next op
+    draw_as_bot(hub, room, emit, usize::MAX).await;
+    hub.events.emit(ServerEvent::UserJoined { room: room.id.clone(), user_id, remote_addr, first });
+    hub.metrics.connections_opened.inc();
+}
+
+/// Take a connection out of its room once it ended, for why `stats.close_reason` says
+pub async fn leave(hub: &Hub, room: &SharedRoom, stats: &ConnectionStats, connected_at: Instant) {
+    depart(hub, room, stats, stats.close_reason(), connected_at).await;
+}
+
+// Take a connection out of the room it joined at `joined_at`, for `reason`
+async fn depart(hub: &Hub, room: &SharedRoom, stats: &ConnectionStats, reason: DisconnectReason, joined_at: Instant) {
+    let (room_id, user_id) = (stats.room_id(), stats.user_id);
+    user_disconnected(hub, user_id, reason, room).await;
+    hub.usage.disconnected(&room_id, user_id, joined_at.elapsed().as_secs());
+    hub.events.emit(ServerEvent::UserLeft { room: room_id, user_id, reason });
+}
+
+/// Move a socket to the room its `SwitchRoom` asked for: checked as an upgrade to it would be,
+/// then taken out of its room, with `switched_room` as why, and into the new one, which catches it
+/// up like any joiner. If anything stops it the socket stays where it was, with an error saying why
+#[allow(clippy::too_many_arguments)]
+async fn move_to_room(switch: Switch, sender: &mpsc::UnboundedSender<Message>, hub: &Hub, room: &mut SharedRoom, joined_at: &mut Instant, admission: &Admission, stats: &Arc<ConnectionStats>, config: &ConfigHandle) {
+    let current = config.borrow().clone();
+    let from = stats.room_id();
+    let (here, from_id, asked) = (&*room, &from, &switch);
+    let refuse = |code: &'static str, message: String| async move {
+        log::info!("User {} could not switch from room {} to {}: {}", stats.user_id, from_id, asked.room_id, message);
+        if let Some(peer) = here.read().await.users.get(&stats.user_id) {
+            send_frame(peer, &ServerMessage::Error { code: code.to_string(), message, correlation_id: asked.correlation_id.clone() });
+        }
+    };
+    let Some(asked_id) = hub.room_id(&switch.room_id) else {
+        return refuse("invalid_room", "invalid room id".to_string()).await;
+    };
+    // Within the tenant it's in, whose key it was let in with
+    let target = match tenants::tenant_of(&current, &from) {
+        Some((tenant, _)) => tenant.scope(&asked_id),
+        None => asked_id,
+    };
+    if target == from {
+        return refuse("same_room", "already in that room".to_string()).await;
+    }
+    if admission.via_link {
+        return refuse("forbidden", "a join link is only for its own room".to_string()).await;
+    }
+    let dead_after = Duration::from_secs(current.cluster.dead_after_secs);
+    if let Some(owner) = hub.cluster.remote_owner(&target, dead_after) {
+        // Joined with a socket of its own to that node, this one stays
+        if let Some(peer) = here.read().await.users.get(&stats.user_id) {
+            send_frame(peer, &ServerMessage::Redirect { url: cluster::join_url(&owner, &tenants::join_path(&current, &*hub.public_ids, &target)) });
+        }
+        return;
+    }
+    if !hub.may_access(&target, admission.token.as_deref()) {
+        return refuse("forbidden", "not on the room's access list".to_string()).await;
+    }
+    if let Err(e) = hub.quotas.check_join(&target, &hub.usage.room(&target)) {
+        return refuse("quota_exceeded", e.to_string()).await;
+    }
+    let next = match hub.open(&target).await {
+        Ok(next) => next,
+        Err(e) if is_archived(&e) => return refuse("room_archived", "room is archived".to_string()).await,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", target, e);
+            return refuse("unavailable", "room unavailable".to_string()).await;
+        }
+    };
+    if let Some(opens_at) = next.read().await.info.opens_later() {
+        return refuse("not_yet_open", format!("the room opens at {}", opens_at)).await;
+    }
+    // No waitlist, someone switching can stay where they are until there's room. Their slot is
+    // held from here, so nobody takes it while they leave this room
+    let takes_slot = !(stats.is_bot && current.bots.exclude_from_capacity);
+    if let Err((code, message)) = reserve(hub, &next, trial::capacity_of(&current, &target), (takes_slot, stats.is_bot), &current.bots).await {
+        return refuse(code, message.to_string()).await;
+    }
+
+    log::info!("User {} switched from room {} to {}", stats.user_id, from, target);
+    // Leaving files their profile under their resume token, which the new room finds it by
+    let resume = room.read().await.users.get(&stats.user_id).map(|peer| peer.resume_token.clone());
+    let owner = owns(hub, admission.account.as_deref(), &target);
+    depart(hub, room, stats, DisconnectReason::SwitchedRoom, *joined_at).await;
+    stats.moved_to(target);
+    let capabilities = capabilities(hub, &next, config, stats.transport).await;
+    let peer = Peer::new(sender.clone(), stats.clone())
+        .resuming(resume.as_ref())
+        .signed_in(admission.account.clone())
+        .owning(owner)
+        .with_role(admission.role)
+        .echoing(admission.echo)
+        .with_capabilities(capabilities)
+        .with_filters(Filters::for_connection(&current, admission.role))
+        .counted_as(&current.bots);
+    {
+        let mut next = next.write().await;
+        next.reserved -= takes_slot as usize;
+        next.reserved_bots -= stats.is_bot as usize;
+        admit(hub, &mut next, peer).await;
+    }
+    *room = next;
+    *joined_at = Instant::now();
+}
+
+// Hold a slot in `room` for someone switching into it, and a place among its bots for a bot,
+// checked and taken under one lock. The error frame's code and message if there's no room for them
+async fn reserve(hub: &Hub, room: &SharedRoom, capacity: usize, (takes_slot, is_bot): (bool, bool), bots: &BotConfig) -> Result<(), (&'static str, &'static str)> {
+    let mut room = room.write().await;
+    if is_bot && too_many_bots(&room, bots) {
+        return Err(("too_many_bots", "too many bots in the room"));
+    }
+    set_capacity(hub, &mut room, capacity).await;
+    if takes_slot && room.is_full() {
+        return Err(("room_full", "room is full"));
+    }
+    room.reserved += takes_slot as usize;
+    room.reserved_bots += is_bot as usize;
+    Ok(())
+}
+
+/// Wait for a waitlisted socket's turn, false if it closed first. Anything it sends meanwhile is dropped
+async fn wait_for_slot(admitted: &mut oneshot::Receiver<()>, receiver: &mut SplitStream<WebSocket>, stats: &ConnectionStats) -> bool {
+    loop {
+        tokio::select! {
+            result = &mut *admitted => return result.is_ok(),
+            msg = receiver.next() => match msg {
+                Some(Ok(msg)) if msg.is_close() => return false,
+                Some(Ok(msg)) if msg.is_pong() => stats.pong_received(),
+                Some(Ok(_)) => {}
+                _ => return false,
+            },
+        }
+    }
+}
+
+/// Apply and relay everything this user sends until their socket closes. Text frames are JSON,
+/// binary ones go to `codec`. The socket is closed if its first valid frame doesn't come within
+/// `limits.handshake_timeout_secs`, and stops counting as pending once it does
+#[allow(clippy::too_many_arguments)]
+async fn read_messages(
+    current_user_id: UserId,
+    receiver: &mut SplitStream<WebSocket>,
+    writer: &mut JoinHandle<Option<()>>,
+    sender: &mpsc::UnboundedSender<Message>,
+    pending: PendingSlot,
+    hub: &Hub,
+    (room, joined_at): (&mut SharedRoom, &mut Instant),
+    admission: &Admission,
+    stats: &Arc<ConnectionStats>,
+    config: &ConfigHandle,
+) {
+    let mut inbound = Inbound::new(&config.borrow().limits).switching();
+    let mut pending = Some(pending);
+    let handshake_timeout = Duration::from_secs(config.borrow().limits.handshake_timeout_secs);
+    let handshake = tokio::time::sleep(handshake_timeout);
+    tokio::pin!(handshake);
+
+    loop {
+        let result = tokio::select! {
+            result = receiver.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            // The writer only stops early if it panicked, and then nothing can reach this socket
+            _ = &mut *writer => {
+                stats.closing(DisconnectReason::Error);
+                break;
+            }
+            _ = &mut handshake, if pending.is_some() && !handshake_timeout.is_zero() => {
+                log::info!("Closing the socket of user {}, it sent no valid frame within {:?}", current_user_id, handshake_timeout);
+                stats.closing(DisconnectReason::Timeout);
+                let _ = sender.send(Message::close_with(1008u16, "handshake timeout"));
+                break;
+            }
+        };
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("Could not receive message from user {}: {}", current_user_id, e);
+                stats.closing(DisconnectReason::Error);
+                break;
+            } 
+        };
+        if msg.is_pong() {
+            stats.pong_received();
+            continue;
+        }
+        if msg.is_ping() {
+            // Also queued by tungstenite, but only sent with whatever it reads or writes next. This
+            // replaces that one rather than adding a second
+            let _ = sender.send(Message::pong(msg.into_bytes()));
+            continue;
+        }
+        if msg.is_close() {
+            match msg.close_frame() {
+                Some((code, reason)) if !reason.is_empty() => log::info!("User {} closed their socket with {}: {}", current_user_id, code, reason),
+                Some((code, _)) => log::info!("User {} closed their socket with {}", current_user_id, code),
+                None => log::info!("User {} closed their socket", current_user_id),
+            }
+            break;
+        }
+        // Rejections are already logged and counted
+        let result = inbound.handle(msg, hub, room, stats, config).await;
+        if !matches!(result, Err(Rejected::RateLimited | Rejected::Invalid(_))) {
+            pending = None;
+        }
+        if let Some(switch) = inbound.switch.take() {
+            move_to_room(switch, sender, hub, room, joined_at, admission, stats, config).await;
+        }
+    }
+}
+
+/// Why `Inbound::handle` didn't apply a frame
+#[derive(Debug)]
+pub enum Rejected {
+    RateLimited,
+    /// Refused before it got to the room, the sender was sent an error frame with its code if it's in the room
+    Invalid(Invalid),
+    /// Parsed but refused by the quota provider, the sender was sent an error frame if it's in the room
+    OverQuota(String),
+    /// Rejected by a hook, as an invalid control frame or because shared state couldn't take it, with its reason, the sender was sent an error frame if it's in the room
+    Refused(String),
+}
+
+/// Per-connection state for turning inbound frames into room ops, shared by sockets and long-poll sessions
+pub struct Inbound {
+    limiter: RateLimiter,
+    // Only the first message of each limited burst is reported, not every dropped one
+    limited: bool,
+    rate_limit: bool,
+    // Whether `SwitchRoom` is left to the caller in `switch`, rather than refused as `unsupported`
+    switching: bool,
+    switch: Option<Switch>,
+}
+
+/// A `SwitchRoom` for the connection's transport to carry out, see `move_to_room`
+struct Switch {
+    room_id: String,
+    // For the error if it can't, like `send_error`'s
+    correlation_id: Option<String>,
+}
+
+impl Inbound {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        Inbound { limiter: RateLimiter::new(limits), limited: false, rate_limit: true, switching: false, switch: None }
+    }
+
+    /// For trusted server-side callers, which are still subject to quotas
+    pub fn unlimited(limits: &LimitsConfig) -> Self {
+        Inbound { rate_limit: false, ..Inbound::new(limits) }
+    }
+
+    // For WebSockets, which move to another room when asked, see `move_to_room`
+    fn switching(self) -> Self {
+        Inbound { switching: true, ..self }
+    }
+
+    /// Rate limit, parse, apply and relay one frame from `stats.user_id`. Callers handle a
+    /// connection's frames one at a time, and an op is applied, relayed and published to `Hub::ops`
+    /// under one hold of the room lock, so a sender's ops reach every peer, the board and the op log
+    /// in the order they arrived
+    pub async fn handle(&mut self, msg: Message, hub: &Hub, room: &SharedRoom, stats: &ConnectionStats, config: &ConfigHandle) -> Result<(), Rejected> {
+        let current_user_id = stats.user_id;
+        let room_id = &stats.room_id();
+        let received_at = now_millis();
+        stats.received(msg.as_bytes().len());
+        let correlation_id = CorrelationId::next();
+        log::trace!("[{}] {} byte frame from user {} in room {}", correlation_id, msg.as_bytes().len(), current_user_id, room_id);
+
+        let current = config.borrow().clone();
+        if self.rate_limit && !self.limiter.allow(&trial::limits(&current, room_id)) {
+            log::debug!("[{}] Rate limited message from user {}", correlation_id, current_user_id);
+            stats.rate_limited();
+            if !self.limited {
+                hub.events.emit(ServerEvent::RateLimited { room: room_id.clone(), user_id: current_user_id });
+                self.limited = true;
+            }
+            return Err(Rejected::RateLimited);
+        }
+        self.limited = false;
+        let (flags, permissions) = {
+            let room = room.read().await;
+            (room.info.features, room.info.permissions)
+        };
+        let features = hub.tenant_features.resolve(&current, room_id, flags);
+        let frame = Frame {
+            user_id: current_user_id,
+            correlation_id,
+            echo_correlation_id: current.server.echo_correlation_ids,
+            outbound_bytes_per_second: current.limits.outbound_bytes_per_second,
+            clear_undo: Duration::from_secs(current.rooms.clear_undo_secs),
+            interpolate_gaps_px: current.rooms.interpolate_gaps_px,
+            received_at,
+            features,
+            max_notes_bytes: current.rooms.max_notes_bytes,
+            permissions: permissions.or(current.permissions),
+            moderation: current.moderation.clone(),
+        };
+        let bytes = msg.as_bytes().len();
+        let result = match bytes > current.limits.max_message_bytes {
+            true => Err(Rejected::Invalid(Invalid::Oversized { bytes, limit: current.limits.max_message_bytes })),
+            false => send_user_message(&frame, msg, hub, room, features.history_limit, self.switching.then_some(&mut self.switch)).await,
+        };
+        if let Err(Rejected::Invalid(e)) = result {
+            log::warn!("[{}] Refused a frame from user {}, {}: {}", correlation_id, current_user_id, e.code(), e);
+            hub.metrics.invalid_frames[e.index()].inc();
+            if let Some(peer) = room.read().await.users.get(&current_user_id) {
+                send_error(peer, e.code(), &e.to_string(), &frame);
+            }
+            let parse_errors = stats.parse_error();
+            hub.events.emit(ServerEvent::Error {
+                room: Some(room_id.clone()),
+                user_id: Some(current_user_id),
+                message: format!("parse error in frame {}: {}", correlation_id, e),
+            });
+            if parse_errors == current.reporting.repeated_error_threshold as u64 {
+                reporting::capture(
+                    "handler",
+                    format!("{} parse errors from one connection, last: {}", parse_errors, e),
+                    [("room", room_id.clone()), ("user", current_user_id.to_string())],
+                );
+            }
+            return Err(Rejected::Invalid(e));
+        }
+        result
+    }
+}
+
+/// Run one of a connection's tasks, so a panic in it is logged and counted and ends only that connection.
+/// Returns `None` if it panicked
+pub async fn isolated<F: Future>(hub: Arc<Hub>, task: &str, user_id: UserId, room_id: String, task_future: F) -> Option<F::Output> {
+    match AssertUnwindSafe(task_future).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            log::error!("{} task for user {} in room {} panicked, closing the connection: {}", task, user_id, room_id, message);
+            hub.metrics.connection_panics.inc();
+            hub.events.emit(ServerEvent::Error {
+                room: Some(room_id),
+                user_id: Some(user_id),
+                message: format!("{} task panicked: {}", task, message),
+            });
+            None
+        }
+    }
+}
+
+/// Where an inbound frame came from, carried through everything done with it
+struct Frame {
+    user_id: UserId,
+    correlation_id: CorrelationId,
+    echo_correlation_id: bool,
+    // `limits.outbound_bytes_per_second`, for the ephemeral frames this causes
+    outbound_bytes_per_second: u64,
+    // `rooms.clear_undo_secs`
+    clear_undo: Duration,
+    // `rooms.interpolate_gaps_px`
+    interpolate_gaps_px: u32,
+    // Unix milliseconds, for `TimeSync`
+    received_at: u64,
+    // What the room may do
+    features: Features,
+    // `rooms.max_notes_bytes`
+    max_notes_bytes: usize,
+    // The room's, over `[permissions]`
+    permissions: Permissions,
+    moderation: ModerationConfig,
+}
+
+async fn send_user_message(frame: &Frame, msg: Message, hub: &Hub, room: &SharedRoom, history_limit: usize, switch: Option<&mut Option<Switch>>) -> Result<(), Rejected> {
+    let user_id = frame.user_id;
+    let parsed = match msg.to_str() {
+        Ok(s) => ClientFrame::parse(s),
+        Err(()) if msg.is_binary() && !frame.features.binary => return disabled(frame, "binary frames", room).await,
+        // Binary ops have no epoch, they're applied whenever they arrive
+        Err(()) if msg.is_binary() => codec::decode(msg.as_bytes()).map(|op| ClientFrame::Op { op, epoch: None }),
+        Err(()) => return Ok(()),
+    };
+    let (msg, epoch) = match parsed.map_err(Rejected::Invalid)? {
+        ClientFrame::Control(ControlMessage::UpdateRoom(update)) => return update_room(frame, update, hub, room).await,
+        ClientFrame::Control(ControlMessage::UndoClear) => return undo_clear(frame, hub, room, history_limit).await,
+        ClientFrame::Control(ControlMessage::TimeSync { client_time }) => return time_sync(frame, client_time, room).await,
+        ClientFrame::Control(ControlMessage::Report { user_id, reason }) => return report(frame, user_id, reason, hub, room).await,
+        ClientFrame::Control(ControlMessage::SignIn { token }) => return sign_in(frame, token, hub, room).await,
+        ClientFrame::Control(ControlMessage::Freeze) => return freeze(frame, true, room).await,
+        ClientFrame::Control(ControlMessage::Unfreeze) => return freeze(frame, false, room).await,
+        ClientFrame::Control(ControlMessage::SwitchRoom { room_id }) => return switch_room(frame, room_id, switch, room).await,
+        ClientFrame::Control(ControlMessage::NotesUpdate { .. } | ControlMessage::NotesSync { .. }) if !frame.features.notes => return disabled(frame, "shared notes", room).await,
+        ClientFrame::Control(ControlMessage::NotesUpdate { update }) => return notes_update(frame, update, hub, room).await,
+        ClientFrame::Control(ControlMessage::NotesSync { state_vector }) => return notes_sync(frame, state_vector, room).await,
+        ClientFrame::Control(ControlMessage::Dm { to_user_id, text }) => return dm(frame, to_user_id, text, hub, room).await,
+        ClientFrame::Control(control) => return apply_control(frame, control, room).await,
+        ClientFrame::Unknown { kind, frame: unknown } => return relay_unknown(frame, kind, unknown, room).await,
+        ClientFrame::Op { op, epoch } => (op, epoch),
+    };
+    let mut room = room.write().await;
+    mark_active(&mut room, user_id);
+    permitted(frame, Tool::of(&msg), &room)?;
+    // Sent before a clear reached its sender, which already took it off their screen. Applying it
+    // would leave it on the board only for those who got the clear first
+    if let Some(epoch) = epoch.filter(|&epoch| epoch != room.epoch) {
+        let reason = format!("sent in epoch {}, the board was cleared since and is in epoch {}", epoch, room.epoch);
+        log::debug!("[{}] Dropped a stale {} from user {} in room {}: {}", frame.correlation_id, msg.name(), user_id, room.id, reason);
+        if let Some(peer) = room.users.get(&user_id) {
+            send_error(peer, "stale_epoch", &reason, frame);
+        }
+        return Err(Rejected::Refused(reason));
+    }
+
+    let (msg, emit) = if hub.hooks.is_empty() {
+        (Ok(msg), Vec::new())
+    } else {
+        hub.hooks.on_message(&room.id, user_id, msg)
+    };
+    let msg = match msg {
+        Ok(msg) => msg,
+        Err(reason) => {
+            if let Some(peer) = room.users.get(&user_id) {
+                send_error(peer, "rejected", &reason, frame);
+            }
+            draw_as_bot(hub, &mut room, emit, history_limit).await;
+            return Err(Rejected::Refused(reason));
+        }
+    };
+    let quota = hub.quotas.check_write(&room.id, user_id, &hub.usage.room(&room.id), &hub.usage.user(user_id)).and_then(|()| match &msg {
+        MessageType::Draw(draw) => {
+            let participant = room.participant_of(user_id).unwrap_or(user_id);
+            hub.quotas.check_draw(&room.id, user_id, &room.drawn_with(participant, draw))
+        }
+        _ => Ok(()),
+    });
+    if let Err(e) = quota {
+        log::debug!("[{}] Dropped op from user {} in room {}: {}", frame.correlation_id, user_id, room.id, e);
+        if let Some(peer) = room.users.get(&user_id) {
+            send_error(peer, "quota_exceeded", &e.to_string(), frame);
+        }
+        return Err(Rejected::OverQuota(e.to_string()));
+    }
+    hub.usage.message(&room.id, user_id);
+
+    // The sender drew the segments in between, everyone else would see a gap
+    let bridge = match (&msg, room.users.get_mut(&user_id)) {
+        (MessageType::Draw(draw), Some(peer)) => bridge_gap(peer, draw, frame.interpolate_gaps_px),
+        _ => None,
+    };
+    if let Some(bridge) = bridge {
+        log::debug!("[{}] Bridged a gap before a segment from user {} in room {}", frame.correlation_id, user_id, room.id);
+        apply_op(frame, MessageType::Draw(bridge), hub, &mut room, history_limit).await?;
+    }
+    apply_op(frame, msg, hub, &mut room, history_limit).await?;
+    draw_as_bot(hub, &mut room, emit, history_limit).await;
+    Ok(())
+}
+
+// Apply a sender's op and relay it to everyone else, or back to them too if they asked for echoes.
+// Refused if shared state couldn't take it
+async fn apply_op(frame: &Frame, msg: MessageType, hub: &Hub, room: &mut Room, history_limit: usize) -> Result<(), Rejected> {
+    let started = Instant::now();
+    let user_id = frame.user_id;
+    let seq = match sequence(hub, room, &msg, user_id, history_limit).await {
+        Ok(seq) => seq,
+        Err(e) => {
+            log::error!("[{}] Dropped a {} from user {} in room {}, shared state couldn't take it: {}", frame.correlation_id, msg.name(), user_id, room.id, e);
+            if let Some(peer) = room.users.get(&user_id) {
+                send_error(peer, "unavailable", "the board couldn't be reached, try again", frame);
+            }
+            return Err(Rejected::Refused(format!("shared state unavailable: {}", e)));
+        }
+    };
+    let timestamp = now_millis();
+    // After applying, hooks may have changed it and a clear goes out in the epoch it started
+    let outgoing = Outgoing::new(room, &msg, seq, timestamp, user_id);
+    room.contribute(user_id, |c| match &msg {
+        MessageType::Draw(draw) if draw.composite.erases() => c.erases += 1,
+        MessageType::Draw(_) => c.strokes += 1,
+        MessageType::Clear => c.clears += 1,
+    });
+    room.last_correlation_id = Some(frame.correlation_id);
+    room.messages_in.record(1);
+    // Published under the room lock so the feed sees ops in the room's order
+    hub.ops.publish(OpRecord {
+        room: room.id.clone(),
+        user_id,
+        seq,
+        stroke_id: room.applied_stroke_id(&msg),
+        correlation_id: frame.correlation_id,
+        timestamp,
+        op: msg,
+    })
+    .await;
+    let outgoing = match outgoing {
+        Ok(outgoing) => outgoing,
+        Err(e) => {
+            log::error!("[{}] Serialization error: {}", frame.correlation_id, e);
+            return Ok(());
+        }
+    };
+    let sent = outgoing.relay(room, Some(user_id));
+    room.messages_out.record(sent);
+    hub.metrics.room_traffic.lock().unwrap().broadcast(&room.id, started.elapsed());
+    log::debug!("[{}] Applied op {} in room {}, relayed to {} peers", frame.correlation_id, seq, room.id, sent);
+    Ok(())
+}
+
+/// The segment from where the sender's last one ended to where `draw` starts, when it's the same
+/// stroke but points were lost in between, e.g. to rate limiting or a client dropping events.
+/// Only gaps up to `max_gap` board pixels are bridged, 0 being none, and only within
+/// `STROKE_PAUSE` of the last segment. `draw` becomes the sender's last segment either way
+fn bridge_gap(peer: &mut Peer, draw: &DrawCommand, max_gap: u32) -> Option<DrawCommand> {
+    if max_gap == 0 {
+        return None;
+    }
+    let (last, at) = peer.last_segment.replace((draw.clone(), Instant::now()))?;
+    let same_stroke = at.elapsed() <= STROKE_PAUSE && last.color == draw.color && last.brush_size == draw.brush_size && last.composite == draw.composite;
+    let gap = (draw.prev[0] - last.cur[0]).hypot(draw.prev[1] - last.cur[1]);
+    (same_stroke && gap > 0.0 && gap <= max_gap as f64).then(|| DrawCommand { prev: last.cur, cur: draw.prev, ..draw.clone() })
+}
+
+// A direct message, checked with `moderation.url` before the room is locked to send it. What
+// can't be sent anyway is left to `apply_control` to refuse
+async fn dm(frame: &Frame, to_user_id: UserId, text: String, hub: &Hub, room: &SharedRoom) -> Result<(), Rejected> {
+    if let Some(normalized) = profiles::normalize_text(&text).ok().filter(|_| frame.features.dms) {
+        let id = room.read().await.id.clone();
+        if let Err(refused) = hub.moderation.check(&frame.moderation, &id, &[(Kind::Dm, &normalized)]).await {
+            log::debug!("[{}] Refused a direct message from user {} in room {}: {}", frame.correlation_id, frame.user_id, id, refused.code);
+            if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+                send_error(peer, refused.code, &refused.message, frame);
+            }
+            return Err(Rejected::Refused(refused.message));
+        }
+    }
+    apply_control(frame, ControlMessage::Dm { to_user_id, text }, room).await
+}
+
+/// Update the sender's own state, nothing here touches the board or goes through hooks and quotas
+async fn apply_control(frame: &Frame, control: ControlMessage, room: &SharedRoom) -> Result<(), Rejected> {
+    let mut room = room.write().await;
+    // Everything is done to and seen as coming from the participant, all of a signed-in user's tabs
+    let Some(me) = room.participant_of(frame.user_id) else {
+        return Ok(());
+    };
+    mark_active(&mut room, frame.user_id);
+    match control {
+        ControlMessage::SetProfile(profile) => {
+            let mut profile = match profiles::normalize(profile) {
+                Ok(profile) => profile,
+                Err(reason) => {
+                    if let Some(peer) = room.users.get(&frame.user_id) {
+                        send_error(peer, "invalid_profile", &reason, frame);
+                    }
+                    return Err(Rejected::Refused(reason));
+                }
+            };
+            let others = room.users.values().filter(|peer| peer.participant != me).filter_map(|peer| peer.profile.name.as_deref());
+            profile.name = profile.name.map(|name| unique_name(&name, others));
+            log::debug!("[{}] User {} in room {} set their profile to {:?}", frame.correlation_id, me, room.id, profile);
+            for peer in room.tabs_mut(me) {
+                peer.profile = profile.clone();
+            }
+            if let Some(peer) = room.users.get(&frame.user_id) {
+                broadcast(&room, &ServerMessage::Profile(peer.member()));
+            }
+        }
+        ControlMessage::RaiseHand | ControlMessage::LowerHand => {
+            let raise = matches!(control, ControlMessage::RaiseHand);
+            // Raising an already raised hand keeps its place in line
+            if room.users.get(&frame.user_id).is_none_or(|peer| raise == peer.hand_raised_at.is_some()) {
+                return Ok(());
+            }
+            let raised_at = raise.then(now_millis);
+            for peer in room.tabs_mut(me) {
+                peer.hand_raised_at = raised_at;
+            }
+            log::debug!("[{}] User {} in room {} {} their hand", frame.correlation_id, me, room.id, if raise { "raised" } else { "lowered" });
+            broadcast(&room, &ServerMessage::Hand { user_id: me, raised_at });
+        }
+        ControlMessage::React { emoji } => {
+            let emoji = match profiles::normalize_emoji(&emoji) {
+                Ok(emoji) => emoji,
+                Err(reason) => {
+                    if let Some(peer) = room.users.get(&frame.user_id) {
+                        send_error(peer, "invalid_reaction", &reason, frame);
+                    }
+                    return Err(Rejected::Refused(reason));
+                }
+            };
+            let now = Instant::now();
+            for peer in room.tabs_mut(me) {
+                peer.reaction = Some((emoji.clone(), now));
+            }
+            broadcast_ephemeral(&room, frame, &ServerMessage::Reaction { user_id: me, emoji });
+        }
+        ControlMessage::Follow { user_id } => {
+            let Some(followed) = room.tabs(user_id).next().filter(|_| user_id != me) else {
+                if let Some(peer) = room.users.get(&frame.user_id) {
+                    send_error(peer, "unknown_user", &format!("no one else in the room is user {}", user_id), frame);
+                }
+                return Err(Rejected::Refused(format!("unknown user {}", user_id)));
+            };
+            let (followed, viewport) = (followed.identity(), room.tabs(user_id).find_map(|peer| peer.viewport));
+            let Some(follower) = room.users.get(&frame.user_id).map(Peer::identity) else {
+                return Ok(());
+            };
+            for peer in room.tabs(me) {
+                send_frame(peer, &ServerMessage::Following { user_id: Some(user_id) });
+                if let Some(viewport) = viewport {
+                    send_frame(peer, &ServerMessage::Viewport { user_id, viewport });
+                }
+            }
+            room.follows.follow(&follower, &followed);
+        }
+        ControlMessage::Unfollow => {
+            let Some(follower) = room.users.get(&frame.user_id).map(Peer::identity) else {
+                return Ok(());
+            };
+            for peer in room.tabs(me) {
+                send_frame(peer, &ServerMessage::Following { user_id: None });
+            }
+            room.follows.unfollow(&follower);
+        }
+        ControlMessage::Viewport(viewport) => {
+            let Some(peer) = room.users.get_mut(&frame.user_id) else {
+                return Ok(());
+            };
+            if !viewport.is_valid() {
+                send_error(peer, "invalid_viewport", "viewport needs finite coordinates and a positive zoom", frame);
+                return Err(Rejected::Refused("invalid viewport".to_string()));
+            }
+            peer.viewport = Some(viewport);
+            broadcast_ephemeral(&room, frame, &ServerMessage::Viewport { user_id: me, viewport });
+        }
+        ControlMessage::Cursor { x, y } => {
+            if !(x.is_finite() && y.is_finite()) {
+                if let Some(peer) = room.users.get(&frame.user_id) {
+                    send_error(peer, "invalid_cursor", "cursor needs finite coordinates", frame);
+                }
+                return Err(Rejected::Refused("invalid cursor".to_string()));
+            }
+            broadcast_ephemeral(&room, frame, &ServerMessage::Cursor { user_id: me, x, y });
+        }
+        ControlMessage::Block { user_id } | ControlMessage::Unblock { user_id } => {
+            let block = matches!(control, ControlMessage::Block { .. });
+            let Some(blocked) = room.tabs(user_id).next().filter(|_| user_id != me).map(Peer::identity) else {
+                if let Some(peer) = room.users.get(&frame.user_id) {
+                    send_error(peer, "unknown_user", &format!("no one else in the room is user {}", user_id), frame);
+                }
+                return Err(Rejected::Refused(format!("unknown user {}", user_id)));
+            };
+            let Some(blocker) = room.users.get(&frame.user_id).map(Peer::identity) else {
+                return Ok(());
+            };
+            if block {
+                room.blocked.entry(blocker).or_default().insert(blocked);
+            } else if let Some(blocked_by_them) = room.blocked.get_mut(&blocker) {
+                blocked_by_them.remove(&blocked);
+            }
+            log::debug!("[{}] User {} in room {} {} user {}", frame.correlation_id, me, room.id, if block { "blocked" } else { "unblocked" }, user_id);
+            for peer in room.tabs(me) {
+                send_frame(peer, &ServerMessage::Blocking { user_id, blocked: block });
+            }
+        }
+        ControlMessage::Dm { to_user_id, text } => {
+            let Some(peer) = room.users.get(&frame.user_id) else {
+                return Ok(());
+            };
+            if !frame.features.dms {
+                send_error(peer, "feature_disabled", "direct messages are turned off in this room", frame);
+                return Err(Rejected::Refused("direct messages are turned off".to_string()));
+            }
+            let text = match profiles::normalize_text(&text) {
+                Ok(text) => text,
+                Err(reason) => {
+                    send_error(peer, "invalid_message", &reason, frame);
+                    return Err(Rejected::Refused(reason));
+                }
+            };
+            if to_user_id == me || room.tabs(to_user_id).next().is_none() {
+                send_error(peer, "unknown_user", &format!("no one else in the room is user {}", to_user_id), frame);
+                return Err(Rejected::Refused(format!("unknown user {}", to_user_id)));
+            }
+            // Only who to whom is logged, never the text
+            log::debug!("[{}] User {} in room {} messaged user {}", frame.correlation_id, me, room.id, to_user_id);
+            let dm = ServerMessage::Dm { from_user_id: me, to_user_id, text, sent_at: now_millis() };
+            // Still echoed when the recipient blocked the sender, so it doesn't tell them
+            let sender = peer.identity();
+            deliver(&room, room.tabs(to_user_id), Some(&sender), &dm, None);
+            for peer in room.tabs(me) {
+                send_frame(peer, &dm);
+            }
+            room.contribute(frame.user_id, |c| c.messages += 1);
+        }
+        ControlMessage::Signal { to_user_id, payload } => {
+            let Some(peer) = room.users.get(&frame.user_id) else {
+                return Ok(());
+            };
+            if to_user_id == me || room.tabs(to_user_id).next().is_none() {
+                send_error(peer, "unknown_user", &format!("no one else in the room is user {}", to_user_id), frame);
+                return Err(Rejected::Refused(format!("unknown user {}", to_user_id)));
+            }
+            log::trace!("[{}] User {} in room {} signaled user {}", frame.correlation_id, me, room.id, to_user_id);
+            let signal = ServerMessage::Signal { from_user_id: me, payload };
+            // Dropped without a word when the recipient blocked the sender, as with DMs
+            let sender = peer.identity();
+            deliver(&room, room.tabs(to_user_id), Some(&sender), &signal, None);
+        }
+        ControlMessage::VoiceState(_) | ControlMessage::LeaveVoice => {
+            let voice = match control {
+                ControlMessage::VoiceState(voice) => Some(voice),
+                _ => None,
+            };
+            // Voice activity detection repeats itself, only changes go out
+            if room.users.get(&frame.user_id).is_none_or(|peer| peer.voice == voice) {
+                return Ok(());
+            }
+            for peer in room.tabs_mut(me) {
+                peer.voice = voice;
+            }
+            log::trace!("[{}] User {} in room {} is in voice as {:?}", frame.correlation_id, me, room.id, voice);
+            broadcast(&room, &ServerMessage::VoiceState { user_id: me, voice });
+        }
+        ControlMessage::Inspect { stroke_id } => {
+            let Some(peer) = room.users.get(&frame.user_id) else {
+                return Ok(());
+            };
+            let Some((op, stroke)) = room.stroke(stroke_id) else {
+                send_error(peer, "unknown_stroke", &format!("stroke {} isn't on the board", stroke_id), frame);
+                return Err(Rejected::Refused(format!("unknown stroke {}", stroke_id)));
+            };
+            send_frame(peer, &ServerMessage::Stroke { stroke: stroke.clone(), op: op.clone() });
+        }
+        // Only marks the connection active
+        ControlMessage::Hello => {}
+        ControlMessage::Resync => {
+            let Some(peer) = room.users.get(&frame.user_id) else {
+                return Ok(());
+            };
+            log::debug!("[{}] Resyncing user {} in room {}, {} ops", frame.correlation_id, frame.user_id, room.id, room.history.len());
+            send_frame(peer, &ServerMessage::Resync { epoch: room.epoch });
+            send_board(peer, &room);
+            send_frame(peer, &checksum(&room));
+        }
+        // See `update_room`, `undo_clear`, `time_sync`, `report`, `notes_update` and `notes_sync`
+        ControlMessage::UpdateRoom(_) | ControlMessage::UndoClear | ControlMessage::TimeSync { .. } | ControlMessage::Report { .. } | ControlMessage::SignIn { .. } | ControlMessage::Freeze | ControlMessage::Unfreeze | ControlMessage::SwitchRoom { .. } | ControlMessage::NotesUpdate { .. } | ControlMessage::NotesSync { .. } => {}
+    }
+    Ok(())
+}
+
+/// Pass on a frame of a `type` the server doesn't know if the room's `unknown_frames` says to, to
+/// everyone else in it who didn't block the sender. They may be ops, so like ops they're for
+/// editors unless the room's `permissions.relayed` says otherwise
+async fn relay_unknown(frame: &Frame, kind: String, unknown: Value, room: &SharedRoom) -> Result<(), Rejected> {
+    let mut room = room.write().await;
+    if room.info.unknown_frames.is_reject() {
+        return Err(Rejected::Invalid(Invalid::UnknownType(kind)));
+    }
+    mark_active(&mut room, frame.user_id);
+    permitted(frame, Tool::Relayed, &room)?;
+    let Some(sender) = room.users.get(&frame.user_id) else {
+        return Ok(());
+    };
+    let identity = sender.identity();
+    let relayed = ServerMessage::Relayed { user_id: sender.participant, frame: unknown };
+    let others = room.users.iter().filter(|&(&uid, _)| uid != frame.user_id).map(|(_, peer)| peer);
+    let sent = deliver(&room, others, Some(&identity), &relayed, Some(frame.outbound_bytes_per_second));
+    log::debug!("[{}] Relayed a {} frame from user {} in room {} to {} peers", frame.correlation_id, kind, frame.user_id, room.id, sent);
+    Ok(())
+}
+
+/// Answer a `TimeSync` straight away, under a read lock and without marking anyone active, so
+/// the reply's times are as close to the round trip as the server can make them
+async fn time_sync(frame: &Frame, client_time: f64, room: &SharedRoom) -> Result<(), Rejected> {
+    let room = room.read().await;
+    if let Some(peer) = room.users.get(&frame.user_id) {
+        send_frame(peer, &ServerMessage::TimeSync { client_time, received_at: frame.received_at, sent_at: now_millis() });
+    }
+    Ok(())
+}
+
+/// Merge an edit into the room's shared notes and pass it on as it came to every other
+/// connection, the sender's other tabs too. Blocking doesn't hold these back, everyone has to get
+/// every edit for their notes to match. Everyone can read the notes, editing them is
+/// `permissions.notes`
+async fn notes_update(frame: &Frame, update: String, hub: &Hub, room: &SharedRoom) -> Result<(), Rejected> {
+    let mut room = room.write().await;
+    mark_active(&mut room, frame.user_id);
+    let Some(me) = room.participant_of(frame.user_id) else {
+        return Ok(());
+    };
+    permitted(frame, Tool::Notes, &room)?;
+    let refuse = |room: &Room, code: &str, reason: String| {
+        if let Some(peer) = room.users.get(&frame.user_id) {
+            send_error(peer, code, &reason, frame);
+        }
+        Err(Rejected::Refused(reason))
+    };
+    let bytes = match notes::decode(&update) {
+        Ok(bytes) => bytes,
+        Err(reason) => return refuse(&room, "invalid_notes", reason),
+    };
+    if room.notes.size() + bytes.len() > frame.max_notes_bytes {
+        return refuse(&room, "notes_full", format!("the notes can't grow past {} bytes", frame.max_notes_bytes));
+    }
+    if let Err(reason) = room.notes.apply(&bytes) {
+        return refuse(&room, "invalid_notes", reason);
+    }
+    room.notes_dirty = true;
+    hub.changes.notify_one();
+    log::trace!("[{}] User {} in room {} edited the notes, {} bytes", frame.correlation_id, me, room.id, bytes.len());
+    let relayed = ServerMessage::NotesUpdate { user_id: me, update };
+    for (_, peer) in room.users.iter().filter(|(&user_id, _)| user_id != frame.user_id) {
+        send_frame(peer, &relayed);
+    }
+    Ok(())
+}
+
+/// Send the sender what a Yjs document with `state_vector` is missing of the notes, after a
+/// reconnect or to check nothing was lost
+async fn notes_sync(frame: &Frame, state_vector: String, room: &SharedRoom) -> Result<(), Rejected> {
+    let room = room.read().await;
+    let Some(peer) = room.users.get(&frame.user_id) else {
+        return Ok(());
+    };
+    match notes::decode(&state_vector).and_then(|state_vector| room.notes.diff(&state_vector)) {
+        Ok(missing) => send_frame(peer, &ServerMessage::Notes { update: notes::encode(&missing) }),
+        Err(reason) => {
+            send_error(peer, "invalid_notes", &reason, frame);
+            return Err(Rejected::Refused(reason));
+        }
+    }
+    Ok(())
+}
+
+/// Put back the board from before the last clear, with whatever was drawn since on top, if the
+/// clear was within `rooms.clear_undo_secs`. It's cleared again and redrawn by the sender, so
+/// everyone's board, the op log and replays get it like any other ops
+async fn undo_clear(frame: &Frame, hub: &Hub, room: &SharedRoom, history_limit: usize) -> Result<(), Rejected> {
+    let mut room = room.write().await;
+    mark_active(&mut room, frame.user_id);
+    permitted(frame, Tool::UndoClear, &room)?;
+    let Some((ops, strokes)) = room.take_cleared(frame.clear_undo) else {
+        if let Some(peer) = room.users.get(&frame.user_id) {
+            send_error(peer, "nothing_to_undo", "the board wasn't cleared lately", frame);
+        }
+        return Err(Rejected::Refused("nothing to undo".to_string()));
+    };
+    if let Err(e) = draw_as(hub, &mut room, frame.user_id, MessageType::Clear, history_limit).await {
+        log::error!("[{}] Could not undo the clear of room {}, shared state couldn't take it: {}", frame.correlation_id, room.id, e);
+        // Still there to undo once it can
+        room.cleared = Some((ops, strokes, Instant::now()));
+        if let Some(peer) = room.users.get(&frame.user_id) {
+            send_error(peer, "unavailable", "the board couldn't be reached, try again", frame);
+        }
+        return Err(Rejected::Refused(format!("shared state unavailable: {}", e)));
+    }
+    log::info!("[{}] User {} undid the clear of room {}, redrawing {} ops", frame.correlation_id, frame.user_id, room.id, ops.len());
+    // Undoing the undo isn't a thing
+    room.cleared = None;
+    for op in ops {
+        if let Err(e) = draw_as(hub, &mut room, frame.user_id, op, history_limit).await {
+            log::error!("[{}] Stopped redrawing the board of room {}, shared state couldn't take it: {}", frame.correlation_id, room.id, e);
+            break;
+        }
+    }
+    room.reattribute(strokes);
+    room.last_correlation_id = Some(frame.correlation_id);
+    Ok(())
+}
+
+/// Change the room's info, for a connection signed in as the room's owner. Saving it happens
+/// outside the room lock, like every other storage write
+async fn update_room(frame: &Frame, update: RoomUpdate, hub: &Hub, room: &SharedRoom) -> Result<(), Rejected> {
+    let refuse = |code: &'static str, reason: String| async move {
+        if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+            send_error(peer, code, &reason, frame);
+        }
+        Err(Rejected::Refused(reason))
+    };
+    let (id, account, info) = {
+        let mut room = room.write().await;
+        mark_active(&mut room, frame.user_id);
+        (room.id.clone(), room.users.get(&frame.user_id).and_then(|peer| peer.account.clone()), room.info.clone())
+    };
+    if account.is_none() || account != hub.owner(&id) {
+        return refuse("forbidden", "only the room's owner can do that".to_string()).await;
+    }
+    let info = match update.apply(info).normalize() {
+        Ok(info) => info,
+        Err(reason) => return refuse("invalid_room", reason).await,
+    };
+    if let Err(refused) = hub.moderation.check(&frame.moderation, &id, &moderation::room_texts(&info)).await {
+        return refuse(refused.code, refused.message).await;
+    }
+    if let Err(e) = hub.set_info(room, info.clone()).await {
+        log::error!("[{}] Could not save the info of room {}: {}", frame.correlation_id, id, e);
+        return refuse("unavailable", "could not save the room's info".to_string()).await;
+    }
+    log::debug!("[{}] Owner of room {} updated it to {:?}", frame.correlation_id, id, info);
+    broadcast(&*room.read().await, &ServerMessage::Room(info));
+    Ok(())
+}
+
+/// Keep a report of `user_id` for moderators, with the end of the board, and tell the sender it was
+async fn report(frame: &Frame, user_id: UserId, reason: String, hub: &Hub, room: &SharedRoom) -> Result<(), Rejected> {
+    let filed = {
+        let mut room = room.write().await;
+        mark_active(&mut room, frame.user_id);
+        let Some(peer) = room.users.get(&frame.user_id) else {
+            return Ok(());
+        };
+        let (reporter, account) = (peer.participant, peer.account.clone());
+        reports::file(&room, user_id, &reason, Some(reporter), account)
+    };
+    let refuse = |code: &'static str, reason: String| async move {
+        if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+            send_error(peer, code, &reason, frame);
+        }
+        Err(Rejected::Refused(reason))
+    };
+    let report = match filed {
+        Ok(report) => report,
+        Err(refused) => return refuse(refused.code, refused.message).await,
+    };
+    if let Err(e) = reports::submit(hub, &report).await {
+        log::error!("[{}] Could not keep a report in room {}: {}", frame.correlation_id, report.room, e);
+        return refuse("unavailable", "could not keep the report".to_string()).await;
+    }
+    if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+        send_frame(peer, &ServerMessage::Reported { report_id: report.id });
+    }
+    Ok(())
+}
+
+/// Sign a guest connection in as the account `token` is a session of, without leaving. What
+/// they did as a guest stays theirs: their strokes and contributions are put down to the account,
+/// their follows and blocks move over to it, and their user id, presence and resume token stay as
+/// they are. Everyone gets the roster again, with the account on them
+async fn sign_in(frame: &Frame, token: String, hub: &Hub, room: &SharedRoom) -> Result<(), Rejected> {
+    // Looked up before taking the room lock, they're database queries
+    let account = hub.account(Some(&token));
+    let room_id = room.read().await.id.clone();
+    let owner = owns(hub, account.as_ref().map(|account| account.username.as_str()), &room_id);
+    let mut room = room.write().await;
+    mark_active(&mut room, frame.user_id);
+    let Some(peer) = room.users.get(&frame.user_id) else {
+        return Ok(());
+    };
+    let refused = match &account {
+        None => Some(("invalid_session", "missing or expired session")),
+        Some(_) if peer.account.is_some() => Some(("already_signed_in", "this connection is already signed in")),
+        // Tabs of one account are one participant, which this guest can't become after the fact
+        Some(account) if room.users.values().any(|p| p.account.as_ref() == Some(&account.username)) => Some(("already_joined", "the account is already in the room in another tab")),
+        Some(_) => None,
+    };
+    if let Some((code, reason)) = refused {
+        send_error(peer, code, reason, frame);
+        return Err(Rejected::Refused(reason.to_string()));
+    }
+    let username = account.map(|account| account.username).unwrap_or_default();
+    let (participant, guest) = (peer.participant, peer.identity());
+    let Some(peer) = room.users.get_mut(&frame.user_id) else {
+        return Ok(());
+    };
+    peer.account = Some(username.clone());
+    peer.owner = owner;
+    let identity = peer.identity();
+
+    room.follows.rename(&guest, &identity);
+    if let Some(blocked) = room.blocked.remove(&guest) {
+        room.blocked.insert(identity.clone(), blocked);
+    }
+    for blocked in room.blocked.values_mut() {
+        if blocked.remove(&guest) {
+            blocked.insert(identity.clone());
+        }
+    }
+    let mut attributed = 0;
+    for stroke in room.strokes.iter_mut().filter(|stroke| stroke.user_id == Some(participant) && stroke.account.is_none()) {
+        stroke.account = Some(username.clone());
+        attributed += 1;
+    }
+    // So storage gets the attribution too
+    room.dirty |= attributed > 0;
+    hub.changes.notify_one();
+    if let Some(contribution) = room.contributions.get_mut(&participant) {
+        contribution.account = Some(username.clone());
+    }
+    log::info!("[{}] Guest {} in room {} signed in as {}, keeping {} ops", frame.correlation_id, participant, room.id, username, attributed);
+
+    let mut users: Vec<Member> = room.users.values().map(Peer::member).chain(room.remote_members.values().cloned()).collect();
+    users.sort_by_key(|member| member.user_id);
+    users.dedup_by_key(|member| member.user_id);
+    broadcast(&room, &ServerMessage::Roster { users });
+    Ok(())
+}
+
+// Leaves a `SwitchRoom` to the transport, if it can move a connection to another room at all
+async fn switch_room(frame: &Frame, room_id: String, switch: Option<&mut Option<Switch>>, room: &SharedRoom) -> Result<(), Rejected> {
+    let Some(switch) = switch else {
+        let reason = "only WebSockets can switch rooms, connect to the other room instead";
+        if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+            send_error(peer, "unsupported", reason, frame);
+        }
+        return Err(Rejected::Refused(reason.to_string()));
+    };
+    *switch = Some(Switch { room_id, correlation_id: frame.echo_correlation_id.then(|| frame.correlation_id.to_string()) });
+    Ok(())
+}
+
+/// Make the room read-only for everyone, its owner included, or let them change it again, for a
+/// connection signed in as the room's owner. Lasts until `Unfreeze` or the room is unloaded
+async fn freeze(frame: &Frame, frozen: bool, room: &SharedRoom) -> Result<(), Rejected> {
+    let mut room = room.write().await;
+    mark_active(&mut room, frame.user_id);
+    let Some(peer) = room.users.get(&frame.user_id) else {
+        return Ok(());
+    };
+    if !peer.owner {
+        let reason = "only the room's owner can do that";
+        send_error(peer, "forbidden", reason, frame);
+        return Err(Rejected::Refused(reason.to_string()));
+    }
+    let participant = peer.participant;
+    let changed = room.frozen.is_some() != frozen;
+    if changed {
+        room.frozen = frozen.then_some(participant);
+        log::info!("[{}] Owner {} {} room {}", frame.correlation_id, participant, if frozen { "froze" } else { "unfroze" }, room.id);
+    }
+    let update = ServerMessage::Frozen { frozen, user_id: room.frozen.unwrap_or(participant) };
+    match changed {
+        true => broadcast(&room, &update),
+        false => {
+            for peer in room.tabs(participant) {
+                send_frame(peer, &update);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether `account` is the room's owner, a database query to make before taking the room lock
+fn owns(hub: &Hub, account: Option<&str>, room_id: &str) -> bool {
+    account.is_some() && account == hub.owner(room_id).as_deref()
+}
+
+// Refuses `tool` to everyone while the room is frozen, and otherwise to a sender the room's
+// permissions don't grant it to
+fn permitted(frame: &Frame, tool: Tool, room: &Room) -> Result<(), Rejected> {
+    // `POST /api/rooms/<id>/commands` too, whose bot isn't in `users`
+    if room.frozen.is_some() {
+        let reason = format!("the room is frozen, {} is refused until its owner unfreezes it", tool.name());
+        if let Some(peer) = room.users.get(&frame.user_id) {
+            send_error(peer, "room_frozen", &reason, frame);
+        }
+        return Err(Rejected::Refused(reason));
+    }
+    let Some(peer) = room.users.get(&frame.user_id) else {
+        return Ok(());
+    };
+    let grant = frame.permissions.grant(tool);
+    if grant.allows(peer.role, peer.owner) {
+        return Ok(());
+    }
+    let reason = format!("{} is for {} in this room", tool.name(), grant.name());
+    send_error(peer, "permission_denied", &reason, frame);
+    Err(Rejected::Refused(reason))
+}
+
+// Refuses a frame doing something the room's features turned off
+async fn disabled(frame: &Frame, what: &str, room: &SharedRoom) -> Result<(), Rejected> {
+    if let Some(peer) = room.read().await.users.get(&frame.user_id) {
+        send_error(peer, "feature_disabled", &format!("{} are turned off in this room", what), frame);
+    }
+    Err(Rejected::Refused(format!("{} are turned off", what)))
+}
+
+// Every op on the board, as a joiner gets it, with its stroke id if they asked for echoes. Ops
+// in the room's `SyncSnapshot` go out as it serialized them, only those drawn since are serialized
+// here under the room's lock. How many came from the snapshot
+fn send_board(peer: &Peer, room: &Room) -> usize {
+    let snapshot = room.sync.as_deref().filter(|_| !peer.echo).and_then(|sync| sync.covering(room));
+    let from = match snapshot {
+        Some((frames, ops)) => {
+            for frame in frames {
+                peer.send(Message::text(frame.clone()));
+            }
+            ops
+        }
+        None => 0,
+    };
+    for (msg, stroke) in room.history[from..].iter().zip(&room.strokes[from..]) {
+        let stroke_id = peer.echo.then_some(stroke.stroke_id);
+        match serde_json::to_string(&Stamped { op: msg, epoch: room.epoch, stroke_id }) {
+            Ok(serialized) => { peer.send(Message::text(serialized)); },
+            Err(e) => log::error!("Serialization error: {}", e),
+        }
+    }
+    from
+}
+
+fn checksum(room: &Room) -> ServerMessage {
+    ServerMessage::Checksum { epoch: room.epoch, seq: room.seq, ops: room.history.len(), hash: format!("{:016x}", room.checksum()) }
+}
+
+/// Send everyone in the room its `Checksum` if the board changed since the last one
+pub fn send_checksum(room: &mut Room) {
+    if room.seq == room.checksummed_seq {
+        return;
+    }
+    room.checksummed_seq = room.seq;
+    broadcast(room, &checksum(room));
+}
+
+/// Note input from a connection, bringing its participant back if they were idle or away
+fn mark_active(room: &mut Room, user_id: UserId) {
+    let Some(peer) = room.users.get_mut(&user_id) else {
+        return;
+    };
+    peer.last_input = Instant::now();
+    if peer.presence.is_active() {
+        return;
+    }
+    let (participant, latency_ms) = (peer.participant, peer.latency_ms);
+    for peer in room.tabs_mut(participant) {
+        peer.presence = Presence::Active;
+    }
+    broadcast(room, &ServerMessage::Presence { user_id: participant, presence: Presence::Active, latency_ms });
+}
+
+/// Mark who went idle or away since the last pass, going by the most recently used of their
+/// tabs. A zero threshold is never reached. With `share_latency`, also tell everyone when someone's
+/// ping round trip, their fastest tab's, moved by `LATENCY_STEP_MS` or more
+pub fn update_presence(room: &mut Room, idle_after: Duration, away_after: Duration, share_latency: bool) {
+    let mut quiet: HashMap<UserId, Duration> = HashMap::new();
+    let mut latency: HashMap<UserId, Option<u32>> = HashMap::new();
+    for peer in room.users.values() {
+        let elapsed = peer.last_input.elapsed();
+        quiet.entry(peer.participant).and_modify(|q| *q = (*q).min(elapsed)).or_insert(elapsed);
+        let rtt = peer.stats.rtt_ms().filter(|_| share_latency).map(|ms| ms.round() as u32);
+        let fastest = latency.entry(peer.participant).or_insert(rtt);
+        *fastest = match (*fastest, rtt) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+    let reached = |quiet: Duration, after: Duration| !after.is_zero() && quiet >= after;
+    let mut changed = Vec::new();
+    for peer in room.users.values_mut() {
+        let quiet = quiet[&peer.participant];
+        let presence = match () {
+            _ if reached(quiet, away_after) => Presence::Away,
+            _ if reached(quiet, idle_after) => Presence::Idle,
+            _ => Presence::Active,
+        };
+        let latency_ms = latency[&peer.participant];
+        let moved = match (peer.latency_ms, latency_ms) {
+            (Some(old), Some(new)) => old.abs_diff(new) >= LATENCY_STEP_MS,
+            (old, new) => old.is_some() != new.is_some(),
+        };
+        if presence != peer.presence || moved {
+            peer.presence = presence;
+            peer.latency_ms = latency_ms;
+            if !changed.contains(&(peer.participant, presence, latency_ms)) {
+                changed.push((peer.participant, presence, latency_ms));
+            }
+        }
+    }
+    for (user_id, presence, latency_ms) in changed {
+        broadcast(room, &ServerMessage::Presence { user_id, presence, latency_ms });
+    }
+}
+
+/// Apply ops on behalf of the room's bot, e.g. ones emitted by hooks, and relay them to everyone in
+/// the room. Those after one shared state couldn't take are dropped, nobody is waiting on them
+pub async fn draw_as_bot(hub: &Hub, room: &mut Room, ops: Vec<MessageType>, history_limit: usize) {
+    if ops.is_empty() {
+        return;
+    }
+    let user_id = hub.bots.get(&room.id).user_id;
+    let count = ops.len();
+    for (n, op) in ops.into_iter().enumerate() {
+        if let Err(e) = draw_as(hub, room, user_id, op, history_limit).await {
+            log::error!("Dropped {} ops of the bot of room {}, shared state couldn't take them: {}", count - n, room.id, e);
+            return;
+        }
+    }
+}
+
+/// Apply an op as `user_id` without hooks or quotas and relay it to everyone in the room, returning
+/// its seq. Not applied at all if shared state couldn't take it
+pub async fn draw_as(hub: &Hub, room: &mut Room, user_id: UserId, op: MessageType, history_limit: usize) -> io::Result<u64> {
+    let seq = sequence(hub, room, &op, user_id, history_limit).await?;
+    let timestamp = now_millis();
+    let outgoing = Outgoing::new(room, &op, seq, timestamp, user_id);
+    hub.usage.message(&room.id, user_id);
+    hub.ops.publish(OpRecord {
+        room: room.id.clone(),
+        user_id,
+        seq,
+        stroke_id: room.applied_stroke_id(&op),
+        correlation_id: CorrelationId::next(),
+        timestamp,
+        op,
+    })
+    .await;
+    match outgoing {
+        Ok(outgoing) => {
+            let sent = outgoing.relay(room, None);
+            room.messages_out.record(sent);
+        }
+        Err(e) => log::error!("Serialization error: {}", e),
+    }
+    Ok(seq)
+}
+
+// `Room::apply`, with the seq and epoch handed out by shared state when nodes share it. An op
+// shared state couldn't take isn't applied here either, or this node's board would drift from the
+// others' without anyone knowing
+async fn sequence(hub: &Hub, room: &mut Room, op: &MessageType, user_id: UserId, history_limit: usize) -> io::Result<u64> {
+    let shared = match &hub.shared {
+        Some(shared) => Some(shared.append(&room.id, op, user_id, history_limit).await?),
+        None => None,
+    };
+    let mut author = room.author(user_id);
+    if hub.bots.is_bot(&room.id, user_id) {
+        author.device = Some("bot".to_string());
+    }
+    let seq = room.apply(op, author, history_limit);
+    hub.changes.notify_one();
+    match shared {
+        Some((seq, epoch)) => {
+            (room.seq, room.epoch) = (seq, epoch);
+            Ok(seq)
+        }
+        None => Ok(seq),
+    }
+}
+
+/// Apply an op another node appended to shared state and relay it to everyone in the room here.
+/// It went through hooks, quotas and the op feed on that node
+pub fn draw_shared(room: &mut Room, shared: SharedOp, history_limit: usize) {
+    let author = Stroke { drawn_at: Some(shared.at), ..room.author(shared.user_id) };
+    room.apply(&shared.op, author, history_limit);
+    (room.seq, room.epoch) = (room.seq.max(shared.seq), shared.epoch);
+    match Outgoing::new(room, &shared.op, shared.seq, shared.at, shared.user_id) {
+        Ok(outgoing) => {
+            let sent = outgoing.relay(room, None);
+            room.messages_out.record(sent);
+        }
+        Err(e) => log::error!("Serialization error: {}", e),
+    }
+}
+
+/// Take in who's in the room on other nodes, telling everyone here who joined, changed or left
+pub fn remote_members(room: &mut Room, members: Vec<Member>) {
+    let mut gone: HashSet<UserId> = room.remote_members.keys().copied().collect();
+    for member in members {
+        gone.remove(&member.user_id);
+        let Some(old) = room.remote_members.insert(member.user_id, member.clone()) else {
+            broadcast(room, &ServerMessage::Joined(member));
+            continue;
+        };
+        if (&old.profile, old.hand_raised_at, &old.reaction, &old.account) != (&member.profile, member.hand_raised_at, &member.reaction, &member.account) {
+            broadcast(room, &ServerMessage::Profile(member.clone()));
+        }
+        if (old.presence, old.latency_ms) != (member.presence, member.latency_ms) {
+            broadcast(room, &ServerMessage::Presence { user_id: member.user_id, presence: member.presence, latency_ms: member.latency_ms });
+        }
+        if old.voice != member.voice {
+            broadcast(room, &ServerMessage::VoiceState { user_id: member.user_id, voice: member.voice });
+        }
+    }
+    for user_id in gone {
+        room.remote_members.remove(&user_id);
+        broadcast(room, &ServerMessage::Left { user_id, reason: None });
+    }
+}
+
+/// An applied op serialized for the room, and with its seq for connections that asked for echoes
+struct Outgoing {
+    stamped: String,
+    echoed: Option<String>,
+}
+
+impl Outgoing {
+    fn new(room: &Room, op: &MessageType, seq: u64, timestamp: u64, user_id: UserId) -> serde_json::Result<Self> {
+        let stamped = Stamped { op, epoch: room.epoch, stroke_id: None };
+        let echoed = match room.users.values().any(|peer| peer.echo) {
+            true => {
+                let user_id = room.users.get(&user_id).map_or(user_id, |peer| peer.participant);
+                let op = Stamped { stroke_id: room.applied_stroke_id(op), ..stamped };
+                Some(serde_json::to_string(&Echoed { op, seq, timestamp, user_id })?)
+            }
+            false => None,
+        };
+        Ok(Outgoing { stamped: serde_json::to_string(&stamped)?, echoed })
+    }
+
+    /// Send to everyone in the room but `except`, who still gets it if they asked for echoes. Returns how many it went to
+    fn relay(&self, room: &Room, except: Option<UserId>) -> u32 {
+        let mut sent = 0;
+        for (&uid, peer) in room.users.iter() {
+            let text = match &self.echoed {
+                Some(echoed) if peer.echo => echoed,
+                _ if except == Some(uid) => continue,
+                _ => &self.stamped,
+            };
+            if peer.send(Message::text(text)) {
+                sent += 1;
+            } else {
+                log::debug!("User {} disconnected", uid);
+            }
+        }
+        sent
+    }
+}
+
+fn send_error(peer: &Peer, code: &str, message: &str, frame: &Frame) {
+    send_frame(peer, &ServerMessage::Error {
+        code: code.to_string(),
+        message: message.to_string(),
+        correlation_id: frame.echo_correlation_id.then(|| frame.correlation_id.to_string()),
+    });
+}
+
+fn send_frame(peer: &Peer, frame: &ServerMessage) {
+    match serde_json::to_string(frame) {
+        Ok(serialized) => { peer.send(Message::text(serialized)); },
+        Err(e) => log::error!("Serialization error: {}", e),
+    }
+}
+
+// Like `broadcast`, for frames from the sender of `frame` that may be dropped for clients over
+// their bandwidth. Their filters see who it's from, so e.g. anyone who blocked them doesn't get it
+fn broadcast_ephemeral(room: &Room, frame: &Frame, msg: &ServerMessage) {
+    let Some(sender) = room.users.get(&frame.user_id).map(Peer::identity) else {
+        return;
+    };
+    deliver(room, room.users.values(), Some(&sender), msg, Some(frame.outbound_bytes_per_second));
+}
+
+/// Send a frame to everyone in the room whose filters let it through
+pub fn broadcast(room: &Room, frame: &ServerMessage) {
+    deliver(room, room.users.values(), None, frame, None);
+}
+
+// Send `msg` to each of `to` whose filters let it through, see `filters`, serialized once.
+// `from` is the sender's `Peer::identity`, None for the server's own frames, and ephemeral frames
+// come with `Some(bytes_per_second)`, see `Peer::send_ephemeral`. How many it was sent to
+fn deliver<'a>(room: &Room, to: impl Iterator<Item = &'a Peer>, from: Option<&str>, msg: &ServerMessage, ephemeral: Option<u64>) -> u32 {
+    let outbound = Outbound { message: msg, from };
+    let mut serialized = None;
+    let mut sent = 0;
+    for peer in to.filter(|peer| peer.filters.allows(room, peer, &outbound)) {
+        if serialized.is_none() {
+            match serde_json::to_string(msg) {
+                Ok(text) => serialized = Some(text),
+                Err(e) => {
+                    log::error!("Serialization error: {}", e);
+                    return 0;
+                }
+            }
+        }
+        let text = Message::text(serialized.as_deref().unwrap_or_default());
+        let queued = match ephemeral {
+            Some(bytes_per_second) => peer.send_ephemeral(text, bytes_per_second),
+            None => peer.send(text),
+        };
+        sent += u32::from(queued);
+    }
+    sent
+}
+
+async fn user_disconnected(hub: &Hub, my_id: UserId, reason: DisconnectReason, shared: &SharedRoom) {
+    log::info!("good bye user: {} ({})", my_id, reason.as_str());
+
+    // Stream closed up, so remove from the user list
+    let mut room = shared.write().await;
+    // A signed-in user with other tabs still open hasn't left
+    if let Some(peer) = room.users.remove(&my_id).filter(|peer| room.tabs(peer.participant).next().is_none()) {
+        room.follows.left(&peer.identity());
+        broadcast(&room, &ServerMessage::Left { user_id: peer.participant, reason: Some(reason) });
+        if peer.profile != Default::default() {
+            hub.profiles.remember(peer.resume_token, peer.profile);
+        }
+    }
+    promote(hub, &mut room).await;
+    if !room.users.is_empty() {
+        return;
+    }
+    room.empty_since = Some(Instant::now());
+    drop(room);
+    // The session is over, so its final state goes to disk now rather than at the next periodic
+    // save or when the room is unloaded, with nothing left to lose if the server dies in between
+    hub.save(shared).await;
+    hub.ops.flush();
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::{ConfigHandle, SocketIoConfig};
+use crate::connection::{ConnectionStats, DisconnectReason, Peer};
+use crate::hub::{Hub, DEFAULT_ROOM};
+use crate::ids::UserId;
+use crate::listener;
+use crate::room::SharedRoom;
+use crate::socket::{self, Inbound, Rejected};
+use crate::trial;
+
+// Engine.IO packet types, the outer framing
+const EIO_OPEN: char = '0';
+const EIO_CLOSE: char = '1';
+const EIO_PING: char = '2';
+const EIO_PONG: char = '3';
+const EIO_MESSAGE: char = '4';
+
+// Socket.IO packet types, carried in Engine.IO messages
+const SIO_CONNECT: char = '0';
+const SIO_DISCONNECT: char = '1';
+const SIO_EVENT: char = '2';
+const SIO_ACK: char = '3';
+const SIO_CONNECT_ERROR: char = '4';
+
+/// `/socket.io/`, for socket.io v3/v4 clients using the WebSocket transport
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let remote = listener::remote(config.clone());
+    let config = warp::any().map(move || config.clone());
+    let hub = warp::any().map(move || hub.clone());
+
+    let socket_io = warp::path("socket.io").and(warp::path::end());
+
+    let websocket = socket_io
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(remote)
+        .and(hub)
+        .and(config.clone())
+        .and_then(upgrade);
+
+    // What Engine.IO answers for a transport it doesn't offer, so clients report something useful
+    let polling = socket_io
+        .and(config)
+        .and_then(|config: ConfigHandle| async move {
+            if !config.borrow().socketio.enabled {
+                return Err(warp::reject::not_found());
+            }
+            let body = json!({ "code": 0, "message": "Transport unknown" });
+            Ok(warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST))
+        });
+
+    websocket.or(polling)
+}
+
+async fn upgrade(
+    ws: Ws,
+    origin: Option<String>,
+    query: HashMap<String, String>,
+    remote_addr: Option<SocketAddr>,
+    hub: Arc<Hub>,
+    config: ConfigHandle,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let current = config.borrow().clone();
+    if !current.socketio.enabled {
+        return Err(warp::reject::not_found());
+    }
+    if query.get("EIO").map(String::as_str) != Some("4") {
+        let body = json!({ "code": 5, "message": "Unsupported protocol version" });
+        return Ok(Box::new(warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)));
+    }
+
+    let allowed = &current.server.allowed_origins;
+    if !allowed.is_empty() && !origin.as_ref().is_some_and(|o| allowed.contains(o)) {
+        log::warn!("Rejected socket.io upgrade from origin {:?}", origin);
+        return Ok(Box::new(warp::reply::with_status("origin not allowed", StatusCode::FORBIDDEN)));
+    }
+
+    // Room and key come with the Socket.IO connect packet, so those checks happen once it arrives
+    let ws = ws
+        .max_message_size(current.limits.max_message_bytes)
+        .max_frame_size(current.limits.max_message_bytes);
+    Ok(Box::new(ws.on_upgrade(move |socket| connect_user(socket, query, hub, remote_addr, config))))
+}
+
+/// Who a Socket.IO connection is and where it's drawing, settled by its connect packet
+struct Joined {
+    room: SharedRoom,
+    stats: Arc<ConnectionStats>,
+}
+
+async fn connect_user(ws: WebSocket, query: HashMap<String, String>, hub: Arc<Hub>, remote_addr: Option<SocketAddr>, config: ConfigHandle) {
+    let settings = config.borrow().socketio.clone();
+    let (mut sender, mut receiver) = ws.split();
+
+    let open = json!({
+        "sid": new_sid(),
+        "upgrades": [],
+        "pingInterval": settings.ping_interval_secs * 1000,
+        "pingTimeout": settings.ping_timeout_secs * 1000,
+        "maxPayload": config.borrow().limits.max_message_bytes,
+    });
+    if sender.send(Message::text(format!("{}{}", EIO_OPEN, open))).await.is_err() {
+        return;
+    }
+
+    let Some(joined) = handshake(&mut sender, &mut receiver, &query, &hub, remote_addr, &config, &settings).await else {
+        let _ = sender.send(Message::close()).await;
+        return;
+    };
+    let Joined { room, stats } = joined;
+    let current_user_id = stats.user_id;
+    let room_id = stats.room_id();
+    let connected_at = Instant::now();
+
+    // Acks skip the room's queue, they aren't counted as broadcasts
+    let (control_sender, mut control) = mpsc::unbounded_channel::<String>();
+    let (message_sender, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    let writer_stats = stats.clone();
+    let ping_interval = Duration::from_secs(settings.ping_interval_secs.max(1));
+    let mut writer = tokio::task::spawn(socket::isolated(hub.clone(), "writer", current_user_id, room_id.clone(), async move {
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+        loop {
+            let packet = tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) if message.is_close() => {
+                        let _ = sender.send(Message::text(format!("{}{}", EIO_MESSAGE, SIO_DISCONNECT))).await;
+                        let _ = sender.send(Message::close()).await;
+                        break;
+                    }
+                    Some(message) => {
+                        writer_stats.sent();
+                        match event_packet(&message) {
+                            Some(packet) => packet,
+                            None => continue,
+                        }
+                    }
+                    None => break,
+                },
+                packet = control.recv() => match packet {
+                    Some(packet) => packet,
+                    None => break,
+                },
+                _ = ping.tick() => {
+                    writer_stats.ping_sent();
+                    EIO_PING.to_string()
+                }
+            };
+            if let Err(e) = sender.send(Message::text(packet)).await {
+                log::warn!("socket.io send error: {}", e);
+                break;
+            }
+        }
+    }));
+
+    let capabilities = socket::capabilities(&hub, &room, &config, stats.transport).await;
+    socket::join(&hub, &room, Peer::new(message_sender.clone(), stats.clone()).with_capabilities(capabilities)).await;
+
+    let reader = read_messages(&mut receiver, &mut writer, &control_sender, &hub, &room, &stats, &config, &settings);
+    if socket::isolated(hub.clone(), "reader", current_user_id, room_id, reader).await.is_none() {
+        stats.closing(DisconnectReason::Error);
+        let _ = message_sender.send(Message::close_with(1011u16, "internal error"));
+    }
+    drop(message_sender);
+
+    socket::leave(&hub, &room, &stats, connected_at).await;
+}
+
+/// Wait for the client's connect packet, check its room and key, and answer it
+async fn handshake(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    query: &HashMap<String, String>,
+    hub: &Hub,
+    remote_addr: Option<SocketAddr>,
+    config: &ConfigHandle,
+    settings: &SocketIoConfig,
+) -> Option<Joined> {
+    let timeout = Duration::from_secs(settings.ping_interval_secs + settings.ping_timeout_secs);
+    let auth = loop {
+        let msg = tokio::time::timeout(timeout, receiver.next()).await.ok()??.ok()?;
+        let Ok(text) = msg.to_str() else {
+            continue;
+        };
+        match parse_engine(text) {
+            Some((EIO_MESSAGE, packet)) => {
+                let packet = parse_socket(packet)?;
+                if packet.kind != SIO_CONNECT {
+                    continue;
+                }
+                if packet.namespace != "/" {
+                    let error = json!({ "message": "Invalid namespace" });
+                    let _ = sender.send(Message::text(format!("{}{}{},{}", EIO_MESSAGE, SIO_CONNECT_ERROR, packet.namespace, error))).await;
+                    continue;
+                }
+                break packet.payload.unwrap_or(Value::Null);
+            }
+            Some((EIO_CLOSE, _)) | None => return None,
+            Some(_) => continue,
+        }
+    };
+
+    // `io(url, { auth: { room, key, token } })`, or the older `query` option
+    let field = |name: &str| auth.get(name).and_then(Value::as_str).map(str::to_string).or_else(|| query.get(name).cloned());
+    let asked = field("room").unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    let (room_id, valid) = match hub.room_id(&asked) {
+        Some(room_id) => (room_id, true),
+        None => (asked, false),
+    };
+    let current = config.borrow().clone();
+
+    // The owner checks everything else, `data.url` is where to connect instead
+    if let Some(owner) = valid.then(|| hub.cluster.remote_owner(&room_id, Duration::from_secs(current.cluster.dead_after_secs))).flatten() {
+        log::info!("Sent socket.io join to room {} to cluster node {}", room_id, owner.node_id);
+        let error = json!({ "message": "room is on another node", "data": { "url": owner.url } });
+        let _ = sender.send(Message::text(format!("{}{}{}", EIO_MESSAGE, SIO_CONNECT_ERROR, error))).await;
+        return None;
+    }
+
+    let refusal = if !valid {
+        Some("invalid room id".to_string())
+    } else if !current.auth.access_keys.is_empty() && !field("key").is_some_and(|k| current.auth.access_keys.contains(&k)) {
+        Some("missing or invalid key".to_string())
+    } else if !hub.may_access(&room_id, field("token").as_deref()) {
+        Some("not on the room's access list".to_string())
+    } else {
+        hub.quotas.check_join(&room_id, &hub.usage.room(&room_id)).err().map(|e| e.to_string())
+    };
+    let room = match refusal {
+        Some(refusal) => Err(refusal),
+        None => hub.open(&room_id).await.map_err(|e| {
+            log::error!("Could not load room {}: {}", room_id, e);
+            "room unavailable".to_string()
+        }),
+    };
+    let opens_at = match &room {
+        Ok(room) => room.read().await.info.opens_later(),
+        Err(_) => None,
+    };
+    let room = match room {
+        Ok(_) if opens_at.is_some() => Err(format!("not yet open, opens at {}", opens_at.unwrap_or_default())),
+        Ok(room) if socket::is_full(hub, &room, trial::capacity_of(&current, &room_id)).await => Err("room is full".to_string()),
+        room => room,
+    };
+    let room = match room {
+        Ok(room) => room,
+        Err(refusal) => {
+            log::info!("Refused socket.io join to room {}: {}", room_id, refusal);
+            let error = json!({ "message": refusal });
+            let _ = sender.send(Message::text(format!("{}{}{}", EIO_MESSAGE, SIO_CONNECT_ERROR, error))).await;
+            return None;
+        }
+    };
+
+    let connected = json!({ "sid": new_sid() });
+    sender.send(Message::text(format!("{}{}{}", EIO_MESSAGE, SIO_CONNECT, connected))).await.ok()?;
+    let stats = Arc::new(ConnectionStats::new(UserId::random(), room_id, remote_addr, "socketio"));
+    Some(Joined { room, stats })
+}
+
+/// Apply and relay every event this user emits until they disconnect or stop answering pings
+#[allow(clippy::too_many_arguments)]
+async fn read_messages(
+    receiver: &mut SplitStream<WebSocket>,
+    writer: &mut JoinHandle<Option<()>>,
+    control: &mpsc::UnboundedSender<String>,
+    hub: &Hub,
+    room: &SharedRoom,
+    stats: &ConnectionStats,
+    config: &ConfigHandle,
+    settings: &SocketIoConfig,
+) {
+    let current_user_id = stats.user_id;
+    let mut inbound = Inbound::new(&config.borrow().limits);
+    // The client pongs every ping, so this long without hearing from it means it's gone
+    let timeout = Duration::from_secs(settings.ping_interval_secs + settings.ping_timeout_secs);
+
+    loop {
+        let result = tokio::select! {
+            result = tokio::time::timeout(timeout, receiver.next()) => match result {
+                Ok(Some(result)) => result,
+                Ok(None) => break,
+                Err(_) => {
+                    log::info!("socket.io client of user {} stopped answering pings", current_user_id);
+                    stats.closing(DisconnectReason::Timeout);
+                    break;
+                }
+            },
+            _ = &mut *writer => {
+                stats.closing(DisconnectReason::Error);
+                break;
+            }
+        };
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("Could not receive message from user {}: {}", current_user_id, e);
+                stats.closing(DisconnectReason::Error);
+                break;
+            }
+        };
+        let Ok(text) = msg.to_str() else {
+            continue;
+        };
+        let packet = match parse_engine(text) {
+            Some((EIO_PONG, _)) => {
+                stats.pong_received();
+                continue;
+            }
+            Some((EIO_MESSAGE, packet)) => packet,
+            Some((EIO_CLOSE, _)) | None => break,
+            Some(_) => continue,
+        };
+        let Some(packet) = parse_socket(packet) else {
+            log::debug!("Dropped malformed socket.io packet from user {}", current_user_id);
+            continue;
+        };
+        match packet.kind {
+            SIO_DISCONNECT => break,
+            SIO_EVENT => {}
+            _ => continue,
+        }
+
+        let Some(frame) = packet.payload.as_ref().and_then(event_frame) else {
+            log::debug!("Dropped socket.io packet without an event from user {}", current_user_id);
+            continue;
+        };
+        let result = inbound.handle(Message::text(frame.to_string()), hub, room, stats, config).await;
+        if let Some(id) = packet.ack_id {
+            let args = match result {
+                Ok(()) => json!([]),
+                Err(Rejected::RateLimited) => json!([{ "error": "rate limited" }]),
+                Err(Rejected::Invalid(e)) => json!([{ "error": format!("invalid message: {}", e) }]),
+                Err(Rejected::OverQuota(e) | Rejected::Refused(e)) => json!([{ "error": e }]),
+            };
+            let _ = control.send(format!("{}{}{}{}", EIO_MESSAGE, SIO_ACK, id, args));
+        }
+    }
+}
+
+struct SocketPacket<'a> {
+    kind: char,
+    namespace: &'a str,
+    ack_id: Option<u64>,
+    payload: Option<Value>,
+}
+
+fn new_sid() -> String {
+    format!("{:020x}", rand::random::<u128>() >> 48)
+}
+
+fn parse_engine(text: &str) -> Option<(char, &str)> {
+    let kind = text.chars().next()?;
+    Some((kind, &text[kind.len_utf8()..]))
+}
+
+/// `<type>[/<namespace>,][<ack id>][<json>]`
+fn parse_socket(packet: &str) -> Option<SocketPacket<'_>> {
+    let kind = packet.chars().next()?;
+    let mut rest = &packet[kind.len_utf8()..];
+
+    let mut namespace = "/";
+    if rest.starts_with('/') {
+        let end = rest.find(',').unwrap_or(rest.len());
+        namespace = &rest[..end];
+        rest = rest.get(end + 1..).unwrap_or_default();
+    }
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let ack_id = rest[..digits].parse().ok();
+    rest = &rest[digits..];
+
+    let payload = match rest {
+        "" => None,
+        json => Some(serde_json::from_str(json).ok()?),
+    };
+    Some(SocketPacket { kind, namespace, ack_id, payload })
+}
+
+/// `["draw", {...}]` as the frame a WebSocket client would send, `{"type": "Draw", "data": {...}}`
+fn event_frame(args: &Value) -> Option<Value> {
+    let args = args.as_array()?;
+    let event = args.first()?.as_str()?;
+    let mut chars = event.chars();
+    let kind: String = chars.next()?.to_uppercase().chain(chars).collect();
+    Some(match args.get(1) {
+        Some(data) => json!({ "type": kind, "data": data }),
+        None => json!({ "type": kind }),
+    })
+}
+
+/// A relayed frame as the event a socket.io client listens for, `{"type": "Draw", ...}` becomes `draw`
+fn event_packet(message: &Message) -> Option<String> {
+    let frame: Value = serde_json::from_str(message.to_str().ok()?).ok()?;
+    let kind = frame.get("type")?.as_str()?;
+    let mut chars = kind.chars();
+    let event: String = chars.next()?.to_lowercase().chain(chars).collect();
+    let args = match frame.get("data") {
+        Some(data) => json!([event, data]),
+        None => json!([event]),
+    };
+    Some(format!("{}{}{}", EIO_MESSAGE, SIO_EVENT, args))
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::stream::{self, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use warp::http::StatusCode;
+use warp::sse::Event;
+
+use crate::cluster;
+use crate::config::ConfigHandle;
+use crate::hub::Hub;
+use crate::protocol::MessageType;
+
+fn op_event(op: &MessageType) -> Event {
+    Event::default().event("op").data(serde_json::to_string(op).unwrap_or_default())
+}
+
+/// `GET /api/rooms/<id>/events`, a read-only Server-Sent Events view of a room: its current history,
+/// then every op as it's accepted. Ops carry the same JSON as WebSocket frames
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/events",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("key" = Option<String>, Query, description = "Access key, when `auth.access_keys` is set"),
+        ("token" = Option<String>, Query, description = "Login session, for rooms with an access list"),
+    ),
+    responses(
+        (status = 200, description = "`op` events, each one's data a frame in the WebSocket format", content_type = "text/event-stream", body = String),
+        (status = 307, description = "The room is owned by another cluster node, at `Location`"),
+        (status = 400, description = "Invalid room id"),
+        (status = 401, description = "Missing or invalid key"),
+        (status = 403, description = "Not on the room's access list"),
+    ),
+)]
+pub async fn room_events(
+    room_id: String,
+    query: HashMap<String, String>,
+    hub: Arc<Hub>,
+    config: ConfigHandle,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let current = config.borrow().clone();
+
+    let path = format!("api/rooms/{}/events", room_id);
+    let Some(room_id) = hub.room_id(&room_id) else {
+        return Ok(Box::new(warp::reply::with_status("invalid room id", StatusCode::BAD_REQUEST)));
+    };
+    // The owner checks everything else
+    if let Some(owner) = hub.cluster.remote_owner(&room_id, Duration::from_secs(current.cluster.dead_after_secs)) {
+        return Ok(cluster::moved(&owner, &path, &query));
+    }
+    let keys = &current.auth.access_keys;
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return Ok(Box::new(warp::reply::with_status("missing or invalid key", StatusCode::UNAUTHORIZED)));
+    }
+    if !hub.may_access(&room_id, query.get("token").map(String::as_str)) {
+        return Ok(Box::new(warp::reply::with_status("not on the room's access list", StatusCode::FORBIDDEN)));
+    }
+
+    let room = match hub.open(&room_id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", room_id, e);
+            return Ok(Box::new(warp::reply::with_status("room unavailable", StatusCode::SERVICE_UNAVAILABLE)));
+        }
+    };
+
+    // Ops are published under the room's write lock, so subscribing under the read lock
+    // means nothing falls between the history and the live feed
+    let (history, ops) = {
+        let room = room.read().await;
+        (room.history.clone(), hub.ops.subscribe())
+    };
+    log::info!("SSE viewer attached to room {}, synced {} ops", room_id, history.len());
+
+    let synced = stream::iter(history).map(|op| Ok::<_, Infallible>(op_event(&op)));
+    let live = stream::unfold(ops, move |mut ops| {
+        let room_id = room_id.clone();
+        async move {
+            loop {
+                match ops.recv().await {
+                    Ok(record) if record.room == room_id => {
+                        let event = op_event(&record.op).id(record.seq.to_string());
+                        return Some((Ok(event), ops));
+                    }
+                    Ok(_) => continue,
+                    // The viewer's board would be missing ops, end it so EventSource reconnects and resyncs
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("SSE viewer of room {} fell {} ops behind, closing", room_id, skipped);
+                        return None;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(synced.chain(live)))))
+}
@@ -0,0 +1,154 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use warp::{Filter, Rejection, Reply};
+
+use crate::api;
+use crate::config::ConfigHandle;
+use crate::hub::{stored_size, Hub};
+
+// When the routes were built, near enough when the server started
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+struct RoomRow {
+    id: String,
+    name: Option<String>,
+    participants: usize,
+    waitlisted: usize,
+    history_ops: usize,
+    history_bytes: u64,
+    messages_in_per_sec: f64,
+    messages_out_per_sec: f64,
+    age_secs: u64,
+}
+
+/// `GET /status`, a page of what the server is doing for deployments without their own dashboards.
+/// Admin only, as a bearer token like the rest of the admin API
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    STARTED.get_or_init(Instant::now);
+    let hub = warp::any().map(move || hub.clone());
+    warp::path!("status")
+        .and(warp::get())
+        .and(api::admin(config))
+        .and(hub)
+        .then(|hub: Arc<Hub>| async move { warp::reply::html(render(&hub).await) })
+}
+
+async fn render(hub: &Hub) -> String {
+    let mut rows = Vec::new();
+    for room in hub.rooms().await {
+        let room = room.read().await;
+        rows.push(RoomRow {
+            id: room.id.clone(),
+            name: room.info.name.clone(),
+            participants: room.participants(),
+            waitlisted: room.waitlist.len(),
+            history_ops: room.history.len(),
+            history_bytes: stored_size(&room.history),
+            messages_in_per_sec: room.messages_in.per_second(),
+            messages_out_per_sec: room.messages_out.per_second(),
+            age_secs: room.created_at.elapsed().as_secs(),
+        });
+    }
+    rows.sort_by(|a, b| b.participants.cmp(&a.participants).then_with(|| a.id.cmp(&b.id)));
+
+    let uptime = STARTED.get().map_or(0, |started| started.elapsed().as_secs());
+    let participants: usize = rows.iter().map(|row| row.participants).sum();
+    let history_bytes: u64 = rows.iter().map(|row| row.history_bytes).sum();
+    let messages_in: f64 = rows.iter().map(|row| row.messages_in_per_sec).sum();
+    let messages_out: f64 = rows.iter().map(|row| row.messages_out_per_sec).sum();
+    let memory = resident_bytes().map_or("unknown".to_string(), bytes);
+
+    let mut summary = String::new();
+    let mut item = |label: &str, value: String| {
+        let _ = write!(summary, "<tr><th>{}</th><td>{}</td></tr>", label, escape_html(&value));
+    };
+    item("Version", env!("CARGO_PKG_VERSION").to_string());
+    item("Uptime", duration(uptime));
+    item("Resident memory", memory);
+    item("Rooms loaded", rows.len().to_string());
+    item("Participants", participants.to_string());
+    item("Pending connections", hub.pending.load(Ordering::Relaxed).to_string());
+    item("Connections opened", hub.metrics.connections_opened.get().to_string());
+    item("Messages in / out", format!("{:.1}/s / {:.1}/s", messages_in, messages_out));
+    item("Board history", bytes(history_bytes));
+
+    let mut table = String::new();
+    for row in &rows {
+        let _ = write!(
+            table,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}/s</td><td>{:.1}/s</td><td>{}</td></tr>",
+            escape_html(&row.id),
+            escape_html(row.name.as_deref().unwrap_or("")),
+            row.participants,
+            row.waitlisted,
+            row.history_ops,
+            bytes(row.history_bytes),
+            row.messages_in_per_sec,
+            row.messages_out_per_sec,
+            duration(row.age_secs),
+        );
+    }
+    if rows.is_empty() {
+        table.push_str(r#"<tr><td colspan="9">No rooms are loaded</td></tr>"#);
+    }
+
+    format!(
+        r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="10">
+<title>Whiteboard server status</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.8em; text-align: left; }}
+td {{ font-variant-numeric: tabular-nums; }}
+</style>
+</head>
+<body>
+<h1>Whiteboard server status</h1>
+<table>{summary}</table>
+<h2>Rooms</h2>
+<table>
+<tr><th>Room</th><th>Name</th><th>Participants</th><th>Waitlisted</th><th>Ops on board</th><th>History</th><th>Messages in</th><th>Messages out</th><th>Loaded for</th></tr>
+{table}
+</table>
+</body>
+</html>
+"##
+    )
+}
+
+// Linux only, the page says unknown elsewhere
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.trim_start_matches("VmRSS:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+fn bytes(n: u64) -> String {
+    match n {
+        n if n >= 1 << 30 => format!("{:.1} GiB", n as f64 / (1u64 << 30) as f64),
+        n if n >= 1 << 20 => format!("{:.1} MiB", n as f64 / (1u64 << 20) as f64),
+        n if n >= 1 << 10 => format!("{:.1} KiB", n as f64 / (1u64 << 10) as f64),
+        n => format!("{} B", n),
+    }
+}
+
+fn duration(secs: u64) -> String {
+    match secs {
+        s if s >= 86400 => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
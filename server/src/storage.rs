@@ -0,0 +1,646 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::hub::valid_room_id;
+use crate::notes;
+use crate::protocol::MessageType;
+use crate::reports::Report;
+use crate::room::{RoomInfo, SessionSummary, Stroke, Template};
+use crate::sealing::{self, Keyring};
+use crate::tenants;
+
+// Summaries kept per room, oldest dropped first
+const MAX_SUMMARIES: usize = 100;
+
+// Reports kept per room, oldest dropped first, so a flood of them can't fill the disk
+const MAX_REPORTS: usize = 100;
+
+const TEMPLATE_SUFFIX: &str = ".template.json";
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load(&self, room: &str) -> io::Result<Vec<MessageType>>;
+    async fn save(&self, room: &str, history: &[MessageType]) -> io::Result<()>;
+    // Used by /readyz, should fail if writes would fail
+    async fn ping(&self) -> io::Result<()>;
+    /// Keep the summary of a session that ended, see `Hub::expire_idle`
+    async fn save_summary(&self, summary: &SessionSummary) -> io::Result<()>;
+    /// A room's session summaries, oldest first
+    async fn summaries(&self, room: &str) -> io::Result<Vec<SessionSummary>>;
+    /// Replace a room's session summaries, none removes them, see `retention`
+    async fn replace_summaries(&self, room: &str, summaries: &[SessionSummary]) -> io::Result<()>;
+    /// The info a room was last saved with, None if it never was
+    async fn load_info(&self, room: &str) -> io::Result<Option<RoomInfo>>;
+    async fn save_info(&self, room: &str, info: &RoomInfo) -> io::Result<()>;
+    /// Keep a room's board and mark it archived at `archived_at` (unix seconds), see `Hub::archive`
+    async fn archive(&self, room: &str, history: &[MessageType], archived_at: u64) -> io::Result<()>;
+    /// When the room was archived, None unless it is
+    async fn archived(&self, room: &str) -> io::Result<Option<u64>>;
+    /// Unmark an archived room so its board loads again, false if it wasn't archived
+    async fn restore(&self, room: &str) -> io::Result<bool>;
+    /// Keep a template, replacing one with the same name
+    async fn save_template(&self, template: &Template) -> io::Result<()>;
+    async fn load_template(&self, name: &str) -> io::Result<Option<Template>>;
+    /// Every template, in no particular order
+    async fn templates(&self) -> io::Result<Vec<Template>>;
+    /// The ids of every room with anything stored, in no particular order
+    async fn rooms(&self) -> io::Result<Vec<String>>;
+    /// A room's shared notes as `Notes::state` left them, None if it has none
+    async fn load_notes(&self, room: &str) -> io::Result<Option<Vec<u8>>>;
+    async fn save_notes(&self, room: &str, state: &[u8]) -> io::Result<()>;
+    /// Who drew each op of the board `load` returns, None if nobody was kept
+    async fn load_strokes(&self, room: &str) -> io::Result<Option<Vec<Stroke>>>;
+    async fn save_strokes(&self, room: &str, strokes: &[Stroke]) -> io::Result<()>;
+    /// Keep an abuse report, see `reports`
+    async fn save_report(&self, report: &Report) -> io::Result<()>;
+    /// A room's reports, oldest first
+    async fn reports(&self, room: &str) -> io::Result<Vec<Report>>;
+    /// Replace a room's reports, none removes them
+    async fn replace_reports(&self, room: &str, reports: &[Report]) -> io::Result<()>;
+    /// Remove everything kept for a room, its board, who drew it, info, notes, summaries, reports and archive mark
+    async fn delete(&self, room: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StorageSpec {
+    Memory,
+    File(PathBuf),
+}
+
+impl TryFrom<String> for StorageSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<StorageSpec> for String {
+    fn from(spec: StorageSpec) -> Self {
+        match spec {
+            StorageSpec::Memory => "memory".to_string(),
+            StorageSpec::File(dir) => format!("file:{}", dir.display()),
+        }
+    }
+}
+
+impl FromStr for StorageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(StorageSpec::Memory),
+            Some(("file", dir)) if !dir.is_empty() => Ok(StorageSpec::File(PathBuf::from(dir))),
+            _ => Err(format!("unknown storage `{}`, expected `memory` or `file:<dir>`", s)),
+        }
+    }
+}
+
+impl StorageSpec {
+    /// With a `keyring`, file storage seals what it writes, and flushes it to disk when
+    /// `durability` asks for that
+    pub async fn open(&self, keyring: Option<Arc<Keyring>>, durability: Durability) -> io::Result<Arc<dyn Storage>> {
+        match self {
+            StorageSpec::Memory => Ok(Arc::new(MemoryStorage::default())),
+            StorageSpec::File(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                Ok(Arc::new(FileStorage { dir: dir.clone(), keyring, sync: durability.syncs() }))
+            }
+        }
+    }
+
+    /// The directory of file storage
+    pub fn dir(&self) -> Option<&Path> {
+        match self {
+            StorageSpec::Memory => None,
+            StorageSpec::File(dir) => Some(dir),
+        }
+    }
+}
+
+/// How soon a change to a room is saved, traded against how often its files are rewritten: each
+/// save writes the whole board, however few ops changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Saved every few seconds and left to the OS to flush, a crash can lose those seconds
+    #[default]
+    Async,
+    /// Saved every `storage.commit_interval_ms`, with every change since in one write per room,
+    /// and flushed to disk
+    Batched,
+    /// Saved and flushed to disk as soon as anything changes. Changes made while a save is
+    /// under way go in the next one together, so a busy board isn't written once per op
+    Fsync,
+}
+
+impl Durability {
+    /// Whether what's written is flushed to disk before it counts as saved
+    pub fn syncs(self) -> bool {
+        self != Durability::Async
+    }
+}
+
+/// Keeps nothing beyond what the rooms already hold, history is lost on restart. Session
+/// summaries, room info, archived boards, templates and reports last as long as the process
+#[derive(Default)]
+pub struct MemoryStorage {
+    summaries: Mutex<HashMap<String, Vec<SessionSummary>>>,
+    infos: Mutex<HashMap<String, RoomInfo>>,
+    archived: Mutex<HashMap<String, (Vec<MessageType>, u64)>>,
+    // Boards of restored rooms, until they're saved
+    restored: Mutex<HashMap<String, Vec<MessageType>>>,
+    templates: Mutex<HashMap<String, Template>>,
+    reports: Mutex<HashMap<String, Vec<Report>>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn load(&self, room: &str) -> io::Result<Vec<MessageType>> {
+        Ok(self.restored.lock().unwrap().get(room).cloned().unwrap_or_default())
+    }
+
+    // Only a restored board is kept, until the room changes
+    async fn save(&self, room: &str, _history: &[MessageType]) -> io::Result<()> {
+        self.restored.lock().unwrap().remove(room);
+        Ok(())
+    }
+
+    async fn ping(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn save_summary(&self, summary: &SessionSummary) -> io::Result<()> {
+        let mut summaries = self.summaries.lock().unwrap();
+        let room = summaries.entry(summary.room.clone()).or_default();
+        room.push(summary.clone());
+        let overflow = room.len().saturating_sub(MAX_SUMMARIES);
+        room.drain(..overflow);
+        Ok(())
+    }
+
+    async fn summaries(&self, room: &str) -> io::Result<Vec<SessionSummary>> {
+        Ok(self.summaries.lock().unwrap().get(room).cloned().unwrap_or_default())
+    }
+
+    async fn replace_summaries(&self, room: &str, summaries: &[SessionSummary]) -> io::Result<()> {
+        let mut all = self.summaries.lock().unwrap();
+        match summaries.is_empty() {
+            true => all.remove(room),
+            false => all.insert(room.to_string(), summaries.to_vec()),
+        };
+        Ok(())
+    }
+
+    async fn load_info(&self, room: &str) -> io::Result<Option<RoomInfo>> {
+        Ok(self.infos.lock().unwrap().get(room).cloned())
+    }
+
+    async fn save_info(&self, room: &str, info: &RoomInfo) -> io::Result<()> {
+        self.infos.lock().unwrap().insert(room.to_string(), info.clone());
+        Ok(())
+    }
+
+    async fn archive(&self, room: &str, history: &[MessageType], archived_at: u64) -> io::Result<()> {
+        self.archived.lock().unwrap().insert(room.to_string(), (history.to_vec(), archived_at));
+        Ok(())
+    }
+
+    async fn archived(&self, room: &str) -> io::Result<Option<u64>> {
+        Ok(self.archived.lock().unwrap().get(room).map(|(_, archived_at)| *archived_at))
+    }
+
+    async fn restore(&self, room: &str) -> io::Result<bool> {
+        let Some((history, _)) = self.archived.lock().unwrap().remove(room) else {
+            return Ok(false);
+        };
+        self.restored.lock().unwrap().insert(room.to_string(), history);
+        Ok(true)
+    }
+
+    async fn save_template(&self, template: &Template) -> io::Result<()> {
+        self.templates.lock().unwrap().insert(template.name.clone(), template.clone());
+        Ok(())
+    }
+
+    async fn load_template(&self, name: &str) -> io::Result<Option<Template>> {
+        Ok(self.templates.lock().unwrap().get(name).cloned())
+    }
+
+    async fn templates(&self) -> io::Result<Vec<Template>> {
+        Ok(self.templates.lock().unwrap().values().cloned().collect())
+    }
+
+    // Like boards, notes are only in the room while it's resident
+    async fn load_notes(&self, _room: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    async fn save_notes(&self, _room: &str, _state: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn load_strokes(&self, _room: &str) -> io::Result<Option<Vec<Stroke>>> {
+        Ok(None)
+    }
+
+    async fn save_strokes(&self, _room: &str, _strokes: &[Stroke]) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn save_report(&self, report: &Report) -> io::Result<()> {
+        let mut reports = self.reports.lock().unwrap();
+        let room = reports.entry(report.room.clone()).or_default();
+        room.push(report.clone());
+        let overflow = room.len().saturating_sub(MAX_REPORTS);
+        room.drain(..overflow);
+        Ok(())
+    }
+
+    async fn reports(&self, room: &str) -> io::Result<Vec<Report>> {
+        Ok(self.reports.lock().unwrap().get(room).cloned().unwrap_or_default())
+    }
+
+    async fn replace_reports(&self, room: &str, reports: &[Report]) -> io::Result<()> {
+        let mut all = self.reports.lock().unwrap();
+        match reports.is_empty() {
+            true => all.remove(room),
+            false => all.insert(room.to_string(), reports.to_vec()),
+        };
+        Ok(())
+    }
+
+    // Boards aren't kept, rooms with only a board are in the hub alone
+    async fn rooms(&self) -> io::Result<Vec<String>> {
+        let mut rooms: Vec<String> = self.infos.lock().unwrap().keys().cloned().collect();
+        rooms.extend(self.summaries.lock().unwrap().keys().cloned());
+        rooms.extend(self.archived.lock().unwrap().keys().cloned());
+        rooms.extend(self.restored.lock().unwrap().keys().cloned());
+        rooms.extend(self.reports.lock().unwrap().keys().cloned());
+        rooms.sort();
+        rooms.dedup();
+        Ok(rooms)
+    }
+
+    async fn delete(&self, room: &str) -> io::Result<()> {
+        self.summaries.lock().unwrap().remove(room);
+        self.infos.lock().unwrap().remove(room);
+        self.archived.lock().unwrap().remove(room);
+        self.restored.lock().unwrap().remove(room);
+        self.reports.lock().unwrap().remove(room);
+        Ok(())
+    }
+}
+
+/// One JSON file per room in a directory, each sealed when there's a `keyring`
+pub struct FileStorage {
+    dir: PathBuf,
+    keyring: Option<Arc<Keyring>>,
+    // Flush each file, and the directory after renaming it in, see `Durability`
+    sync: bool,
+}
+
+impl FileStorage {
+    // A tenant's rooms, `<prefix>.<room>`, get a directory of their own. Room ids have no dots
+    // otherwise, so no room's file can be taken for another's, e.g. tenant `acme`'s room `info`
+    // for room `acme`'s info
+    fn file(&self, room: &str, suffix: &str) -> PathBuf {
+        match room.split_once(tenants::SEPARATOR) {
+            Some((prefix, room)) => self.dir.join(prefix).join(format!("{}{}.json", room, suffix)),
+            None => self.dir.join(format!("{}{}.json", room, suffix)),
+        }
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        self.file(room, "")
+    }
+
+    fn summaries_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".sessions")
+    }
+
+    fn info_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".info")
+    }
+
+    fn notes_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".notes")
+    }
+
+    fn strokes_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".strokes")
+    }
+
+    fn reports_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".reports")
+    }
+
+    // The board stays in the room's own file, this only marks it
+    fn archived_path(&self, room: &str) -> PathBuf {
+        self.file(room, ".archived")
+    }
+
+    // Template names follow the room id rules, the suffix keeps them apart from rooms
+    fn template_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", name, TEMPLATE_SUFFIX))
+    }
+
+    async fn write(&self, path: PathBuf, bytes: Vec<u8>) -> io::Result<()> {
+        let bytes = match &self.keyring {
+            Some(keyring) => keyring.seal(&bytes),
+            None => bytes,
+        };
+        // A tenant's directory is made with its first file
+        let dir = path.parent().unwrap_or(&self.dir).to_path_buf();
+        if dir != self.dir {
+            tokio::fs::create_dir_all(&dir).await?;
+        }
+        // Write then rename so a crash mid-write never leaves a truncated file behind
+        let tmp = path.with_extension("json.tmp");
+        if !self.sync {
+            tokio::fs::write(&tmp, bytes).await?;
+            return tokio::fs::rename(&tmp, path).await;
+        }
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        sync_dir(&dir).await
+    }
+
+    async fn read(&self, path: PathBuf) -> io::Result<Vec<u8>> {
+        let bytes = tokio::fs::read(path).await?;
+        match &self.keyring {
+            Some(keyring) => keyring.open(bytes),
+            None if sealing::is_sealed(&bytes) => Err(io::Error::new(io::ErrorKind::InvalidData, "sealed, but storage.encryption.key isn't set")),
+            None => Ok(bytes),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn load(&self, room: &str) -> io::Result<Vec<MessageType>> {
+        match self.read(self.path(room)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn save(&self, room: &str, history: &[MessageType]) -> io::Result<()> {
+        let bytes = serde_json::to_vec(history).map_err(io::Error::other)?;
+        self.write(self.path(room), bytes).await
+    }
+
+    async fn ping(&self) -> io::Result<()> {
+        let meta = tokio::fs::metadata(&self.dir).await?;
+        if meta.permissions().readonly() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "storage directory is read-only"));
+        }
+        Ok(())
+    }
+
+    async fn save_summary(&self, summary: &SessionSummary) -> io::Result<()> {
+        let mut summaries = self.summaries(&summary.room).await?;
+        summaries.push(summary.clone());
+        let overflow = summaries.len().saturating_sub(MAX_SUMMARIES);
+        summaries.drain(..overflow);
+        let bytes = serde_json::to_vec(&summaries).map_err(io::Error::other)?;
+        self.write(self.summaries_path(&summary.room), bytes).await
+    }
+
+    async fn summaries(&self, room: &str) -> io::Result<Vec<SessionSummary>> {
+        match self.read(self.summaries_path(room)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn replace_summaries(&self, room: &str, summaries: &[SessionSummary]) -> io::Result<()> {
+        if summaries.is_empty() {
+            return remove(self.summaries_path(room)).await;
+        }
+        let bytes = serde_json::to_vec(summaries).map_err(io::Error::other)?;
+        self.write(self.summaries_path(room), bytes).await
+    }
+
+    async fn load_info(&self, room: &str) -> io::Result<Option<RoomInfo>> {
+        match self.read(self.info_path(room)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn save_info(&self, room: &str, info: &RoomInfo) -> io::Result<()> {
+        let bytes = serde_json::to_vec(info).map_err(io::Error::other)?;
+        self.write(self.info_path(room), bytes).await
+    }
+
+    async fn archive(&self, room: &str, history: &[MessageType], archived_at: u64) -> io::Result<()> {
+        self.save(room, history).await?;
+        let bytes = serde_json::to_vec(&serde_json::json!({ "archived_at": archived_at })).map_err(io::Error::other)?;
+        self.write(self.archived_path(room), bytes).await
+    }
+
+    async fn archived(&self, room: &str) -> io::Result<Option<u64>> {
+        #[derive(Deserialize)]
+        struct Archived {
+            archived_at: u64,
+        }
+        match self.read(self.archived_path(room)).await {
+            Ok(bytes) => serde_json::from_slice::<Archived>(&bytes).map(|a| Some(a.archived_at)).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn restore(&self, room: &str) -> io::Result<bool> {
+        match tokio::fs::remove_file(self.archived_path(room)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn save_template(&self, template: &Template) -> io::Result<()> {
+        let bytes = serde_json::to_vec(template).map_err(io::Error::other)?;
+        self.write(self.template_path(&template.name), bytes).await
+    }
+
+    async fn load_template(&self, name: &str) -> io::Result<Option<Template>> {
+        match self.read(self.template_path(name)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn templates(&self) -> io::Result<Vec<Template>> {
+        let mut templates = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str().and_then(|f| f.strip_suffix(TEMPLATE_SUFFIX)) else {
+                continue;
+            };
+            // Deleted since the directory was listed
+            if let Some(template) = self.load_template(name).await? {
+                templates.push(template);
+            }
+        }
+        Ok(templates)
+    }
+
+    async fn load_notes(&self, room: &str) -> io::Result<Option<Vec<u8>>> {
+        #[derive(Deserialize)]
+        struct Stored {
+            state: String,
+        }
+        match self.read(self.notes_path(room)).await {
+            Ok(bytes) => {
+                let stored: Stored = serde_json::from_slice(&bytes).map_err(io::Error::other)?;
+                notes::decode(&stored.state).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // In JSON like the rest, so it's sealed, resealed and listed the same way
+    async fn save_notes(&self, room: &str, state: &[u8]) -> io::Result<()> {
+        let bytes = serde_json::to_vec(&serde_json::json!({ "state": notes::encode(state) })).map_err(io::Error::other)?;
+        self.write(self.notes_path(room), bytes).await
+    }
+
+    async fn load_strokes(&self, room: &str) -> io::Result<Option<Vec<Stroke>>> {
+        match self.read(self.strokes_path(room)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn save_strokes(&self, room: &str, strokes: &[Stroke]) -> io::Result<()> {
+        let bytes = serde_json::to_vec(strokes).map_err(io::Error::other)?;
+        self.write(self.strokes_path(room), bytes).await
+    }
+
+    async fn save_report(&self, report: &Report) -> io::Result<()> {
+        let mut reports = self.reports(&report.room).await?;
+        reports.push(report.clone());
+        let overflow = reports.len().saturating_sub(MAX_REPORTS);
+        reports.drain(..overflow);
+        self.replace_reports(&report.room, &reports).await
+    }
+
+    async fn reports(&self, room: &str) -> io::Result<Vec<Report>> {
+        match self.read(self.reports_path(room)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn replace_reports(&self, room: &str, reports: &[Report]) -> io::Result<()> {
+        if reports.is_empty() {
+            return remove(self.reports_path(room)).await;
+        }
+        let bytes = serde_json::to_vec(reports).map_err(io::Error::other)?;
+        self.write(self.reports_path(room), bytes).await
+    }
+
+    async fn rooms(&self) -> io::Result<Vec<String>> {
+        let mut rooms = Vec::new();
+        let mut dirs = vec![(self.dir.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_name = entry.file_name();
+                let Some(name) = file_name.to_str() else {
+                    continue;
+                };
+                // A tenant's rooms, only one level down
+                if prefix.is_empty() && valid_room_id(name) && entry.file_type().await?.is_dir() {
+                    dirs.push((entry.path(), format!("{}{}", name, tenants::SEPARATOR)));
+                    continue;
+                }
+                let Some(stem) = name.strip_suffix(".json") else {
+                    continue;
+                };
+                // The rest are a room's other files or a template
+                let suffixes = [".sessions", ".info", ".notes", ".strokes", ".reports", ".archived", ".template"];
+                let room = format!("{}{}", prefix, suffixes.iter().find_map(|suffix| stem.strip_suffix(suffix)).unwrap_or(stem));
+                if !rooms.contains(&room) && !stem.ends_with(".template") {
+                    rooms.push(room);
+                }
+            }
+        }
+        Ok(rooms)
+    }
+
+    async fn delete(&self, room: &str) -> io::Result<()> {
+        for path in [self.path(room), self.summaries_path(room), self.info_path(room), self.notes_path(room), self.strokes_path(room), self.reports_path(room), self.archived_path(room)] {
+            remove(path).await?;
+        }
+        Ok(())
+    }
+}
+
+// So a rename into it survives a crash too
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
+
+// Directories can't be opened as files elsewhere, renames there are as durable as they get
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Already gone is fine
+async fn remove(path: PathBuf) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_tenants_rooms_dont_share_files_with_plain_rooms() {
+        let dir = std::env::temp_dir().join(format!("ws-demo-storage-{}", crate::ids::random_token()));
+        let storage = StorageSpec::File(dir.clone()).open(None, Durability::Async).await.unwrap();
+        let board = |n: usize| vec![MessageType::Clear; n];
+        storage.save("acme", &board(1)).await.unwrap();
+        storage.save_info("acme", &RoomInfo { name: Some("plain".to_string()), ..RoomInfo::default() }).await.unwrap();
+        // Named like plain room `acme`'s other files
+        for (n, room) in ["info", "sessions", "notes", "strokes", "reports", "archived"].into_iter().enumerate() {
+            storage.save(&format!("acme.{}", room), &board(n + 2)).await.unwrap();
+        }
+
+        assert_eq!(storage.load("acme").await.unwrap().len(), 1);
+        assert_eq!(storage.load_info("acme").await.unwrap().and_then(|info| info.name).as_deref(), Some("plain"));
+        assert_eq!(storage.archived("acme").await.unwrap(), None);
+        assert_eq!(storage.load("acme.info").await.unwrap().len(), 2);
+        assert_eq!(storage.load("acme.archived").await.unwrap().len(), 7);
+        let mut rooms = storage.rooms().await.unwrap();
+        rooms.sort();
+        assert_eq!(rooms, ["acme", "acme.archived", "acme.info", "acme.notes", "acme.reports", "acme.sessions", "acme.strokes"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::protocol::{MessageType, Stamped};
+use crate::room::Room;
+
+/// A room's board serialized as joiners get it, made from a copy of the board off the room's lock.
+/// A joiner gets the part of it still on the board and only the ops drawn since are serialized
+/// while they're let in, so joining a busy room with a long board doesn't hold up everyone else
+pub struct SyncSnapshot {
+    epoch: u64,
+    // `Stroke::stroke_id` of each op, which only grow along the board
+    stroke_ids: Vec<u64>,
+    // Each op as a `Stamped` frame without its stroke id
+    frames: Vec<String>,
+}
+
+impl SyncSnapshot {
+    /// Serializes every op, which can take a while, so not under the room's lock
+    pub fn build(epoch: u64, history: &[MessageType], stroke_ids: Vec<u64>) -> Self {
+        let frames = history.iter().map(|op| serde_json::to_string(&Stamped { op, epoch, stroke_id: None }).unwrap_or_default()).collect();
+        SyncSnapshot { epoch, stroke_ids, frames }
+    }
+
+    /// The frames of the ops at the start of `room`'s board, which may have lost ops off its front
+    /// to `rooms.history_limit` since, and how many ops they are. None if the board was cleared or
+    /// replaced since
+    pub fn covering(&self, room: &Room) -> Option<(&[String], usize)> {
+        let first = room.strokes.first()?.stroke_id;
+        if self.epoch != room.epoch {
+            return None;
+        }
+        let skip = self.stroke_ids.binary_search(&first).ok()?;
+        let ops = self.stroke_ids.len() - skip;
+        match room.strokes.get(ops - 1) {
+            Some(stroke) if Some(&stroke.stroke_id) == self.stroke_ids.last() => Some((&self.frames[skip..], ops)),
+            _ => None,
+        }
+    }
+
+    /// Whether `room` should get a new snapshot: its board is at least `min_ops` long and this one
+    /// is gone, doesn't fit it any more or leaves `min_ops` or more ops to serialize live
+    pub fn is_stale(snapshot: Option<&SyncSnapshot>, room: &Room, min_ops: usize) -> bool {
+        if min_ops == 0 || room.history.len() < min_ops {
+            return false;
+        }
+        match snapshot.and_then(|snapshot| snapshot.covering(room)) {
+            Some((_, ops)) => room.history.len() - ops >= min_ops,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Composite, DrawCommand};
+    use crate::room::Stroke;
+
+    fn draw(x: f64) -> MessageType {
+        MessageType::Draw(DrawCommand { prev: [x, 0.0], cur: [x + 1.0, 0.0], color: "#000".to_string(), brush_size: 2, composite: Composite::SourceOver })
+    }
+
+    fn live(room: &Room) -> Vec<String> {
+        room.history.iter().map(|op| serde_json::to_string(&Stamped { op, epoch: room.epoch, stroke_id: None }).unwrap()).collect()
+    }
+
+    fn snapshot(room: &Room) -> SyncSnapshot {
+        SyncSnapshot::build(room.epoch, &room.history, room.strokes.iter().map(|stroke| stroke.stroke_id).collect())
+    }
+
+    #[test]
+    fn snapshot_and_tail_are_the_board() {
+        let mut room = Room::new("room".to_string(), Vec::new());
+        for x in 0..8 {
+            room.apply(&draw(x as f64), Stroke::default(), 6);
+        }
+        let sync = snapshot(&room);
+        assert!(!SyncSnapshot::is_stale(Some(&sync), &room, 4));
+
+        // Two ops fall off the front
+        room.apply(&draw(8.0), Stroke::default(), 6);
+        room.apply(&draw(9.0), Stroke::default(), 6);
+        let (frames, ops) = sync.covering(&room).unwrap();
+        assert_eq!(ops, 4);
+        assert_eq!(frames, &live(&room)[..ops]);
+        assert!(!SyncSnapshot::is_stale(Some(&sync), &room, 4));
+
+        // Past the end of the snapshot
+        for x in 10..16 {
+            room.apply(&draw(x as f64), Stroke::default(), 6);
+        }
+        assert!(sync.covering(&room).is_none());
+        assert!(SyncSnapshot::is_stale(Some(&sync), &room, 4));
+
+        let sync = snapshot(&room);
+        room.apply(&MessageType::Clear, Stroke::default(), 6);
+        room.apply(&draw(0.0), Stroke::default(), 6);
+        assert!(sync.covering(&room).is_none());
+        assert!(!SyncSnapshot::is_stale(None, &room, 4));
+    }
+}
@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::{Branding, Config, ConfigHandle, TenantConfig};
+use crate::hub::{valid_room_id, Hub};
+use crate::ids;
+use crate::openapi::ApiError;
+use crate::public_ids::{public_id, PublicIds};
+
+/// Between a tenant's storage prefix and its room id in the ids the hub and storage use. Plain
+/// room ids never have one, so nothing outside a tenant can name a room in it
+pub const SEPARATOR: char = '.';
+
+/// A tenant a request is for
+#[derive(Clone, Copy)]
+pub struct Tenant<'a> {
+    pub id: &'a str,
+    pub config: &'a TenantConfig,
+}
+
+impl Tenant<'_> {
+    fn prefix(&self) -> &str {
+        match valid_room_id(&self.config.storage_prefix) {
+            true => &self.config.storage_prefix,
+            false => self.id,
+        }
+    }
+
+    /// The id its room `room_id` is kept under
+    pub fn scope(&self, room_id: &str) -> String {
+        format!("{}{}{}", self.prefix(), SEPARATOR, room_id)
+    }
+}
+
+/// Why a join can't go to the tenant it asked for
+pub enum Refusal {
+    UnknownTenant,
+    InvalidKey,
+}
+
+impl Refusal {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Refusal::UnknownTenant => StatusCode::NOT_FOUND,
+            Refusal::InvalidKey => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Refusal::UnknownTenant => "unknown tenant",
+            Refusal::InvalidKey => "missing or invalid key",
+        }
+    }
+}
+
+/// The tenant a join is for: the one in its `/t/<id>` path, whose access key it then needs if
+/// there are any, or else the one whose access key it has. `None` for a plain room
+pub fn resolve<'a>(config: &'a Config, path: Option<&str>, key: Option<&str>) -> Result<Option<Tenant<'a>>, Refusal> {
+    let Some(id) = path else {
+        let by_key = key.and_then(|key| config.tenants.iter().find(|(_, tenant)| tenant.access_keys.iter().any(|k| k == key)));
+        return Ok(by_key.map(|(id, config)| Tenant { id, config }));
+    };
+    let (id, tenant) = config.tenants.get_key_value(id).ok_or(Refusal::UnknownTenant)?;
+    if !tenant.access_keys.is_empty() && !key.is_some_and(|key| tenant.access_keys.iter().any(|k| k == key)) {
+        return Err(Refusal::InvalidKey);
+    }
+    Ok(Some(Tenant { id, config: tenant }))
+}
+
+/// The tenant a hub room id is scoped to, and its id within the tenant
+pub fn tenant_of<'a, 'b>(config: &'a Config, room_id: &'b str) -> Option<(Tenant<'a>, &'b str)> {
+    let (prefix, room) = room_id.split_once(SEPARATOR)?;
+    config
+        .tenants
+        .iter()
+        .map(|(id, config)| Tenant { id, config })
+        .find(|tenant| tenant.prefix() == prefix)
+        .map(|tenant| (tenant, room))
+}
+
+/// Whether a hub room id is in some tenant, so kept out of everything tenants don't share
+pub fn is_scoped(room_id: &str) -> bool {
+    room_id.contains(SEPARATOR)
+}
+
+/// Where a hub room is joined over WebSocket, relative to the server's root
+pub fn join_path(config: &Config, ids: &dyn PublicIds, room_id: &str) -> String {
+    match tenant_of(config, room_id) {
+        Some((tenant, room)) => format!("t/{}/room/{}", tenant.id, public_id(ids, room)),
+        None => format!("room/{}", public_id(ids, room_id)),
+    }
+}
+
+/// `rooms.capacity_of`, with the tenant's `max_participants` for its rooms
+pub fn capacity_of(config: &Config, room_id: &str) -> usize {
+    match tenant_of(config, room_id).and_then(|(tenant, _)| tenant.config.max_participants) {
+        Some(capacity) => capacity,
+        None => config.rooms.capacity_of(room_id),
+    }
+}
+
+/// Whether an `Authorization` header has one of the tenant's `admin_tokens` or `auth.admin_tokens`
+pub fn is_admin(config: &Config, tenant: &Tenant, authorization: Option<&str>) -> bool {
+    let token = authorization.and_then(|h| h.strip_prefix("Bearer "));
+    token.is_some_and(|token| ids::is_one_of(tenant.config.admin_tokens.iter().chain(&config.auth.admin_tokens), token))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TenantInfo {
+    id: String,
+    branding: Branding,
+}
+
+/// A tenant's room resident in memory
+#[derive(Serialize, ToSchema)]
+pub struct TenantRoom {
+    /// Within the tenant, as in `/t/<tenant>/room/<id>`
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// A signed-in user's tabs count once
+    participants: usize,
+    ops: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TenantRooms {
+    rooms: Vec<TenantRoom>,
+}
+
+/// `GET /api/tenants/<id>` for anyone, and `GET /api/tenants/<id>/rooms` for its admins
+pub fn routes(hub: Arc<Hub>, config: ConfigHandle) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+    let config = warp::any().map(move || config.clone());
+
+    let info = warp::path!("api" / "tenants" / String)
+        .and(warp::get())
+        .and(config.clone())
+        .map(tenant_info);
+
+    let rooms = warp::path!("api" / "tenants" / String / "rooms")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(hub)
+        .and(config)
+        .then(tenant_rooms);
+
+    info.or(rooms)
+}
+
+/// A tenant's branding, for clients to show
+#[utoipa::path(
+    get,
+    path = "/api/tenants/{id}",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id")),
+    responses(
+        (status = 200, description = "OK", body = TenantInfo),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+)]
+fn tenant_info(id: String, config: ConfigHandle) -> Box<dyn Reply> {
+    match config.borrow().tenants.get(&id) {
+        Some(tenant) => Box::new(warp::reply::json(&TenantInfo { id, branding: tenant.branding.clone() })),
+        None => error(StatusCode::NOT_FOUND, "unknown tenant"),
+    }
+}
+
+/// A tenant's rooms resident in memory, for its `admin_tokens` or `auth.admin_tokens`
+#[utoipa::path(
+    get,
+    path = "/api/tenants/{id}/rooms",
+    tag = "tenants",
+    params(("id" = String, Path, description = "Tenant id")),
+    responses(
+        (status = 200, description = "OK", body = TenantRooms),
+        (status = 401, description = "Missing or invalid admin token", body = ApiError),
+        (status = 404, description = "No such tenant", body = ApiError),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn tenant_rooms(id: String, authorization: Option<String>, hub: Arc<Hub>, config: ConfigHandle) -> Box<dyn Reply> {
+    let current = config.borrow().clone();
+    let Some(tenant) = current.tenants.get(&id) else {
+        return error(StatusCode::NOT_FOUND, "unknown tenant");
+    };
+    if !is_admin(&current, &Tenant { id: &id, config: tenant }, authorization.as_deref()) {
+        return error(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    let mut rooms = Vec::new();
+    for room in hub.rooms().await {
+        let room = room.read().await;
+        let Some((owner, room_id)) = tenant_of(&current, &room.id) else {
+            continue;
+        };
+        if owner.id != id {
+            continue;
+        }
+        rooms.push(TenantRoom { id: room_id.to_string(), name: room.info.name.clone(), participants: room.participants(), ops: room.history.len() });
+    }
+    rooms.sort_by(|a, b| a.id.cmp(&b.id));
+    Box::new(warp::reply::json(&TenantRooms { rooms }))
+}
+
+fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::public_ids::Plain;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        let acme = TenantConfig { access_keys: vec!["acme-key".to_string()], ..TenantConfig::default() };
+        let globex = TenantConfig { storage_prefix: "gx".to_string(), ..TenantConfig::default() };
+        config.tenants.insert("acme".to_string(), acme);
+        config.tenants.insert("globex".to_string(), globex);
+        config
+    }
+
+    #[test]
+    fn joins_find_their_tenant_by_path_or_key() {
+        let config = config();
+        let id = |tenant: Result<Option<Tenant>, Refusal>| tenant.ok().map(|tenant| tenant.map(|tenant| tenant.id.to_string()));
+        assert_eq!(id(resolve(&config, None, None)), Some(None));
+        assert_eq!(id(resolve(&config, None, Some("acme-key"))), Some(Some("acme".to_string())));
+        assert_eq!(id(resolve(&config, Some("acme"), Some("acme-key"))), Some(Some("acme".to_string())));
+        assert!(matches!(resolve(&config, Some("acme"), None), Err(Refusal::InvalidKey)));
+        assert_eq!(id(resolve(&config, Some("globex"), None)), Some(Some("globex".to_string())));
+        assert!(matches!(resolve(&config, Some("initech"), None), Err(Refusal::UnknownTenant)));
+    }
+
+    #[test]
+    fn scoped_ids_map_back_to_their_tenant() {
+        let config = config();
+        let globex = Tenant { id: "globex", config: &config.tenants["globex"] };
+        let scoped = globex.scope("sketch");
+        assert_eq!(scoped, "gx.sketch");
+        assert!(is_scoped(&scoped) && !valid_room_id(&scoped));
+        assert_eq!(join_path(&config, &Plain, &scoped), "t/globex/room/sketch");
+        assert_eq!(join_path(&config, &Plain, "sketch"), "room/sketch");
+    }
+}
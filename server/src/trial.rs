@@ -0,0 +1,40 @@
+use crate::config::{Config, LimitsConfig};
+use crate::ids;
+use crate::tenants;
+
+/// What trial room ids start with, any room with such an id is one whether or not `trial` is enabled
+pub const PREFIX: &str = "trial-";
+
+/// Whether a room is a throwaway trial room, which is never saved, see `trial`
+pub fn is_trial(room_id: &str) -> bool {
+    room_id.starts_with(PREFIX)
+}
+
+/// A new trial room's id, unguessable like `ids::random_room_id`
+pub fn random_room_id() -> String {
+    format!("{}{}", PREFIX, ids::random_room_id())
+}
+
+/// `tenants::capacity_of`, with `trial.max_participants` for trial rooms
+pub fn capacity_of(config: &Config, room_id: &str) -> usize {
+    match is_trial(room_id) && config.trial.max_participants > 0 {
+        true => config.trial.max_participants,
+        false => tenants::capacity_of(config, room_id),
+    }
+}
+
+/// `limits`, with the trial caps on rate limiting for trial rooms
+pub fn limits(config: &Config, room_id: &str) -> LimitsConfig {
+    let mut limits = config.limits.clone();
+    if is_trial(room_id) {
+        // 0 is unlimited on either side
+        let cap = |limit: u32, cap: u32| match (limit, cap) {
+            (limit, 0) => limit,
+            (0, cap) => cap,
+            (limit, cap) => limit.min(cap),
+        };
+        limits.messages_per_second = cap(limits.messages_per_second, config.trial.messages_per_second);
+        limits.burst = cap(limits.burst, config.trial.burst);
+    }
+    limits
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::ConfigHandle;
+use crate::ids::UserId;
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct Usage {
+    pub connection_secs: u64,
+    pub messages: u64,
+    pub stored_bytes: u64,
+}
+
+/// Running usage totals per room (kept across unloads) and per connected user
+#[derive(Default)]
+pub struct Metering {
+    rooms: Mutex<HashMap<String, Usage>>,
+    users: Mutex<HashMap<UserId, Usage>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageReport {
+    pub rooms: HashMap<String, Usage>,
+    pub users: HashMap<UserId, Usage>,
+}
+
+impl Metering {
+    pub fn room(&self, room: &str) -> Usage {
+        self.rooms.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+
+    pub fn user(&self, user_id: UserId) -> Usage {
+        self.users.lock().unwrap().get(&user_id).cloned().unwrap_or_default()
+    }
+
+    pub fn message(&self, room: &str, user_id: UserId) {
+        self.rooms.lock().unwrap().entry(room.to_string()).or_default().messages += 1;
+        self.users.lock().unwrap().entry(user_id).or_default().messages += 1;
+    }
+
+    pub fn stored(&self, room: &str, bytes: u64) {
+        self.rooms.lock().unwrap().entry(room.to_string()).or_default().stored_bytes = bytes;
+    }
+
+    /// Book the connection time to the room and forget the user, their usage only lives as long as the socket
+    pub fn disconnected(&self, room: &str, user_id: UserId, connection_secs: u64) {
+        self.rooms.lock().unwrap().entry(room.to_string()).or_default().connection_secs += connection_secs;
+        self.users.lock().unwrap().remove(&user_id);
+    }
+
+    pub fn report(&self) -> UsageReport {
+        UsageReport {
+            rooms: self.rooms.lock().unwrap().clone(),
+            users: self.users.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// What a participant has on a room's board, see `Room::drawn_with`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Drawn {
+    pub strokes: u64,
+    pub points: u64,
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded(pub String);
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quota exceeded: {}", self.0)
+    }
+}
+
+/// Consulted before a user joins a room and before each of their writes is accepted
+pub trait QuotaProvider: Send + Sync {
+    fn check_join(&self, room: &str, room_usage: &Usage) -> Result<(), QuotaExceeded>;
+    fn check_write(&self, room: &str, user_id: UserId, room_usage: &Usage, user_usage: &Usage) -> Result<(), QuotaExceeded>;
+    /// After `check_write` for a `Draw`, with what the user would have on the board with it
+    fn check_draw(&self, room: &str, user_id: UserId, drawn: &Drawn) -> Result<(), QuotaExceeded>;
+}
+
+/// Flat limits from the `[quotas]` config section, 0 meaning unlimited
+pub struct ConfigQuotas {
+    config: ConfigHandle,
+}
+
+impl ConfigQuotas {
+    pub fn new(config: ConfigHandle) -> Self {
+        ConfigQuotas { config }
+    }
+}
+
+fn over(limit: u64, used: u64) -> bool {
+    limit > 0 && used >= limit
+}
+
+impl QuotaProvider for ConfigQuotas {
+    fn check_join(&self, _room: &str, room_usage: &Usage) -> Result<(), QuotaExceeded> {
+        let quotas = self.config.borrow().quotas.clone();
+        if over(quotas.room_connection_minutes * 60, room_usage.connection_secs) {
+            return Err(QuotaExceeded("room connection minutes".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_write(&self, _room: &str, _user_id: UserId, room_usage: &Usage, user_usage: &Usage) -> Result<(), QuotaExceeded> {
+        let quotas = self.config.borrow().quotas.clone();
+        if over(quotas.room_messages, room_usage.messages) {
+            return Err(QuotaExceeded("room messages".to_string()));
+        }
+        if over(quotas.room_stored_bytes, room_usage.stored_bytes) {
+            return Err(QuotaExceeded("room storage".to_string()));
+        }
+        if over(quotas.user_messages, user_usage.messages) {
+            return Err(QuotaExceeded("user messages".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_draw(&self, _room: &str, _user_id: UserId, drawn: &Drawn) -> Result<(), QuotaExceeded> {
+        let quotas = self.config.borrow().quotas.clone();
+        // `drawn` already has the draw in it, reaching the limit is fine
+        if over(quotas.user_strokes, drawn.strokes - 1) {
+            return Err(QuotaExceeded("user strokes".to_string()));
+        }
+        if over(quotas.user_points, drawn.points - 1) {
+            return Err(QuotaExceeded("user points".to_string()));
+        }
+        Ok(())
+    }
+}
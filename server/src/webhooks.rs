@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::config::WebhookConfig;
+use crate::events::{EventBus, TimedEvent};
+
+// Deliveries waiting per webhook before new ones are dropped
+const QUEUE_SIZE: usize = 256;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Forward room lifecycle events to every configured webhook
+pub fn spawn(webhooks: &[WebhookConfig], events: &EventBus) {
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("failed to build HTTP client");
+
+    let mut queues = Vec::new();
+    for webhook in webhooks {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver(client.clone(), webhook.clone(), rx));
+        queues.push((webhook.clone(), tx));
+    }
+
+    let mut rx = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Webhooks fell behind, {} events not delivered", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some(name) = event.event.webhook_name() else {
+                continue;
+            };
+            for (webhook, tx) in &queues {
+                if !webhook.events.is_empty() && !webhook.events.iter().any(|e| e == name) {
+                    continue;
+                }
+                if tx.try_send(event.clone()).is_err() {
+                    log::warn!("Webhook {} queue full, dropping {}", webhook.url, name);
+                }
+            }
+        }
+    });
+}
+
+/// One webhook's deliveries in order, retrying each with exponential backoff
+async fn deliver(client: reqwest::Client, webhook: WebhookConfig, mut rx: mpsc::Receiver<TimedEvent>) {
+    while let Some(event) = rx.recv().await {
+        let name = event.event.webhook_name().unwrap_or_default();
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Serialization error: {}", e);
+                continue;
+            }
+        };
+        let signature = sign(&webhook.secret, &body);
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=webhook.max_attempts.max(1) {
+            let result = client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .header("X-Whiteboard-Event", name)
+                .header("X-Whiteboard-Signature", &signature)
+                .body(body.clone())
+                .send()
+                .await;
+
+            match result {
+                Ok(res) if res.status().is_success() => break,
+                Ok(res) => log::warn!("Webhook {} answered {} to {} (attempt {})", webhook.url, res.status(), name, attempt),
+                Err(e) => log::warn!("Webhook {} failed for {} (attempt {}): {}", webhook.url, name, attempt, e),
+            }
+
+            if attempt == webhook.max_attempts {
+                log::error!("Giving up on {} for webhook {}", name, webhook.url);
+            } else {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// `sha256=<hex hmac of the body>`, so receivers can check the payload came from us
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
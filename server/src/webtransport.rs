@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use warp::ws::Message;
+use wtransport::endpoint::endpoint_side::Server;
+use wtransport::endpoint::{IncomingSession, SessionRequest};
+use wtransport::tls::Sha256DigestFmt;
+use wtransport::{Connection, Endpoint, Identity, RecvStream, SendStream, VarInt};
+
+use crate::cluster;
+use crate::config::{Config, ConfigHandle};
+use crate::connection::{ConnectionStats, DisconnectReason, Peer};
+use crate::hub::{Hub, DEFAULT_ROOM};
+use crate::ids::UserId;
+use crate::protocol::ServerMessage;
+use crate::room::SharedRoom;
+use crate::socket::{self, Inbound};
+use crate::tenants;
+use crate::trial;
+
+// Application close codes sent to clients, mirroring the WebSocket ones
+const CLOSE_NORMAL: u32 = 1000;
+const CLOSE_TOO_LARGE: u32 = 1009;
+const CLOSE_INTERNAL: u32 = 1011;
+
+/// Binds the UDP socket for `webtransport`, TLS is mandatory for HTTP/3
+pub async fn bind(config: &Config) -> Result<Endpoint<Server>, Box<dyn std::error::Error>> {
+    let settings = &config.webtransport;
+    let identity = match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => Identity::load_pemfiles(cert, key).await?,
+        (None, None) => {
+            let identity = Identity::self_signed(["localhost", "127.0.0.1", "::1"])?;
+            // Browsers only accept a self-signed certificate when the page pins its hash
+            if let Some(cert) = identity.certificate_chain().as_slice().first() {
+                log::warn!(
+                    "No webtransport.cert set, using a self-signed certificate, pass serverCertificateHashes: [{{ algorithm: \"sha-256\", value: new Uint8Array({}) }}]",
+                    cert.hash().fmt(Sha256DigestFmt::BytesArray),
+                );
+            }
+            identity
+        }
+        _ => return Err("webtransport.cert and webtransport.key must be set together".into()),
+    };
+
+    let addr = SocketAddr::new(settings.bind.unwrap_or(config.server.bind), settings.port);
+    let server_config = wtransport::ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_identity(identity)
+        .keep_alive_interval(Some(Duration::from_secs(config.server.ping_interval_secs.max(1))))
+        .build();
+    Ok(Endpoint::server(server_config)?)
+}
+
+/// Accepts WebTransport sessions on `/room` or `/room/<id>`, which join rooms just like WebSockets.
+/// The client opens one bidirectional stream and sends newline-delimited JSON frames on it, and
+/// may send frames as datagrams too, e.g. cursor and stroke updates where a lost one doesn't matter.
+/// Everything the room relays comes back on the stream
+pub async fn serve(endpoint: Endpoint<Server>, hub: Arc<Hub>, config: ConfigHandle) {
+    loop {
+        let incoming = endpoint.accept().await;
+        tokio::spawn(accept_session(incoming, hub.clone(), config.clone()));
+    }
+}
+
+async fn accept_session(incoming: IncomingSession, hub: Arc<Hub>, config: ConfigHandle) {
+    let request = match incoming.await {
+        Ok(request) => request,
+        Err(e) => {
+            log::debug!("WebTransport handshake failed: {}", e);
+            return;
+        }
+    };
+    let current = config.borrow().clone();
+
+    let (path, query) = match request.path().split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (request.path(), HashMap::new()),
+    };
+    let room_id = match path.trim_end_matches('/') {
+        "/room" => DEFAULT_ROOM.to_string(),
+        path => match path.strip_prefix("/room/") {
+            Some(id) => match hub.room_id(id) {
+                Some(id) => id,
+                None => return request.not_found().await,
+            },
+            None => return request.not_found().await,
+        },
+    };
+
+    let allowed = &current.server.allowed_origins;
+    if !allowed.is_empty() && !request.origin().is_some_and(|o| allowed.iter().any(|a| a == o)) {
+        log::warn!("Rejected WebTransport session from origin {:?}", request.origin());
+        return request.forbidden().await;
+    }
+    // The owner checks everything else
+    if let Some(owner) = hub.cluster.remote_owner(&room_id, Duration::from_secs(current.cluster.dead_after_secs)) {
+        let url = cluster::join_url(&owner, &tenants::join_path(&current, &*hub.public_ids, &room_id));
+        return redirect(request, url).await;
+    }
+    let keys = &current.auth.access_keys;
+    if !keys.is_empty() && !query.get("key").is_some_and(|k| keys.contains(k)) {
+        return request.forbidden().await;
+    }
+    if !hub.may_access(&room_id, query.get("token").map(String::as_str)) {
+        return request.forbidden().await;
+    }
+    if let Err(e) = hub.quotas.check_join(&room_id, &hub.usage.room(&room_id)) {
+        log::info!("Refused WebTransport join to room {}: {}", room_id, e);
+        return request.too_many_requests().await;
+    }
+    let room = match hub.open(&room_id).await {
+        Ok(room) => room,
+        Err(e) => {
+            log::error!("Could not load room {}: {}", room_id, e);
+            return request.not_found().await;
+        }
+    };
+    if room.read().await.info.opens_later().is_some() {
+        return request.forbidden().await;
+    }
+    if socket::is_full(&hub, &room, trial::capacity_of(&current, &room_id)).await {
+        return request.too_many_requests().await;
+    }
+
+    let connection = match request.accept().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::debug!("Could not accept WebTransport session: {}", e);
+            return;
+        }
+    };
+    // The protocol is carried on the client's stream, so there's nothing to do until it opens one
+    let (send, recv) = match connection.accept_bi().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("WebTransport session closed before opening a stream: {}", e);
+            return;
+        }
+    };
+    connect_user(connection, send, recv, hub, room, config).await;
+}
+
+/// Send a session to the cluster node that owns its room: a `Redirect` to its WebSocket URL on
+/// the client's stream, then a close with `socket::REDIRECT_CODE`
+async fn redirect(request: SessionRequest, url: String) {
+    let Ok(connection) = request.accept().await else {
+        return;
+    };
+    let Ok((mut send, _recv)) = connection.accept_bi().await else {
+        return;
+    };
+    if let Ok(mut line) = serde_json::to_vec(&ServerMessage::Redirect { url }) {
+        line.push(b'\n');
+        let _ = send.write_all(&line).await;
+        let _ = send.finish().await;
+    }
+    connection.close(VarInt::from_u32(socket::REDIRECT_CODE.into()), b"redirect");
+}
+
+async fn connect_user(connection: Connection, send: SendStream, recv: RecvStream, hub: Arc<Hub>, room: SharedRoom, config: ConfigHandle) {
+    let current_user_id = UserId::random();
+    let connected_at = Instant::now();
+    let room_id = room.read().await.id.clone();
+    let remote = connection.remote_address();
+    let remote_addr = SocketAddr::new(remote.ip().to_canonical(), remote.port());
+    let stats = Arc::new(ConnectionStats::new(current_user_id, room_id.clone(), Some(remote_addr), "webtransport"));
+
+    let (message_sender, message_receiver) = mpsc::unbounded_channel();
+    let writer = write_messages(connection.clone(), send, message_receiver, stats.clone());
+    let mut writer = tokio::task::spawn(socket::isolated(hub.clone(), "writer", current_user_id, room_id.clone(), writer));
+
+    let capabilities = socket::capabilities(&hub, &room, &config, stats.transport).await;
+    socket::join(&hub, &room, Peer::new(message_sender.clone(), stats.clone()).with_capabilities(capabilities)).await;
+
+    let reader = read_messages(&connection, recv, &mut writer, &hub, &room, &stats, &config);
+    if socket::isolated(hub.clone(), "reader", current_user_id, room_id, reader).await.is_none() {
+        stats.closing(DisconnectReason::Error);
+        connection.close(VarInt::from_u32(CLOSE_INTERNAL), b"internal error");
+    }
+    drop(message_sender);
+
+    socket::leave(&hub, &room, &stats, connected_at).await;
+}
+
+/// Relay the room's broadcasts to the client's stream, one JSON frame per line
+async fn write_messages(connection: Connection, mut send: SendStream, mut rx: mpsc::UnboundedReceiver<Message>, stats: Arc<ConnectionStats>) {
+    while let Some(message) = rx.recv().await {
+        if message.is_close() {
+            connection.close(VarInt::from_u32(CLOSE_NORMAL), b"server shutting down");
+            break;
+        }
+        let mut line = message.into_bytes();
+        line.push(b'\n');
+        if let Err(e) = send.write_all(&line).await {
+            log::warn!("WebTransport send error: {}", e);
+            break;
+        }
+        stats.sent();
+    }
+}
+
+/// Apply and relay everything this user sends, on their stream or as datagrams, until the session closes
+async fn read_messages(
+    connection: &Connection,
+    recv: RecvStream,
+    writer: &mut tokio::task::JoinHandle<Option<()>>,
+    hub: &Hub,
+    room: &SharedRoom,
+    stats: &ConnectionStats,
+    config: &ConfigHandle,
+) {
+    let current_user_id = stats.user_id;
+    let mut inbound = Inbound::new(&config.borrow().limits);
+    let mut lines = BufReader::new(recv);
+    let mut line = Vec::new();
+
+    loop {
+        let max_message_bytes = config.borrow().limits.max_message_bytes;
+        // A read cut short by a datagram leaves its bytes in `line`, so the limit counts them too
+        let remaining = (max_message_bytes + 1).saturating_sub(line.len()) as u64;
+        let mut limited = (&mut lines).take(remaining);
+        let frame = tokio::select! {
+            read = limited.read_until(b'\n', &mut line) => {
+                match read {
+                    Ok(0) => break,
+                    Ok(_) if !line.ends_with(b"\n") && line.len() > max_message_bytes => {
+                        log::warn!("Closing WebTransport session of user {}, frame over {} bytes", current_user_id, max_message_bytes);
+                        stats.closing(DisconnectReason::Error);
+                        connection.close(VarInt::from_u32(CLOSE_TOO_LARGE), b"message too large");
+                        break;
+                    }
+                    // A partial line at the end of the stream is still a frame
+                    Ok(_) => std::mem::take(&mut line),
+                    Err(e) => {
+                        log::warn!("Could not receive message from user {}: {}", current_user_id, e);
+                        stats.closing(DisconnectReason::Error);
+                        break;
+                    }
+                }
+            }
+            datagram = connection.receive_datagram() => match datagram {
+                Ok(datagram) => datagram.payload().to_vec(),
+                Err(e) => {
+                    log::debug!("WebTransport session of user {} closed: {}", current_user_id, e);
+                    break;
+                }
+            },
+            // The writer only stops early if it panicked or the stream failed
+            _ = &mut *writer => {
+                stats.closing(DisconnectReason::Error);
+                break;
+            }
+        };
+
+        let text = match String::from_utf8(frame) {
+            Ok(text) => text,
+            Err(_) => {
+                log::debug!("Dropped non UTF-8 WebTransport frame from user {}", current_user_id);
+                continue;
+            }
+        };
+        let text = text.trim_end_matches(['\r', '\n']);
+        if text.is_empty() {
+            continue;
+        }
+        // Rejections are already logged and counted
+        let _ = inbound.handle(Message::text(text), hub, room, stats, config).await;
+    }
+}
+
+/// `key=value&...` from the session's URL, values are taken as-is
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
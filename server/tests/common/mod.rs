@@ -0,0 +1,249 @@
+//! Boots the server binary on a port of its own and talks to it over real WebSockets
+
+// Each test binary uses its own part of this
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use whiteboard_client::{Client, ClientBuilder, Composite, DrawCommand, Event};
+
+// Generous, debug builds on a busy CI machine are slow to start
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+// How long a frame that should arrive may take
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait for a frame that shouldn't arrive
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+static CONFIGS: AtomicUsize = AtomicUsize::new(0);
+
+/// A server process listening on an ephemeral port, killed when dropped
+pub struct TestServer {
+    child: Child,
+    pub addr: SocketAddr,
+    config: PathBuf,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        Self::with_config("")
+    }
+
+    /// Start with `config` as its config file, in TOML
+    pub fn with_config(config: &str) -> Self {
+        Self::with_storage(config, "memory")
+    }
+
+    /// `with_config`, keeping rooms in `storage` as `--storage` takes it
+    pub fn with_storage(config: &str, storage: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ws-demo-test-{}-{}.toml", std::process::id(), CONFIGS.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&path, config).expect("write the config file");
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_ws-demo"));
+        command
+            .args(["--port", "0", "--shutdown-grace", "0", "--log-level", "info", "--storage", storage])
+            .arg("--config")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        // Whatever the environment configures would override the config file
+        for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("WHITEBOARD_")) {
+            command.env_remove(name);
+        }
+        let mut child = command.spawn().expect("start the server");
+
+        // Logs are read to the end so the server never blocks on a full pipe, and echoed for failing tests
+        let stderr = child.stderr.take().expect("server stderr");
+        let (listening, addr) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("server: {}", line);
+                if let Some(addr) = line.split("Listening on ").nth(1).and_then(|addr| addr.trim().parse::<SocketAddr>().ok()) {
+                    let _ = listening.send(addr);
+                }
+            }
+        });
+        let addr = match addr.recv_timeout(STARTUP_TIMEOUT) {
+            Ok(addr) => addr,
+            Err(_) => {
+                let _ = child.kill();
+                panic!("server didn't start listening within {:?}", STARTUP_TIMEOUT);
+            }
+        };
+        TestServer { child, addr, config: path }
+    }
+
+    pub fn http_url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Open a socket to `room` without reading anything from it
+    pub async fn connect(&self, room: &str) -> TestClient {
+        self.connect_at(&format!("/room/{}", room)).await
+    }
+
+    /// Open a socket to `path`, such as a tenant's `/t/<tenant>/room/<id>`
+    pub async fn connect_at(&self, path: &str) -> TestClient {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}{}", self.addr, path)).await.expect("connect");
+        TestClient { ws, user_id: String::new(), epoch: 0, roster: Vec::new() }
+    }
+
+    /// Join `room` and read the `Welcome` and `Roster` every join starts with. The board comes next
+    pub async fn join(&self, room: &str) -> TestClient {
+        self.join_at(&format!("/room/{}", room)).await
+    }
+
+    /// `join` at `path`, see `connect_at`
+    pub async fn join_at(&self, path: &str) -> TestClient {
+        let mut client = self.connect_at(path).await;
+        let welcome = client.recv_type("Welcome").await;
+        client.user_id = welcome["data"]["user_id"].as_str().expect("user id").to_string();
+        client.epoch = welcome["data"]["epoch"].as_u64().expect("epoch");
+        let roster = client.recv().await;
+        assert_eq!(roster["type"], "Roster", "expected the roster after the welcome");
+        client.roster = roster["data"]["users"].as_array().expect("roster users").iter().filter_map(|user| user["user_id"].as_str().map(str::to_string)).collect();
+        client
+    }
+
+    /// A `whiteboard_client::Client` for this server, as apps built on the client library connect
+    pub fn client(&self) -> ClientBuilder {
+        Client::builder(format!("ws://{}", self.addr))
+    }
+
+    /// `GET` a JSON endpoint
+    pub async fn get(&self, path: &str) -> Value {
+        reqwest::get(self.http_url(path)).await.expect("request").json().await.expect("JSON body")
+    }
+
+    /// `POST` a JSON body to an API endpoint, expecting a JSON reply
+    pub async fn post(&self, path: &str, body: &Value) -> Value {
+        reqwest::Client::new().post(self.http_url(path)).json(body).send().await.expect("request").json().await.expect("JSON body")
+    }
+
+    /// `GET` a text endpoint such as `/metrics`
+    pub async fn get_text(&self, path: &str) -> String {
+        reqwest::get(self.http_url(path)).await.expect("request").text().await.expect("text body")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config);
+    }
+}
+
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub user_id: String,
+    /// The board's epoch as of the join
+    pub epoch: u64,
+    /// User ids in the roster this client joined with, including its own
+    pub roster: Vec<String>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, frame: &Value) {
+        self.ws.send(Message::text(frame.to_string())).await.expect("send");
+    }
+
+    pub async fn send_binary(&mut self, bytes: Vec<u8>) {
+        self.ws.send(Message::binary(bytes)).await.expect("send");
+    }
+
+    /// The next text frame, panicking if none arrives in time or the socket closes
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .unwrap_or_else(|_| panic!("nothing arrived within {:?}", RECV_TIMEOUT))
+                .expect("socket closed")
+                .expect("socket error");
+            match message {
+                Message::Text(text) => return serde_json::from_str(&text).expect("frame is JSON"),
+                Message::Close(frame) => panic!("socket closed: {:?}", frame),
+                // Pings and pongs
+                _ => continue,
+            }
+        }
+    }
+
+    /// The next frame of type `kind`, skipping others such as other people's joins and presence
+    pub async fn recv_type(&mut self, kind: &str) -> Value {
+        loop {
+            let frame = self.recv().await;
+            if frame["type"] == kind {
+                return frame;
+            }
+        }
+    }
+
+    /// Panic if a board op arrives within a short while
+    pub async fn assert_no_ops(&mut self) {
+        let deadline = tokio::time::Instant::now() + QUIET_PERIOD;
+        while let Ok(Some(Ok(message))) = tokio::time::timeout_at(deadline, self.ws.next()).await {
+            if let Message::Text(text) = message {
+                let frame: Value = serde_json::from_str(&text).expect("frame is JSON");
+                assert!(!matches!(frame["type"].as_str(), Some("Draw" | "Erase" | "Clear")), "unexpected op {}", frame);
+            }
+        }
+    }
+
+    /// Wait for the server to close the socket, skipping other frames, and return its close frame
+    pub async fn closed(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .unwrap_or_else(|_| panic!("socket still open after {:?}", RECV_TIMEOUT));
+            match message {
+                Some(Ok(Message::Close(frame))) => return frame,
+                Some(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        self.ws.close(None).await.expect("close");
+        // Drain until the server's close frame so it has seen ours
+        while let Some(Ok(_)) = self.ws.next().await {}
+    }
+}
+
+/// A `Draw` frame, `n` tells frames apart
+pub fn draw(n: u32) -> Value {
+    json!({ "type": "Draw", "data": { "prev": [0.0, 0.0], "cur": [f64::from(n), 1.0], "color": "#112233", "brush_size": 1 + n } })
+}
+
+/// The next event of a `whiteboard_client::Client`, panicking if none arrives in time
+pub async fn next_event(client: &mut Client) -> Event {
+    tokio::time::timeout(RECV_TIMEOUT, client.next_event()).await.unwrap_or_else(|_| panic!("no event within {:?}", RECV_TIMEOUT)).expect("client gave up")
+}
+
+/// `draw(n)` as the client library has it
+pub fn line(n: u32) -> DrawCommand {
+    DrawCommand { prev: [0.0, 0.0], cur: [f64::from(n), 1.0], color: "#112233".to_string(), brush_size: 1 + n, composite: Composite::SourceOver }
+}
+
+/// `frame` with an `epoch` next to its `type`, as the server sends ops and clients may send them
+pub fn stamped(mut frame: Value, epoch: u64) -> Value {
+    frame["epoch"] = json!(epoch);
+    frame
+}
+
+/// A unique room id, so tests sharing a server don't share a board
+pub fn room_id(name: &str) -> String {
+    format!("{}-{}", name, CONFIGS.fetch_add(1, Ordering::Relaxed))
+}
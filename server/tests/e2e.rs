@@ -0,0 +1,1481 @@
+mod common;
+
+use common::{draw, line, next_event, room_id, stamped, TestServer};
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use whiteboard_client::{Error, Event, MessageType};
+use yrs::updates::decoder::Decode;
+use yrs::{GetString, ReadTxn, StateVector, Text, Transact};
+
+#[tokio::test]
+async fn ops_fan_out_to_everyone_but_the_sender() {
+    let server = TestServer::start();
+    let room = room_id("fanout");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let mut carol = server.join(&room).await;
+
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    assert_eq!(carol.recv_type("Draw").await, stamped(draw(1), 0));
+    alice.assert_no_ops().await;
+
+    carol.send(&draw(2)).await;
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(2), 0));
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(2), 0));
+    carol.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn the_client_library_draws_and_is_drawn_to() {
+    let server = TestServer::with_config("[permissions]\nclear = \"owner\"\n");
+    let room = room_id("client");
+    let mut alice = server.join(&room).await;
+    alice.send(&draw(1)).await;
+
+    let mut bob = server.client().room(room.as_str()).connect().await.expect("connect");
+    assert_eq!(next_event(&mut bob).await, Event::Connected);
+    assert_eq!(next_event(&mut bob).await, Event::Op(MessageType::Draw(line(1))));
+    bob.send(&MessageType::Draw(line(2))).unwrap();
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(2), 0));
+    alice.send(&draw(3)).await;
+    assert_eq!(next_event(&mut bob).await, Event::Op(MessageType::Draw(line(3))));
+
+    bob.send(&MessageType::Clear).unwrap();
+    match next_event(&mut bob).await {
+        Event::Error(error) => assert_eq!(error.code, "permission_denied"),
+        event => panic!("expected the clear to be refused, got {:?}", event),
+    }
+    alice.assert_no_ops().await;
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn the_client_library_is_refused_without_the_access_key() {
+    let server = TestServer::with_config("[auth]\naccess_keys = [\"key\"]\n");
+    let room = room_id("client-key");
+    match server.client().room(room.as_str()).connect().await {
+        Err(Error::Refused { status, .. }) => assert_eq!(status, 401),
+        Err(e) => panic!("expected a refusal, got {}", e),
+        Ok(_) => panic!("joined without the access key"),
+    }
+    let mut client = server.client().room(room.as_str()).key("key").connect().await.expect("connect");
+    assert_eq!(next_event(&mut client).await, Event::Connected);
+}
+
+#[tokio::test]
+async fn the_client_library_comes_back_to_the_board_as_restored() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n");
+    let room = room_id("client-resync");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.client().room(room.as_str()).connect().await.expect("connect");
+    assert_eq!(next_event(&mut bob).await, Event::Connected);
+    alice.send(&draw(1)).await;
+    assert_eq!(next_event(&mut bob).await, Event::Op(MessageType::Draw(line(1))));
+
+    let admin = reqwest::Client::new();
+    let backup = admin.get(server.http_url("/api/admin/backup")).bearer_auth("secret").send().await.unwrap().bytes().await.unwrap();
+    alice.send(&draw(2)).await;
+    assert_eq!(next_event(&mut bob).await, Event::Op(MessageType::Draw(line(2))));
+
+    // Restoring kicks everyone in the room, the client reconnects and gets the board from the backup
+    let restored = admin.post(server.http_url("/api/admin/restore")).bearer_auth("secret").body(backup).send().await.unwrap();
+    assert!(restored.status().is_success(), "{}", restored.text().await.unwrap());
+    assert!(matches!(next_event(&mut bob).await, Event::Disconnected { .. }));
+    assert_eq!(next_event(&mut bob).await, Event::Connected);
+    assert_eq!(next_event(&mut bob).await, Event::Op(MessageType::Draw(line(1))));
+    let mut carol = server.join(&room).await;
+    bob.send(&MessageType::Draw(line(3))).unwrap();
+    assert_eq!(carol.recv().await, stamped(draw(1), 0));
+    assert_eq!(carol.recv_type("Draw").await, stamped(draw(3), 0));
+}
+
+#[tokio::test]
+async fn joiners_of_a_long_board_get_the_same_board_from_its_snapshot() {
+    let server = TestServer::with_config("[rooms]\nhistory_limit = 12\nsync_snapshot_ops = 4\n");
+    let room = room_id("snapshot");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    for n in 0..14 {
+        alice.send(&draw(n)).await;
+        assert_eq!(bob.recv_type("Draw").await, stamped(draw(n), 0));
+    }
+    // Snapshotted in the background, then more drawn on top, pushing the oldest ops off the board
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !server.get_text("/metrics").await.contains("\nwhiteboard_sync_snapshots 1\n") {
+        assert!(Instant::now() < deadline, "no snapshot was taken");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for n in 14..17 {
+        alice.send(&draw(n)).await;
+        assert_eq!(bob.recv_type("Draw").await, stamped(draw(n), 0));
+    }
+
+    let mut carol = server.join(&room).await;
+    for n in 5..17 {
+        assert_eq!(carol.recv().await, stamped(draw(n), 0));
+    }
+    carol.assert_no_ops().await;
+    assert!(server.get_text("/metrics").await.contains("\nwhiteboard_snapshot_joins_total 1\n"), "carol's board didn't come from the snapshot");
+}
+
+#[tokio::test]
+async fn rooms_are_kept_apart() {
+    let server = TestServer::start();
+    let mut alice = server.join(&room_id("apart")).await;
+    let mut bob = server.join(&room_id("apart")).await;
+
+    alice.send(&draw(1)).await;
+    bob.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn joiners_are_synced_with_the_board() {
+    let server = TestServer::start();
+    let room = room_id("sync");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    for n in 0..3 {
+        alice.send(&draw(n)).await;
+        // Relayed means applied, so the board holds it before the next joiner comes
+        assert_eq!(bob.recv_type("Draw").await, stamped(draw(n), 0));
+    }
+
+    let mut carol = server.join(&room).await;
+    assert!(carol.roster.contains(&alice.user_id) && carol.roster.contains(&bob.user_id));
+    // The board comes right after the roster, oldest op first
+    for n in 0..3 {
+        assert_eq!(carol.recv().await, stamped(draw(n), 0));
+    }
+    carol.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn leaving_is_announced_and_cleaned_up() {
+    let server = TestServer::start();
+    let room = room_id("leave");
+    let mut alice = server.join(&room).await;
+    let bob = server.join(&room).await;
+    let joined = alice.recv_type("Joined").await;
+    assert_eq!(joined["data"]["user_id"], bob.user_id);
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["participants"], 2);
+
+    let bob_id = bob.user_id.clone();
+    bob.close().await;
+    let left = alice.recv_type("Left").await;
+    assert_eq!(left["data"]["user_id"], bob_id);
+    assert_eq!(left["data"]["reason"], "client_close");
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["participants"], 1);
+
+    let carol = server.join(&room).await;
+    assert!(!carol.roster.contains(&bob_id));
+    assert!(carol.roster.contains(&alice.user_id) && carol.roster.contains(&carol.user_id));
+}
+
+#[tokio::test]
+async fn why_a_connection_ended_reaches_the_room_and_the_event_stream() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n");
+    let mut monitor = server.connect_at("/admin/events?token=secret").await;
+    let room = room_id("reasons");
+    let mut alice = server.join(&room).await;
+    let bob = server.join(&room).await;
+    alice.recv_type("Joined").await;
+
+    let bob_id = bob.user_id.clone();
+    bob.close().await;
+    assert_eq!(alice.recv_type("Left").await["data"], json!({ "user_id": bob_id, "reason": "client_close" }));
+
+    let archived = reqwest::Client::new().post(server.http_url(&format!("/api/rooms/{}/archive", room))).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(archived.status(), 204);
+    let closed = alice.closed().await.expect("close frame");
+    assert_eq!((u16::from(closed.code), closed.reason.as_ref()), (1001, "room archived"));
+    let alice_id = alice.user_id.clone();
+    drop(alice);
+
+    let mut left = Vec::new();
+    while left.len() < 2 {
+        let event = monitor.recv().await;
+        if event["event"] == "user_left" && event["room"] == room {
+            left.push((event["user_id"].clone(), event["reason"].clone()));
+        }
+    }
+    assert_eq!(left, [(json!(bob_id), json!("client_close")), (json!(alice_id), json!("kicked"))]);
+}
+
+#[tokio::test]
+async fn invalid_frames_get_an_error_code_and_change_nothing() {
+    let server = TestServer::start();
+    let room = room_id("invalid");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    let frames = [
+        ("malformed", json!("not a frame")),
+        ("unknown_type", json!({ "type": "Scribble" })),
+        ("unknown_field", json!({ "type": "Draw", "data": { "prev": [0, 0], "cur": [1, 1], "color": "#000", "brush_size": 1, "opacity": 0.5 } })),
+        ("missing_field", json!({ "type": "Draw", "data": { "prev": [0, 0], "cur": [1, 1], "brush_size": 1 } })),
+        ("invalid_field", json!({ "type": "Draw", "data": { "prev": [0, 0], "cur": [1, 1], "color": 7, "brush_size": 1 } })),
+        ("out_of_range", json!({ "type": "Erase", "data": { "prev": [0, 0], "cur": [1, 1], "brush_size": 0 } })),
+    ];
+    for (code, frame) in &frames {
+        alice.send(frame).await;
+        let error = alice.recv_type("Error").await;
+        assert_eq!(error["data"]["code"], *code, "for {}", frame);
+    }
+    bob.assert_no_ops().await;
+
+    // Still connected, and the next valid op goes through
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    let metrics = server.get_text("/metrics").await;
+    for (code, _) in &frames {
+        assert!(metrics.contains(&format!("whiteboard_invalid_frames_total{{code=\"{}\"}} 1", code)), "{} isn't counted in {}", code, metrics);
+    }
+}
+
+#[tokio::test]
+async fn unknown_types_are_relayed_where_the_room_allows_it() {
+    let server = TestServer::start();
+    let created = server.post("/api/rooms", &json!({ "unknown_frames": "relay" })).await;
+    let room = created["id"].as_str().expect("room id");
+    let mut alice = server.join(room).await;
+    let mut bob = server.join(room).await;
+
+    let frame = json!({ "type": "Sticky", "data": { "text": "hi", "at": [1, 2] } });
+    alice.send(&frame).await;
+    let relayed = bob.recv_type("Relayed").await;
+    assert_eq!(relayed["data"]["user_id"], alice.user_id);
+    assert_eq!(relayed["data"]["frame"], frame);
+    bob.assert_no_ops().await;
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["history_size"], 0);
+}
+
+#[tokio::test]
+async fn webrtc_signals_reach_only_their_recipient() {
+    let server = TestServer::start();
+    let room = room_id("signal");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let mut carol = server.join(&room).await;
+
+    let offer = json!({ "sdp": { "type": "offer", "sdp": "v=0\r\n" } });
+    alice.send(&json!({ "type": "Signal", "data": { "to_user_id": bob.user_id, "payload": offer } })).await;
+    let signal = bob.recv_type("Signal").await;
+    assert_eq!((&signal["data"]["from_user_id"], &signal["data"]["payload"]), (&json!(alice.user_id), &offer));
+
+    // Anything sent to Carol would have arrived before this
+    alice.send(&draw(1)).await;
+    loop {
+        let frame = carol.recv().await;
+        assert_ne!(frame["type"], "Signal");
+        if frame["type"] == "Draw" {
+            break;
+        }
+    }
+    alice.send(&json!({ "type": "Signal", "data": { "to_user_id": alice.user_id, "payload": {} } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "unknown_user");
+}
+
+#[tokio::test]
+async fn voice_states_are_shown_to_the_room_and_to_joiners() {
+    let server = TestServer::start();
+    let room = room_id("voice");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    alice.send(&json!({ "type": "VoiceState", "data": { "muted": false, "speaking": true } })).await;
+    let voice = bob.recv_type("VoiceState").await;
+    assert_eq!(voice["data"], json!({ "user_id": alice.user_id, "voice": { "muted": false, "speaking": true } }));
+    assert_eq!(alice.recv_type("VoiceState").await["data"]["voice"]["speaking"], true);
+
+    let mut carol = server.connect(&room).await;
+    let roster = carol.recv_type("Roster").await;
+    let users = roster["data"]["users"].as_array().unwrap();
+    let voice_of = |user_id: &str| users.iter().find(|user| user["user_id"] == user_id).unwrap().get("voice").cloned();
+    assert_eq!(voice_of(&alice.user_id), Some(json!({ "muted": false, "speaking": true })));
+    assert_eq!(voice_of(&bob.user_id), None);
+
+    alice.send(&json!({ "type": "LeaveVoice" })).await;
+    assert_eq!(bob.recv_type("VoiceState").await["data"], json!({ "user_id": alice.user_id }));
+}
+
+#[tokio::test]
+async fn shared_notes_are_merged_and_caught_up_on_join() {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    let server = TestServer::start();
+    let room = room_id("notes");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    // Alice's Yjs document, as a browser would keep it
+    let doc = yrs::Doc::new();
+    let text = doc.get_or_insert_text("notes");
+    text.insert(&mut doc.transact_mut(), 0, "1. agenda");
+    let update = STANDARD.encode(doc.transact().encode_state_as_update_v1(&StateVector::default()));
+    alice.send(&json!({ "type": "NotesUpdate", "data": { "update": update } })).await;
+    let relayed = bob.recv_type("NotesUpdate").await;
+    assert_eq!((&relayed["data"]["user_id"], &relayed["data"]["update"]), (&json!(alice.user_id), &json!(update)));
+
+    let mut carol = server.join(&room).await;
+    let notes = carol.recv_type("Notes").await;
+    let late = yrs::Doc::new();
+    let late_text = late.get_or_insert_text("notes");
+    let state = STANDARD.decode(notes["data"]["update"].as_str().unwrap()).unwrap();
+    late.transact_mut().apply_update(yrs::Update::decode_v1(&state).unwrap()).unwrap();
+    assert_eq!(late_text.get_string(&late.transact()), "1. agenda");
+    assert_eq!(server.get(&format!("/api/rooms/{}/notes", room)).await["text"], "1. agenda");
+
+    carol.send(&json!({ "type": "NotesUpdate", "data": { "update": "bm90IGEgdXBkYXRl" } })).await;
+    assert_eq!(carol.recv_type("Error").await["data"]["code"], "invalid_notes");
+}
+
+#[tokio::test]
+async fn binary_ops_are_relayed_as_json() {
+    let server = TestServer::start();
+    let room = room_id("binary");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    let mut bytes = vec![0];
+    for v in [0.0f64, 0.0, 1.0, 1.0] {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend_from_slice(b"#112233");
+    alice.send_binary(bytes).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+
+    alice.send_binary(vec![1, 0]).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "malformed");
+    bob.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn silent_sockets_are_closed_after_the_handshake_timeout() {
+    let server = TestServer::with_config("[limits]\nhandshake_timeout_secs = 1\n");
+    let room = room_id("handshake");
+    let mut silent = server.join(&room).await;
+    let mut hello = server.join(&room).await;
+    hello.send(&json!({ "type": "Hello" })).await;
+
+    let frame = silent.closed().await.expect("close frame");
+    assert_eq!(u16::from(frame.code), 1008);
+    assert_eq!(frame.reason, "handshake timeout");
+    // Still in once the silent one is gone
+    assert_eq!(hello.recv_type("Left").await["data"]["reason"], "timeout");
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["participants"], 1);
+}
+
+#[tokio::test]
+async fn each_senders_ops_keep_their_order_everywhere() {
+    const SENDERS: usize = 4;
+    const OPS: usize = 250;
+    let log = std::env::temp_dir().join(format!("ws-demo-test-{}-{}.jsonl", std::process::id(), room_id("oplog")));
+    let server = TestServer::with_config(&format!("[limits]\nmessages_per_second = 0\n\n[export]\nfile = {:?}\n", log));
+    let room = room_id("ordering");
+    let mut observer = server.join(&room).await;
+    let mut senders = Vec::new();
+    for _ in 0..SENDERS {
+        senders.push(server.join(&room).await);
+    }
+    // Which sender and which of its ops, `prev` says both
+    let op = |sender: usize, n: usize| json!({ "type": "Draw", "data": { "prev": [sender as f64, n as f64], "cur": [0.0, 0.0], "color": "#000", "brush_size": 1 } });
+    let origin = |frame: &Value| (frame["data"]["prev"][0].as_f64().unwrap() as usize, frame["data"]["prev"][1].as_f64().unwrap() as usize);
+    let in_order = |frames: &[Value]| {
+        let mut next = [0; SENDERS];
+        for frame in frames {
+            let (sender, n) = origin(frame);
+            assert_eq!(n, next[sender], "sender {}'s ops arrived out of order", sender);
+            next[sender] += 1;
+        }
+        assert_eq!(next, [OPS; SENDERS]);
+    };
+
+    join_all(senders.iter_mut().enumerate().map(|(sender, client)| async move {
+        for n in 0..OPS {
+            client.send(&op(sender, n)).await;
+        }
+    }))
+    .await;
+    let mut relayed = Vec::new();
+    while relayed.len() < SENDERS * OPS {
+        relayed.push(observer.recv_type("Draw").await);
+    }
+    in_order(&relayed);
+
+    let mut joiner = server.join(&room).await;
+    let mut board = Vec::new();
+    while board.len() < SENDERS * OPS {
+        board.push(joiner.recv().await);
+    }
+    // The board is the order the observer saw, not just each sender's
+    assert_eq!(board, relayed);
+
+    // Written once the feed pauses
+    let mut logged: Vec<Value> = Vec::new();
+    for _ in 0..50 {
+        let text = std::fs::read_to_string(&log).unwrap_or_default();
+        logged = text.lines().map(|line| serde_json::from_str(line).expect("op log line is JSON")).collect();
+        if logged.len() >= SENDERS * OPS {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let _ = std::fs::remove_file(&log);
+    assert!(logged.windows(2).all(|pair| pair[0]["seq"].as_u64() < pair[1]["seq"].as_u64()), "op log seqs aren't increasing");
+    let ops: Vec<Value> = logged.iter().map(|record| stamped(record["op"].clone(), 0)).collect();
+    assert_eq!(ops, relayed);
+}
+
+#[tokio::test]
+async fn ops_from_before_a_clear_are_dropped() {
+    let server = TestServer::start();
+    let room = room_id("epoch");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let epoch = alice.epoch;
+
+    alice.send(&stamped(json!({ "type": "Clear" }), epoch)).await;
+    assert_eq!(bob.recv_type("Clear").await["epoch"], epoch + 1);
+    // Drawn by bob before he saw the clear
+    bob.send(&stamped(draw(1), epoch)).await;
+    assert_eq!(bob.recv_type("Error").await["data"]["code"], "stale_epoch");
+    // And a second clear at the same time as alice's
+    bob.send(&stamped(json!({ "type": "Clear" }), epoch)).await;
+    assert_eq!(bob.recv_type("Error").await["data"]["code"], "stale_epoch");
+    alice.assert_no_ops().await;
+
+    bob.send(&stamped(draw(2), epoch + 1)).await;
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(2), epoch + 1));
+    bob.send(&draw(3)).await;
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(3), epoch + 1));
+    let carol = server.join(&room).await;
+    assert_eq!(carol.epoch, epoch + 1);
+}
+
+#[tokio::test]
+async fn echoing_clients_get_their_own_ops_with_seqs() {
+    let server = TestServer::start();
+    let room = room_id("echo");
+    let mut alice = server.join(&format!("{}?echo=1", room)).await;
+    let mut bob = server.join(&room).await;
+
+    alice.send(&draw(1)).await;
+    let echoed = alice.recv_type("Draw").await;
+    assert_eq!(echoed["data"], draw(1)["data"]);
+    assert_eq!(echoed["user_id"], alice.user_id);
+    assert!(echoed["timestamp"].as_u64().is_some());
+    let seq = echoed["seq"].as_u64().expect("seq");
+    // Others see ops as they always have
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+
+    bob.send(&draw(2)).await;
+    let relayed = alice.recv_type("Draw").await;
+    assert_eq!((relayed["seq"].as_u64(), &relayed["user_id"]), (Some(seq + 1), &json!(bob.user_id)));
+    bob.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn small_gaps_in_a_stroke_are_bridged() {
+    let server = TestServer::with_config("[rooms]\ninterpolate_gaps_px = 50\n");
+    let room = room_id("gaps");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let segment = |from: f64, to: f64| json!({ "type": "Draw", "data": { "prev": [from, 0.0], "cur": [to, 0.0], "color": "#112233", "brush_size": 2 } });
+
+    alice.send(&segment(0.0, 10.0)).await;
+    alice.send(&segment(20.0, 30.0)).await;
+    // Too far to be the same stroke
+    alice.send(&segment(200.0, 210.0)).await;
+    for expected in [segment(0.0, 10.0), segment(10.0, 20.0), segment(20.0, 30.0), segment(200.0, 210.0)] {
+        assert_eq!(bob.recv_type("Draw").await, stamped(expected, 0));
+    }
+    alice.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn checksums_match_the_board_and_resync_restores_it() {
+    let server = TestServer::with_config("[rooms]\nchecksum_interval_secs = 1\n");
+    let room = room_id("checksum");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    alice.send(&draw(1)).await;
+    bob.recv_type("Draw").await;
+
+    // FNV-1a over the op's binary frame, worked out the way a client would
+    let mut frame = vec![0u8];
+    for v in [0.0f64, 0.0, 1.0, 1.0] {
+        frame.extend_from_slice(&v.to_le_bytes());
+    }
+    frame.extend_from_slice(&2u32.to_le_bytes());
+    frame.extend_from_slice(b"#112233");
+    let hash = frame.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3));
+    let checksum = bob.recv_type("Checksum").await;
+    assert_eq!(checksum["data"], json!({ "epoch": 0, "seq": 1, "ops": 1, "hash": format!("{:016x}", hash) }));
+    assert_eq!(alice.recv_type("Checksum").await, checksum);
+
+    bob.send(&json!({ "type": "Resync" })).await;
+    assert_eq!(bob.recv_type("Resync").await["data"], json!({ "epoch": 0 }));
+    assert_eq!(bob.recv().await, stamped(draw(1), 0));
+    assert_eq!(bob.recv().await, checksum);
+    alice.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn time_sync_is_answered_with_the_servers_clock() {
+    let server = TestServer::start();
+    let mut alice = server.join(&room_id("time")).await;
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    alice.send(&json!({ "type": "TimeSync", "data": { "client_time": 1234.5 } })).await;
+    let reply = alice.recv_type("TimeSync").await;
+    let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    assert_eq!(reply["data"]["client_time"], 1234.5);
+    let (received_at, sent_at) = (reply["data"]["received_at"].as_u64().unwrap(), reply["data"]["sent_at"].as_u64().unwrap());
+    assert!(before <= received_at && received_at <= sent_at && sent_at <= after, "{} {} {} {}", before, received_at, sent_at, after);
+}
+
+#[tokio::test]
+async fn round_trips_are_shared_as_presence() {
+    let server = TestServer::with_config("[server]\nping_interval_secs = 1\n\n[presence]\nshare_latency = true\n");
+    let room = room_id("latency");
+    let mut alice = server.join(&room).await;
+    // Reading answers the server's pings, which gives it a round trip
+    let presence = alice.recv_type("Presence").await;
+    assert_eq!(presence["data"]["user_id"], alice.user_id);
+    assert_eq!(presence["data"]["presence"], "active");
+    assert!(presence["data"]["latency_ms"].as_u64().is_some(), "{}", presence);
+    let stats = server.get(&format!("/api/rooms/{}/stats", room)).await;
+    assert!(stats["median_rtt_ms"].as_f64().is_some() && stats["max_rtt_ms"].as_f64().is_some(), "{}", stats);
+}
+
+#[tokio::test]
+async fn joins_are_redirected_to_the_node_owning_the_room() {
+    let config = "[auth]\nadmin_tokens = [\"secret\"]\n\n[cluster]\nenabled = true\nheartbeat_secs = 1\n";
+    let a = TestServer::with_config(&format!("{}node_id = \"a\"\n", config));
+    let b = TestServer::with_config(&format!("{}node_id = \"b\"\npeers = [\"{}\"]\n", config, a.http_url("")));
+    for server in [&a, &b] {
+        let mut alive = false;
+        for _ in 0..50 {
+            let status: Value = reqwest::Client::new().get(server.http_url("/api/admin/cluster")).bearer_auth("secret").send().await.unwrap().json().await.unwrap();
+            if status["members"].as_array().is_some_and(|members| members.len() == 1 && members[0]["alive"] == true) {
+                alive = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(alive, "nodes didn't find each other");
+    }
+
+    // Each room is joined on one node and sent there from the other
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    for n in 0..10 {
+        let room = room_id(&format!("cluster-{}", n));
+        let mut from_a = a.connect(&room).await;
+        let mut from_b = b.connect(&room).await;
+        let (first_a, first_b) = (from_a.recv().await, from_b.recv().await);
+        let (mut redirected, redirect, owner, other) = match (first_a["type"].as_str(), first_b["type"].as_str()) {
+            (Some("Redirect"), Some("Welcome")) => (from_a, first_a, &b, &a),
+            (Some("Welcome"), Some("Redirect")) => (from_b, first_b, &a, &b),
+            _ => panic!("expected one welcome and one redirect, got {} and {}", first_a, first_b),
+        };
+        assert_eq!(redirect["data"]["url"], format!("ws://{}/room/{}", owner.addr, room));
+        assert_eq!(redirected.closed().await.map(|frame| u16::from(frame.code)), Some(4307));
+
+        // So are the room's HTTP endpoints and other transports
+        let moved = client.get(other.http_url(&format!("/api/rooms/{}/notes?key=k", room))).send().await.unwrap();
+        assert_eq!(moved.status(), 307);
+        assert_eq!(moved.headers()["location"], format!("http://{}/api/rooms/{}/notes?key=k", owner.addr, room).as_str());
+        let opened = client.post(other.http_url(&format!("/api/rooms/{}/sessions", room))).send().await.unwrap();
+        assert_eq!(opened.status(), 307);
+        let served = client.get(owner.http_url(&format!("/api/rooms/{}/notes", room))).send().await.unwrap();
+        assert_eq!(served.status(), 200);
+    }
+}
+
+#[tokio::test]
+async fn nodes_sharing_state_serve_the_same_room() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-shared-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let config = format!("[shared_state]\nenabled = true\ndatabase = {:?}\npoll_ms = 20\n", database);
+    let (a, b) = (TestServer::with_config(&config), TestServer::with_config(&config));
+    let room = room_id("shared");
+    let mut alice = a.join(&format!("{}?echo=1", room)).await;
+    let mut bob = b.join(&room).await;
+    let joined = bob.recv_type("Joined").await;
+    assert_eq!(joined["data"]["user_id"], alice.user_id);
+
+    alice.send(&draw(1)).await;
+    assert_eq!(alice.recv_type("Draw").await["seq"], 1);
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    bob.send(&json!({ "type": "Clear" })).await;
+    assert_eq!(alice.recv_type("Clear").await["seq"], 2);
+    bob.send(&stamped(draw(2), 1)).await;
+    assert_eq!(alice.recv_type("Draw").await["seq"], 3);
+
+    // Either node has the whole board for whoever joins next
+    let mut carol = a.join(&room).await;
+    assert_eq!(carol.recv().await, stamped(draw(2), 1));
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn ops_shared_state_cannot_take_are_refused() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-shared-down-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let server = TestServer::with_config(&format!("[shared_state]\nenabled = true\ndatabase = {:?}\n", database));
+    let room = room_id("shared-down");
+    let mut alice = server.join(&format!("{}?echo=1", room)).await;
+    let mut bob = server.join(&room).await;
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+
+    // Every append fails from here on
+    rusqlite::Connection::open(&database).unwrap().execute_batch("DROP TABLE ops").unwrap();
+    alice.send(&draw(2)).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "unavailable");
+    bob.assert_no_ops().await;
+    let mut carol = server.join(&room).await;
+    assert_eq!(carol.recv_type("Draw").await, stamped(draw(1), 0));
+    carol.assert_no_ops().await;
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn tenants_rooms_are_kept_apart() {
+    let server = TestServer::with_config("[tenants.acme]\naccess_keys = [\"acme-key\"]\nadmin_tokens = [\"acme-admin\"]\nbranding = { name = \"Acme\" }\n");
+    let room = room_id("tenant");
+    let mut outsider = server.join(&room).await;
+    let mut alice = server.join_at(&format!("/t/acme/room/{}?key=acme-key", room)).await;
+    // The key alone is enough to land in the tenant's room
+    let mut bob = server.join(&format!("{}?key=acme-key", room)).await;
+
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    outsider.assert_no_ops().await;
+
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/t/acme/room/{}", server.addr, room)).await;
+    assert!(refused.is_err(), "joined a tenant's room without its key");
+    assert_eq!(server.get("/api/tenants/acme").await["branding"]["name"], "Acme");
+    let rooms = reqwest::Client::new().get(server.http_url("/api/tenants/acme/rooms")).bearer_auth("acme-admin").send().await.unwrap();
+    let rooms: Value = rooms.json().await.unwrap();
+    assert_eq!(rooms["rooms"][0]["id"], room);
+    assert_eq!(rooms["rooms"][0]["participants"], 2);
+}
+
+#[tokio::test]
+async fn exports_can_be_cropped_to_a_region() {
+    let server = TestServer::start();
+    let room = room_id("crop");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let far = json!({ "type": "Draw", "data": { "prev": [2000.0, 800.0], "cur": [2100.0, 900.0], "color": "#ff0000", "brush_size": 4 } });
+    alice.send(&draw(1)).await;
+    alice.send(&far).await;
+    bob.recv_type("Draw").await;
+    bob.recv_type("Draw").await;
+
+    let svg = |query: &'static str| {
+        let url = server.http_url(&format!("/api/rooms/{}/export.svg{}", room, query));
+        async move { reqwest::get(url).await.unwrap().text().await.unwrap() }
+    };
+    let cropped = svg("?x=1900&y=700&w=300&h=300").await;
+    assert!(cropped.contains(r#"viewBox="1900 700 300 300""#), "{}", cropped);
+    assert_eq!(cropped.matches("<path").count(), 1);
+    assert!(cropped.contains("#ff0000"));
+    assert_eq!(svg("").await.matches("<path").count(), 2);
+
+    let png = reqwest::get(server.http_url(&format!("/api/rooms/{}/render.png?x=2000&y=800&w=120&h=80", room))).await.unwrap();
+    assert_eq!(png.headers()["content-type"], "image/png");
+    let bad = reqwest::get(server.http_url(&format!("/api/rooms/{}/render.png?x=left", room))).await.unwrap();
+    assert_eq!(bad.status(), 400);
+}
+
+#[tokio::test]
+async fn strokes_can_be_traced_back_to_who_drew_them() {
+    let server = TestServer::start();
+    let room = room_id("inspect");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&format!("{}?echo=1", room)).await;
+    alice.send(&draw(1)).await;
+    let drawn = bob.recv_type("Draw").await;
+    let stroke_id = drawn["stroke_id"].as_u64().unwrap();
+
+    bob.send(&json!({ "type": "Inspect", "data": { "stroke_id": stroke_id } })).await;
+    let inspected = bob.recv_type("Stroke").await;
+    let stroke = &inspected["data"]["stroke"];
+    assert_eq!((&stroke["stroke_id"], &stroke["user_id"], &stroke["device"]), (&json!(stroke_id), &json!(alice.user_id), &json!("websocket")));
+    assert!(stroke["drawn_at"].as_u64().is_some());
+    assert_eq!(inspected["data"]["op"]["data"]["cur"], json!([1.0, 1.0]));
+
+    let mut carol = server.connect(&format!("{}?echo=1", room)).await;
+    assert_eq!(carol.recv_type("Draw").await["stroke_id"], json!(stroke_id));
+    bob.send(&json!({ "type": "Inspect", "data": { "stroke_id": stroke_id + 100 } })).await;
+    assert_eq!(bob.recv_type("Error").await["data"]["code"], "unknown_stroke");
+
+    let svg = server.get_text(&format!("/api/rooms/{}/export.svg", room)).await;
+    assert!(svg.contains(&format!(r#"data-stroke-id="{}" data-user-id="{}""#, stroke_id, alice.user_id)), "{}", svg);
+}
+
+#[tokio::test]
+async fn each_persons_strokes_can_be_played_back_on_their_own() {
+    let server = TestServer::start();
+    let room = room_id("timeline");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    alice.send(&draw(1)).await;
+    bob.recv_type("Draw").await;
+    bob.send(&draw(2)).await;
+    alice.recv_type("Draw").await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    alice.send(&draw(3)).await;
+    bob.recv_type("Draw").await;
+
+    let timeline = server.get(&format!("/api/rooms/{}/users/{}/timeline", room, alice.user_id)).await;
+    assert_eq!(timeline["user_id"], json!(alice.user_id));
+    let ops = timeline["ops"].as_array().unwrap();
+    let drawn: Vec<&Value> = ops.iter().map(|op| &op["op"]["data"]["cur"]).collect();
+    assert_eq!(drawn, [&json!([1.0, 1.0]), &json!([3.0, 1.0])], "{}", timeline);
+    assert_eq!(ops[0]["offset_ms"], 0);
+    assert!(ops[1]["offset_ms"].as_u64().unwrap() >= 20, "{}", timeline);
+    assert_eq!(ops[1]["drawn_at"].as_u64().unwrap() - ops[0]["drawn_at"].as_u64().unwrap(), ops[1]["offset_ms"].as_u64().unwrap());
+
+    let nobody = server.get(&format!("/api/rooms/{}/users/00000000000000aa/timeline", room)).await;
+    assert_eq!(nobody["ops"], json!([]));
+}
+
+#[tokio::test]
+async fn tools_are_refused_to_whoever_the_permissions_leave_out() {
+    let server = TestServer::with_config("[permissions]\nclear = \"owner\"\n");
+    let room = room_id("permissions");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    // Nobody owns a room on a server without accounts
+    alice.send(&json!({ "type": "Clear" })).await;
+    let error = alice.recv_type("Error").await;
+    assert_eq!(error["data"]["code"], "permission_denied");
+    assert_eq!(error["data"]["message"], "clear is for the room's owner in this room");
+    bob.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn owner_only_tools_are_let_through_for_the_owner() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-owner-tools-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let server = TestServer::with_config(&format!("[permissions]\nclear = \"owner\"\n\n[accounts]\nenabled = true\ndatabase = {:?}\n", database));
+    let credentials = json!({ "username": "teacher", "password": "correct horse" });
+    server.post("/api/accounts", &credentials).await;
+    let token = server.post("/api/accounts/login", &credentials).await["token"].as_str().expect("session token").to_string();
+    let created: Value = reqwest::Client::new().post(server.http_url("/api/rooms")).bearer_auth(&token).json(&json!({ "visibility": "unlisted" })).send().await.unwrap().json().await.unwrap();
+    let room = created["id"].as_str().expect("room id").to_string();
+
+    let mut teacher = server.join(&format!("{}?token={}", room, token)).await;
+    let mut pupil = server.join(&room).await;
+    teacher.send(&json!({ "type": "Clear" })).await;
+    pupil.recv_type("Clear").await;
+    teacher.close().await;
+
+    // Owning the room comes with signing in after joining too
+    let mut guest = server.join(&room).await;
+    guest.send(&json!({ "type": "Clear" })).await;
+    assert_eq!(guest.recv_type("Error").await["data"]["code"], "permission_denied");
+    guest.send(&json!({ "type": "SignIn", "data": { "token": token } })).await;
+    guest.send(&json!({ "type": "Clear" })).await;
+    pupil.recv_type("Clear").await;
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn feature_flags_turn_things_off_per_room() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n\n[features]\nhistory_limit = 2\n");
+    let room = room_id("features");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+
+    let flags = json!({ "dms": false, "binary": false });
+    let set = reqwest::Client::new().put(server.http_url(&format!("/api/rooms/{}/features", room))).bearer_auth("secret").json(&flags).send().await.unwrap();
+    let set: Value = set.json().await.unwrap();
+    assert_eq!(set["flags"], flags);
+    assert_eq!(set["effective"], json!({ "dms": false, "binary": false, "history_limit": 2, "notes": true }));
+    assert_eq!(bob.recv_type("Room").await["data"]["features"], flags);
+
+    alice.send(&json!({ "type": "Dm", "data": { "to_user_id": bob.user_id, "text": "hi" } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "feature_disabled");
+    alice.send_binary(vec![0]).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "feature_disabled");
+    for n in 1..=3 {
+        alice.send(&draw(n)).await;
+        bob.recv_type("Draw").await;
+    }
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["history_size"], 2);
+
+    // Joiners are told up front
+    let mut carol = server.connect(&room).await;
+    assert_eq!(carol.recv_type("Welcome").await["data"]["capabilities"], json!(["notes", "undo_clear", "checksums"]));
+    let mut dave = server.connect(&room_id("features")).await;
+    assert_eq!(dave.recv_type("Welcome").await["data"]["capabilities"], json!(["binary", "dms", "notes", "undo_clear", "checksums"]));
+}
+
+#[tokio::test]
+async fn moderation_refuses_flagged_direct_messages_and_room_names() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use warp::Filter;
+
+    // Refuses anything mentioning spam, counting what it's asked
+    let asked = Arc::new(AtomicUsize::new(0));
+    let counted = asked.clone();
+    let service = warp::post().and(warp::body::json()).map(move |request: Value| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        let allowed = !request["text"].as_str().unwrap_or_default().contains("spam");
+        warp::reply::json(&json!({ "allowed": allowed, "reason": "no spam please" }))
+    });
+    let (addr, serve) = warp::serve(service).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+    let server = TestServer::with_config(&format!("[moderation]\nurl = \"http://{}/check\"\n", addr));
+    let room = room_id("moderated");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let to = bob.user_id.clone();
+    let dm = |text: &str| json!({ "type": "Dm", "data": { "to_user_id": to, "text": text } });
+
+    alice.send(&dm("buy spam")).await;
+    let error = alice.recv_type("Error").await;
+    assert_eq!(error["data"]["code"], "moderated");
+    assert_eq!(error["data"]["message"], "no spam please");
+    // The same text again is refused from the cache, and what's allowed gets through
+    alice.send(&dm("buy spam")).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "moderated");
+    for _ in 0..2 {
+        alice.send(&dm("hello")).await;
+        assert_eq!(bob.recv_type("Dm").await["data"]["text"], "hello");
+    }
+    assert_eq!(asked.load(Ordering::SeqCst), 2);
+
+    let refused = reqwest::Client::new().post(server.http_url("/api/rooms")).json(&json!({ "name": "spam corner" })).send().await.unwrap();
+    assert_eq!(refused.status(), 422);
+    let created = server.post("/api/rooms", &json!({ "name": "Sketches", "tags": ["art"] })).await;
+    assert!(created["id"].is_string(), "{}", created);
+
+    // Failing closed, nothing gets through while the service is down
+    let down = TestServer::with_config("[moderation]\nurl = \"http://127.0.0.1:9/check\"\nfail_closed = true\n");
+    let mut alice = down.join(&room).await;
+    let bob = down.join(&room).await;
+    alice.send(&json!({ "type": "Dm", "data": { "to_user_id": bob.user_id, "text": "hello" } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "unavailable");
+}
+
+#[tokio::test]
+async fn boards_are_sealed_on_disk() {
+    let dir = std::env::temp_dir().join(format!("ws-demo-test-sealed-{}", std::process::id()));
+    let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let config = format!("[auth]\nadmin_tokens = [\"secret\"]\n\n[storage.encryption]\nkey = \"{}\"\n", key);
+    let server = TestServer::with_storage(&config, &format!("file:{}", dir.display()));
+    let room = room_id("sealed");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    alice.send(&draw(1)).await;
+    bob.recv_type("Draw").await;
+
+    let client = reqwest::Client::new();
+    let archived = client.post(server.http_url(&format!("/api/rooms/{}/archive", room))).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(archived.status(), 204);
+    let file = std::fs::read(dir.join(format!("{}.json", room))).unwrap();
+    assert!(file.starts_with(b"WBE1"));
+    assert!(!file.windows(7).any(|w| w == b"#112233"), "the board is readable on disk");
+
+    let restored = client.post(server.http_url(&format!("/api/rooms/{}/restore", room))).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(restored.status(), 204);
+    let mut carol = server.join(&room).await;
+    assert_eq!(carol.recv_type("Draw").await, stamped(draw(1), 0));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn a_room_is_saved_as_soon_as_its_last_user_leaves() {
+    let dir = std::env::temp_dir().join(format!("ws-demo-test-last-leave-{}", std::process::id()));
+    let log = dir.with_extension("ops.jsonl");
+    let server = TestServer::with_storage(&format!("[export]\nfile = \"{}\"\n", log.display()), &format!("file:{}", dir.display()));
+    let room = room_id("last-leave");
+    let mut alice = server.join(&format!("{}?echo=1", room)).await;
+    alice.send(&draw(1)).await;
+    alice.recv_type("Draw").await;
+    alice.close().await;
+
+    // Well before the next periodic save
+    let deadline = Instant::now() + Duration::from_millis(1500);
+    let saved = loop {
+        let board = std::fs::read_to_string(dir.join(format!("{}.json", room))).unwrap_or_default();
+        let ops = std::fs::read_to_string(&log).unwrap_or_default();
+        if board.contains("#112233") && ops.contains(&room) {
+            break true;
+        }
+        if Instant::now() > deadline {
+            break false;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::remove_file(&log);
+    assert!(saved, "the board and op log weren't written when the room emptied");
+}
+
+#[tokio::test]
+async fn a_replica_follows_the_primarys_ops_and_serves_only_reads() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    // Just enough of a NATS server to hand the replica one op, as the primary's export publishes it
+    let nats = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = nats.local_addr().unwrap().port();
+    let server = TestServer::with_config(&format!("[replica]\nnats_url = \"nats://127.0.0.1:{}\"\n", port));
+    let room = room_id("replica");
+    let (socket, _) = nats.accept().await.unwrap();
+    let (reader, mut writer) = socket.into_split();
+    writer.write_all(b"INFO {}\r\n").await.unwrap();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if line.starts_with("SUB whiteboard.ops.>") {
+            break;
+        }
+    }
+    let record = json!({ "room": room, "user_id": "00000000000000aa", "seq": 1, "correlation_id": 1, "timestamp": 1, "op": draw(1) });
+    let payload = record.to_string();
+    writer.write_all(format!("MSG whiteboard.ops.{} 1 {}\r\n{}\r\n", room, payload.len(), payload).as_bytes()).await.unwrap();
+
+    let mut stats = json!(null);
+    for _ in 0..50 {
+        stats = server.get(&format!("/api/rooms/{}/stats", room)).await;
+        if stats["history_size"] == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(stats["history_size"], 1, "{}", stats);
+
+    let joined = reqwest::get(server.http_url(&format!("/room/{}", room))).await.unwrap();
+    assert_eq!(joined.status(), 503);
+    let created = reqwest::Client::new().post(server.http_url("/api/rooms")).send().await.unwrap();
+    assert_eq!(created.status(), 503);
+}
+
+#[tokio::test]
+async fn rooms_are_backed_up_from_one_server_and_restored_into_another() {
+    let config = "[auth]\nadmin_tokens = [\"secret\"]\n";
+    let dir = std::env::temp_dir().join(format!("ws-demo-test-backup-{}", std::process::id()));
+    let room = room_id("backup");
+    let old = TestServer::with_config(config);
+    let mut alice = old.join(&room).await;
+    let mut bob = old.join(&room).await;
+    alice.send(&draw(1)).await;
+    bob.recv_type("Draw").await;
+
+    let cli = |command: &str, dir_flag: &str, server: &TestServer| {
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_ws-demo"))
+            .args(["--storage", "memory", command, dir_flag])
+            .arg(&dir)
+            .args(["--server", &server.http_url(""), "--token", "secret"])
+            .status()
+            .expect("run the CLI");
+        assert!(status.success(), "{} failed", command);
+    };
+    cli("backup", "--out", &old);
+    let new = TestServer::with_config(config);
+    cli("restore", "--in", &new);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut carol = new.join(&room).await;
+    assert_eq!(carol.recv_type("Draw").await, stamped(draw(1), 0));
+}
+
+#[tokio::test]
+async fn anonymous_visitors_get_trial_rooms_that_end() {
+    let server = TestServer::with_config("[rooms]\nwaitlist = false\n\n[trial]\nenabled = true\nttl_secs = 2\nmax_participants = 1\n");
+    let created = server.post("/api/rooms", &json!({})).await;
+    let room = created["id"].as_str().unwrap();
+    assert!(room.starts_with("trial-"), "{}", room);
+    assert!(created["expires_at"].is_u64());
+
+    let mut alice = server.join(room).await;
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/room/{}", server.addr, room)).await;
+    assert!(refused.is_err(), "joined a full trial room");
+    alice.send(&draw(1)).await;
+    let close = alice.closed().await.expect("close frame");
+    assert_eq!(close.reason, "trial ended");
+    // Never saved, so it starts over
+    let mut bob = server.join(room).await;
+    bob.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn reports_reach_moderators_with_what_was_on_the_board() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n");
+    let room = room_id("reports");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    bob.send(&draw(1)).await;
+    alice.recv_type("Draw").await;
+
+    alice.send(&json!({ "type": "Report", "data": { "user_id": bob.user_id, "reason": " scribbling over everything " } })).await;
+    let report_id = alice.recv_type("Reported").await["data"]["report_id"].clone();
+    alice.send(&json!({ "type": "Report", "data": { "user_id": alice.user_id, "reason": "me" } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "unknown_user");
+    let filed = server.post("/api/reports", &json!({ "room": room, "user_id": bob.user_id, "reason": "again" })).await;
+    assert!(filed["id"].is_string(), "{}", filed);
+
+    let client = reqwest::Client::new();
+    let list = |path: String| client.get(server.http_url(&path)).bearer_auth("secret").send();
+    let reports: Value = list(format!("/api/admin/reports?room={}", room)).await.unwrap().json().await.unwrap();
+    let reports = reports["reports"].as_array().unwrap();
+    assert_eq!(reports.len(), 2);
+    let first = &reports[1];
+    assert_eq!((&first["id"], &first["user_id"], &first["reported_by"], &first["reason"]), (&report_id, &json!(bob.user_id), &json!(alice.user_id), &json!("scribbling over everything")));
+    assert_eq!(first["context"][0]["stroke"]["user_id"], json!(bob.user_id));
+    assert_eq!(first["context"][0]["op"], draw(1));
+
+    let dismissed = client.delete(server.http_url(&format!("/api/admin/reports/{}", report_id.as_str().unwrap()))).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(dismissed.status(), 204);
+    let reports: Value = list(format!("/api/admin/reports?room={}", room)).await.unwrap().json().await.unwrap();
+    assert_eq!(reports["reports"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn the_status_page_lists_rooms_for_admins() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n");
+    let room = room_id("status");
+    let _alice = server.join(&room).await;
+
+    let page = reqwest::Client::new().get(server.http_url("/status")).bearer_auth("secret").send().await.expect("request");
+    assert_eq!(page.status(), 200);
+    assert!(page.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    assert!(page.text().await.unwrap().contains(&room));
+    assert_eq!(reqwest::get(server.http_url("/status")).await.expect("request").status(), 401);
+    // Only the events socket takes the token in the query
+    assert_eq!(reqwest::get(server.http_url("/status?token=secret")).await.expect("request").status(), 401);
+}
+
+#[tokio::test]
+async fn a_clear_can_be_undone_once() {
+    let server = TestServer::start();
+    let room = room_id("undo");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    for n in 1..=2 {
+        alice.send(&draw(n)).await;
+        bob.recv_type("Draw").await;
+    }
+    alice.send(&json!({ "type": "Clear" })).await;
+    bob.recv_type("Clear").await;
+    alice.send(&draw(3)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(3), 1));
+
+    bob.send(&json!({ "type": "UndoClear" })).await;
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(client.recv_type("Clear").await["epoch"], 2);
+        for n in 1..=3 {
+            assert_eq!(client.recv().await, stamped(draw(n), 2));
+        }
+    }
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["history_size"], 3);
+
+    bob.send(&json!({ "type": "UndoClear" })).await;
+    assert_eq!(bob.recv_type("Error").await["data"]["code"], "nothing_to_undo");
+    alice.assert_no_ops().await;
+}
+
+#[tokio::test]
+async fn everyone_is_warned_before_a_scheduled_clear() {
+    let server = TestServer::start();
+    let created = server.post("/api/rooms", &json!({ "visibility": "unlisted", "schedule": [{ "cron": "* * * * *", "action": "clear" }] })).await;
+    let room = created["id"].as_str().expect("room id").to_string();
+    let mut alice = server.join(&room).await;
+
+    let warning = alice.recv_type("Scheduled").await["data"].clone();
+    assert_eq!(warning["action"], "clear");
+    assert!(warning["in_secs"].as_u64().unwrap() <= 60, "{}", warning);
+    assert_eq!(warning["at"].as_u64().unwrap() % 60, 0);
+
+    let invalid = server.post("/api/rooms", &json!({ "visibility": "unlisted", "schedule": [{ "cron": "0 25 * * *", "action": "clear" }] })).await;
+    assert!(invalid["error"].as_str().unwrap().contains("hour"), "{}", invalid);
+}
+
+#[tokio::test]
+async fn guests_who_sign_in_keep_what_they_drew() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-accounts-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let server = TestServer::with_config(&format!("[accounts]\nenabled = true\ndatabase = {:?}\n", database));
+    let credentials = json!({ "username": "ann", "password": "correct horse" });
+    server.post("/api/accounts", &credentials).await;
+    let token = server.post("/api/accounts/login", &credentials).await["token"].as_str().expect("session token").to_string();
+
+    let room = room_id("sign-in");
+    let mut guest = server.join(&format!("{}?echo=1", room)).await;
+    let mut bob = server.join(&room).await;
+    guest.send(&draw(1)).await;
+    let stroke_id = guest.recv_type("Draw").await["stroke_id"].clone();
+    bob.recv_type("Draw").await;
+
+    guest.send(&json!({ "type": "SignIn", "data": { "token": "nope" } })).await;
+    assert_eq!(guest.recv_type("Error").await["data"]["code"], "invalid_session");
+    guest.send(&json!({ "type": "SignIn", "data": { "token": token } })).await;
+    let roster = bob.recv_type("Roster").await;
+    let ann = roster["data"]["users"].as_array().unwrap().iter().find(|user| user["user_id"] == json!(guest.user_id)).expect("still in the roster").clone();
+    assert_eq!(ann["account"], "ann");
+
+    bob.send(&json!({ "type": "Inspect", "data": { "stroke_id": stroke_id } })).await;
+    let stroke = bob.recv_type("Stroke").await["data"]["stroke"].clone();
+    assert_eq!((&stroke["user_id"], &stroke["account"]), (&json!(guest.user_id), &json!("ann")));
+    guest.send(&json!({ "type": "SignIn", "data": { "token": token } })).await;
+    assert_eq!(guest.recv_type("Error").await["data"]["code"], "already_signed_in");
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn viewers_are_kept_from_what_the_filters_hide() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-filters-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let server = TestServer::with_config(&format!("[accounts]\nenabled = true\ndatabase = {:?}\n\n[filters]\nhidden_from_viewers = [\"Reaction\"]\n", database));
+    let credentials = json!({ "username": "owner", "password": "correct horse" });
+    server.post("/api/accounts", &credentials).await;
+    let token = server.post("/api/accounts/login", &credentials).await["token"].as_str().expect("session token").to_string();
+    let client = reqwest::Client::new();
+    let created: Value = client.post(server.http_url("/api/rooms")).bearer_auth(&token).json(&json!({ "visibility": "unlisted" })).send().await.unwrap().json().await.unwrap();
+    let room = created["id"].as_str().expect("room id").to_string();
+    let link: Value = client.post(server.http_url(&format!("/api/rooms/{}/links", room))).bearer_auth(&token).json(&json!({ "role": "viewer" })).send().await.unwrap().json().await.unwrap();
+
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let mut viewer = server.join_at(link["url"].as_str().expect("link url")).await;
+    alice.send(&json!({ "type": "React", "data": { "emoji": "🎉" } })).await;
+    assert_eq!(bob.recv_type("Reaction").await["data"]["emoji"], "🎉");
+    alice.send(&draw(1)).await;
+    loop {
+        let frame = viewer.recv().await;
+        assert_ne!(frame["type"], "Reaction", "viewers don't get reactions");
+        if frame["type"] == "Draw" {
+            break;
+        }
+    }
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn owners_can_freeze_the_whole_room() {
+    let database = std::env::temp_dir().join(format!("ws-demo-test-freeze-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+    let server = TestServer::with_config(&format!("[auth]\nadmin_tokens = [\"secret\"]\n\n[accounts]\nenabled = true\ndatabase = {:?}\n", database));
+    let credentials = json!({ "username": "teacher", "password": "correct horse" });
+    server.post("/api/accounts", &credentials).await;
+    let token = server.post("/api/accounts/login", &credentials).await["token"].as_str().expect("session token").to_string();
+    let created: Value = reqwest::Client::new().post(server.http_url("/api/rooms")).bearer_auth(&token).json(&json!({ "visibility": "unlisted" })).send().await.unwrap().json().await.unwrap();
+    let room = created["id"].as_str().expect("room id").to_string();
+
+    let mut teacher = server.join(&format!("{}?token={}", room, token)).await;
+    let mut pupil = server.join(&room).await;
+    pupil.send(&json!({ "type": "Freeze" })).await;
+    assert_eq!(pupil.recv_type("Error").await["data"]["code"], "forbidden");
+
+    teacher.send(&json!({ "type": "Freeze" })).await;
+    assert_eq!(pupil.recv_type("Frozen").await["data"], json!({ "frozen": true, "user_id": teacher.user_id }));
+    pupil.send(&draw(1)).await;
+    assert_eq!(pupil.recv_type("Error").await["data"]["code"], "room_frozen");
+    teacher.send(&json!({ "type": "Clear" })).await;
+    assert_eq!(teacher.recv_type("Error").await["data"]["code"], "room_frozen");
+    let mut late = server.join(&room).await;
+    assert_eq!(late.recv_type("Frozen").await["data"]["frozen"], true);
+    let injected: Value = reqwest::Client::new().post(server.http_url(&format!("/api/rooms/{}/commands", room))).bearer_auth("secret").json(&draw(3)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(injected["applied"], 0, "{}", injected);
+
+    teacher.send(&json!({ "type": "Unfreeze" })).await;
+    assert_eq!(pupil.recv_type("Frozen").await["data"]["frozen"], false);
+    pupil.send(&draw(2)).await;
+    assert_eq!(late.recv_type("Draw").await, stamped(draw(2), 0));
+    let _ = std::fs::remove_file(&database);
+}
+
+#[tokio::test]
+async fn boards_are_saved_as_soon_as_the_durability_asks() {
+    for durability in ["fsync", "batched"] {
+        let dir = std::env::temp_dir().join(format!("ws-demo-test-durability-{}-{}", durability, std::process::id()));
+        let config = format!("[storage]\ndurability = \"{}\"\ncommit_interval_ms = 200\n", durability);
+        let server = TestServer::with_storage(&config, &format!("file:{}", dir.display()));
+        let room = room_id("durability");
+        let mut alice = server.join(&room).await;
+        let mut bob = server.join(&room).await;
+        alice.send(&draw(1)).await;
+        bob.recv_type("Draw").await;
+
+        // Still in the room, and well before the next save without either
+        let deadline = Instant::now() + Duration::from_millis(1500);
+        let saved = loop {
+            if std::fs::read_to_string(dir.join(format!("{}.json", room))).unwrap_or_default().contains("#112233") {
+                break true;
+            }
+            if Instant::now() > deadline {
+                break false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(saved, "the board wasn't saved right away with {} durability", durability);
+    }
+}
+
+#[tokio::test]
+async fn sockets_can_switch_rooms_without_reconnecting() {
+    let server = TestServer::start();
+    let (lobby, room) = (room_id("switch-lobby"), room_id("switch-room"));
+    let mut alice = server.join(&lobby).await;
+    let mut bob = server.join(&lobby).await;
+    let mut carol = server.join(&format!("{}?echo=1", room)).await;
+    carol.send(&draw(1)).await;
+    carol.recv_type("Draw").await;
+
+    alice.send(&json!({ "type": "SwitchRoom", "data": { "room_id": "not a room" } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "invalid_room");
+    alice.send(&json!({ "type": "SwitchRoom", "data": { "room_id": lobby } })).await;
+    assert_eq!(alice.recv_type("Error").await["data"]["code"], "same_room");
+
+    alice.send(&json!({ "type": "SetProfile", "data": { "name": "Alice" } })).await;
+    bob.recv_type("Profile").await;
+    alice.send(&json!({ "type": "SwitchRoom", "data": { "room_id": room } })).await;
+    let left = bob.recv_type("Left").await;
+    assert_eq!(left["data"], json!({ "user_id": alice.user_id, "reason": "switched_room" }));
+    // Caught up on the new room like any joiner
+    let welcome = alice.recv_type("Welcome").await;
+    assert_eq!(welcome["data"]["user_id"], json!(alice.user_id));
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(1), 0));
+    let joined = carol.recv_type("Joined").await;
+    assert_eq!((&joined["data"]["user_id"], &joined["data"]["name"]), (&json!(alice.user_id), &json!("Alice")));
+
+    alice.send(&draw(2)).await;
+    assert_eq!(carol.recv_type("Draw").await["data"], draw(2)["data"]);
+    bob.assert_no_ops().await;
+
+    // The resume token the new room gave out still finds the profile
+    alice.close().await;
+    carol.recv_type("Left").await;
+    let resume = welcome["data"]["resume_token"].as_str().unwrap();
+    let _alice = server.join(&format!("{}?resume={}", room, resume)).await;
+    assert_eq!(carol.recv_type("Joined").await["data"]["name"], "Alice");
+}
+
+#[tokio::test]
+async fn only_as_many_switch_into_a_room_as_it_has_slots() {
+    let server = TestServer::with_config("[rooms]\nmax_participants = 1\nwaitlist = false\n");
+    let target = room_id("switch-full");
+    let mut switchers = Vec::new();
+    for n in 0..4 {
+        switchers.push(server.join(&format!("{}?echo=1", room_id(&format!("switch-from-{}", n)))).await);
+    }
+    let switch = json!({ "type": "SwitchRoom", "data": { "room_id": target } });
+    join_all(switchers.iter_mut().map(|switcher| switcher.send(&switch))).await;
+
+    let mut switched = 0;
+    for switcher in &mut switchers {
+        loop {
+            let frame = switcher.recv().await;
+            match frame["type"].as_str() {
+                Some("Welcome") => switched += 1,
+                Some("Error") => assert_eq!(frame["data"]["code"], "room_full"),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    assert_eq!(switched, 1);
+    // Those turned away are still in their own rooms
+    for (n, switcher) in switchers.iter_mut().enumerate() {
+        switcher.send(&draw(n as u32)).await;
+        assert_eq!(switcher.recv_type("Draw").await["data"], draw(n as u32)["data"]);
+    }
+}
+
+#[tokio::test]
+async fn only_as_many_bots_get_in_as_the_room_allows() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = TestServer::with_config("[bots]\nmax_per_room = 1\n");
+    let room = room_id("bot-limit");
+    // All past the check before the upgrade at once, the one as they're let in turns all but one away
+    let url = format!("ws://{}/room/{}?bot=1", server.addr, room);
+    let connected = join_all((0..4).map(|_| tokio_tungstenite::connect_async(url.clone()))).await;
+    let mut admitted = 0;
+    let mut sockets = Vec::new();
+    for (mut ws, _) in connected.into_iter().flatten() {
+        let first = loop {
+            match ws.next().await.expect("a frame").expect("socket error") {
+                Message::Text(text) => break serde_json::from_str::<Value>(&text).unwrap(),
+                _ => continue,
+            }
+        };
+        match first["type"].as_str() {
+            Some("Welcome") => admitted += 1,
+            _ => assert_eq!(first["data"]["code"], "too_many_bots", "{}", first),
+        }
+        sockets.push(ws);
+    }
+    assert_eq!(admitted, 1);
+
+    // Nor do bots switching in all at once
+    let target = room_id("bot-limit-switch");
+    let mut bots = Vec::new();
+    for n in 0..4 {
+        bots.push(server.join_at(&format!("/room/{}?bot=1", room_id(&format!("bot-limit-from-{}", n)))).await);
+    }
+    let switch = json!({ "type": "SwitchRoom", "data": { "room_id": target } });
+    join_all(bots.iter_mut().map(|bot| bot.send(&switch))).await;
+    let mut switched = 0;
+    for bot in &mut bots {
+        loop {
+            let frame = bot.recv().await;
+            match frame["type"].as_str() {
+                Some("Welcome") => switched += 1,
+                Some("Error") => assert_eq!(frame["data"]["code"], "too_many_bots"),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    assert_eq!(switched, 1);
+}
+
+#[tokio::test]
+async fn each_person_has_a_quota_of_strokes_on_the_board() {
+    let server = TestServer::with_config("[quotas]\nuser_strokes = 2\nuser_points = 6\n");
+    let room = room_id("stroke-quota");
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    let segment = |from: f64, to: f64| json!({ "type": "Draw", "data": { "prev": [from, 0.0], "cur": [to, 0.0], "color": "#112233", "brush_size": 2 } });
+
+    // Two lines, the first with three points and the second with two
+    for (from, to) in [(0.0, 10.0), (10.0, 20.0), (100.0, 110.0)] {
+        alice.send(&segment(from, to)).await;
+        assert_eq!(bob.recv_type("Draw").await, stamped(segment(from, to), 0));
+    }
+    alice.send(&segment(200.0, 210.0)).await;
+    let error = alice.recv_type("Error").await;
+    assert_eq!(error["data"]["code"], "quota_exceeded");
+    assert!(error["data"]["message"].as_str().unwrap().contains("user strokes"), "{}", error);
+    alice.send(&segment(110.0, 120.0)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(segment(110.0, 120.0), 0));
+    alice.send(&segment(120.0, 130.0)).await;
+    let error = alice.recv_type("Error").await;
+    assert!(error["data"]["message"].as_str().unwrap().contains("user points"), "{}", error);
+    bob.assert_no_ops().await;
+
+    // Everyone has their own, and a clear gives it back
+    bob.send(&segment(0.0, 10.0)).await;
+    assert_eq!(alice.recv_type("Draw").await, stamped(segment(0.0, 10.0), 0));
+    alice.send(&json!({ "type": "Clear" })).await;
+    bob.recv_type("Clear").await;
+    alice.send(&segment(200.0, 210.0)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(segment(200.0, 210.0), 1));
+}
+
+#[tokio::test]
+async fn admins_can_put_a_notice_up_in_every_room() {
+    let server = TestServer::with_config("[auth]\nadmin_tokens = [\"secret\"]\n");
+    let (lobby, class) = (room_id("notice-lobby"), room_id("notice-class"));
+    let mut alice = server.join(&lobby).await;
+    let mut bob = server.join(&class).await;
+    let post = |body: Value| reqwest::Client::new().post(server.http_url("/api/admin/notices")).bearer_auth("secret").json(&body).send();
+
+    let refused = reqwest::Client::new().post(server.http_url("/api/admin/notices")).json(&json!({ "text": "hi" })).send().await.unwrap();
+    assert_eq!(refused.status(), 401);
+    assert_eq!(post(json!({ "text": " \n " })).await.unwrap().status(), 400);
+
+    let posted: Value = post(json!({ "text": "Maintenance in 10 minutes", "severity": "warning" })).await.unwrap().json().await.unwrap();
+    assert!(posted["rooms"].as_u64().unwrap() >= 2, "{}", posted);
+    for socket in [&mut alice, &mut bob] {
+        let notice = socket.recv_type("Notice").await;
+        assert_eq!(notice["data"]["text"], "Maintenance in 10 minutes");
+        assert_eq!(notice["data"]["severity"], "warning");
+    }
+    let only_class = post(json!({ "text": "Class ends soon", "room": class })).await.unwrap();
+    assert_eq!(only_class.status(), 201);
+    assert_eq!(bob.recv_type("Notice").await["data"]["text"], "Class ends soon");
+
+    // Joiners get what's still up, oldest first
+    let mut carol = server.join(&class).await;
+    assert_eq!(carol.recv_type("Notice").await["data"]["text"], "Maintenance in 10 minutes");
+    assert_eq!(carol.recv_type("Notice").await["data"]["text"], "Class ends soon");
+    let mut dave = server.join(&lobby).await;
+    assert_eq!(dave.recv_type("Notice").await["data"]["severity"], "warning");
+    // Never the other room's
+    post(json!({ "text": "All done" })).await.unwrap();
+    for socket in [&mut alice, &mut dave] {
+        assert_eq!(socket.recv_type("Notice").await["data"]["text"], "All done");
+    }
+}
+
+#[tokio::test]
+async fn rooms_can_go_by_signed_ids_that_cant_be_guessed() {
+    let server = TestServer::with_config("[public_ids]\nscheme = \"signed\"\nsecret = \"not-so-secret\"\n");
+    let room = server.post("/api/rooms", &json!({ "name": "Planning" })).await["id"].as_str().expect("public id").to_string();
+    let mut alice = server.join(&room).await;
+    let mut bob = server.join(&room).await;
+    alice.send(&draw(1)).await;
+    assert_eq!(bob.recv_type("Draw").await, stamped(draw(1), 0));
+    assert_eq!(server.get(&format!("/api/rooms/{}/stats", room)).await["id"], room.as_str());
+
+    // Anything the server didn't hand out is no room at all
+    let mut forged = room.clone().into_bytes();
+    forged[0] = if forged[0] == b'A' { b'B' } else { b'A' };
+    for guess in [String::from_utf8(forged).unwrap(), room_id("guess"), "0".repeat(32)] {
+        let notes = reqwest::get(server.http_url(&format!("/api/rooms/{}/notes", guess))).await.unwrap();
+        assert_eq!(notes.status(), 400, "{}", guess);
+        alice.send(&json!({ "type": "SwitchRoom", "data": { "room_id": guess } })).await;
+        assert_eq!(alice.recv_type("Error").await["data"]["code"], "invalid_room");
+    }
+    // The default room is the same for everyone anyway
+    server.join("default").await;
+}
+
+#[tokio::test]
+async fn bots_say_what_they_are_and_keep_out_of_the_count() {
+    let server = TestServer::with_config(
+        "[rooms]\nmax_participants = 1\nwaitlist = false\n\n[bots]\nmax_per_room = 1\nexclude_from_capacity = true\nexclude_from_contributions = true\n",
+    );
+    let room = room_id("bots");
+    let mut alice = server.join(&room).await;
+    // The room is full of people but still has a slot for its bot
+    let mut bot = server.join_at(&format!("/room/{}?bot=1&agent=grading-bot/2.1", room)).await;
+    let joined = alice.recv_type("Joined").await;
+    assert_eq!(joined["data"]["user_id"], json!(bot.user_id));
+    assert_eq!(joined["data"]["agent"], "grading-bot/2.1");
+    assert_eq!(joined["data"]["is_bot"], true);
+
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/room/{}?bot=1", server.addr, room)).await;
+    assert!(refused.is_err(), "joined past bots.max_per_room");
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/room/{}", server.addr, room)).await;
+    assert!(refused.is_err(), "joined a full room");
+
+    bot.send(&draw(1)).await;
+    assert_eq!(alice.recv_type("Draw").await, stamped(draw(1), 0));
+    alice.send(&draw(2)).await;
+    assert_eq!(bot.recv_type("Draw").await, stamped(draw(2), 0));
+    let contributions = server.get(&format!("/api/rooms/{}/contributions", room)).await;
+    let users = contributions["users"].as_array().unwrap();
+    assert_eq!(users.len(), 1, "{}", contributions);
+    assert_eq!(users[0]["user_id"], json!(alice.user_id));
+}
+
+#[tokio::test]
+async fn proxy_headers_are_only_believed_from_trusted_proxies() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Nobody's trusted, so whoever connects is served as themselves
+    let server = TestServer::with_config("[server]\nproxy_protocol = true\n");
+    assert_eq!(reqwest::get(server.http_url("/healthz")).await.expect("request").status(), 200);
+    let mut conn = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    conn.write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\nGET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use whiteboard_client::{Client, Composite, DrawCommand, Event, MessageType};
+
+/// Draws a diagonal line, then prints everything drawn in the room.
+/// `cargo run -p whiteboard-client --example bot -- ws://localhost:8000 <room>`
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let server = args.next().unwrap_or_else(|| "ws://localhost:8000".to_string());
+    let room = args.next().unwrap_or_else(|| "bots".to_string());
+    let mut client = Client::builder(server).room(room).max_backoff(Duration::from_secs(5)).connect().await?;
+
+    for step in 0..10 {
+        let at = |i: i32| (i * 20) as f64;
+        client.send(&MessageType::Draw(DrawCommand {
+            prev: [at(step), at(step)],
+            cur: [at(step + 1), at(step + 1)],
+            color: "#000000".to_string(),
+            brush_size: 4,
+            composite: Composite::SourceOver,
+        }))?;
+    }
+
+    while let Some(event) = client.next_event().await {
+        match event {
+            Event::Connected => println!("connected, replaying the board"),
+            Event::Op(op) => println!("{:?}", op),
+            Event::Error(error) => println!("error {}: {}", error.code, error.message),
+            Event::Disconnected { reason } => println!("disconnected: {}", reason),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,458 @@
+mod protocol;
+
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+pub use protocol::{Composite, DrawCommand, MessageType, ServerError};
+use protocol::Incoming;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+// Our first frame on every connection, for servers that close sockets which stay silent
+const HELLO: &str = r#"{"type":"Hello"}"#;
+
+/// What happened on the connection, in the order it happened
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Joined the room. The server replays the whole board as `Op`s next, so after a reconnect
+    /// whatever was drawn locally should be thrown away
+    Connected,
+    /// An op drawn in the room by someone else, the server doesn't echo our own
+    Op(MessageType),
+    /// The server refused one of our frames
+    Error(ServerError),
+    /// Lost the connection. Unless reconnecting is off or the server refused us, `Connected` follows once it's back
+    Disconnected { reason: String },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Url(String),
+    /// The server answered the upgrade with an error, e.g. 401 for a missing access key
+    Refused { status: u16, body: String },
+    WebSocket(Box<tungstenite::Error>),
+    /// The client's connection task has stopped, after `Event::Disconnected` when it gave up
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Url(e) => write!(f, "invalid server url: {}", e),
+            Error::Refused { status, body } => write!(f, "server refused the connection with {}: {}", status, body),
+            Error::WebSocket(e) => write!(f, "websocket error: {}", e),
+            Error::Closed => write!(f, "client closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Http(response) => Error::Refused {
+                status: response.status().as_u16(),
+                body: String::from_utf8_lossy(response.body().as_deref().unwrap_or_default()).into_owned(),
+            },
+            e => Error::WebSocket(Box::new(e)),
+        }
+    }
+}
+
+impl Error {
+    /// Refusals that will be refused again, e.g. a bad key or room id, aren't retried
+    fn is_permanent(&self) -> bool {
+        matches!(self, Error::Refused { status, .. } if (400..500).contains(status) && *status != 429)
+    }
+}
+
+pub struct ClientBuilder {
+    server: String,
+    room: Option<String>,
+    key: Option<String>,
+    reconnect: bool,
+    max_backoff: Duration,
+}
+
+impl ClientBuilder {
+    /// `server` is the server's base url, e.g. `ws://localhost:8000`, `http(s)://` works too
+    pub fn new(server: impl Into<String>) -> Self {
+        ClientBuilder { server: server.into(), room: None, key: None, reconnect: true, max_backoff: Duration::from_secs(30) }
+    }
+
+    /// Room to join, the server's default room if not set
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// Access key, needed when the server sets `auth.access_keys`
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Reconnect when the connection drops, on by default
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Reconnect attempts back off from 500ms, doubling up to this
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    fn url(&self) -> Result<Url, Error> {
+        let mut url = Url::parse(&self.server).map_err(|e| Error::Url(e.to_string()))?;
+        let scheme = match url.scheme() {
+            "ws" | "http" => "ws",
+            "wss" | "https" => "wss",
+            scheme => return Err(Error::Url(format!("unsupported scheme {}", scheme))),
+        };
+        url.set_scheme(scheme).map_err(|_| Error::Url(format!("can't use {} with {}", scheme, self.server)))?;
+        url.path_segments_mut()
+            .map_err(|_| Error::Url(format!("{} can't be a base url", self.server)))?
+            .pop_if_empty()
+            .push("room")
+            .extend(&self.room);
+        if let Some(key) = &self.key {
+            url.query_pairs_mut().append_pair("key", key);
+        }
+        Ok(url)
+    }
+
+    /// Connect and join the room. Errors are only returned for this first attempt, later ones are retried
+    pub async fn connect(self) -> Result<Client, Error> {
+        let url = self.url()?;
+        let (socket, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+        log::info!("Connected to {}", url);
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let _ = events_tx.send(Event::Connected);
+        let task = tokio::spawn(run(socket, url, self, outgoing_rx, events_tx));
+        Ok(Client { outgoing, events, task })
+    }
+}
+
+/// A connection to one room, reconnecting in the background.
+/// Dropping it closes the connection
+pub struct Client {
+    outgoing: mpsc::UnboundedSender<Message>,
+    events: mpsc::UnboundedReceiver<Event>,
+    task: JoinHandle<()>,
+}
+
+impl Client {
+    pub fn builder(server: impl Into<String>) -> ClientBuilder {
+        ClientBuilder::new(server)
+    }
+
+    /// Connect to `room` on `server` with the default settings
+    pub async fn connect(server: impl Into<String>, room: impl Into<String>) -> Result<Client, Error> {
+        ClientBuilder::new(server).room(room).connect().await
+    }
+
+    /// Queue an op to draw. Ops queued while reconnecting are sent once the board has been replayed,
+    /// one being written when the connection drops is lost
+    pub fn send(&self, op: &MessageType) -> Result<(), Error> {
+        let text = serde_json::to_string(op).expect("ops serialize to JSON");
+        self.outgoing.send(Message::Text(text)).map_err(|_| Error::Closed)
+    }
+
+    /// The next event, `None` once the client has given up reconnecting
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Send what's queued, close the socket and wait for it to shut down
+    pub async fn close(self) {
+        drop(self.outgoing);
+        let _ = self.task.await;
+    }
+}
+
+/// Why a session ended
+enum Ended {
+    /// The `Client` was dropped or closed
+    Closed,
+    Lost(String),
+}
+
+async fn run(mut socket: Socket, url: Url, settings: ClientBuilder, mut outgoing: mpsc::UnboundedReceiver<Message>, events: mpsc::UnboundedSender<Event>) {
+    loop {
+        let reason = match session(&mut socket, &mut outgoing, &events).await {
+            Ended::Closed => return,
+            Ended::Lost(reason) => reason,
+        };
+        log::warn!("Lost connection to {}: {}", url, reason);
+        let _ = events.send(Event::Disconnected { reason });
+        if !settings.reconnect {
+            return;
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        socket = loop {
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                // Nobody is listening any more
+                _ = events.closed() => return,
+            }
+            match tokio_tungstenite::connect_async(url.as_str()).await.map_err(Error::from) {
+                Ok((socket, _)) => break socket,
+                Err(e) if e.is_permanent() => {
+                    log::error!("Giving up reconnecting to {}: {}", url, e);
+                    let _ = events.send(Event::Disconnected { reason: e.to_string() });
+                    return;
+                }
+                Err(e) => {
+                    backoff = (backoff * 2).min(settings.max_backoff);
+                    log::warn!("Could not reconnect to {}, retrying in {:?}: {}", url, backoff, e);
+                }
+            }
+        };
+        log::info!("Reconnected to {}", url);
+        let _ = events.send(Event::Connected);
+    }
+}
+
+async fn session(socket: &mut Socket, outgoing: &mut mpsc::UnboundedReceiver<Message>, events: &mpsc::UnboundedSender<Event>) -> Ended {
+    if let Err(e) = socket.send(Message::Text(HELLO.to_string())).await {
+        return Ended::Lost(e.to_string());
+    }
+    loop {
+        tokio::select! {
+            msg = socket.next() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => return Ended::Lost(e.to_string()),
+                    None => return Ended::Lost("connection closed".to_string()),
+                };
+                let text = match msg {
+                    Message::Text(text) => text,
+                    Message::Close(frame) => {
+                        let reason = frame.map(|f| format!("closed by server: {} {}", f.code, f.reason));
+                        return Ended::Lost(reason.unwrap_or_else(|| "closed by server".to_string()));
+                    }
+                    // Pings are answered by tungstenite
+                    _ => continue,
+                };
+                let event = match serde_json::from_str(&text) {
+                    Ok(Incoming::Draw(draw)) => Event::Op(MessageType::Draw(draw)),
+                    Ok(Incoming::Clear) => Event::Op(MessageType::Clear),
+                    Ok(Incoming::Erase(erase)) => Event::Op(MessageType::Draw(erase.into())),
+                    Ok(Incoming::Error(error)) => Event::Error(error),
+                    Err(e) => {
+                        log::debug!("Ignored a frame this client doesn't know: {}", e);
+                        continue;
+                    }
+                };
+                let _ = events.send(event);
+            }
+            msg = outgoing.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = socket.send(msg).await {
+                        return Ended::Lost(e.to_string());
+                    }
+                }
+                None => {
+                    let _ = socket.close(None).await;
+                    return Ended::Closed;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+    use tokio_tungstenite::tungstenite::http;
+
+    use super::*;
+
+    // How long an event the test waits for may take, reconnecting included
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A stand-in for the server on a port of its own, taking one socket at a time
+    struct Server {
+        listener: TcpListener,
+        // Paths and queries the client asked to upgrade
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Server {
+        async fn start() -> Self {
+            Server { listener: TcpListener::bind("127.0.0.1:0").await.unwrap(), requests: Arc::default() }
+        }
+
+        fn url(&self) -> String {
+            format!("http://{}", self.listener.local_addr().unwrap())
+        }
+
+        async fn accept(&self) -> WebSocketStream<TcpStream> {
+            self.answer(None).await.expect("upgraded")
+        }
+
+        /// Refuse the next upgrade with `status`
+        async fn refuse(&self, status: u16) {
+            assert!(self.answer(Some(status)).await.is_none());
+        }
+
+        // The callback's error type is tungstenite's
+        #[allow(clippy::result_large_err)]
+        async fn answer(&self, refuse: Option<u16>) -> Option<WebSocketStream<TcpStream>> {
+            let (stream, _) = self.listener.accept().await.unwrap();
+            let requests = self.requests.clone();
+            let callback = move |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                requests.lock().unwrap().push(request.uri().to_string());
+                match refuse {
+                    Some(status) => Err(http::Response::builder().status(status).body(Some(format!("refused with {}", status))).unwrap()),
+                    None => Ok(response),
+                }
+            };
+            let mut socket = tokio_tungstenite::accept_hdr_async(stream, callback).await.ok()?;
+            assert_eq!(recv(&mut socket).await, HELLO, "the client speaks first");
+            Some(socket)
+        }
+    }
+
+    async fn recv(socket: &mut WebSocketStream<TcpStream>) -> String {
+        match tokio::time::timeout(EVENT_TIMEOUT, socket.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => text,
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    async fn send(socket: &mut WebSocketStream<TcpStream>, frame: &str) {
+        socket.send(Message::Text(frame.to_string())).await.unwrap();
+    }
+
+    async fn next(client: &mut Client) -> Event {
+        tokio::time::timeout(EVENT_TIMEOUT, client.next_event()).await.expect("an event in time").expect("client still running")
+    }
+
+    fn line(n: u32) -> DrawCommand {
+        DrawCommand { prev: [0.0, 0.0], cur: [f64::from(n), 1.0], color: "#112233".to_string(), brush_size: n, composite: Composite::SourceOver }
+    }
+
+    #[test]
+    fn urls_name_the_room_and_key() {
+        let url = |builder: ClientBuilder| builder.url().map(|url| url.to_string());
+        assert_eq!(url(Client::builder("http://localhost:8000").room("class 7b").key("k&1")).unwrap(), "ws://localhost:8000/room/class%207b?key=k%261");
+        assert_eq!(url(Client::builder("https://example.com/board/")).unwrap(), "wss://example.com/board/room");
+        assert!(matches!(url(Client::builder("ftp://example.com")), Err(Error::Url(_))));
+        assert!(matches!(url(Client::builder("not a url")), Err(Error::Url(_))));
+    }
+
+    #[test]
+    fn refusals_map_to_errors_and_only_client_errors_are_permanent() {
+        let refused = |status: u16| Error::from(tungstenite::Error::Http(http::Response::builder().status(status).body(Some(b"nope".to_vec())).unwrap()));
+        match refused(401) {
+            Error::Refused { status, body } => assert_eq!((status, body.as_str()), (401, "nope")),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        assert!(refused(401).is_permanent());
+        assert!(refused(404).is_permanent());
+        assert!(!refused(429).is_permanent(), "rate limits pass");
+        assert!(!refused(503).is_permanent());
+        let closed = Error::from(tungstenite::Error::ConnectionClosed);
+        assert!(matches!(closed, Error::WebSocket(_)) && !closed.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn ops_go_both_ways_and_errors_come_back() {
+        let server = Server::start().await;
+        let (client, socket) = tokio::join!(Client::builder(server.url()).room("lobby").key("secret").connect(), server.accept());
+        let (mut client, mut socket) = (client.unwrap(), socket);
+        assert_eq!(server.requests.lock().unwrap()[..], ["/room/lobby?key=secret"]);
+        assert_eq!(next(&mut client).await, Event::Connected);
+
+        client.send(&MessageType::Draw(line(1))).unwrap();
+        client.send(&MessageType::Clear).unwrap();
+        assert_eq!(serde_json::from_str::<MessageType>(&recv(&mut socket).await).unwrap(), MessageType::Draw(line(1)));
+        assert_eq!(recv(&mut socket).await, r#"{"type":"Clear"}"#);
+
+        send(&mut socket, r##"{"type":"Draw","epoch":0,"data":{"prev":[0.0,0.0],"cur":[2.0,1.0],"color":"#112233","brush_size":2}}"##).await;
+        send(&mut socket, r#"{"type":"Roster","data":{"users":[]}}"#).await;
+        send(&mut socket, r#"{"type":"Erase","data":{"prev":[0.0,0.0],"cur":[3.0,1.0],"brush_size":3}}"#).await;
+        send(&mut socket, r#"{"type":"Error","data":{"code":"rate_limited","message":"slow down"}}"#).await;
+        assert_eq!(next(&mut client).await, Event::Op(MessageType::Draw(line(2))));
+        let erase = DrawCommand { color: "#ffffff".to_string(), composite: Composite::DestinationOut, ..line(3) };
+        assert_eq!(next(&mut client).await, Event::Op(MessageType::Draw(erase)), "frames it doesn't know are skipped");
+        assert_eq!(next(&mut client).await, Event::Error(ServerError { code: "rate_limited".to_string(), message: "slow down".to_string(), correlation_id: None }));
+
+        client.close().await;
+        assert!(matches!(socket.next().await, Some(Ok(Message::Close(_)))));
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_come_back_with_the_board_replayed() {
+        let server = Server::start().await;
+        let (client, socket) = tokio::join!(Client::connect(server.url(), "lobby"), server.accept());
+        let mut client = client.unwrap();
+        assert_eq!(next(&mut client).await, Event::Connected);
+        drop(socket);
+        assert!(matches!(next(&mut client).await, Event::Disconnected { .. }));
+
+        // Drawn while it's away, sent once it's back
+        client.send(&MessageType::Draw(line(1))).unwrap();
+        server.refuse(503).await;
+        let mut socket = server.accept().await;
+        assert_eq!(next(&mut client).await, Event::Connected);
+        send(&mut socket, r##"{"type":"Draw","data":{"prev":[0.0,0.0],"cur":[2.0,1.0],"color":"#112233","brush_size":2}}"##).await;
+        assert_eq!(next(&mut client).await, Event::Op(MessageType::Draw(line(2))));
+        assert_eq!(serde_json::from_str::<MessageType>(&recv(&mut socket).await).unwrap(), MessageType::Draw(line(1)));
+        assert_eq!(server.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refusals_end_the_client() {
+        let server = Server::start().await;
+        let (client, ()) = tokio::join!(Client::connect(server.url(), "lobby"), server.refuse(401));
+        match client {
+            Err(Error::Refused { status, body }) => assert_eq!((status, body.as_str()), (401, "refused with 401")),
+            _ => panic!("connected past a refusal"),
+        }
+
+        let (client, socket) = tokio::join!(Client::connect(server.url(), "lobby"), server.accept());
+        let mut client = client.unwrap();
+        assert_eq!(next(&mut client).await, Event::Connected);
+        drop(socket);
+        assert!(matches!(next(&mut client).await, Event::Disconnected { .. }));
+        server.refuse(404).await;
+        match next(&mut client).await {
+            Event::Disconnected { reason } => assert!(reason.contains("404"), "{}", reason),
+            other => panic!("expected to give up, got {:?}", other),
+        }
+        assert_eq!(client.next_event().await, None);
+        assert!(matches!(client.send(&MessageType::Clear), Err(Error::Closed)));
+    }
+
+    #[tokio::test]
+    async fn reconnecting_can_be_turned_off() {
+        let server = Server::start().await;
+        let (client, socket) = tokio::join!(Client::builder(server.url()).reconnect(false).connect(), server.accept());
+        let mut client = client.unwrap();
+        assert_eq!(server.requests.lock().unwrap()[..], ["/room"]);
+        assert_eq!(next(&mut client).await, Event::Connected);
+        drop(socket);
+        assert!(matches!(next(&mut client).await, Event::Disconnected { .. }));
+        assert_eq!(client.next_event().await, None);
+    }
+}
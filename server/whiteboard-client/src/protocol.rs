@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+/// An op on the board, the JSON frame `{"type": ..., "data": ...}` sent and received over the socket.
+/// An erase is a `Draw` with `Composite::DestinationOut`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "data")]
+pub enum MessageType {
+    Draw(DrawCommand),
+    Clear,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DrawCommand {
+    pub prev: [f64; 2],
+    pub cur: [f64; 2],
+    pub color: String,
+    pub brush_size: u32,
+    #[serde(default, skip_serializing_if = "Composite::is_default")]
+    pub composite: Composite,
+}
+
+/// How a stroke goes onto the board, named like the canvas `globalCompositeOperation`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Composite {
+    #[default]
+    SourceOver,
+    DestinationOut,
+}
+
+impl Composite {
+    fn is_default(&self) -> bool {
+        *self == Composite::SourceOver
+    }
+}
+
+/// An `Erase` from a server from before `composite`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct EraseCommand {
+    pub prev: [f64; 2],
+    pub cur: [f64; 2],
+    pub brush_size: u32,
+}
+
+impl From<EraseCommand> for DrawCommand {
+    fn from(erase: EraseCommand) -> Self {
+        DrawCommand { prev: erase.prev, cur: erase.cur, color: "#ffffff".to_string(), brush_size: erase.brush_size, composite: Composite::DestinationOut }
+    }
+}
+
+/// Sent by the server when it refuses one of our frames
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerError {
+    pub code: String,
+    pub message: String,
+    /// The frame that caused this, when the server echoes correlation ids
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+}
+
+/// Everything the server sends, ops relayed from the room and errors for us
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub(crate) enum Incoming {
+    Draw(DrawCommand),
+    Clear,
+    Erase(EraseCommand),
+    Error(ServerError),
+}